@@ -49,6 +49,17 @@ fn test_file_zlib_fast(b: &mut Bencher) {
     b.iter(|| deflate_bytes_zlib_conf(&test_data, CompressionOptions::fast()));
 }
 
+#[bench]
+fn test_file_zlib_best_prefetch(b: &mut Bencher) {
+    let test_data = get_test_data();
+    let options = CompressionOptions {
+        prefetch: true,
+        ..CompressionOptions::high()
+    };
+
+    b.iter(|| deflate_bytes_zlib_conf(&test_data, options));
+}
+
 #[bench]
 fn test_file_zlib_rle(b: &mut Bencher) {
     let test_data = get_test_data();
@@ -0,0 +1,112 @@
+//! A minimal HTTP server streaming gzip-compressed responses.
+//!
+//! The response is generated a piece at a time, and the encoder is flushed after each piece, so
+//! the client can decompress and use it right away instead of waiting for the encoder to have
+//! enough input to output a block.
+//!
+//! Run with `cargo run` in this directory, and try it with
+//! `curl --compressed --no-buffer http://127.0.0.1:3000/`.
+
+use std::io::{self, Write};
+use std::mem;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use deflate::write::GzEncoder;
+use deflate::Compression;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// The number of pieces in each response.
+const PIECES: usize = 10;
+/// The time it takes to generate each piece.
+const PIECE_DELAY: Duration = Duration::from_millis(500);
+
+type Sender = mpsc::Sender<io::Result<Vec<u8>>>;
+
+/// Generate a piece of the response.
+fn piece(n: usize) -> String {
+    format!(
+        "event {}: {}\n",
+        n,
+        "the quick brown fox jumps over the lazy dog ".repeat(4)
+    )
+}
+
+/// Check whether the client accepts gzip-compressed responses.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|coding| coding.split(';').next().unwrap_or("").trim() == "gzip")
+        })
+}
+
+/// Generate the response and send it to the client, compressed if `gzip` is `true`.
+async fn generate(gzip: bool, tx: &Sender) -> io::Result<()> {
+    // As latency matters more than size here, use the fast settings.
+    let mut encoder = if gzip {
+        Some(GzEncoder::new(Vec::new(), Compression::Fast))
+    } else {
+        None
+    };
+
+    for n in 0..PIECES {
+        tokio::time::sleep(PIECE_DELAY).await;
+        let data = piece(n);
+        let output = match encoder {
+            Some(ref mut encoder) => {
+                encoder.write_all(data.as_bytes())?;
+                // A sync flush outputs everything written so far, so the client can decompress
+                // it without waiting for the next piece.
+                encoder.flush()?;
+                mem::take(encoder.get_mut())
+            }
+            None => data.into_bytes(),
+        };
+        if tx.send(Ok(output)).await.is_err() {
+            // The client has gone away.
+            return Ok(());
+        }
+    }
+
+    if let Some(encoder) = encoder {
+        // Output the end of the stream and the gzip trailer.
+        let _ = tx.send(Ok(encoder.finish()?)).await;
+    }
+    Ok(())
+}
+
+async fn events(headers: HeaderMap) -> Response {
+    let gzip = accepts_gzip(&headers);
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        if let Err(e) = generate(gzip, &tx).await {
+            // Abort the response.
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+
+    let body = Body::from_stream(ReceiverStream::new(rx));
+    let content_type = (header::CONTENT_TYPE, "text/plain; charset=utf-8");
+    if gzip {
+        ([content_type, (header::CONTENT_ENCODING, "gzip")], body).into_response()
+    } else {
+        ([content_type], body).into_response()
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let app = Router::new().route("/", get(events));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await
+}
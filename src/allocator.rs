@@ -0,0 +1,191 @@
+//! This module handles allocating the large internal buffers of the compressor (the hash chains,
+//! the input window and the buffer of lz77 symbols), which make up most of its memory use.
+//!
+//! With the (nightly-only) `allocator_api` feature enabled, these can be allocated using a
+//! custom allocator instead of the global one, e.g for embedding the compressor in an
+//! environment where memory is handed out from an arena. Without it, they are allocated
+//! normally.
+
+#[cfg(feature = "allocator_api")]
+use std::alloc::{AllocError, Allocator, Global, Layout};
+#[cfg(feature = "allocator_api")]
+use std::fmt;
+#[cfg(feature = "allocator_api")]
+use std::ptr::NonNull;
+#[cfg(feature = "allocator_api")]
+use std::sync::Arc;
+
+/// A boxed value allocated by a `BufferAllocator`.
+#[cfg(feature = "allocator_api")]
+pub type BufferBox<T> = Box<T, BufferAllocator>;
+#[cfg(not(feature = "allocator_api"))]
+pub type BufferBox<T> = Box<T>;
+
+/// A vector allocated by a `BufferAllocator`.
+#[cfg(feature = "allocator_api")]
+pub type BufferVec<T> = Vec<T, BufferAllocator>;
+#[cfg(not(feature = "allocator_api"))]
+pub type BufferVec<T> = Vec<T>;
+
+/// Types for which a value with all bytes set to zero is valid and equal to the default value.
+///
+/// # Safety
+///
+/// Implementing this for a type where all zeroes is not a valid value is undefined behaviour.
+pub unsafe trait Zeroable: Default {}
+
+/// The allocator used for the large internal buffers of a compressor.
+///
+/// Wraps any [`Allocator`](https://doc.rust-lang.org/std/alloc/trait.Allocator.html), so the
+/// encoders don't need an extra type parameter for it. The default value uses the global
+/// allocator.
+///
+/// Only available with the `allocator_api` feature, which requires a nightly compiler.
+///
+/// # Examples
+///
+/// ```ignore
+/// #![feature(allocator_api)]
+/// use std::alloc::System;
+/// use std::io::Write;
+///
+/// use deflate::write::DeflateEncoder;
+/// use deflate::{BufferAllocator, Compression};
+///
+/// let mut encoder =
+///     DeflateEncoder::new_in(Vec::new(), Compression::Default, BufferAllocator::new(System));
+/// encoder.write_all(b"Some data").unwrap();
+/// let compressed = encoder.finish().unwrap();
+/// ```
+#[cfg(feature = "allocator_api")]
+#[derive(Clone, Default)]
+pub struct BufferAllocator {
+    /// The allocator to use, or `None` to use the global one.
+    inner: Option<Arc<dyn Allocator + Send + Sync>>,
+}
+
+#[cfg(not(feature = "allocator_api"))]
+#[derive(Clone, Default)]
+pub struct BufferAllocator {}
+
+#[cfg(feature = "allocator_api")]
+impl BufferAllocator {
+    /// Allocate the buffers using `allocator`.
+    pub fn new<A: Allocator + Send + Sync + 'static>(allocator: A) -> BufferAllocator {
+        BufferAllocator {
+            inner: Some(Arc::new(allocator)),
+        }
+    }
+
+    fn allocator(&self) -> &dyn Allocator {
+        match self.inner {
+            Some(ref allocator) => &**allocator,
+            None => &Global,
+        }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl Allocator for BufferAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator().allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator().allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // Memory is always deallocated by a clone of the allocator that allocated it, which
+        // shares the same inner allocator.
+        unsafe { self.allocator().deallocate(ptr, layout) }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl fmt::Debug for BufferAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BufferAllocator")
+            .field("custom", &self.inner.is_some())
+            .finish()
+    }
+}
+
+impl BufferAllocator {
+    /// Create an empty vector with space for `capacity` elements.
+    pub fn vec_with_capacity<T>(&self, capacity: usize) -> BufferVec<T> {
+        #[cfg(feature = "allocator_api")]
+        {
+            Vec::with_capacity_in(capacity, self.clone())
+        }
+        #[cfg(not(feature = "allocator_api"))]
+        {
+            Vec::with_capacity(capacity)
+        }
+    }
+
+    /// Create a box containing the default value of `T`, without creating the value on the stack
+    /// first.
+    pub fn boxed_default<T: Zeroable>(&self) -> BufferBox<T> {
+        #[cfg(feature = "allocator_api")]
+        {
+            // SAFETY: `T` is `Zeroable`, so the zeroed memory is a valid value.
+            unsafe { Box::new_zeroed_in(self.clone()).assume_init() }
+        }
+        #[cfg(not(feature = "allocator_api"))]
+        {
+            // Using default here is a trick to get around the lack of box syntax on stable rust.
+            //
+            // Box::new(value) can end up creating a temporary value on the stack which is not
+            // optimised away, but Box::default() allocates it directly on the heap.
+            Box::default()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "allocator_api"))]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+    use crate::write::DeflateEncoder;
+    use crate::Compression;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An allocator counting the number of bytes currently allocated through it.
+    #[derive(Default)]
+    struct CountingAllocator {
+        allocated: Arc<AtomicUsize>,
+    }
+
+    unsafe impl Allocator for CountingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.allocated.fetch_add(layout.size(), Ordering::SeqCst);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.allocated.fetch_sub(layout.size(), Ordering::SeqCst);
+            unsafe { Global.deallocate(ptr, layout) }
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let data = get_test_data();
+        let allocator = CountingAllocator::default();
+        let allocated = allocator.allocated.clone();
+
+        let mut encoder = DeflateEncoder::new_in(
+            Vec::new(),
+            Compression::Default,
+            BufferAllocator::new(allocator),
+        );
+        // The hash chains alone take up 128 KiB.
+        assert!(allocated.load(Ordering::SeqCst) > 128 * 1024);
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+        assert_eq!(allocated.load(Ordering::SeqCst), 0);
+    }
+}
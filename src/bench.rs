@@ -0,0 +1,128 @@
+//! Functions for measuring how fast the compressor runs on the current machine, e.g for quick
+//! self-benchmarks when qualifying a deployment on new hardware, without needing a benchmark
+//! harness.
+//!
+//! Only available with the `bench` feature.
+
+use std::time::{Duration, Instant};
+
+use crate::compression_options::CompressionOptions;
+use crate::pool::CompressorPool;
+
+/// The result of a benchmark run with [`run`](fn.run.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BenchResult {
+    /// The total number of bytes compressed, counting every pass over the corpus.
+    pub input_bytes: u64,
+    /// The total number of compressed bytes produced.
+    pub output_bytes: u64,
+    /// The number of passes over the corpus.
+    pub passes: u32,
+    /// The time spent compressing.
+    pub duration: Duration,
+}
+
+impl BenchResult {
+    /// The number of bytes of input compressed per second.
+    pub fn throughput(&self) -> f64 {
+        self.input_bytes as f64 / self.duration.as_secs_f64()
+    }
+
+    /// The compression ratio, i.e the size of the input divided by the size of the compressed
+    /// data, so higher is better.
+    pub fn ratio(&self) -> f64 {
+        self.input_bytes as f64 / self.output_bytes as f64
+    }
+}
+
+/// Compress every item of `corpus` with the given options as a separate DEFLATE stream, passing
+/// over the whole corpus repeatedly until at least `min_duration` has passed, and return the
+/// totals.
+///
+/// The corpus is compressed at least once. The same compressor and output buffer are reused for
+/// every item, so the results reflect the steady state of a long-running application rather
+/// than the cost of setting up a compressor for each item.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use deflate::bench;
+/// use deflate::Compression;
+///
+/// let corpus = [&b"This is some test data, this is some test data"[..]];
+/// let result = bench::run(&corpus, Compression::Default, Duration::from_millis(10));
+/// println!(
+///     "{:.1} MB/s, ratio {:.2}",
+///     result.throughput() / 1e6,
+///     result.ratio()
+/// );
+/// ```
+pub fn run<S: AsRef<[u8]>, O: Into<CompressionOptions>>(
+    corpus: &[S],
+    options: O,
+    min_duration: Duration,
+) -> BenchResult {
+    let pool = CompressorPool::new(options, 1);
+    let mut compressor = pool.get();
+    let mut out = Vec::new();
+    let mut result = BenchResult {
+        input_bytes: 0,
+        output_bytes: 0,
+        passes: 0,
+        duration: Duration::default(),
+    };
+
+    let start = Instant::now();
+    while result.passes == 0 || result.duration < min_duration {
+        for item in corpus {
+            let item = item.as_ref();
+            compressor.compress_to_buf(item, &mut out);
+            result.input_bytes += item.len() as u64;
+            result.output_bytes += out.len() as u64;
+        }
+        result.passes += 1;
+        result.duration = start.elapsed();
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::get_test_data;
+    use crate::{deflate_bytes_conf, Compression};
+
+    #[test]
+    fn bench_run() {
+        let data = get_test_data();
+        let corpus: Vec<&[u8]> = data.chunks(10000).collect();
+        let result = run(&corpus, Compression::Fast, Duration::from_millis(20));
+        assert!(result.passes >= 1);
+        assert!(result.duration >= Duration::from_millis(20));
+        assert_eq!(
+            result.input_bytes,
+            data.len() as u64 * u64::from(result.passes)
+        );
+
+        let pass_output: usize = corpus
+            .iter()
+            .map(|c| deflate_bytes_conf(c, Compression::Fast).len())
+            .sum();
+        assert_eq!(
+            result.output_bytes,
+            pass_output as u64 * u64::from(result.passes)
+        );
+        assert!(result.ratio() > 2.0);
+        assert!(result.throughput() > 0.0);
+    }
+
+    #[test]
+    fn bench_empty_corpus() {
+        let empty: &[&[u8]] = &[];
+        let result = run(empty, Compression::Default, Duration::from_secs(0));
+        assert_eq!(result.passes, 1);
+        assert_eq!(result.input_bytes, 0);
+    }
+}
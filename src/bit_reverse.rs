@@ -1,7 +1,32 @@
-/// Reverse the first length bits of n.
-/// (Passing more than 16 as length will produce garbage.
-pub fn reverse_bits(mut n: u16, length: u8) -> u16 {
-    debug_assert!(length <= 16);
+/// Reverse the lowest `length` bits of `n`, dropping any bits above them.
+///
+/// DEFLATE streams are written starting with the least significant bit of each byte, and most
+/// fields, like the block header and the extra bits of lengths and distances, are packed
+/// starting with their least significant bit. Huffman codes however are packed starting with
+/// their *most* significant bit (see section 3.1.1 of
+/// [RFC 1951](https://tools.ietf.org/html/rfc1951)), so they have to be reversed before they can
+/// be written with the same bit writer as everything else.
+///
+/// # Panics
+///
+/// Panics if `length` is larger than 16.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::reverse_bits;
+///
+/// // The fixed huffman code for the literal `a` (0x61) is the 8 bits 0b1001_0001.
+/// assert_eq!(reverse_bits(0b1001_0001, 8), 0b1000_1001);
+/// assert_eq!(reverse_bits(0b110, 3), 0b011);
+/// assert_eq!(reverse_bits(0b1111_0001, 4), 0b1000);
+/// assert_eq!(reverse_bits(0b1, 0), 0);
+/// ```
+pub const fn reverse_bits(mut n: u16, length: u8) -> u16 {
+    assert!(length <= 16, "Can't reverse more than 16 bits!");
+    if length == 0 {
+        return 0;
+    }
     // Borrowed from http://aggregate.org/MAGIC/#Bit%20Reversal
     n = ((n & 0xaaaa) >> 1) | ((n & 0x5555) << 1);
     n = ((n & 0xcccc) >> 2) | ((n & 0x3333) << 2);
@@ -20,8 +45,21 @@ mod test {
             reverse_bits(0b1100_1100_1100_1100, 16),
             0b0011_0011_0011_0011
         );
-        // Check that we ignore >16 length
-        // We no longer check for this.
-        // assert_eq!(reverse_bits(0b11001100_11001100, 32), 0b0011001100110011);
+        assert_eq!(reverse_bits(0b1, 1), 0b1);
+        assert_eq!(reverse_bits(0b10, 5), 0b01000);
+        assert_eq!(reverse_bits(0xffff, 0), 0);
+        // Reversing twice gives back the original bits.
+        for length in 0..=16u8 {
+            for &n in &[0u16, 1, 0x1234, 0xbeef, 0xffff] {
+                let mask = ((1u32 << length) - 1) as u16;
+                assert_eq!(reverse_bits(reverse_bits(n, length), length), n & mask);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_long() {
+        reverse_bits(0b1100_1100_1100_1100, 17);
     }
 }
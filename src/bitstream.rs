@@ -50,6 +50,9 @@ mod arch_dep {
 
 use self::arch_dep::*;
 
+/// The largest number of bits that can be written at once with `write_bits_wide`.
+pub const MAX_WIDE_BITS: u8 = 57;
+
 /// Writes bits to a byte stream, LSB first.
 pub struct LsbWriter {
     // Public for now so it can be replaced after initialization.
@@ -72,9 +75,45 @@ impl LsbWriter {
         self.bits
     }
 
+    /// The number of bits written so far, including the pending bits.
+    pub fn bit_position(&self) -> usize {
+        self.w.len() * 8 + usize::from(self.bits)
+    }
+
+    /// Get the bytes written from the one containing the bit at `position` (as returned by
+    /// `bit_position`) and on, including the pending bits, padded with zeroes to a whole byte.
+    pub fn bytes_from(&self, position: usize) -> Vec<u8> {
+        let start = position / 8;
+        let mut bytes = self.w.get(start..).unwrap_or(&[]).to_vec();
+        let mut acc = self.acc;
+        for n in 0..usize::from(self.bits.div_ceil(8)) {
+            if self.w.len() + n >= start {
+                bytes.push(acc as u8);
+            }
+            acc >>= 8;
+        }
+        bytes
+    }
+
+    /// Set the bit at `position` (as returned by `bit_position`), which has to be a bit that
+    /// has already been written.
+    pub fn set_bit(&mut self, position: usize) {
+        debug_assert!(position < self.bit_position());
+        let flushed = self.w.len() * 8;
+        if position >= flushed {
+            self.acc |= 1 << (position - flushed);
+        } else {
+            self.w[position / 8] |= 1 << (position % 8);
+        }
+    }
+
     /// Buffer n number of bits, and write them to the vec if there are enough pending bits.
+    ///
+    /// `v` must not have any bits set above the lowest `n` ones, as they would end up in the
+    /// bits written after them.
     pub fn write_bits(&mut self, v: u16, n: u8) {
-        // NOTE: This outputs garbage data if n is 0, but v is not 0
+        debug_assert!(n <= 16, "Can't write more than 16 bits at once!");
+        debug_assert_fits(v, n);
         self.acc |= (u64::from(v)) << self.bits;
         self.bits += n;
         // Waiting until we have FLUSH_AT bits and pushing them all in one batch.
@@ -85,8 +124,65 @@ impl LsbWriter {
         }
     }
 
+    /// Like `write_bits`, but for up to 32 bits at once, like a huffman code along with its
+    /// extra bits, or the codes of two literals.
+    #[inline]
+    pub fn write_bits_long(&mut self, v: u32, n: u8) {
+        debug_assert!(n <= 32, "Can't write more than 32 bits at once!");
+        debug_assert!(
+            u64::from(v).checked_shr(n.into()).unwrap_or(0) == 0,
+            "Value {} does not fit in {} bits!",
+            v,
+            n
+        );
+        if self.bits + n > 64 {
+            // There are more than 32 bits pending in this case, so make room for the new ones by
+            // writing out 32 of them.
+            self.w.extend_from_slice(&(self.acc as u32).to_le_bytes());
+            self.acc >>= 32;
+            self.bits -= 32;
+        }
+        self.acc |= (u64::from(v)) << self.bits;
+        self.bits += n;
+        while self.bits >= FLUSH_AT {
+            push!(self);
+            self.acc >>= FLUSH_AT;
+            self.bits -= FLUSH_AT;
+        }
+    }
+
+    /// Like `write_bits`, but for up to `MAX_WIDE_BITS` bits at once, like the codes of a run of
+    /// literals.
+    ///
+    /// To make room for the new bits, all the whole bytes pending are written out first if
+    /// needed, leaving at most 7 bits in the accumulator.
+    #[inline]
+    pub fn write_bits_wide(&mut self, v: u64, n: u8) {
+        debug_assert!(n <= MAX_WIDE_BITS, "Can't write more than 57 bits at once!");
+        debug_assert!(
+            v.checked_shr(n.into()).unwrap_or(0) == 0,
+            "Value {} does not fit in {} bits!",
+            v,
+            n
+        );
+        if self.bits + n > 64 {
+            let bytes = self.bits / 8;
+            self.w
+                .extend_from_slice(&self.acc.to_le_bytes()[..usize::from(bytes)]);
+            self.acc >>= bytes * 8;
+            self.bits -= bytes * 8;
+        }
+        self.acc |= v << self.bits;
+        self.bits += n;
+        while self.bits >= FLUSH_AT {
+            push!(self);
+            self.acc >>= FLUSH_AT;
+            self.bits -= FLUSH_AT;
+        }
+    }
+
     fn write_bits_finish(&mut self, v: u16, n: u8) {
-        // NOTE: This outputs garbage data if n is 0, but v is not 0
+        debug_assert_fits(v, n);
         self.acc |= (u64::from(v)) << self.bits;
         self.bits += n % 8;
         while self.bits >= 8 {
@@ -96,6 +192,30 @@ impl LsbWriter {
         }
     }
 
+    /// Check that the internal state is consistent: fewer than `FLUSH_AT` bits pending, and no
+    /// bits set in the accumulator above the pending ones.
+    pub fn check_state(&self) -> Result<(), &'static str> {
+        if self.bits >= FLUSH_AT {
+            Err("too many pending bits")
+        } else if self.acc.checked_shr(self.bits.into()).unwrap_or(0) != 0 {
+            Err("bits set above the pending bits")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write out the pending bits, padding the last byte with zeroes.
+    ///
+    /// Unlike `flush_raw`, this checks the state of the writer first, and returns an error
+    /// rather than writing corrupt output or panicking if it's not consistent.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.check_state()
+            .map_err(|e| io::Error::other(format!("Bit writer in an invalid state: {}", e)))?;
+        self.flush_raw();
+        debug_assert_eq!(self.bits, 0);
+        Ok(())
+    }
+
     pub fn flush_raw(&mut self) {
         let missing = FLUSH_AT - self.bits;
         // Have to test for self.bits > 0 here,
@@ -106,6 +226,17 @@ impl LsbWriter {
     }
 }
 
+/// Check that `v` only has bits set in the lowest `n` bits (in debug builds).
+#[inline(always)]
+fn debug_assert_fits(v: u16, n: u8) {
+    debug_assert!(
+        u32::from(v).checked_shr(n.into()).unwrap_or(0) == 0,
+        "Value {} does not fit in {} bits!",
+        v,
+        n
+    );
+}
+
 impl Write for LsbWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if self.acc == 0 {
@@ -119,14 +250,32 @@ impl Write for LsbWriter {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.flush_raw();
-        Ok(())
+        self.finish()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::LsbWriter;
+    use super::{LsbWriter, FLUSH_AT, MAX_WIDE_BITS};
+
+    #[test]
+    fn set_bit() {
+        let mut writer = LsbWriter::new(Vec::new());
+        writer.write_bits(0, 3);
+        for _ in 0..10 {
+            writer.write_bits(0, 8);
+        }
+        let position = writer.bit_position();
+        writer.write_bits(0, 5);
+        // Set one bit that has been pushed to the vector and one that is still pending.
+        writer.set_bit(1);
+        writer.set_bit(position);
+        writer.flush_raw();
+        let mut expected = vec![0; 11];
+        expected[0] = 0b10;
+        expected[position / 8] |= 1 << (position % 8);
+        assert_eq!(writer.w, expected);
+    }
 
     #[test]
     fn write_bits() {
@@ -176,6 +325,104 @@ mod test {
         writer.flush_raw();
         assert_eq!(writer.w, expected);
     }
+
+    #[test]
+    fn write_bits_long() {
+        // Writing values of up to 32 bits should give the same output as writing them in two
+        // parts, whatever the number of pending bits.
+        let mut long = LsbWriter::new(Vec::new());
+        let mut short = LsbWriter::new(Vec::new());
+        let mut v = 0x1234_5678u32;
+        for n in (0..=32u8).cycle().take(2000) {
+            v = v.rotate_left(7) ^ 0x9e37_79b9;
+            let value = v.checked_shr(32 - u32::from(n)).unwrap_or(0);
+            long.write_bits_long(value, n);
+            assert_eq!(long.check_state(), Ok(()));
+            let low = n.min(16);
+            short.write_bits((value & 0xffff) as u16, low);
+            short.write_bits((value >> 16) as u16, n - low);
+        }
+        long.flush_raw();
+        short.flush_raw();
+        assert_eq!(long.w, short.w);
+    }
+
+    #[test]
+    fn write_bits_wide() {
+        let mut wide = LsbWriter::new(Vec::new());
+        let mut short = LsbWriter::new(Vec::new());
+        let mut v = 0x1234_5678_9abc_def0u64;
+        for n in (0..=MAX_WIDE_BITS).cycle().take(2000) {
+            v = v.rotate_left(13) ^ 0x9e37_79b9_7f4a_7c15;
+            let value = v.checked_shr(64 - u32::from(n)).unwrap_or(0);
+            wide.write_bits_wide(value, n);
+            assert_eq!(wide.check_state(), Ok(()));
+            for i in (0..n).step_by(16) {
+                let len = (n - i).min(16);
+                short.write_bits(((value >> i) & ((1 << len) - 1)) as u16, len);
+            }
+        }
+        wide.flush_raw();
+        short.flush_raw();
+        assert_eq!(wide.w, short.w);
+    }
+
+    #[test]
+    fn bytes_from() {
+        let mut writer = LsbWriter::new(Vec::new());
+        for _ in 0..10 {
+            writer.write_bits(0xff, 8);
+        }
+        writer.write_bits(0b10101, 5);
+        // Part of the data has been pushed to the vector at this point.
+        assert!(!writer.w.is_empty() && writer.w.len() < 10);
+        assert_eq!(writer.bytes_from(0).len(), 11);
+        assert_eq!(writer.bytes_from(70), [0xff, 0xff, 0b10101]);
+        assert_eq!(writer.bytes_from(80), [0b10101]);
+        writer.write_bits(0b111, 3);
+        assert_eq!(writer.bytes_from(80), [0b11110101]);
+    }
+
+    #[test]
+    fn finish() {
+        let mut writer = LsbWriter::new(Vec::new());
+        writer.write_bits(0b101, 3);
+        assert_eq!(writer.check_state(), Ok(()));
+        writer.finish().unwrap();
+        assert_eq!(writer.w, [0b101]);
+        assert_eq!(writer.pending_bits(), 0);
+        // Finishing at a byte boundary doesn't output anything more.
+        writer.finish().unwrap();
+        assert_eq!(writer.w, [0b101]);
+
+        // Corrupt the state in the ways `check_state` looks for.
+        writer.write_bits(0b1, 2);
+        writer.acc |= 1 << 5;
+        assert!(writer.check_state().is_err());
+        assert!(writer.finish().is_err());
+        assert_eq!(writer.w, [0b101]);
+
+        let mut writer = LsbWriter::new(Vec::new());
+        writer.bits = FLUSH_AT;
+        assert!(writer.finish().is_err());
+        assert!(writer.w.is_empty());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not fit")]
+    fn write_bits_too_large() {
+        let mut writer = LsbWriter::new(Vec::new());
+        writer.write_bits(8, 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not fit")]
+    fn write_bits_zero_length() {
+        let mut writer = LsbWriter::new(Vec::new());
+        writer.write_bits(1, 0);
+    }
 }
 
 #[cfg(all(test, feature = "benchmarks"))]
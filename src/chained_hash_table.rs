@@ -1,3 +1,5 @@
+use crate::allocator::{BufferAllocator, BufferBox, Zeroable};
+
 pub const WINDOW_SIZE: usize = 32768;
 pub const WINDOW_MASK: usize = WINDOW_SIZE - 1;
 #[cfg(test)]
@@ -23,6 +25,9 @@ impl Default for Tables {
     }
 }
 
+// SAFETY: The tables only contain integers.
+unsafe impl Zeroable for Tables {}
+
 impl Tables {
     #[inline]
     fn fill_prev(&mut self) {
@@ -31,15 +36,10 @@ impl Tables {
 }
 
 /// Create and box the hash chains.
-fn create_tables() -> Box<Tables> {
-    // Using default here is a trick to get around the lack of box syntax on stable rust.
-    //
-    // Box::new([0u16,n]) ends up creating an temporary array on the stack which is not optimised
-    // but using default, which simply calls `box value` internally allows us to get around this.
-    //
+fn create_tables(alloc: &BufferAllocator) -> BufferBox<Tables> {
     // We could use vec instead, but using a boxed array helps the compiler optimise
     // away bounds checks as `n & WINDOW_MASK < WINDOW_SIZE` will always be true.
-    let mut t: Box<Tables> = Box::default();
+    let mut t = alloc.boxed_default::<Tables>();
 
     for (n, b) in t.head.iter_mut().enumerate() {
         *b = n as u16;
@@ -50,15 +50,33 @@ fn create_tables() -> Box<Tables> {
     t
 }
 
-/// Returns a new hash value based on the previous value and the next byte
 #[inline]
-pub fn update_hash(current_hash: u16, to_insert: u8) -> u16 {
-    update_hash_conf(current_hash, to_insert, HASH_SHIFT, HASH_MASK)
+fn update_hash_conf(current_hash: u16, to_insert: u16, shift: u16, mask: u16) -> u16 {
+    ((current_hash << shift) ^ to_insert) & mask
 }
 
-#[inline]
-fn update_hash_conf(current_hash: u16, to_insert: u8, shift: u16, mask: u16) -> u16 {
-    ((current_hash << shift) ^ (u16::from(to_insert))) & mask
+/// Create the table of values each byte is mapped to before being added to the hash.
+///
+/// Without a seed, each byte maps to itself. With a seed, each byte maps to a pseudo-random
+/// value using the full width of the hash, so which strings end up with the same hash value
+/// depends on the seed. As the hash is shifted by `HASH_SHIFT` for each byte, the hash still
+/// only depends on the last 3 bytes.
+fn byte_hashes(seed: u64) -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut state = seed;
+    for (n, value) in table.iter_mut().enumerate() {
+        *value = if seed == 0 {
+            n as u16
+        } else {
+            // SplitMix64
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            (z ^ (z >> 31)) as u16 & HASH_MASK
+        };
+    }
+    table
 }
 
 #[inline]
@@ -72,16 +90,25 @@ pub struct ChainedHashTable {
     // Current running hash value of the last 3 bytes
     current_hash: u16,
     // Hash chains.
-    c: Box<Tables>,
+    c: BufferBox<Tables>,
+    // The value each byte is mapped to when hashing.
+    byte_hashes: [u16; 256],
     // Used for testing
     // count: DebugCounter,
 }
 
 impl ChainedHashTable {
+    #[cfg(test)]
     pub fn new() -> ChainedHashTable {
+        ChainedHashTable::new_in(&BufferAllocator::default())
+    }
+
+    /// Create a new hash table, allocating the hash chains using `alloc`.
+    pub fn new_in(alloc: &BufferAllocator) -> ChainedHashTable {
         ChainedHashTable {
             current_hash: 0,
-            c: create_tables(),
+            c: create_tables(alloc),
+            byte_hashes: byte_hashes(0),
             //count: DebugCounter::default(),
         }
     }
@@ -89,11 +116,28 @@ impl ChainedHashTable {
     #[cfg(test)]
     pub fn from_starting_values(v1: u8, v2: u8) -> ChainedHashTable {
         let mut t = ChainedHashTable::new();
-        t.current_hash = update_hash(t.current_hash, v1);
-        t.current_hash = update_hash(t.current_hash, v2);
+        t.add_initial_hash_values(v1, v2);
         t
     }
 
+    /// Use a hash function seeded with `seed`, or the standard one if `seed` is 0.
+    ///
+    /// This has to be done before adding any values to the table.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.byte_hashes = byte_hashes(seed);
+    }
+
+    /// Returns a new hash value based on the previous value and the next byte
+    #[inline]
+    pub fn update_hash(&self, current_hash: u16, to_insert: u8) -> u16 {
+        update_hash_conf(
+            current_hash,
+            self.byte_hashes[usize::from(to_insert)],
+            HASH_SHIFT,
+            HASH_MASK,
+        )
+    }
+
     /// Resets the hash value and hash chains
     pub fn reset(&mut self) {
         self.current_hash = 0;
@@ -109,8 +153,8 @@ impl ChainedHashTable {
     }
 
     pub fn add_initial_hash_values(&mut self, v1: u8, v2: u8) {
-        self.current_hash = update_hash(self.current_hash, v1);
-        self.current_hash = update_hash(self.current_hash, v2);
+        self.current_hash = self.update_hash(self.current_hash, v1);
+        self.current_hash = self.update_hash(self.current_hash, v2);
     }
 
     /// Insert a byte into the hash table
@@ -129,7 +173,7 @@ impl ChainedHashTable {
         );
         // Storing the hash in a temporary variable here makes the compiler avoid the
         // bounds checks in this function.
-        let new_hash = update_hash(self.current_hash, value);
+        let new_hash = self.update_hash(self.current_hash, value);
 
         self.add_with_hash(position, new_hash);
 
@@ -174,6 +218,13 @@ impl ChainedHashTable {
         self.c.prev[bytes & WINDOW_MASK]
     }
 
+    /// Get a reference to the entry that `get_prev` reads for the position `bytes`, for
+    /// prefetching.
+    #[inline]
+    pub fn prev_entry(&self, bytes: usize) -> &u16 {
+        &self.c.prev[bytes & WINDOW_MASK]
+    }
+
     #[cfg(test)]
     #[inline]
     pub fn farthest_next(&self, match_pos: usize, match_len: usize) -> usize {
@@ -231,7 +282,7 @@ pub fn filled_hash_table(data: &[u8]) -> ChainedHashTable {
 
 #[cfg(test)]
 mod test {
-    use super::{filled_hash_table, ChainedHashTable};
+    use super::{filled_hash_table, ChainedHashTable, HASH_MASK};
 
     #[test]
     fn chained_hash() {
@@ -274,7 +325,6 @@ mod test {
     fn table_unique() {
         let mut test_data = Vec::new();
         test_data.extend(0u8..255);
-        test_data.extend(255u8..0);
         let hash_table = filled_hash_table(&test_data);
         let prev_pos = hash_table.get_prev(hash_table.current_head() as usize);
         // Since all sequences in the input are unique, there shouldn't be any previous values.
@@ -330,13 +380,33 @@ mod test {
         let end_byte = input[(window_size / 2) - 1 - 2];
         let mut iterations = 0;
         while pos > window_size16 && iterations < 5000 {
-            assert_eq!(input[pos as usize & window_size - 1], end_byte);
+            assert_eq!(input[pos as usize & (window_size - 1)], end_byte);
 
             pos = hash_table.get_prev(pos as usize);
             iterations += 1;
         }
     }
 
+    #[test]
+    fn seeded_hash() {
+        let unseeded = ChainedHashTable::new();
+        let mut seeded = ChainedHashTable::new();
+        seeded.set_seed(0x1234_5678);
+
+        let hash =
+            |t: &ChainedHashTable, bytes: &[u8]| bytes.iter().fold(0, |h, &b| t.update_hash(h, b));
+        // The hash should only depend on the last 3 bytes, whether it's seeded or not.
+        for t in &[&unseeded, &seeded] {
+            assert_eq!(hash(t, b"xyzabc"), hash(t, b"abc"));
+            assert_eq!(hash(t, b"\xff\xff\xffabc"), hash(t, b"abc"));
+            assert!(hash(t, b"abc") <= HASH_MASK);
+        }
+        // These collide in the unseeded hash, as flipping the lowest bit of the middle byte
+        // cancels out flipping the 6th bit of the last one.
+        assert_eq!(hash(&unseeded, b"abc"), hash(&unseeded, b"acC"));
+        assert_ne!(hash(&seeded, b"abc"), hash(&seeded, b"acC"));
+    }
+
     #[test]
     /// Ensure that the initial hash values are correct.
     fn initial_chains() {
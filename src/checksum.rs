@@ -1,7 +1,11 @@
-use adler32::RollingAdler32;
+use std::fmt;
+use std::hash::Hasher;
+
+use crate::cpu_dispatch::kernels;
+#[cfg(feature = "gzip")]
+use gzip_header::Crc;
 
 pub trait RollingChecksum {
-    fn update(&mut self, byte: u8);
     fn update_from_slice(&mut self, data: &[u8]);
     fn current_hash(&self) -> u32;
 }
@@ -15,15 +19,6 @@ impl NoChecksum {
 }
 
 impl RollingChecksum for NoChecksum {
-    fn update(&mut self, _: u8) {}
-    fn update_from_slice(&mut self, _: &[u8]) {}
-    fn current_hash(&self) -> u32 {
-        1
-    }
-}
-
-impl<'a> RollingChecksum for &'a mut NoChecksum {
-    fn update(&mut self, _: u8) {}
     fn update_from_slice(&mut self, _: &[u8]) {}
     fn current_hash(&self) -> u32 {
         1
@@ -31,41 +26,316 @@ impl<'a> RollingChecksum for &'a mut NoChecksum {
 }
 
 pub struct Adler32Checksum {
-    adler32: RollingAdler32,
+    hash: u32,
 }
 
 impl Adler32Checksum {
     pub fn new() -> Adler32Checksum {
-        Adler32Checksum {
-            adler32: RollingAdler32::new(),
-        }
+        Adler32Checksum { hash: 1 }
     }
 }
 
 impl RollingChecksum for Adler32Checksum {
-    fn update(&mut self, byte: u8) {
-        self.adler32.update(byte);
-    }
-
     fn update_from_slice(&mut self, data: &[u8]) {
-        self.adler32.update_buffer(data);
+        self.hash = (kernels().adler32)(self.hash, data);
     }
 
     fn current_hash(&self) -> u32 {
-        self.adler32.hash()
+        self.hash
     }
 }
 
-impl<'a> RollingChecksum for &'a mut Adler32Checksum {
-    fn update(&mut self, byte: u8) {
-        self.adler32.update(byte);
+#[cfg(feature = "gzip")]
+impl RollingChecksum for Crc {
+    fn update_from_slice(&mut self, data: &[u8]) {
+        self.update(data);
     }
 
+    fn current_hash(&self) -> u32 {
+        self.sum()
+    }
+}
+
+impl<RC: RollingChecksum> RollingChecksum for &mut RC {
     fn update_from_slice(&mut self, data: &[u8]) {
-        self.adler32.update_buffer(data);
+        (**self).update_from_slice(data);
     }
 
     fn current_hash(&self) -> u32 {
-        self.adler32.hash()
+        (**self).current_hash()
+    }
+}
+
+/// The Adler-32 checksum used by the zlib format, as a `Hasher`.
+///
+/// Only the lower 32 bits of the value returned by `finish` are used.
+#[derive(Clone)]
+pub struct Adler32 {
+    hash: u32,
+}
+
+impl Adler32 {
+    /// Create a new hasher with the initial checksum value.
+    pub fn new() -> Adler32 {
+        Adler32 { hash: 1 }
+    }
+}
+
+impl Default for Adler32 {
+    fn default() -> Adler32 {
+        Adler32::new()
+    }
+}
+
+impl fmt::Debug for Adler32 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Adler32").field("hash", &self.hash).finish()
+    }
+}
+
+impl Hasher for Adler32 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.hash = (kernels().adler32)(self.hash, bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        u64::from(self.hash)
+    }
+}
+
+/// The modulus used by the Adler-32 checksum.
+const ADLER32_BASE: u32 = 65521;
+
+/// Combine the Adler-32 checksums `adler1` of some data and `adler2` of `len2` bytes of data
+/// following it into the checksum of the two pieces together, without needing the data.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{adler32_combine, Adler32};
+/// use std::hash::Hasher;
+///
+/// let checksum = |data: &[u8]| {
+///     let mut adler32 = Adler32::new();
+///     adler32.write(data);
+///     adler32.finish() as u32
+/// };
+/// assert_eq!(
+///     adler32_combine(checksum(b"Wiki"), checksum(b"pedia"), 5),
+///     checksum(b"Wikipedia")
+/// );
+/// ```
+pub fn adler32_combine(adler1: u32, adler2: u32, len2: u64) -> u32 {
+    // Same as adler32_combine in zlib. The lower half is the sum of the bytes plus one, and the
+    // upper half the sum of the lower half after each byte, so each byte of the first piece
+    // (and the initial one) is counted `len2` more times in the upper half.
+    let rem = (len2 % u64::from(ADLER32_BASE)) as u32;
+    let mut sum1 = adler1 & 0xffff;
+    let mut sum2 = (rem * sum1) % ADLER32_BASE;
+    sum1 += (adler2 & 0xffff) + ADLER32_BASE - 1;
+    sum2 += (adler1 >> 16) + (adler2 >> 16) + ADLER32_BASE - rem;
+    if sum1 >= ADLER32_BASE {
+        sum1 -= ADLER32_BASE;
+    }
+    if sum1 >= ADLER32_BASE {
+        sum1 -= ADLER32_BASE;
+    }
+    if sum2 >= ADLER32_BASE << 1 {
+        sum2 -= ADLER32_BASE << 1;
+    }
+    if sum2 >= ADLER32_BASE {
+        sum2 -= ADLER32_BASE;
+    }
+    sum1 | (sum2 << 16)
+}
+
+/// The reversed CRC-32 polynomial.
+const CRC32_POLY: u32 = 0xedb8_8320;
+
+/// Multiply the polynomials `a` and `b` modulo the CRC-32 polynomial, with the bits in the
+/// reversed order used by the checksum.
+const fn multiply_mod_crc32_poly(a: u32, mut b: u32) -> u32 {
+    let mut m = 1 << 31;
+    let mut p = 0;
+    loop {
+        if a & m != 0 {
+            p ^= b;
+            if a & (m - 1) == 0 {
+                return p;
+            }
+        }
+        m >>= 1;
+        b = if b & 1 == 1 {
+            (b >> 1) ^ CRC32_POLY
+        } else {
+            b >> 1
+        };
+    }
+}
+
+/// Table of x^(2^n) modulo the CRC-32 polynomial, which repeats after 32 entries.
+const CRC32_X2N_TABLE: [u32; 32] = crc32_x2n_table();
+
+const fn crc32_x2n_table() -> [u32; 32] {
+    let mut table = [0; 32];
+    // x^1
+    let mut p = 1 << 30;
+    let mut n = 0;
+    while n < 32 {
+        table[n] = p;
+        p = multiply_mod_crc32_poly(p, p);
+        n += 1;
+    }
+    table
+}
+
+/// Combine the CRC-32 checksums `crc1` of some data and `crc2` of `len2` bytes of data
+/// following it into the checksum of the two pieces together, without needing the data.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{crc32_combine, Crc32};
+/// use std::hash::Hasher;
+///
+/// let checksum = |data: &[u8]| {
+///     let mut crc32 = Crc32::new();
+///     crc32.write(data);
+///     crc32.finish() as u32
+/// };
+/// assert_eq!(
+///     crc32_combine(checksum(b"1234"), checksum(b"56789"), 5),
+///     checksum(b"123456789")
+/// );
+/// ```
+pub fn crc32_combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    // Same as crc32_combine in zlib: appending `len2` bytes multiplies the checksum of the first
+    // piece by x^(8 * len2), which is put together from the table of powers of two.
+    let mut p = 1 << 31;
+    // Start at x^(2^3) as each byte is 8 bits.
+    let mut k = 3;
+    let mut n = len2;
+    while n != 0 {
+        if n & 1 == 1 {
+            p = multiply_mod_crc32_poly(CRC32_X2N_TABLE[k & 31], p);
+        }
+        n >>= 1;
+        k += 1;
+    }
+    multiply_mod_crc32_poly(p, crc1) ^ crc2
+}
+
+/// Table used for computing the CRC-32 checksum one byte at a time.
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 {
+                CRC32_POLY ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// The CRC-32 checksum used by the gzip format, as a `Hasher`.
+///
+/// Only the lower 32 bits of the value returned by `finish` are used.
+#[derive(Clone, Debug, Default)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    /// Create a new hasher with the initial checksum value.
+    pub fn new() -> Crc32 {
+        Crc32::default()
+    }
+}
+
+impl Hasher for Crc32 {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut crc = !self.crc;
+        for &b in bytes {
+            crc = CRC32_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8);
+        }
+        self.crc = !crc;
+    }
+
+    fn finish(&self) -> u64 {
+        u64::from(self.crc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn known_checksums() {
+        let mut adler32 = Adler32::new();
+        adler32.write(b"Wiki");
+        adler32.write(b"pedia");
+        assert_eq!(adler32.finish(), 0x11e6_0398);
+
+        let mut crc32 = Crc32::new();
+        assert_eq!(crc32.finish(), 0);
+        crc32.write(b"1234");
+        crc32.write(b"56789");
+        assert_eq!(crc32.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn combine_checksums() {
+        let data = crate::test_utils::get_test_data();
+        let adler32 = |data: &[u8]| {
+            let mut adler32 = Adler32::new();
+            adler32.write(data);
+            adler32.finish() as u32
+        };
+        let crc32 = |data: &[u8]| {
+            let mut crc32 = Crc32::new();
+            crc32.write(data);
+            crc32.finish() as u32
+        };
+        for &split in &[0, 1, 5552, 65521, 100_000, data.len()] {
+            let (first, second) = data.split_at(split);
+            let len2 = second.len() as u64;
+            assert_eq!(
+                adler32_combine(adler32(first), adler32(second), len2),
+                adler32(&data)
+            );
+            assert_eq!(
+                crc32_combine(crc32(first), crc32(second), len2),
+                crc32(&data)
+            );
+        }
+        // Data of all 0xff bytes gives the largest sums.
+        let ones = vec![0xff; 200_000];
+        assert_eq!(
+            adler32_combine(adler32(&ones[..70_000]), adler32(&ones[70_000..]), 130_000),
+            adler32(&ones)
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn crc32_matches_gzip() {
+        let data = crate::test_utils::get_test_data();
+        let mut crc32 = Crc32::new();
+        crc32.write(&data);
+        let mut crc = Crc::new();
+        crc.update(&data);
+        assert_eq!(crc32.finish(), u64::from(crc.sum()));
     }
 }
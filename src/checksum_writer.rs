@@ -0,0 +1,199 @@
+//! This module contains a writer adapter that computes a checksum of the data passing through
+//! it, for computing a checksum of the uncompressed or compressed data while compressing it.
+
+use std::hash::Hasher;
+use std::io::{self, Write};
+
+/// A writer that passes all data on to the wrapped writer, while computing a checksum of it.
+///
+/// Wrapping an encoder in this makes it possible to store a checksum of the uncompressed data
+/// along with the compressed data without a second pass over the data. Any
+/// [`Hasher`](https://doc.rust-lang.org/std/hash/trait.Hasher.html) can be used, e.g
+/// [`Adler32`](../struct.Adler32.html), [`Crc32`](../struct.Crc32.html), or a hasher for
+/// xxHash from another crate.
+///
+/// It can also be used as the writer an encoder outputs to, to compute a checksum of the
+/// compressed data as it is written out, e.g for the integrity metadata of an object store.
+/// The checksum is then available from the writer returned by `finish`.
+///
+/// Only the data actually accepted by the wrapped writer is added to the checksum. Note that
+/// the checksum of the uncompressed data is computed from the data before any pre-filters are
+/// applied, so it will differ from the checksum in the trailer of a zlib or gzip stream if a
+/// filter is used.
+///
+/// # Examples
+///
+/// Computing a checksum of the uncompressed data:
+///
+/// ```rust
+/// use std::io::Write;
+///
+/// use deflate::write::{ChecksumWriter, DeflateEncoder};
+/// use deflate::{Compression, Crc32};
+///
+/// let encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+/// let mut writer = ChecksumWriter::new(encoder, Crc32::new());
+/// writer.write_all(b"123456789").unwrap();
+/// assert_eq!(writer.checksum(), 0xcbf4_3926);
+/// let compressed = writer.into_inner().finish().unwrap();
+/// # let _ = compressed;
+/// ```
+///
+/// Computing a checksum of the compressed data:
+///
+/// ```rust
+/// use std::io::Write;
+///
+/// use deflate::write::{ChecksumWriter, DeflateEncoder};
+/// use deflate::{Compression, Crc32};
+///
+/// let output = ChecksumWriter::new(Vec::new(), Crc32::new());
+/// let mut encoder = DeflateEncoder::new(output, Compression::Default);
+/// encoder.write_all(b"Some data").unwrap();
+/// let output = encoder.finish().unwrap();
+/// let checksum = output.checksum();
+/// let compressed = output.into_inner();
+/// # let _ = (checksum, compressed);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ChecksumWriter<W: Write, H: Hasher> {
+    inner: W,
+    hasher: H,
+    bytes_written: u64,
+}
+
+impl<W: Write, H: Hasher> ChecksumWriter<W, H> {
+    /// Create a new writer passing data on to `inner`, adding it to the checksum computed by
+    /// `hasher`.
+    pub fn new(inner: W, hasher: H) -> ChecksumWriter<W, H> {
+        ChecksumWriter {
+            inner,
+            hasher,
+            bytes_written: 0,
+        }
+    }
+
+    /// The checksum of the data written so far.
+    pub fn checksum(&self) -> u64 {
+        self.hasher.finish()
+    }
+
+    /// The number of bytes written so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Get a reference to the wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the wrapped writer.
+    ///
+    /// Data written directly to the wrapped writer is not added to the checksum.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Consume the adapter, returning the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Consume the adapter, returning the wrapped writer and the hasher.
+    pub fn into_parts(self) -> (W, H) {
+        (self.inner, self.hasher)
+    }
+}
+
+impl<W: Write, H: Hasher> Write for ChecksumWriter<W, H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.write(&buf[..n]);
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::checksum::{Adler32, Crc32};
+    use crate::test_utils::{decompress_zlib, get_test_data};
+    use crate::write::ZlibEncoder;
+    use crate::Compression;
+
+    /// A writer that only accepts a few bytes at a time.
+    struct ShortWriter(Vec<u8>);
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn checksum_zlib_trailer() {
+        let data = get_test_data();
+        let encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        let mut writer = ChecksumWriter::new(encoder, Adler32::new());
+        for chunk in data.chunks(7000) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(writer.bytes_written(), data.len() as u64);
+        assert_eq!(writer.checksum(), u64::from(writer.get_ref().checksum()));
+
+        let checksum = writer.checksum() as u32;
+        let compressed = writer.into_inner().finish().unwrap();
+        assert!(decompress_zlib(&compressed) == data);
+        assert_eq!(compressed[compressed.len() - 4..], checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn checksum_compressed_output() {
+        let data = get_test_data();
+        let output = ChecksumWriter::new(ShortWriter(Vec::new()), Crc32::new());
+        let mut encoder = ZlibEncoder::new(output, Compression::Default);
+        for chunk in data.chunks(7000) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.flush().unwrap();
+        let output = encoder.finish().unwrap();
+
+        // Writing the output a few bytes at a time should not change it.
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        for chunk in data.chunks(7000) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.flush().unwrap();
+        let expected = encoder.finish().unwrap();
+
+        let compressed = &output.get_ref().0;
+        assert!(*compressed == expected);
+        assert!(decompress_zlib(compressed) == data);
+        assert_eq!(output.bytes_written(), compressed.len() as u64);
+        let mut crc32 = Crc32::new();
+        crc32.write(compressed);
+        assert_eq!(output.checksum(), crc32.finish());
+    }
+
+    #[test]
+    fn checksum_partial_writes() {
+        let mut writer = ChecksumWriter::new(ShortWriter(Vec::new()), Crc32::new());
+        assert_eq!(writer.write(b"123456789").unwrap(), 3);
+        writer.write_all(b"456789").unwrap();
+        let (inner, hasher) = writer.into_parts();
+        assert_eq!(inner.0, b"123456789");
+        assert_eq!(hasher.finish(), 0xcbf4_3926);
+    }
+}
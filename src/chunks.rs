@@ -0,0 +1,226 @@
+//! This module contains an adapter for compressing data from an iterator of chunks, such as
+//! what is received from a channel or a chunked download, into an iterator of compressed chunks.
+
+use std::{fmt, io, mem};
+
+use crate::checksum::NoChecksum;
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::writer::{compress_filtered, compress_until_done_filtered};
+
+const ERR_STR: &str = "Write error when writing to a Vec!";
+
+/// An iterator adapter compressing the chunks of data from a fallible iterator with DEFLATE
+/// compression, producing the compressed data in chunks of a given size.
+///
+/// Input is only pulled from the source when the compressed data already produced does not
+/// fill a whole output chunk, so memory use stays bounded by the size of the output chunks and
+/// the input chunks, rather than the whole stream. Every chunk except the last one is exactly
+/// `chunk_size` bytes long.
+///
+/// Errors from the source are passed on as they are, without ending the stream, so the caller
+/// can decide whether to keep going or stop. The stream is finished once the source runs out.
+/// Errors from compressing the data, e.g when it doesn't fit in
+/// [`BlockLimits::max_blocks`](struct.BlockLimits.html#structfield.max_blocks) blocks or
+/// `verify` finds a problem, end the stream, as the output is not usable after them.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::io;
+///
+/// use deflate::{CompressChunks, Compression};
+///
+/// let source = vec![Ok(b"Some ".to_vec()), Ok(b"test data".to_vec())];
+/// let mut compressed = Vec::new();
+/// for chunk in CompressChunks::new(source.into_iter(), Compression::Default, 8192) {
+///     let chunk: Vec<u8> = chunk?;
+///     compressed.extend_from_slice(&chunk);
+/// }
+/// # Ok::<(), io::Error>(())
+/// ```
+pub struct CompressChunks<I> {
+    source: I,
+    deflate_state: Box<DeflateState<Vec<u8>>>,
+    chunk_size: usize,
+    finished: bool,
+}
+
+impl<I, B> CompressChunks<I>
+where
+    I: Iterator<Item = io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    /// Create a new adapter compressing the data from `source` using the provided compression
+    /// options, and outputting it in chunks of `chunk_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn new<O: Into<CompressionOptions>>(
+        source: I,
+        options: O,
+        chunk_size: usize,
+    ) -> CompressChunks<I> {
+        assert!(chunk_size > 0, "Chunk size must be at least 1 byte!");
+        CompressChunks {
+            source,
+            deflate_state: Box::new(DeflateState::new(options.into(), Vec::new())),
+            chunk_size,
+            finished: false,
+        }
+    }
+
+    /// Get a reference to the source iterator.
+    pub fn get_ref(&self) -> &I {
+        &self.source
+    }
+
+    /// The number of compressed bytes that are ready to be output.
+    fn output_len(&self) -> usize {
+        self.deflate_state.inner.as_ref().map_or(0, |v| v.len())
+    }
+
+    /// Take up to `chunk_size` bytes of the compressed data that is ready.
+    fn take_chunk(&mut self) -> Vec<u8> {
+        let output = self.deflate_state.inner.as_mut().expect(ERR_STR);
+        if output.len() <= self.chunk_size {
+            // Hand over the whole buffer, and start a new one of the same size for what follows.
+            let capacity = output.capacity();
+            mem::replace(output, Vec::with_capacity(capacity))
+        } else {
+            output.drain(..self.chunk_size).collect()
+        }
+    }
+
+    fn compress(&mut self, mut input: &[u8]) -> io::Result<()> {
+        while !input.is_empty() {
+            match compress_filtered(input, &mut self.deflate_state, NoChecksum::new()) {
+                Ok(n) => input = &input[n..],
+                // Only means that the output buffer was full.
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<I, B> Iterator for CompressChunks<I>
+where
+    I: Iterator<Item = io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        while !self.finished && self.output_len() < self.chunk_size {
+            let res = match self.source.next() {
+                Some(Ok(data)) => self.compress(data.as_ref()),
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.finished = true;
+                    compress_until_done_filtered(
+                        &mut self.deflate_state,
+                        NoChecksum::new(),
+                        Flush::Finish,
+                    )
+                }
+            };
+            if let Err(e) = res {
+                // Drop the unusable output, so the stream ends here.
+                self.finished = true;
+                self.deflate_state.inner.as_mut().expect(ERR_STR).clear();
+                return Some(Err(e));
+            }
+        }
+
+        if self.output_len() > 0 {
+            Some(Ok(self.take_chunk()))
+        } else {
+            None
+        }
+    }
+}
+
+impl<I: fmt::Debug> fmt::Debug for CompressChunks<I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompressChunks")
+            .field("source", &self.source)
+            .field("options", &self.deflate_state.compression_options)
+            .field("chunk_size", &self.chunk_size)
+            .field("finished", &self.finished)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_random_data, get_test_data};
+    use crate::{BlockLimits, Compression, CompressionOptions};
+
+    fn compress_chunks(data: &[u8], input_size: usize, chunk_size: usize) -> Vec<Vec<u8>> {
+        let source = data.chunks(input_size).map(|c| Ok(c.to_vec()));
+        CompressChunks::new(source, Compression::Default, chunk_size)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn chunks_roundtrip() {
+        let data = get_test_data();
+        for &(input_size, chunk_size) in &[(1, 1000), (100, 1), (5000, 4096), (100000, 77)] {
+            let chunks = compress_chunks(&data, input_size, chunk_size);
+            let (last, rest) = chunks.split_last().unwrap();
+            assert!(rest.iter().all(|c| c.len() == chunk_size));
+            assert!(!last.is_empty() && last.len() <= chunk_size);
+            assert!(decompress_to_end(&chunks.concat()) == data);
+        }
+    }
+
+    #[test]
+    fn chunks_empty() {
+        let chunks = compress_chunks(&[], 10, 10);
+        assert_eq!(chunks.len(), 1);
+        assert!(decompress_to_end(&chunks[0]).is_empty());
+    }
+
+    #[test]
+    fn chunks_source_error() {
+        let data = get_test_data();
+        let source = vec![
+            Ok(&data[..5000]),
+            Err(io::Error::other("source error")),
+            Ok(&data[5000..]),
+        ];
+        let mut compressor = CompressChunks::new(source.into_iter(), Compression::Fast, 1 << 20);
+        assert!(compressor.next().unwrap().is_err());
+        assert!(!compressor.finished);
+        // The data from the source after the error is still compressed.
+        let compressed = compressor.next().unwrap().unwrap();
+        assert!(compressor.next().is_none());
+        assert!(compressor.next().is_none());
+        assert!(decompress_to_end(&compressed) == data);
+    }
+
+    #[test]
+    fn chunks_max_blocks_exceeded() {
+        let data = get_random_data(200_000);
+        let options = CompressionOptions {
+            block_limits: BlockLimits {
+                max_blocks: 1,
+                ..BlockLimits::default()
+            },
+            ..CompressionOptions::default()
+        };
+        let source = data.chunks(10000).map(Ok);
+        let mut compressor = CompressChunks::new(source, options, 1 << 20);
+        assert_eq!(
+            compressor.next().unwrap().unwrap_err().kind(),
+            io::ErrorKind::Other
+        );
+        assert!(compressor.next().is_none());
+    }
+}
@@ -1,15 +1,23 @@
 use std::io;
 use std::io::Write;
 
-use crate::bitstream::LsbWriter;
-use crate::deflate_state::DeflateState;
+#[cfg(test)]
+use crate::compression_options::CompressionOptions;
+use crate::compression_options::SpecialOptions;
+use crate::deflate_state::{DeflateState, LengthBuffers};
+use crate::encoder_metrics::BlockTimer;
 use crate::encoder_state::EncoderState;
-use crate::huffman_lengths::{gen_huffman_lengths, write_huffman_lengths, BlockType};
+use crate::entropy_backend::{flush_to_backend, EntropyBackend};
+use crate::fixed_table::FixedBackend;
+use crate::huffman_lengths::{
+    gen_huffman_lengths, write_huffman_lengths, BlockType, DynamicBlockHeader,
+};
+use crate::inflate;
 use crate::lz77::{lz77_compress_block, LZ77Status};
-use crate::lzvalue::LZValue;
-use crate::stored_block::{compress_block_stored, write_stored_header, MAX_STORED_BLOCK_LENGTH};
+use crate::output_writer::DynamicWriter;
+use crate::stored_block::{compress_block_stored, MAX_STORED_BLOCK_LENGTH};
 
-const LARGEST_OUTPUT_BUF_SIZE: usize = 1024 * 32;
+pub const LARGEST_OUTPUT_BUF_SIZE: usize = 1024 * 32;
 
 /// Flush mode to use when compressing input received in multiple steps.
 ///
@@ -29,34 +37,100 @@ pub enum Flush {
     Finish,
 }
 
-/// Write all the lz77 encoded data in the buffer using the specified `EncoderState`, and finish
-/// with the end of block code.
-pub fn flush_to_bitstream(buffer: &[LZValue], state: &mut EncoderState) {
-    for &b in buffer {
-        state.write_lzvalue(b.value());
-    }
-    state.write_end_of_block()
-}
-
 /// Compress the input data using only fixed huffman codes.
 ///
 /// Currently only used in tests.
 #[cfg(test)]
 pub fn compress_data_fixed(input: &[u8]) -> Vec<u8> {
-    use crate::lz77::lz77_compress;
+    let options = CompressionOptions {
+        special: SpecialOptions::ForceFixed,
+        ..CompressionOptions::default()
+    };
+    crate::deflate_bytes_conf(input, options)
+}
 
-    let mut state = EncoderState::fixed(Vec::new());
-    let compressed = lz77_compress(input).unwrap();
+/// Write the symbols in `lz77_writer`, covering `input_bytes` bytes of input, as a dynamic block
+/// with the header `header` and the code lengths in the huffman table of `state`, or as a fixed
+/// block if `header` is `None`.
+///
+/// If `check_header` is true, the header of a dynamic block is decoded again right after it's
+/// written, and an error is returned if it doesn't describe the intended code lengths.
+pub fn write_compressed_block(
+    state: &mut EncoderState,
+    lz77_writer: &mut DynamicWriter,
+    length_buffers: &LengthBuffers,
+    header: Option<&DynamicBlockHeader>,
+    input_bytes: u64,
+    final_block: bool,
+    check_header: bool,
+) -> io::Result<()> {
+    match header {
+        Some(header) => {
+            let block_start = state.writer.bit_position();
+            // Write the block header.
+            state.write_start_of_block(false, final_block);
+
+            // Output the lengths of the huffman codes used in this block.
+            write_huffman_lengths(
+                header,
+                &state.huffman_table,
+                length_buffers.length_buf.as_slice(),
+                &mut state.writer,
+            );
+            if check_header {
+                check_dynamic_header(state, block_start)?;
+            }
 
-    // We currently don't split blocks here(this function is just used for tests anyhow)
-    state.write_start_of_block(true, true);
-    flush_to_bitstream(&compressed, &mut state);
+            // Uupdate the huffman codes that will be used to encode the
+            // lz77-compressed data.
+            state.huffman_table.update_from_lengths();
 
-    state.flush();
-    state.reset(Vec::new())
+            // Write the huffman compressed data and the end of block marker.
+            flush_to_backend(lz77_writer.drain(), state);
+        }
+        None => {
+            // Write the block header for fixed code blocks.
+            state.write_start_of_block(true, final_block);
+
+            // Write the compressed data and the end of block marker, using the
+            // precomputed tables for the pre-defined static huffman codes.
+            flush_to_backend(
+                lz77_writer.drain(),
+                &mut FixedBackend::new(&mut state.writer),
+            );
+        }
+    }
+    state.add_block_input(input_bytes);
+    Ok(())
+}
+
+/// Check that the dynamic block header written from `block_start` decodes to the code lengths in
+/// the huffman table of `state`, so a bad header is caught before any symbols encoded with the
+/// codes are written.
+fn check_dynamic_header(state: &EncoderState, block_start: usize) -> io::Result<()> {
+    let written = state.writer.bytes_from(block_start);
+    let matches = match inflate::read_dynamic_header(&written, (block_start % 8) as u8) {
+        Ok((literal_lengths, distance_lengths)) => {
+            let (l_lengths, d_lengths) = state.huffman_table.get_lengths();
+            l_lengths[..literal_lengths.len()] == literal_lengths[..]
+                && l_lengths[literal_lengths.len()..].iter().all(|&l| l == 0)
+                && d_lengths[..distance_lengths.len()] == distance_lengths[..]
+                && d_lengths[distance_lengths.len()..].iter().all(|&l| l == 0)
+        }
+        Err(_) => false,
+    };
+    if !matches {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Verification failed: the dynamic block header does not decode to the intended code \
+             lengths",
+        ));
+    }
+    Ok(())
 }
 
-fn write_stored_block(input: &[u8], mut writer: &mut LsbWriter, final_block: bool) {
+/// Write `input` as stored blocks, or an empty stored block if there is no input.
+pub fn write_stored_block(input: &[u8], state: &mut EncoderState, final_block: bool) {
     // If the input is not zero, we write stored blocks for the input data.
     if !input.is_empty() {
         let mut i = input.chunks(MAX_STORED_BLOCK_LENGTH).peekable();
@@ -64,16 +138,110 @@ fn write_stored_block(input: &[u8], mut writer: &mut LsbWriter, final_block: boo
         while let Some(chunk) = i.next() {
             let last_chunk = i.peek().is_none();
             // Write the block header
-            write_stored_header(writer, final_block && last_chunk);
+            state.write_stored_header(final_block && last_chunk);
 
             // Write the actual data.
-            compress_block_stored(chunk, &mut writer).expect("Write error");
+            compress_block_stored(chunk, &mut state.writer).expect("Write error");
+            state.add_block_input(chunk.len() as u64);
         }
     } else {
         // If the input length is zero, we output an empty block. This is used for syncing.
-        write_stored_header(writer, final_block);
-        compress_block_stored(&[], &mut writer).expect("Write error");
+        state.write_stored_header(final_block);
+        compress_block_stored(&[], &mut state.writer).expect("Write error");
+    }
+}
+
+/// Return an error if writing `blocks` more blocks would exceed the maximum number of blocks
+/// set in the options.
+fn check_block_count<W: Write>(deflate_state: &DeflateState<W>, blocks: usize) -> io::Result<()> {
+    let max_blocks = deflate_state.compression_options.block_limits.max_blocks;
+    if max_blocks != 0
+        && deflate_state.encoder_state.blocks() + blocks as u64 > u64::from(max_blocks)
+    {
+        return Err(io::Error::other(
+            "The data does not fit in the maximum number of blocks",
+        ));
+    }
+    Ok(())
+}
+
+/// If verification is enabled, check that the blocks written since `start_bit` decompress to
+/// the next `input_len` bytes of input.
+fn verify_blocks<W: Write>(
+    deflate_state: &mut DeflateState<W>,
+    start_bit: usize,
+    input_len: u64,
+) -> io::Result<()> {
+    if let Some(ref mut verifier) = deflate_state.verifier {
+        let writer = &deflate_state.encoder_state.writer;
+        let compressed = writer.bytes_from(start_bit);
+        let end_bit = writer.bit_position() - start_bit / 8 * 8;
+        verifier.check_blocks(
+            &compressed,
+            (start_bit % 8) as u8,
+            end_bit as u64,
+            input_len as usize,
+        )?;
     }
+    Ok(())
+}
+
+/// The number of bits an empty fixed block takes up: the block header and the end of block code.
+const EMPTY_FIXED_BLOCK_BITS: u64 = 10;
+
+/// Find how to pad a stream at bit `position` with empty blocks, so it ends at a multiple of
+/// `alignment` bytes when followed by `trailer_len` more bytes.
+///
+/// Returns the number of non-final empty fixed blocks, and whether the final block should be an
+/// empty stored block rather than an empty fixed one.
+fn padding_blocks(position: u64, alignment: u64, trailer_len: u64) -> (u64, bool) {
+    let aligned = |end_bit: u64| (end_bit.div_ceil(8) + trailer_len) % alignment == 0;
+    // Adding fixed blocks moves the end of the stream ahead by one or two bytes at a time, and
+    // ending with a stored block instead fills in the gaps, so every alignment is reached
+    // within `alignment + 1` blocks.
+    (0..)
+        .find_map(|n: u64| {
+            let fixed_end = position + (n + 1) * EMPTY_FIXED_BLOCK_BITS;
+            let stored_end = (position + n * EMPTY_FIXED_BLOCK_BITS + 3).div_ceil(8) * 8 + 32;
+            if aligned(fixed_end) {
+                Some((n, false))
+            } else if aligned(stored_end) {
+                Some((n, true))
+            } else {
+                None
+            }
+        })
+        .expect("No padding found!")
+}
+
+/// End the stream with empty blocks, so that it's padded to a multiple of the output alignment.
+fn write_padding<W: Write>(deflate_state: &mut DeflateState<W>) -> io::Result<()> {
+    let alignment = u64::from(deflate_state.output_alignment);
+    let trailer_len = u64::from(deflate_state.trailer_len);
+    let position = deflate_state.encoder_state.output_bit_position();
+    if (position.div_ceil(8) + trailer_len) % alignment == 0
+        && deflate_state
+            .encoder_state
+            .set_last_block_final(deflate_state.output_buf_pos)
+    {
+        // Already aligned, so the last block can just be made the final one.
+        return Ok(());
+    }
+
+    let (empty_blocks, stored_final) = padding_blocks(position, alignment, trailer_len);
+    check_block_count(deflate_state, empty_blocks as usize + 1)?;
+    let es = &mut deflate_state.encoder_state;
+    for _ in 0..empty_blocks {
+        es.write_start_of_block(true, false);
+        FixedBackend::new(&mut es.writer).write_end_of_block();
+    }
+    if stored_final {
+        write_stored_block(&[], es, true);
+    } else {
+        es.write_start_of_block(true, true);
+        FixedBackend::new(&mut es.writer).write_end_of_block();
+    }
+    Ok(())
 }
 
 /// Inner compression function used by both the writers and the simple compression functions.
@@ -82,40 +250,39 @@ pub fn compress_data_dynamic_n<W: Write>(
     deflate_state: &mut DeflateState<W>,
     flush: Flush,
 ) -> io::Result<usize> {
+    if flush == Flush::Sync && deflate_state.synced && input.is_empty() {
+        // The sync flush has already been done, so all that is left is writing out the rest of
+        // the output, without adding another sync marker.
+        return write_pending_output(deflate_state).map(|()| 0);
+    }
+
+    deflate_state.detect_data_kind(input);
+
     let mut bytes_written = 0;
 
     let mut slice = input;
 
+    // Whether the last block written in this call was followed by a sync marker.
+    let mut marker_written = false;
+
     loop {
         let output_buf_len = deflate_state.output_buf().len();
-        let output_buf_pos = deflate_state.output_buf_pos;
         // If the output buffer has too much data in it already, flush it before doing anything
         // else.
         if output_buf_len > LARGEST_OUTPUT_BUF_SIZE {
-            let written = deflate_state
-                .inner
-                .as_mut()
-                .expect("Missing writer!")
-                .write(&deflate_state.encoder_state.inner_vec()[output_buf_pos..])?;
-
-            if written < output_buf_len.checked_sub(output_buf_pos).unwrap() {
-                // Only some of the data was flushed, so keep track of where we were.
-                deflate_state.output_buf_pos += written;
-            } else {
-                // If we flushed all of the output, reset the output buffer.
-                deflate_state.output_buf_pos = 0;
-                deflate_state.output_buf().clear();
-            }
+            let res = write_pending_output(deflate_state);
 
             if bytes_written == 0 {
+                res?;
                 // If the buffer was already full when the function was called, this has to be
                 // returned rather than Ok(0) to indicate that we didn't write anything, but are
-                // not done yet.
-                return Err(io::Error::new(
-                    io::ErrorKind::Interrupted,
-                    "Internal buffer full.",
-                ));
+                // not done yet. The callers retry on this kind of error, so it's created without a
+                // message to avoid allocating, as this happens regularly when compressing.
+                return Err(io::ErrorKind::Interrupted.into());
             } else {
+                // The input has been consumed at this point, so we have to report that even if
+                // writing failed. The error will be returned by the next call instead, as the
+                // output is still buffered.
                 return Ok(bytes_written);
             }
         }
@@ -132,12 +299,18 @@ pub fn compress_data_dynamic_n<W: Write>(
             &mut deflate_state.lz77_writer,
             flush,
         );
+        if let Some(ref mut verifier) = deflate_state.verifier {
+            verifier.add_input(&slice[..written]);
+        }
 
         // Bytes written in this call
         bytes_written += written;
         // Total bytes written since the compression process started
         // TODO: Should we realistically have to worry about overflowing here?
         deflate_state.bytes_written += written as u64;
+        if written > 0 {
+            deflate_state.set_pending_input();
+        }
 
         if status == LZ77Status::NeedInput {
             // If we've consumed all the data input so far, and we're not
@@ -151,7 +324,9 @@ pub fn compress_data_dynamic_n<W: Write>(
 
         // We need to check if this is the last block as the header will then be
         // slightly different to indicate this.
-        let last_block = deflate_state.lz77_state.is_last_block();
+        // When aligning the output, the final block is one of the padding blocks instead.
+        let final_block =
+            deflate_state.lz77_state.is_last_block() && deflate_state.output_alignment <= 1;
 
         let current_block_input_bytes = deflate_state.lz77_state.current_block_input_bytes();
 
@@ -163,85 +338,122 @@ pub fn compress_data_dynamic_n<W: Write>(
 
         let partial_bits = deflate_state.encoder_state.writer.pending_bits();
 
-        let res = {
-            let (l_freqs, d_freqs) = deflate_state.lz77_writer.get_frequencies();
-            let (l_lengths, d_lengths) =
-                deflate_state.encoder_state.huffman_table.get_lengths_mut();
-
-            gen_huffman_lengths(
-                l_freqs,
-                d_freqs,
-                current_block_input_bytes,
-                partial_bits,
-                l_lengths,
-                d_lengths,
-                &mut deflate_state.length_buffers,
-            )
-        };
-
-        // Check if we've actually managed to compress the input, and output stored blocks
-        // if not.
-        match res {
-            BlockType::Dynamic(header) => {
-                // Write the block header.
-                deflate_state
+        // A block without any input can only happen when flushing without any new input, or
+        // when finishing right after a block ended at the end of the input. There is no need to
+        // output it when syncing, as the sync marker is enough, and when finishing, the previous
+        // block can be marked as the final one instead if it hasn't been written out yet.
+        // Otherwise, an empty final block is output.
+        let skip_block = current_block_input_bytes == 0
+            && (!final_block
+                || deflate_state
                     .encoder_state
-                    .write_start_of_block(false, last_block);
-
-                // Output the lengths of the huffman codes used in this block.
-                write_huffman_lengths(
-                    &header,
-                    &deflate_state.encoder_state.huffman_table,
-                    &deflate_state.length_buffers.length_buf,
-                    &mut deflate_state.encoder_state.writer,
-                );
-
-                // Uupdate the huffman codes that will be used to encode the
-                // lz77-compressed data.
-                deflate_state
-                    .encoder_state
-                    .huffman_table
-                    .update_from_lengths();
-
-                // Write the huffman compressed data and the end of block marker.
-                flush_to_bitstream(
-                    deflate_state.lz77_writer.get_buffer(),
-                    &mut deflate_state.encoder_state,
-                );
-            }
-            BlockType::Fixed => {
-                // Write the block header for fixed code blocks.
-                deflate_state
-                    .encoder_state
-                    .write_start_of_block(true, last_block);
-
-                // Use the pre-defined static huffman codes.
-                deflate_state.encoder_state.set_huffman_to_fixed();
-
-                // Write the compressed data and the end of block marker.
-                flush_to_bitstream(
-                    deflate_state.lz77_writer.get_buffer(),
-                    &mut deflate_state.encoder_state,
-                );
-            }
-            BlockType::Stored => {
-                // If compression fails, output a stored block instead.
-
-                let start_pos = position.saturating_sub(current_block_input_bytes as usize);
-
-                assert!(
-                    position >= current_block_input_bytes as usize,
-                    "Error! Trying to output a stored block with forgotten data!\
+                    .set_last_block_final(deflate_state.output_buf_pos));
+        if !skip_block {
+            let timer = BlockTimer::start();
+            let block_start = deflate_state.encoder_state.writer.bit_position();
+            let output_capacity = deflate_state.encoder_state.inner_vec().capacity();
+            let res = if deflate_state.compression_options.special == SpecialOptions::ForceFixed {
+                BlockType::Fixed
+            } else {
+                let (l_freqs, d_freqs) = deflate_state.lz77_writer.get_frequencies();
+                let (l_lengths, d_lengths) =
+                    deflate_state.encoder_state.huffman_table.get_lengths_mut();
+
+                gen_huffman_lengths(
+                    l_freqs,
+                    d_freqs,
+                    current_block_input_bytes,
+                    partial_bits,
+                    deflate_state.compression_options.max_code_length,
+                    l_lengths,
+                    d_lengths,
+                    &mut deflate_state.length_buffers,
+                )
+            };
+
+            // Check if we've actually managed to compress the input, and output stored blocks
+            // if not.
+            match res {
+                BlockType::Dynamic(header) => {
+                    check_block_count(deflate_state, 1)?;
+                    write_compressed_block(
+                        &mut deflate_state.encoder_state,
+                        &mut deflate_state.lz77_writer,
+                        &deflate_state.length_buffers,
+                        Some(&header),
+                        current_block_input_bytes,
+                        final_block,
+                        deflate_state.verifier.is_some(),
+                    )?;
+                    deflate_state.metrics.record_block(
+                        inflate::BlockType::Dynamic,
+                        current_block_input_bytes,
+                        timer,
+                    );
+                }
+                BlockType::Fixed => {
+                    check_block_count(deflate_state, 1)?;
+                    write_compressed_block(
+                        &mut deflate_state.encoder_state,
+                        &mut deflate_state.lz77_writer,
+                        &deflate_state.length_buffers,
+                        None,
+                        current_block_input_bytes,
+                        final_block,
+                        deflate_state.verifier.is_some(),
+                    )?;
+                    deflate_state.metrics.record_block(
+                        inflate::BlockType::Fixed,
+                        current_block_input_bytes,
+                        timer,
+                    );
+                }
+                BlockType::Stored => {
+                    // If compression fails, output a stored block instead.
+
+                    let start_pos = position.saturating_sub(current_block_input_bytes as usize);
+
+                    assert!(
+                        position >= current_block_input_bytes as usize,
+                        "Error! Trying to output a stored block with forgotten data!\
                      if you encounter this error, please file an issue!"
-                );
+                    );
+
+                    check_block_count(
+                        deflate_state,
+                        (position - start_pos).div_ceil(MAX_STORED_BLOCK_LENGTH),
+                    )?;
+                    write_stored_block(
+                        &deflate_state.input_buffer.get_buffer()[start_pos..position],
+                        &mut deflate_state.encoder_state,
+                        final_block,
+                    );
+                    deflate_state.metrics.record_block(
+                        inflate::BlockType::Stored,
+                        current_block_input_bytes,
+                        timer,
+                    );
+                }
+            };
+
+            // Catch corruption of the bit writer state at the block where it happens, rather
+            // than when the output is decoded.
+            debug_assert_eq!(deflate_state.encoder_state.writer.check_state(), Ok(()));
+            verify_blocks(deflate_state, block_start, current_block_input_bytes)?;
+
+            // When aligning blocks, follow every block but the last one with a sync marker so
+            // the next one starts on a byte boundary. This also covers the marker of a sync flush.
+            marker_written =
+                deflate_state.aligned_blocks && !deflate_state.lz77_state.is_last_block();
+            if marker_written {
+                check_block_count(deflate_state, 1)?;
+                deflate_state.encoder_state.write_sync_marker();
+            }
 
-                write_stored_block(
-                    &deflate_state.input_buffer.get_buffer()[start_pos..position],
-                    &mut deflate_state.encoder_state.writer,
-                    flush == Flush::Finish && last_block,
-                );
+            if deflate_state.encoder_state.inner_vec().capacity() > output_capacity {
+                deflate_state.metrics.record_output_buffer_growth();
             }
-        };
+        }
 
         // Clear the current lz77 data in the writer for the next call.
         deflate_state.lz77_writer.clear();
@@ -253,51 +465,155 @@ pub fn compress_data_dynamic_n<W: Write>(
         if status == LZ77Status::Finished {
             // This flush mode means that there should be an empty stored block at the end.
             if flush == Flush::Sync {
-                write_stored_block(&[], &mut deflate_state.encoder_state.writer, false);
-            } else if !deflate_state.lz77_state.is_last_block() {
+                if !marker_written {
+                    check_block_count(deflate_state, 1)?;
+                    write_stored_block(&[], &mut deflate_state.encoder_state, false);
+                }
+                // If the wrapped writer does not accept all of the output at once, this function
+                // is called again to write out the rest of it.
+                deflate_state.synced = true;
+            } else if deflate_state.output_alignment > 1 {
+                write_padding(deflate_state)?;
+            } else if !deflate_state.lz77_state.is_last_block()
+                && !deflate_state
+                    .encoder_state
+                    .set_last_block_final(deflate_state.output_buf_pos)
+            {
                 // Make sure a block with the last block header has been output.
                 // Not sure this can actually happen, but we make sure to finish properly
                 // if it somehow does.
                 // An empty fixed block is the shortest.
+                check_block_count(deflate_state, 1)?;
                 let es = &mut deflate_state.encoder_state;
-                es.set_huffman_to_fixed();
                 es.write_start_of_block(true, true);
-                es.write_end_of_block();
+                FixedBackend::new(&mut es.writer).write_end_of_block();
             }
             break;
         }
     }
 
     // If we reach this point, the remaining data in the buffers is to be flushed.
-    deflate_state.encoder_state.flush();
+    deflate_state.encoder_state.flush()?;
     // Make sure we've output everything, and return the number of bytes written if everything
     // went well.
+    match write_pending_output(deflate_state) {
+        // As above, if some input was consumed, the error is returned by the next call.
+        Err(e) if bytes_written == 0 => Err(e),
+        _ => Ok(bytes_written),
+    }
+}
+
+/// Write as much of the data in the output buffer that has not been output yet as the wrapped
+/// writer will accept, and keep track of how far we got.
+pub fn write_pending_output<W: Write>(deflate_state: &mut DeflateState<W>) -> io::Result<()> {
     let output_buf_pos = deflate_state.output_buf_pos;
-    let written_to_writer = deflate_state
+    let pending = deflate_state.output_buf().len() - output_buf_pos;
+    if pending == 0 {
+        return Ok(());
+    }
+
+    let written = deflate_state
         .inner
         .as_mut()
         .expect("Missing writer!")
         .write(&deflate_state.encoder_state.inner_vec()[output_buf_pos..])?;
-    if written_to_writer
-        < deflate_state
-            .output_buf()
-            .len()
-            .checked_sub(output_buf_pos)
-            .unwrap()
-    {
-        deflate_state.output_buf_pos += written_to_writer;
+    deflate_state.metrics.record_output(written);
+    if written == 0 {
+        // Avoid getting stuck trying to write the same data forever.
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "Failed to write compressed data.",
+        ));
+    }
+
+    if written < pending {
+        // Only some of the data was flushed, so keep track of where we were.
+        deflate_state.output_buf_pos += written;
     } else {
         // If we sucessfully wrote all the data, we can clear the output buffer.
         deflate_state.output_buf_pos = 0;
-        deflate_state.output_buf().clear();
+        deflate_state.encoder_state.clear_output();
     }
-    Ok(bytes_written)
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_utils::{decompress_to_end, get_test_data};
+    use crate::test_utils::{decompress_to_end, get_random_data, get_test_data};
+
+    #[test]
+    fn padding() {
+        for position in 0..64 {
+            for alignment in 2..100 {
+                for &trailer_len in &[0, 4, 8] {
+                    let (blocks, stored_final) = padding_blocks(position, alignment, trailer_len);
+                    let end = if stored_final {
+                        (position + blocks * EMPTY_FIXED_BLOCK_BITS + 3).div_ceil(8) + 4
+                    } else {
+                        (position + (blocks + 1) * EMPTY_FIXED_BLOCK_BITS).div_ceil(8)
+                    };
+                    assert_eq!((end + trailer_len) % alignment, 0);
+                    assert!(blocks <= alignment + 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dynamic_header_check() {
+        let mut lz77_writer = DynamicWriter::new();
+        for &b in &get_test_data()[..5000] {
+            lz77_writer.write_literal(b);
+        }
+        lz77_writer.write_length_distance(20, 300);
+
+        let mut state = EncoderState::new(Vec::new());
+        let mut length_buffers = LengthBuffers::new();
+        let header = {
+            let (l_freqs, d_freqs) = lz77_writer.get_frequencies();
+            let (l_lengths, d_lengths) = state.huffman_table.get_lengths_mut();
+            match gen_huffman_lengths(
+                l_freqs,
+                d_freqs,
+                5020,
+                0,
+                15,
+                l_lengths,
+                d_lengths,
+                &mut length_buffers,
+            ) {
+                BlockType::Dynamic(header) => header,
+                _ => panic!("Expected a dynamic block"),
+            }
+        };
+
+        let block_start = state.writer.bit_position();
+        state.write_start_of_block(false, true);
+        write_huffman_lengths(
+            &header,
+            &state.huffman_table,
+            length_buffers.length_buf.as_slice(),
+            &mut state.writer,
+        );
+        assert!(check_dynamic_header(&state, block_start).is_ok());
+
+        // A header that doesn't match the code lengths the symbols would be encoded with.
+        state.huffman_table.get_lengths_mut().1[3] += 1;
+        let err = check_dynamic_header(&state, block_start).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dynamic_header_check_verify_mode() {
+        let data = get_test_data();
+        let options = CompressionOptions {
+            verify: true,
+            ..CompressionOptions::high()
+        };
+        let compressed = crate::deflate_bytes_conf(&data, options);
+        assert!(decompress_to_end(&compressed) == data);
+    }
 
     #[test]
     /// Test compressing a short string using fixed encoding.
@@ -319,6 +635,18 @@ mod test {
         assert_eq!(data, result);
     }
 
+    #[test]
+    fn final_block_marked_afterwards() {
+        // With this input, the block ends right at the end of the input, before the encoder
+        // knows it's the last one. That block should be marked as final afterwards rather than
+        // being followed by an empty final block.
+        let data = get_random_data(31744);
+        let compressed = crate::deflate_bytes_conf(&data, CompressionOptions::huffman_only());
+        // The data is incompressible, so it should be output as a single stored block.
+        assert_eq!(compressed.len(), data.len() + 5);
+        assert!(decompress_to_end(&compressed) == data);
+    }
+
     /// Test deflate example.
     ///
     /// Check if the encoder produces the same code as the example given by Mark Adler here:
@@ -332,7 +660,12 @@ mod test {
             0x73, 0x49, 0x4d, 0xcb, 0x49, 0x2c, 0x49, 0x55, 0x00, 0x11, 0x00,
         ];
         let compressed = compress_data_fixed(test_data);
-        assert_eq!(&compressed, &check);
+        assert_eq!(
+            &compressed,
+            &check,
+            "\n{}",
+            crate::inflate::dump(&compressed).unwrap()
+        );
         let decompressed = decompress_to_end(&compressed);
         assert_eq!(&decompressed, test_data)
     }
@@ -350,4 +683,123 @@ mod test {
         // Not using assert_eq here deliberately to avoid massive amounts of output spam.
         assert!(input == result);
     }
+
+    #[test]
+    fn fixed_multiple_blocks() {
+        use crate::inflate::{analyze, BlockType};
+
+        let input = get_test_data();
+        let compressed = compress_data_fixed(&input);
+        let blocks = analyze(&compressed).unwrap();
+        assert!(blocks.len() > 1);
+        assert!(blocks.iter().all(|b| b.block_type == BlockType::Fixed));
+        let (last, rest) = blocks.split_last().unwrap();
+        assert!(last.is_final);
+        assert!(rest.iter().all(|b| !b.is_final));
+        assert!(decompress_to_end(&compressed) == input);
+    }
+
+    #[test]
+    fn fixed_streaming_flush() {
+        use crate::inflate::{analyze, BlockType};
+        use crate::write::DeflateEncoder;
+        use std::io::Write;
+
+        let input = get_test_data();
+        let options = CompressionOptions {
+            special: SpecialOptions::ForceFixed,
+            ..CompressionOptions::default()
+        };
+        let mut encoder = DeflateEncoder::new(Vec::new(), options);
+        for chunk in input.chunks(20000) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+
+        let blocks = analyze(&compressed).unwrap();
+        // Apart from the sync markers, all the blocks should use fixed codes.
+        assert!(blocks
+            .iter()
+            .all(|b| b.block_type == BlockType::Fixed || b.output_len == 0));
+        assert_eq!(
+            blocks
+                .iter()
+                .filter(|b| b.block_type == BlockType::Stored)
+                .count(),
+            input.len().div_ceil(20000)
+        );
+        assert!(decompress_to_end(&compressed) == input);
+    }
+
+    /// Create data containing a match for every length, and for the shortest and longest
+    /// distance of every distance code, with random data around them so there are no other
+    /// matches to pick instead. Returns the data and the length/distance pairs added.
+    fn symbol_coverage_data() -> (Vec<u8>, Vec<(u16, u16)>) {
+        use crate::huffman_table::{
+            num_extra_bits_for_distance_code, DISTANCE_BASE, MAX_MATCH, MIN_MATCH,
+        };
+
+        let mut pairs: Vec<(u16, u16)> = (MIN_MATCH..=MAX_MATCH).map(|l| (l, 300)).collect();
+        for (code, &base) in DISTANCE_BASE.iter().enumerate() {
+            let extra = num_extra_bits_for_distance_code(code as u8);
+            pairs.push((8, base + 1));
+            pairs.push((8, base + (1 << extra)));
+        }
+
+        let random = get_random_data(1 << 21);
+        let mut random = random.iter().cloned();
+        let mut data = vec![random.next().unwrap()];
+        for &(length, distance) in &pairs {
+            let (length, distance) = (usize::from(length), usize::from(distance));
+            data.extend(random.by_ref().take(distance));
+            let source = data.len() - distance;
+            // Make sure the match can't be extended backwards or forwards.
+            if data[data.len() - 1] == data[source - 1] {
+                *data.last_mut().unwrap() ^= 0x55;
+            }
+            for i in 0..length {
+                data.push(data[source + i]);
+            }
+            let next = random.next().unwrap();
+            data.push(if next == data[source + length] {
+                next ^ 0x55
+            } else {
+                next
+            });
+        }
+        (data, pairs)
+    }
+
+    #[test]
+    fn all_length_distance_symbols() {
+        use crate::inflate::inflate;
+        use crate::lz77::lz77_compress;
+        use crate::lzvalue::LZType;
+        use std::collections::HashSet;
+
+        let (data, pairs) = symbol_coverage_data();
+
+        let found: HashSet<(u16, u16)> = lz77_compress(&data)
+            .unwrap()
+            .iter()
+            .filter_map(|v| match v.value() {
+                LZType::StoredLengthDistance(l, d) => Some((l.actual_length(), d)),
+                LZType::Literal(_) => None,
+            })
+            .collect();
+        for pair in &pairs {
+            assert!(found.contains(pair), "Match {:?} not found", pair);
+        }
+
+        let fixed = CompressionOptions {
+            special: SpecialOptions::ForceFixed,
+            ..CompressionOptions::high()
+        };
+        for &options in &[CompressionOptions::high(), fixed] {
+            let compressed = crate::deflate_bytes_conf(&data, options);
+            assert!(decompress_to_end(&compressed) == data);
+            assert!(inflate(&compressed).unwrap() == data);
+        }
+    }
 }
@@ -9,7 +9,15 @@
 //! of compression for the provided data.
 //!
 use crate::lz77::MatchingType;
-use std::convert::From;
+use crate::output_writer::MAX_BUFFER_LENGTH;
+use crate::pre_filter::PreFilter;
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::convert::{From, TryFrom};
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
 
 pub const HIGH_MAX_HASH_CHECKS: u16 = 1768;
 pub const HIGH_LAZY_IF_LESS_THAN: u16 = 128;
@@ -18,6 +26,14 @@ pub const HIGH_LAZY_IF_LESS_THAN: u16 = 128;
 pub const MAX_HASH_CHECKS: u16 = 32 * 1024;
 pub const DEFAULT_MAX_HASH_CHECKS: u16 = 128;
 pub const DEFAULT_LAZY_IF_LESS_THAN: u16 = 32;
+pub const DEFAULT_NICE_MATCH: u16 = 128;
+/// The longest huffman code the format allows.
+const LONGEST_CODE_LENGTH: u8 = crate::huffman_table::MAX_CODE_LENGTH as u8;
+/// The lowest limit on the length of the huffman codes that still leaves room for all the
+/// literal/length codes.
+const SHORTEST_CODE_LENGTH_LIMIT: u8 = 9;
+/// The maximum match length, searching for matches stops when a match this long is found.
+pub const MAX_NICE_MATCH: u16 = crate::huffman_table::MAX_MATCH;
 
 /// An enum describing the level of compression to be used by the encoder
 ///
@@ -27,11 +43,12 @@ pub const DEFAULT_LAZY_IF_LESS_THAN: u16 = 32;
 ///
 /// [See also `CompressionOptions`](./struct.CompressionOptions.html) which provides for
 /// tweaking the settings more finely.
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum Compression {
     /// Fast minimal compression (`CompressionOptions::fast()`).
     Fast,
     /// Default level (`CompressionOptions::default()`).
+    #[default]
     Default,
     /// Higher compression level (`CompressionOptions::high()`).
     ///
@@ -39,36 +56,265 @@ pub enum Compression {
     /// the encoder can do, but is meant to emulate the `Best` setting in the `Flate2`
     /// library.
     Best,
+    /// Pick the level from the start of the data, using
+    /// [`DataKind::detect`](./enum.DataKind.html#method.detect).
+    ///
+    /// The data that is looked at is the start of the first write to the encoder (or all of it,
+    /// if shorter than 4 KiB), so this works best when writing a reasonable amount at once. The
+    /// kind of data that was detected can be checked afterwards using e.g
+    /// [`DeflateEncoder::data_kind`](./write/struct.DeflateEncoder.html#method.data_kind).
+    Auto,
 }
 
-impl Default for Compression {
-    fn default() -> Compression {
-        Compression::Default
-    }
-}
-
-/// Enum allowing some special options (not implemented yet)!
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+/// Enum allowing some special options.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
 pub enum SpecialOptions {
     /// Compress normally.
+    #[default]
     Normal,
-    /// Force fixed huffman tables. (Unimplemented!).
-    _ForceFixed,
+    /// Force fixed huffman codes for all blocks, also where stored blocks or dynamic codes would
+    /// give smaller output.
+    ///
+    /// Decoding data using fixed codes can be a bit simpler, and they don't require outputting a
+    /// code table, so this can give smaller output for very short inputs.
+    ForceFixed,
     /// Force stored (uncompressed) blocks only. (Unimplemented!).
     _ForceStored,
+    /// Replace the matching settings (`max_hash_checks`, `lazy_if_less_than`, `nice_match` and
+    /// `matching_type`) with the ones for the kind of data detected at the start of the input,
+    /// see [`Compression::Auto`](./enum.Compression.html#variant.Auto).
+    Auto,
+}
+
+/// The maximum number of symbols (literals and length/distance pairs) in a block.
+pub const MAX_BLOCK_SYMBOLS: u16 = MAX_BUFFER_LENGTH as u16;
+
+/// Limits on the size of each block of compressed data.
+///
+/// A new block, with new huffman codes, is started as soon as any of the limits is reached.
+/// Smaller blocks let the codes adapt faster to changes in the data, at the cost of having to
+/// output the code tables more often. Which limit works best depends on the data, e.g token
+/// count works poorly for data with many long matches, as the blocks get very long.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{deflate_bytes_conf, BlockLimits, CompressionOptions};
+///
+/// let options = CompressionOptions {
+///     block_limits: BlockLimits {
+///         max_input_bytes: 64 * 1024,
+///         ..BlockLimits::default()
+///     },
+///     ..CompressionOptions::default()
+/// };
+/// let compressed = deflate_bytes_conf(b"This is some test data", options);
+/// # let _ = compressed;
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BlockLimits {
+    /// The maximum number of symbols (literals and length/distance pairs) in a block.
+    ///
+    /// * `0`: Use the maximum value.
+    /// * Values above [`MAX_BLOCK_SYMBOLS`](constant.MAX_BLOCK_SYMBOLS.html) are treated as
+    ///   the maximum.
+    /// * Default value: [`MAX_BLOCK_SYMBOLS`](constant.MAX_BLOCK_SYMBOLS.html)
+    pub max_symbols: u16,
+    /// End the block when it contains at least this many bytes of input.
+    ///
+    /// As a match can cover up to 258 bytes, a block may be slightly longer than this.
+    ///
+    /// * `0`: No limit.
+    /// * Default value: `0`
+    pub max_input_bytes: u32,
+    /// End the block when the estimated size of the compressed symbols in it reaches this many
+    /// bits.
+    ///
+    /// The size is estimated using the fixed huffman codes, as the actual codes are not known
+    /// until the block is done. It includes the end of block code, but not the code tables.
+    ///
+    /// * `0`: No limit.
+    /// * Default value: `0`
+    pub max_estimated_bits: u32,
+    /// The maximum number of blocks in the whole stream, for decoders that only support a
+    /// single block, or a few.
+    ///
+    /// This counts every block written, including the empty stored blocks output by sync
+    /// flushes. The other limits still end blocks early, so they should be left at their
+    /// defaults for this to be useful. As a block can't contain more than
+    /// [`MAX_BLOCK_SYMBOLS`](constant.MAX_BLOCK_SYMBOLS.html) symbols, or 65535 bytes if the
+    /// data is stored uncompressed, larger or less compressible data needs more blocks. If the
+    /// data doesn't fit, the encoders and adapters that return `io::Result` fail with an error,
+    /// after which the output is not usable, and the functions compressing a slice directly
+    /// panic. `FrameEncoder`, `CompressorPool` and `ZlibStreamSplitter` don't support this
+    /// limit and reject it when they are created, and so do the pipelined functions, as each
+    /// chunk is compressed on its own.
+    ///
+    /// * `0`: No limit.
+    /// * Default value: `0`
+    pub max_blocks: u32,
+}
+
+impl BlockLimits {
+    /// The default limits, where blocks are only limited by the maximum number of symbols.
+    pub const DEFAULT: BlockLimits = BlockLimits {
+        max_symbols: MAX_BLOCK_SYMBOLS,
+        max_input_bytes: 0,
+        max_estimated_bits: 0,
+        max_blocks: 0,
+    };
+}
+
+impl Default for BlockLimits {
+    fn default() -> BlockLimits {
+        BlockLimits::DEFAULT
+    }
+}
+
+/// The work limit used by [`MatchHardening::untrusted`](struct.MatchHardening.html#method.untrusted).
+const UNTRUSTED_MAX_WORK_PER_POSITION: u32 = 2048;
+
+/// Options protecting against input crafted to make the match finder as slow as possible.
+///
+/// By default, the match finder may walk up to `max_hash_checks` entries of a hash chain for each
+/// input position, and compare up to 258 bytes for each of them, so the worst case is about
+/// `max_hash_checks * 258` byte comparisons per input byte. As the hash function is fixed, input
+/// that fills a single hash chain with positions that almost match is easy to create.
+///
+/// With a work limit of `n`, the search at each position stops once about `n` bytes have been
+/// compared, which limits the worst case to `n + 258` comparisons per input byte, at the cost of
+/// missing some matches on data with very long hash chains. A random hash seed makes it hard to
+/// create input where different strings end up in the same hash chain.
+///
+/// When compressing untrusted input, e.g request bodies on a server, use
+/// [`MatchHardening::untrusted`](#method.untrusted). Note that using a random seed means the
+/// output is no longer the same for the same input.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{deflate_bytes_conf, CompressionOptions, MatchHardening};
+///
+/// let options = CompressionOptions {
+///     match_hardening: MatchHardening::untrusted(),
+///     ..CompressionOptions::default()
+/// };
+/// let compressed = deflate_bytes_conf(b"Some untrusted data", options);
+/// # let _ = compressed;
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct MatchHardening {
+    /// The maximum number of byte comparisons (and hash chain steps) to do when looking for a
+    /// match at a single input position.
+    ///
+    /// * `0`: No limit.
+    /// * Default value: `0`
+    pub max_work_per_position: u32,
+    /// The seed for the hash function used to find matches.
+    ///
+    /// * `0`: Use the standard, unseeded, hash function.
+    /// * Default value: `0`
+    pub hash_seed: u64,
+}
+
+impl MatchHardening {
+    /// No work limit and no hash seed.
+    pub const DEFAULT: MatchHardening = MatchHardening {
+        max_work_per_position: 0,
+        hash_seed: 0,
+    };
+
+    /// Settings suitable for compressing untrusted input: a work limit of 2048 comparisons per
+    /// position, and a randomly generated hash seed.
+    pub fn untrusted() -> MatchHardening {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(UNTRUSTED_MAX_WORK_PER_POSITION);
+        MatchHardening {
+            max_work_per_position: UNTRUSTED_MAX_WORK_PER_POSITION,
+            // 0 means no seed, so make sure we don't end up with that.
+            hash_seed: hasher.finish().max(1),
+        }
+    }
+}
+
+impl Default for MatchHardening {
+    fn default() -> MatchHardening {
+        MatchHardening::DEFAULT
+    }
+}
+
+/// Settings for reducing the number of hash checks while no matches are being found.
+///
+/// Parts of the input that don't compress, like already compressed files in an archive, make the
+/// match finder search the hash chains in vain at every position. With decay enabled, the number
+/// of hash checks is halved each time `miss_window` searches in a row come up empty, down to
+/// `min_checks`. It goes back to `max_hash_checks` as soon as a match is found, and at the start
+/// of each block.
+///
+/// This is a finer-grained version of picking a lower compression level for data that doesn't
+/// compress well, which helps inputs that mix compressible and incompressible parts.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{deflate_bytes_conf, ChainDecay, Compression, CompressionOptions};
+///
+/// let options = CompressionOptions {
+///     chain_decay: ChainDecay::adaptive(),
+///     ..CompressionOptions::from(Compression::Best)
+/// };
+/// let compressed = deflate_bytes_conf(b"Some mixed data", options);
+/// # let _ = compressed;
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ChainDecay {
+    /// The number of searches in a row without finding a match after which the number of hash
+    /// checks is halved.
+    ///
+    /// * `0`: Never reduce the number of hash checks.
+    /// * Default value: `0`
+    pub miss_window: u16,
+    /// The lowest number of hash checks to reduce to. Values below `1` are treated as `1`.
+    ///
+    /// * Default value: `4`
+    pub min_checks: u16,
+}
+
+impl ChainDecay {
+    /// No decay.
+    pub const DEFAULT: ChainDecay = ChainDecay {
+        miss_window: 0,
+        min_checks: 4,
+    };
+
+    /// Halve the number of hash checks every 64 searches in a row without a match, down to 4.
+    pub fn adaptive() -> ChainDecay {
+        ChainDecay {
+            miss_window: 64,
+            min_checks: 4,
+        }
+    }
 }
 
-impl Default for SpecialOptions {
-    fn default() -> SpecialOptions {
-        SpecialOptions::Normal
+impl Default for ChainDecay {
+    fn default() -> ChainDecay {
+        ChainDecay::DEFAULT
     }
 }
 
 pub const DEFAULT_OPTIONS: CompressionOptions = CompressionOptions {
     max_hash_checks: DEFAULT_MAX_HASH_CHECKS,
     lazy_if_less_than: DEFAULT_LAZY_IF_LESS_THAN,
+    nice_match: DEFAULT_NICE_MATCH,
     matching_type: MatchingType::Lazy,
     special: SpecialOptions::Normal,
+    pre_filter: PreFilter::NONE,
+    block_limits: BlockLimits::DEFAULT,
+    match_hardening: MatchHardening::DEFAULT,
+    chain_decay: ChainDecay::DEFAULT,
+    max_code_length: LONGEST_CODE_LENGTH,
+    prefetch: false,
+    verify: false,
 };
 
 /// A struct describing the options for a compressor or compression function.
@@ -99,6 +345,18 @@ pub struct CompressionOptions {
     ///
     /// * Default value: `32`
     pub lazy_if_less_than: u16,
+    /// Stop searching for a longer match once a match at least this long has been found.
+    ///
+    /// Lower values improve compression speed, at the cost of sometimes missing a longer match
+    /// further back, though matches this long are usually good enough.
+    ///
+    /// * `0` or `258`: Only stop searching when a match of the maximum length (`258`) is found.
+    ///
+    /// Values higher than `258` are treated the same as `258` by the encoders, but are rejected
+    /// by [`validate`](#method.validate).
+    ///
+    /// * Default value: `128`
+    pub nice_match: u16,
 
     // pub _decent_match: u16,
     /// Whether to use lazy or greedy matching.
@@ -117,6 +375,76 @@ pub struct CompressionOptions {
     /// Force fixed/stored blocks (Not implemented yet).
     /// * Default value: `SpecialOptions::Normal`
     pub special: SpecialOptions,
+    /// Filters to apply to the input data before compressing it.
+    ///
+    /// The filters are applied to the data covered by the zlib and gzip checksums, so the
+    /// decompressed data has to be passed through
+    /// [`PreFilter::reverse`](./struct.PreFilter.html#method.reverse) to get the original data.
+    ///
+    /// `FrameEncoder` and the pipelined functions don't support filters, and reject them.
+    ///
+    /// * Default value: No filters.
+    pub pre_filter: PreFilter,
+    /// Limits on the size of each block.
+    ///
+    /// [See `BlockLimits`](./struct.BlockLimits.html)
+    ///
+    /// * Default value: `BlockLimits::DEFAULT`
+    pub block_limits: BlockLimits,
+    /// Protection against input crafted to make compression slow.
+    ///
+    /// [See `MatchHardening`](./struct.MatchHardening.html)
+    ///
+    /// * Default value: `MatchHardening::DEFAULT`
+    pub match_hardening: MatchHardening,
+    /// Reducing the number of hash checks while no matches are being found.
+    ///
+    /// [See `ChainDecay`](./struct.ChainDecay.html)
+    ///
+    /// * Default value: `ChainDecay::DEFAULT` (no decay)
+    pub chain_decay: ChainDecay,
+    /// The longest the huffman codes for literals, lengths and distances in dynamic blocks can
+    /// be, in bits.
+    ///
+    /// Some hardware and table-based decoders are simpler with shorter codes, e.g 11 or 12 bits.
+    /// The code lengths are limited in the optimal way, but the limit does make the output a
+    /// bit larger for data where longer codes would be used.
+    ///
+    /// * `0` and values above `15`: Use the longest codes the format allows, `15`.
+    /// * `9...15`: Limit the codes to this length.
+    /// * Values below `9` are treated as `9`, the lowest limit that leaves room for all the
+    ///   literal/length codes.
+    /// * Default value: `15`
+    pub max_code_length: u8,
+    /// Whether to issue software prefetch hints for the next entry in the hash chain and the
+    /// data it points to while searching for matches.
+    ///
+    /// This can speed up compression of large inputs that are not already in the CPU cache,
+    /// such as memory-mapped files passed to
+    /// [`deflate_bytes_conf`](./fn.deflate_bytes_conf.html), at the cost of some extra
+    /// instructions for data that is. Only has an effect on x86 and x86-64.
+    ///
+    /// * Default value: `false`
+    pub prefetch: bool,
+    /// Whether to decompress each block right after it's output, and check that it gives back
+    /// the input, returning an error from the encoder if not.
+    ///
+    /// This is meant as a safeguard for data that is hard or impossible to recreate if it's
+    /// written incorrectly, e.g archives. The checking is done a block at a time, keeping a copy
+    /// of the input for the current block and the last 32 KiB of verified data, so the extra
+    /// memory needed does not depend on the size of the stream. It does make compression
+    /// noticeably slower.
+    ///
+    /// The header of each dynamic block is also decoded right after it's written, before the
+    /// block contents, and checked against the code lengths the block is encoded with.
+    ///
+    /// A failed check is returned as an error of kind `InvalidData` by the encoders and
+    /// adapters that return `io::Result`, and makes the functions compressing a slice directly
+    /// panic. `FrameEncoder`, `CompressorPool` and `ZlibStreamSplitter` don't support this and
+    /// reject it when they are created.
+    ///
+    /// * Default value: `false`
+    pub verify: bool,
 }
 
 // Some standard profiles for the compression options.
@@ -127,8 +455,16 @@ impl CompressionOptions {
         CompressionOptions {
             max_hash_checks: HIGH_MAX_HASH_CHECKS,
             lazy_if_less_than: HIGH_LAZY_IF_LESS_THAN,
+            nice_match: MAX_NICE_MATCH,
             matching_type: MatchingType::Lazy,
             special: SpecialOptions::Normal,
+            pre_filter: PreFilter::NONE,
+            block_limits: BlockLimits::DEFAULT,
+            match_hardening: MatchHardening::DEFAULT,
+            chain_decay: ChainDecay::DEFAULT,
+            max_code_length: LONGEST_CODE_LENGTH,
+            prefetch: false,
+            verify: false,
         }
     }
 
@@ -142,8 +478,16 @@ impl CompressionOptions {
         CompressionOptions {
             max_hash_checks: 1,
             lazy_if_less_than: 0,
+            nice_match: MAX_NICE_MATCH,
             matching_type: MatchingType::Greedy,
             special: SpecialOptions::Normal,
+            pre_filter: PreFilter::NONE,
+            block_limits: BlockLimits::DEFAULT,
+            match_hardening: MatchHardening::DEFAULT,
+            chain_decay: ChainDecay::DEFAULT,
+            max_code_length: LONGEST_CODE_LENGTH,
+            prefetch: false,
+            verify: false,
         }
     }
 
@@ -156,8 +500,16 @@ impl CompressionOptions {
         CompressionOptions {
             max_hash_checks: 0,
             lazy_if_less_than: 0,
+            nice_match: MAX_NICE_MATCH,
             matching_type: MatchingType::Greedy,
             special: SpecialOptions::Normal,
+            pre_filter: PreFilter::NONE,
+            block_limits: BlockLimits::DEFAULT,
+            match_hardening: MatchHardening::DEFAULT,
+            chain_decay: ChainDecay::DEFAULT,
+            max_code_length: LONGEST_CODE_LENGTH,
+            prefetch: false,
+            verify: false,
         }
     }
 
@@ -172,10 +524,110 @@ impl CompressionOptions {
         CompressionOptions {
             max_hash_checks: 0,
             lazy_if_less_than: 0,
+            nice_match: MAX_NICE_MATCH,
             matching_type: MatchingType::Lazy,
             special: SpecialOptions::Normal,
+            pre_filter: PreFilter::NONE,
+            block_limits: BlockLimits::DEFAULT,
+            match_hardening: MatchHardening::DEFAULT,
+            chain_decay: ChainDecay::DEFAULT,
+            max_code_length: LONGEST_CODE_LENGTH,
+            prefetch: false,
+            verify: false,
+        }
+    }
+
+    /// Check that the options are consistent, and that none of the values are out of range.
+    ///
+    /// The encoders created with `new` accept any options, and silently treat values that are
+    /// out of range as the closest valid one, and ignore options that have no effect with the
+    /// other ones. This can be used to catch such mistakes early, e.g when the options come from
+    /// a configuration file. The encoders also have a `try_new` constructor that checks the
+    /// options using this function.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use deflate::{CompressionOptions, ConfigError, MatchingType};
+    ///
+    /// let options = CompressionOptions {
+    ///     matching_type: MatchingType::Greedy,
+    ///     ..CompressionOptions::default()
+    /// };
+    /// assert_eq!(options.validate(), Err(ConfigError::LazyMatchingDisabled));
+    /// ```
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_hash_checks > MAX_HASH_CHECKS {
+            Err(ConfigError::MaxHashChecksTooHigh(self.max_hash_checks))
+        } else if self.lazy_if_less_than > MAX_NICE_MATCH {
+            Err(ConfigError::LazyIfLessThanTooHigh(self.lazy_if_less_than))
+        } else if self.matching_type == MatchingType::Greedy && self.lazy_if_less_than != 0 {
+            Err(ConfigError::LazyMatchingDisabled)
+        } else if self.nice_match > MAX_NICE_MATCH {
+            Err(ConfigError::NiceMatchTooHigh(self.nice_match))
+        } else if self.block_limits.max_symbols > MAX_BLOCK_SYMBOLS {
+            Err(ConfigError::MaxSymbolsTooHigh(
+                self.block_limits.max_symbols,
+            ))
+        } else if self.max_code_length != 0
+            && !(SHORTEST_CODE_LENGTH_LIMIT..=LONGEST_CODE_LENGTH).contains(&self.max_code_length)
+        {
+            Err(ConfigError::MaxCodeLengthOutOfRange(self.max_code_length))
+        } else if self.special == SpecialOptions::_ForceStored {
+            Err(ConfigError::Unsupported("forcing stored blocks"))
+        } else {
+            Ok(())
         }
     }
+
+    /// Get the options with the values that are out of range, or that mean "use the maximum",
+    /// replaced by the values the encoder actually uses.
+    ///
+    /// These are the options returned by e.g
+    /// [`DeflateEncoder::options`](write/struct.DeflateEncoder.html#method.options), which can be
+    /// logged and passed to an encoder again to compress the same way. The window size is always
+    /// 32 KiB, so it's not part of the options. The values are all in range, so the resolved
+    /// options pass [`validate`](#method.validate) unless they ask for an unsupported feature.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use deflate::{CompressionOptions, MAX_BLOCK_SYMBOLS};
+    ///
+    /// let options = CompressionOptions {
+    ///     nice_match: 0,
+    ///     ..CompressionOptions::default()
+    /// };
+    /// assert_eq!(options.resolved().nice_match, 258);
+    /// assert_eq!(options.resolved().block_limits.max_symbols, MAX_BLOCK_SYMBOLS);
+    /// ```
+    pub fn resolved(&self) -> CompressionOptions {
+        let use_max = |value: u16, max: u16| match value {
+            0 => max,
+            n => cmp::min(n, max),
+        };
+        let mut options = *self;
+        options.max_hash_checks = cmp::min(self.max_hash_checks, MAX_HASH_CHECKS);
+        options.lazy_if_less_than = match self.matching_type {
+            // Lazy matching is not done at all with greedy matching.
+            MatchingType::Greedy => 0,
+            MatchingType::Lazy => cmp::min(self.lazy_if_less_than, MAX_NICE_MATCH),
+        };
+        options.nice_match = use_max(self.nice_match, MAX_NICE_MATCH);
+        options.block_limits.max_symbols =
+            use_max(self.block_limits.max_symbols, MAX_BLOCK_SYMBOLS);
+        options.max_code_length = resolve_max_code_length(self.max_code_length);
+        options
+    }
+}
+
+/// Get the limit on the length of the huffman codes the encoder uses for a value of the
+/// `max_code_length` option.
+pub fn resolve_max_code_length(max_code_length: u8) -> u8 {
+    match max_code_length {
+        0 => LONGEST_CODE_LENGTH,
+        n => n.clamp(SHORTEST_CODE_LENGTH_LIMIT, LONGEST_CODE_LENGTH),
+    }
 }
 
 impl Default for CompressionOptions {
@@ -191,6 +643,415 @@ impl From<Compression> for CompressionOptions {
             Compression::Fast => CompressionOptions::fast(),
             Compression::Default => CompressionOptions::default(),
             Compression::Best => CompressionOptions::high(),
+            Compression::Auto => CompressionOptions {
+                special: SpecialOptions::Auto,
+                ..CompressionOptions::default()
+            },
+        }
+    }
+}
+
+/// The highest numeric compression level accepted when converting from a number.
+pub const MAX_COMPRESSION_LEVEL: u8 = 9;
+
+/// An error returned when parsing or converting a compression level fails.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ParseCompressionError {
+    /// The numeric level is outside the supported range of `1...9`.
+    ///
+    /// Level `0` (no compression) is not supported, as the encoder can not be forced to only
+    /// output stored blocks yet.
+    InvalidLevel(u8),
+    /// The string is a number too large to be a compression level, containing the number as it
+    /// was written.
+    LevelTooLarge(String),
+    /// The string is not a number or the name of a known level or strategy.
+    UnknownName(String),
+}
+
+impl fmt::Display for ParseCompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseCompressionError::InvalidLevel(level) => write!(
+                f,
+                "invalid compression level {}, expected a level from 1 to {}",
+                level, MAX_COMPRESSION_LEVEL
+            ),
+            ParseCompressionError::LevelTooLarge(ref level) => write!(
+                f,
+                "invalid compression level {}, expected a level from 1 to {}",
+                level, MAX_COMPRESSION_LEVEL
+            ),
+            ParseCompressionError::UnknownName(ref name) => {
+                write!(f, "unknown compression level or strategy \"{}\"", name)
+            }
+        }
+    }
+}
+
+impl Error for ParseCompressionError {}
+
+/// An error describing an inconsistent or out of range value in a `CompressionOptions`, returned
+/// by [`CompressionOptions::validate`](struct.CompressionOptions.html#method.validate).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ConfigError {
+    /// `max_hash_checks` is higher than the length of the hash chains.
+    MaxHashChecksTooHigh(u16),
+    /// `lazy_if_less_than` is higher than the maximum match length.
+    LazyIfLessThanTooHigh(u16),
+    /// `lazy_if_less_than` is set, but has no effect as `matching_type` is greedy.
+    LazyMatchingDisabled,
+    /// `nice_match` is higher than the maximum match length.
+    NiceMatchTooHigh(u16),
+    /// `block_limits.max_symbols` is higher than
+    /// [`MAX_BLOCK_SYMBOLS`](constant.MAX_BLOCK_SYMBOLS.html).
+    MaxSymbolsTooHigh(u16),
+    /// `max_code_length` is not `0` or in the range `9...15`.
+    MaxCodeLengthOutOfRange(u8),
+    /// The options ask for a feature that is not implemented.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::MaxHashChecksTooHigh(n) => write!(
+                f,
+                "max_hash_checks is {}, but can be at most {}",
+                n, MAX_HASH_CHECKS
+            ),
+            ConfigError::LazyIfLessThanTooHigh(n) => write!(
+                f,
+                "lazy_if_less_than is {}, but can be at most {}",
+                n, MAX_NICE_MATCH
+            ),
+            ConfigError::LazyMatchingDisabled => {
+                f.write_str("lazy_if_less_than has no effect with greedy matching")
+            }
+            ConfigError::NiceMatchTooHigh(n) => write!(
+                f,
+                "nice_match is {}, but can be at most {}",
+                n, MAX_NICE_MATCH
+            ),
+            ConfigError::MaxSymbolsTooHigh(n) => write!(
+                f,
+                "block_limits.max_symbols is {}, but can be at most {}",
+                n, MAX_BLOCK_SYMBOLS
+            ),
+            ConfigError::MaxCodeLengthOutOfRange(n) => write!(
+                f,
+                "max_code_length is {}, but has to be 0 or from {} to {}",
+                n, SHORTEST_CODE_LENGTH_LIMIT, LONGEST_CODE_LENGTH
+            ),
+            ConfigError::Unsupported(feature) => write!(f, "{} is not supported", feature),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl FromStr for Compression {
+    type Err = ParseCompressionError;
+
+    /// Parse one of `"fast"`, `"default"`, `"best"` or `"auto"` (ignoring case) into a
+    /// `Compression`.
+    fn from_str(s: &str) -> Result<Compression, ParseCompressionError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fast" => Ok(Compression::Fast),
+            "default" => Ok(Compression::Default),
+            "best" => Ok(Compression::Best),
+            "auto" => Ok(Compression::Auto),
+            _ => Err(ParseCompressionError::UnknownName(s.to_string())),
         }
     }
 }
+
+impl TryFrom<u8> for CompressionOptions {
+    type Error = ParseCompressionError;
+
+    /// Get the compression options for a numeric level from `1` (fastest) to `9` (best
+    /// compression), roughly emulating the levels in zlib.
+    ///
+    /// Level `1` is the same as `CompressionOptions::fast()`, level `6` the same as
+    /// `CompressionOptions::default()` and level `9` the same as `CompressionOptions::high()`.
+    fn try_from(level: u8) -> Result<CompressionOptions, ParseCompressionError> {
+        let (max_hash_checks, lazy_if_less_than, nice_match, matching_type) = match level {
+            1 => return Ok(CompressionOptions::fast()),
+            2 => (8, 0, 16, MatchingType::Greedy),
+            3 => (32, 0, 32, MatchingType::Greedy),
+            4 => (16, 16, 16, MatchingType::Lazy),
+            5 => (32, 32, 32, MatchingType::Lazy),
+            6 => return Ok(CompressionOptions::default()),
+            7 => (256, 64, 128, MatchingType::Lazy),
+            8 => (
+                1024,
+                HIGH_LAZY_IF_LESS_THAN,
+                MAX_NICE_MATCH,
+                MatchingType::Lazy,
+            ),
+            9 => return Ok(CompressionOptions::high()),
+            _ => return Err(ParseCompressionError::InvalidLevel(level)),
+        };
+        Ok(CompressionOptions {
+            max_hash_checks,
+            lazy_if_less_than,
+            nice_match,
+            matching_type,
+            ..CompressionOptions::default()
+        })
+    }
+}
+
+impl FromStr for CompressionOptions {
+    type Err = ParseCompressionError;
+
+    /// Parse a compression level or strategy into a set of compression options.
+    ///
+    /// Accepts (ignoring case and surrounding whitespace):
+    ///
+    /// * A numeric level from `1` to `9`, see `CompressionOptions::try_from(u8)`.
+    /// * `"fast"`, `"default"`, `"best"` or `"auto"`, see
+    ///   [`Compression`](./enum.Compression.html).
+    /// * `"rle"`, see `CompressionOptions::rle()`.
+    /// * `"huffman-only"`, see `CompressionOptions::huffman_only()`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use deflate::{Compression, CompressionOptions};
+    ///
+    /// let options: CompressionOptions = "6".parse().unwrap();
+    /// assert_eq!(options, Compression::Default.into());
+    /// assert_eq!("rle".parse(), Ok(CompressionOptions::rle()));
+    /// assert!("11".parse::<CompressionOptions>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<CompressionOptions, ParseCompressionError> {
+        let name = s.trim();
+        if !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()) {
+            return match name.parse::<u8>() {
+                Ok(level) => CompressionOptions::try_from(level),
+                // Too large to fit in a u8.
+                Err(_) => Err(ParseCompressionError::LevelTooLarge(name.to_string())),
+            };
+        }
+        match name.to_ascii_lowercase().as_str() {
+            "rle" => Ok(CompressionOptions::rle()),
+            "huffman-only" => Ok(CompressionOptions::huffman_only()),
+            _ => name.parse::<Compression>().map(CompressionOptions::from),
+        }
+        .map_err(|_| ParseCompressionError::UnknownName(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        assert_eq!(
+            CompressionOptions::try_from(1),
+            Ok(CompressionOptions::fast())
+        );
+        assert_eq!(
+            CompressionOptions::try_from(6),
+            Ok(CompressionOptions::default())
+        );
+        assert_eq!(
+            CompressionOptions::try_from(9),
+            Ok(CompressionOptions::high())
+        );
+        assert_eq!(
+            CompressionOptions::try_from(0),
+            Err(ParseCompressionError::InvalidLevel(0))
+        );
+        assert_eq!(
+            CompressionOptions::try_from(10),
+            Err(ParseCompressionError::InvalidLevel(10))
+        );
+
+        for level in 1..=MAX_COMPRESSION_LEVEL {
+            assert!(CompressionOptions::try_from(level).is_ok());
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let presets = [
+            CompressionOptions::fast(),
+            CompressionOptions::default(),
+            CompressionOptions::high(),
+            CompressionOptions::huffman_only(),
+            CompressionOptions::rle(),
+        ];
+        for options in presets.iter() {
+            assert_eq!(options.validate(), Ok(()));
+        }
+        for level in 1..=MAX_COMPRESSION_LEVEL {
+            assert_eq!(
+                CompressionOptions::try_from(level).unwrap().validate(),
+                Ok(())
+            );
+        }
+
+        let check = |options: CompressionOptions, error: ConfigError| {
+            assert_eq!(options.validate(), Err(error));
+            assert!(!error.to_string().is_empty());
+        };
+        let default = CompressionOptions::default();
+        check(
+            CompressionOptions {
+                max_hash_checks: MAX_HASH_CHECKS + 1,
+                ..default
+            },
+            ConfigError::MaxHashChecksTooHigh(MAX_HASH_CHECKS + 1),
+        );
+        check(
+            CompressionOptions {
+                lazy_if_less_than: 259,
+                ..default
+            },
+            ConfigError::LazyIfLessThanTooHigh(259),
+        );
+        check(
+            CompressionOptions {
+                matching_type: MatchingType::Greedy,
+                ..default
+            },
+            ConfigError::LazyMatchingDisabled,
+        );
+        check(
+            CompressionOptions {
+                nice_match: u16::MAX,
+                ..default
+            },
+            ConfigError::NiceMatchTooHigh(u16::MAX),
+        );
+        check(
+            CompressionOptions {
+                block_limits: BlockLimits {
+                    max_symbols: MAX_BLOCK_SYMBOLS + 1,
+                    ..BlockLimits::DEFAULT
+                },
+                ..default
+            },
+            ConfigError::MaxSymbolsTooHigh(MAX_BLOCK_SYMBOLS + 1),
+        );
+        for &max_code_length in &[1, 8, 16] {
+            check(
+                CompressionOptions {
+                    max_code_length,
+                    ..default
+                },
+                ConfigError::MaxCodeLengthOutOfRange(max_code_length),
+            );
+        }
+        check(
+            CompressionOptions {
+                special: SpecialOptions::_ForceStored,
+                ..default
+            },
+            ConfigError::Unsupported("forcing stored blocks"),
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        assert_eq!("3".parse(), CompressionOptions::try_from(3));
+        assert_eq!(" Best ".parse(), Ok(CompressionOptions::high()));
+        assert_eq!("fast".parse(), Ok(CompressionOptions::fast()));
+        assert_eq!("default".parse(), Ok(CompressionOptions::default()));
+        assert_eq!("RLE".parse(), Ok(CompressionOptions::rle()));
+        assert_eq!(
+            "huffman-only".parse(),
+            Ok(CompressionOptions::huffman_only())
+        );
+        assert_eq!(
+            "0".parse::<CompressionOptions>(),
+            Err(ParseCompressionError::InvalidLevel(0))
+        );
+        assert_eq!(
+            "300".parse::<CompressionOptions>(),
+            Err(ParseCompressionError::LevelTooLarge("300".to_string()))
+        );
+        assert_eq!(
+            " 99999999999999999999 ".parse::<CompressionOptions>(),
+            Err(ParseCompressionError::LevelTooLarge(
+                "99999999999999999999".to_string()
+            ))
+        );
+        assert_eq!(
+            "fastest".parse::<CompressionOptions>(),
+            Err(ParseCompressionError::UnknownName("fastest".to_string()))
+        );
+        assert!("".parse::<CompressionOptions>().is_err());
+        assert!("-1".parse::<CompressionOptions>().is_err());
+    }
+
+    #[test]
+    fn resolved() {
+        for level in 1..=MAX_COMPRESSION_LEVEL {
+            let options = CompressionOptions::try_from(level).unwrap();
+            assert_eq!(options.resolved(), options);
+        }
+
+        let options = CompressionOptions {
+            max_hash_checks: u16::MAX,
+            lazy_if_less_than: u16::MAX,
+            nice_match: 0,
+            block_limits: BlockLimits {
+                max_symbols: 0,
+                ..BlockLimits::DEFAULT
+            },
+            ..CompressionOptions::default()
+        };
+        let resolved = options.resolved();
+        assert_eq!(resolved.max_hash_checks, MAX_HASH_CHECKS);
+        assert_eq!(resolved.lazy_if_less_than, MAX_NICE_MATCH);
+        assert_eq!(resolved.nice_match, MAX_NICE_MATCH);
+        assert_eq!(resolved.block_limits.max_symbols, MAX_BLOCK_SYMBOLS);
+        assert_eq!(resolved.resolved(), resolved);
+        assert_eq!(resolved.validate(), Ok(()));
+        let options = CompressionOptions {
+            nice_match: u16::MAX,
+            ..options
+        };
+        assert_eq!(options.resolved(), resolved);
+        let greedy = CompressionOptions {
+            matching_type: MatchingType::Greedy,
+            ..options
+        };
+        assert_eq!(greedy.resolved().lazy_if_less_than, 0);
+        assert_eq!(greedy.resolved().validate(), Ok(()));
+
+        let with_limit = |max_code_length| CompressionOptions {
+            max_code_length,
+            ..CompressionOptions::default()
+        };
+        assert_eq!(with_limit(0).resolved().max_code_length, 15);
+        assert_eq!(with_limit(100).resolved().max_code_length, 15);
+        assert_eq!(with_limit(3).resolved().max_code_length, 9);
+        assert_eq!(with_limit(12).resolved().max_code_length, 12);
+    }
+
+    #[test]
+    fn parse_compression() {
+        assert_eq!("fast".parse(), Ok(Compression::Fast));
+        assert_eq!("Default".parse(), Ok(Compression::Default));
+        assert_eq!("best".parse(), Ok(Compression::Best));
+        assert_eq!("auto".parse(), Ok(Compression::Auto));
+        assert!("6".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn error_message() {
+        assert_eq!(
+            ParseCompressionError::InvalidLevel(12).to_string(),
+            "invalid compression level 12, expected a level from 1 to 9"
+        );
+        assert_eq!(
+            ParseCompressionError::LevelTooLarge("300".to_string()).to_string(),
+            "invalid compression level 300, expected a level from 1 to 9"
+        );
+    }
+}
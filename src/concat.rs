@@ -0,0 +1,308 @@
+//! This module contains functions for joining separately compressed streams into a single one,
+//! e.g after compressing the pieces of a large input on different machines.
+//!
+//! DEFLATE blocks don't have to start on a byte boundary, so the streams can't simply be
+//! appended to each other. Instead, the final block flag of each stream except the last one is
+//! cleared, and if the stream doesn't end on a byte boundary, it's followed by an empty stored
+//! block to get to one, so the next stream can be copied as it is. The streams still have to be
+//! decoded to find where their final block starts and the stream ends, but nothing is
+//! compressed again.
+//!
+//! As the streams were compressed independently, the matches in each stream only refer to data
+//! in the same stream, so the joined stream compresses slightly worse than compressing all of
+//! the data at once.
+
+use crate::checksum::{adler32_combine, crc32_combine};
+use crate::inflate::{self, BlockInfo, InflateError};
+
+/// The bytes of an empty stream, made up of an empty final fixed block.
+const EMPTY_STREAM: [u8; 2] = [0b011, 0];
+
+/// Append the raw DEFLATE stream `data`, made up of `blocks`, to `output`, which has to end on
+/// a byte boundary.
+///
+/// Unless `last` is true, the final block flag is cleared, and the stream is followed by an
+/// empty stored block if it doesn't end on a byte boundary, so the next stream can be appended
+/// the same way.
+fn append_stream(output: &mut Vec<u8>, data: &[u8], blocks: &[BlockInfo], last: bool) {
+    let final_block = blocks
+        .last()
+        .expect("A valid stream has at least one block!");
+    let end_bit = final_block.end_bit;
+    let start = output.len();
+    output.extend_from_slice(&data[..end_bit.div_ceil(8) as usize]);
+    let pending_bits = (end_bit % 8) as u8;
+    if pending_bits != 0 {
+        // Clear whatever comes after the end of the stream in the last byte.
+        *output.last_mut().unwrap() &= (1 << pending_bits) - 1;
+    }
+    if last {
+        return;
+    }
+
+    let final_bit = start * 8 + final_block.start_bit as usize;
+    output[final_bit / 8] &= !(1 << (final_bit % 8));
+    if pending_bits != 0 {
+        // The 3 zero bits of the header of a stored block that isn't the final one, and the
+        // padding up to the next byte, which needs another byte if the header doesn't fit.
+        if pending_bits > 5 {
+            output.push(0);
+        }
+        // The length and its one's complement.
+        output.extend_from_slice(&[0, 0, 0xff, 0xff]);
+    }
+}
+
+/// Join the raw DEFLATE streams in `parts` into a single stream decompressing to the
+/// decompressed data of each of them one after another.
+///
+/// Each part has to be a complete stream, ending with a final block. Any data after the end of
+/// a stream is ignored. If `parts` is empty, an empty stream is returned.
+///
+/// # Errors
+///
+/// Returns the error from decoding the part if one of them is not a valid stream.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{concat_raw_streams, deflate_bytes, inflate};
+///
+/// let first = deflate_bytes(b"This is some ");
+/// let second = deflate_bytes(b"test data");
+/// let joined = concat_raw_streams(&[&first[..], &second[..]]).unwrap();
+/// assert_eq!(inflate::inflate(&joined).unwrap(), b"This is some test data");
+/// ```
+pub fn concat_raw_streams(parts: &[&[u8]]) -> Result<Vec<u8>, InflateError> {
+    if parts.is_empty() {
+        return Ok(EMPTY_STREAM.to_vec());
+    }
+    let mut output = Vec::with_capacity(parts.iter().map(|p| p.len() + 5).sum());
+    for (n, part) in parts.iter().enumerate() {
+        let blocks = inflate::analyze(part)?;
+        append_stream(&mut output, part, &blocks, n == parts.len() - 1);
+    }
+    Ok(output)
+}
+
+/// Join the zlib streams in `parts` into a single zlib stream decompressing to the decompressed
+/// data of each of them one after another.
+///
+/// The header of the first part is used for the joined stream, and the checksum in the trailer
+/// is worked out from the ones of the parts, which are checked along the way. Streams using a
+/// preset dictionary are not supported. Any data after the trailer of a part is ignored.
+///
+/// # Errors
+///
+/// Returns the error from decoding the part if one of them is not a valid zlib stream, or uses
+/// a preset dictionary, which gives `InflateError::InvalidHeader`.
+///
+/// # Panics
+///
+/// Panics if `parts` is empty.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{concat_zlib_streams, deflate_bytes_zlib, inflate};
+///
+/// let first = deflate_bytes_zlib(b"This is some ");
+/// let second = deflate_bytes_zlib(b"test data");
+/// let joined = concat_zlib_streams(&[&first[..], &second[..]]).unwrap();
+/// assert_eq!(inflate::inflate_zlib(&joined).unwrap(), b"This is some test data");
+/// ```
+pub fn concat_zlib_streams(parts: &[&[u8]]) -> Result<Vec<u8>, InflateError> {
+    assert!(!parts.is_empty(), "No streams to join!");
+    // There is no dictionary id in the header as preset dictionaries are not supported.
+    const HEADER_LEN: usize = 2;
+    let mut output = Vec::with_capacity(parts.iter().map(|p| p.len() + 5).sum());
+    let mut checksum = 1;
+    for (n, part) in parts.iter().enumerate() {
+        let (blocks, trailer) = inflate::analyze_zlib(part)?;
+        if n == 0 {
+            output.extend_from_slice(&part[..HEADER_LEN]);
+        }
+        let len: u64 = blocks.iter().map(|b| b.output_len).sum();
+        checksum = adler32_combine(checksum, trailer.checksum, len);
+        append_stream(
+            &mut output,
+            &part[HEADER_LEN..],
+            &blocks,
+            n == parts.len() - 1,
+        );
+    }
+    output.extend_from_slice(&checksum.to_be_bytes());
+    Ok(output)
+}
+
+/// Join the gzip streams in `parts` into a single gzip stream (with a single member)
+/// decompressing to the decompressed data of each of them one after another.
+///
+/// The header of the first part is used for the joined stream as it is, so it should not
+/// contain anything describing the compressed data of that part alone, like the block size of
+/// the BGZF format. The checksum and size in the trailer are worked out from the ones of the
+/// parts, which are checked along the way. Only the first member of each part is used.
+///
+/// (Gzip streams can also be joined by simply appending them, which gives a stream with
+/// several members, but not all decoders support those.)
+///
+/// # Errors
+///
+/// Returns the error from decoding the part if one of them is not a valid gzip stream.
+///
+/// # Panics
+///
+/// Panics if `parts` is empty.
+pub fn concat_gzip_streams(parts: &[&[u8]]) -> Result<Vec<u8>, InflateError> {
+    assert!(!parts.is_empty(), "No streams to join!");
+    let mut output = Vec::with_capacity(parts.iter().map(|p| p.len() + 5).sum());
+    let mut checksum = 0;
+    let mut total_len = 0u64;
+    for (n, part) in parts.iter().enumerate() {
+        let (blocks, trailer) = inflate::analyze_gzip(part)?;
+        let header_len = inflate::gzip_header_len(part)?;
+        if n == 0 {
+            output.extend_from_slice(&part[..header_len]);
+        }
+        let len: u64 = blocks.iter().map(|b| b.output_len).sum();
+        checksum = crc32_combine(checksum, trailer.checksum, len);
+        total_len += len;
+        append_stream(
+            &mut output,
+            &part[header_len..],
+            &blocks,
+            n == parts.len() - 1,
+        );
+    }
+    output.extend_from_slice(&checksum.to_le_bytes());
+    // The size is stored modulo 2^32.
+    output.extend_from_slice(&(total_len as u32).to_le_bytes());
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_random_data, get_test_data};
+    use crate::{deflate_bytes_conf, deflate_bytes_zlib_conf, Compression, CompressionOptions};
+
+    /// Split the test data into pieces, some of them compressing to streams that end on a byte
+    /// boundary and some not, and some of them empty.
+    fn pieces() -> Vec<Vec<u8>> {
+        let data = get_test_data();
+        vec![
+            data[..20_000].to_vec(),
+            Vec::new(),
+            get_random_data(5000),
+            data[20_000..20_001].to_vec(),
+            data[20_001..].to_vec(),
+            b"abc".repeat(1000),
+        ]
+    }
+
+    fn options() -> Vec<CompressionOptions> {
+        vec![
+            Compression::Default.into(),
+            CompressionOptions::fast(),
+            CompressionOptions::huffman_only(),
+            CompressionOptions {
+                special: crate::SpecialOptions::ForceFixed,
+                ..CompressionOptions::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn concat_raw() {
+        let pieces = pieces();
+        let expected = pieces.concat();
+        for options in options() {
+            let parts: Vec<_> = pieces
+                .iter()
+                .map(|p| deflate_bytes_conf(p, options))
+                .collect();
+            let parts: Vec<&[u8]> = parts.iter().map(|p| &p[..]).collect();
+            let joined = concat_raw_streams(&parts).unwrap();
+            assert!(decompress_to_end(&joined) == expected);
+            // Only the final block of the last part is the final one.
+            let blocks = inflate::analyze(&joined).unwrap();
+            assert_eq!(blocks.iter().filter(|b| b.is_final).count(), 1);
+
+            for n in 0..parts.len() {
+                let joined = concat_raw_streams(&parts[n..]).unwrap();
+                assert!(decompress_to_end(&joined) == pieces[n..].concat());
+            }
+        }
+    }
+
+    #[test]
+    fn concat_raw_edge_cases() {
+        assert!(decompress_to_end(&concat_raw_streams(&[]).unwrap()).is_empty());
+
+        // Trailing data after a stream is ignored.
+        let mut part = crate::deflate_bytes(b"abc");
+        part.extend_from_slice(&[0xff; 4]);
+        let joined = concat_raw_streams(&[&part, &part]).unwrap();
+        assert_eq!(decompress_to_end(&joined), b"abcabc");
+
+        let truncated = &part[..1];
+        assert_eq!(
+            concat_raw_streams(&[&part, truncated]),
+            Err(InflateError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn concat_zlib() {
+        let pieces = pieces();
+        let parts: Vec<_> = pieces
+            .iter()
+            .map(|p| deflate_bytes_zlib_conf(p, Compression::Fast))
+            .collect();
+        let parts: Vec<&[u8]> = parts.iter().map(|p| &p[..]).collect();
+        let joined = concat_zlib_streams(&parts).unwrap();
+        assert_eq!(joined[..2], parts[0][..2]);
+        // This also checks the checksum.
+        assert!(inflate::inflate_zlib(&joined).unwrap() == pieces.concat());
+
+        let mut corrupted = parts[1].to_vec();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            concat_zlib_streams(&[parts[0], &corrupted]),
+            Err(InflateError::ChecksumMismatch)
+        );
+        assert_eq!(
+            concat_zlib_streams(&[&[0x78]]),
+            Err(InflateError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn concat_zlib_dictionary() {
+        let mut encoder =
+            crate::write::ZlibEncoder::with_dictionary(Vec::new(), Compression::Default, b"abc");
+        std::io::Write::write_all(&mut encoder, b"abcabc").unwrap();
+        let with_dictionary = encoder.finish().unwrap();
+        let plain = crate::deflate_bytes_zlib(b"abc");
+        assert_eq!(
+            concat_zlib_streams(&[&plain, &with_dictionary]),
+            Err(InflateError::InvalidHeader)
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn concat_gzip() {
+        let pieces = pieces();
+        let parts: Vec<_> = pieces
+            .iter()
+            .map(|p| crate::deflate_bytes_gzip_conf(p, Compression::Default, Default::default()))
+            .collect();
+        let parts: Vec<&[u8]> = parts.iter().map(|p| &p[..]).collect();
+        let joined = concat_gzip_streams(&parts).unwrap();
+        // This also checks the checksum and size, and that there is only one member.
+        let trailer = inflate::verify_gzip_trailer(&joined).unwrap();
+        assert_eq!(trailer.size, Some(pieces.concat().len() as u32));
+        assert!(inflate::inflate_gzip(&joined).unwrap() == pieces.concat());
+    }
+}
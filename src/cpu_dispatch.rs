@@ -0,0 +1,396 @@
+//! Selection of the implementation of the hottest inner loops at run time, based on the
+//! instruction set extensions the CPU supports.
+//!
+//! The extensions are detected the first time one of the functions is needed, and the fastest
+//! implementations are stored as function pointers, so a binary built for the baseline of an
+//! architecture still uses e.g AVX2 on the machines that have it. The implementations are:
+//!
+//! - Comparing match candidates: scalar (8 bytes at a time), SSE2, AVX2 and NEON.
+//! - The Adler-32 checksum: scalar (from the `adler32` crate), SSE2 and AVX2.
+//!
+//! The rolling hash used for finding match candidates is not included, as it is updated one
+//! byte at a time, each step depending on the previous one, so there is nothing to gain from
+//! vector instructions there.
+
+use std::sync::OnceLock;
+
+use adler32::RollingAdler32;
+
+/// The set of instruction set extensions the functions in use were picked for, see
+/// [`simd_level`](fn.simd_level.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SimdLevel {
+    /// Plain rust code without any explicit vector instructions.
+    Scalar,
+    /// SSE2, which all x86-64 CPUs support.
+    Sse2,
+    /// AVX2, supported by most x86-64 CPUs from the last decade.
+    Avx2,
+    /// NEON, which all AArch64 CPUs support.
+    Neon,
+}
+
+type CommonPrefixFn = fn(&[u8], &[u8]) -> usize;
+type Adler32Fn = fn(u32, &[u8]) -> u32;
+
+/// The implementations picked for a given `SimdLevel`.
+pub struct Kernels {
+    level: SimdLevel,
+    /// Get the number of bytes at the start of `a` that are equal to the ones at the start of
+    /// `b`, which has to be at least as long as `a`.
+    pub common_prefix: CommonPrefixFn,
+    /// Update the Adler-32 checksum `adler` with `data`.
+    pub adler32: Adler32Fn,
+}
+
+impl Kernels {
+    /// Get the implementations for `level`, or `None` if the CPU doesn't support it.
+    pub fn for_level(level: SimdLevel) -> Option<Kernels> {
+        let (common_prefix, adler32): (CommonPrefixFn, Adler32Fn) = match level {
+            SimdLevel::Scalar => (common_prefix_scalar, adler32_scalar),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdLevel::Sse2 if is_x86_feature_detected!("sse2") => {
+                (x86::common_prefix_sse2, x86::adler32_sse2)
+            }
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdLevel::Avx2 if is_x86_feature_detected!("avx2") => {
+                (x86::common_prefix_avx2, x86::adler32_avx2)
+            }
+            #[cfg(target_arch = "aarch64")]
+            SimdLevel::Neon if std::arch::is_aarch64_feature_detected!("neon") => {
+                (neon::common_prefix_neon, adler32_scalar)
+            }
+            _ => return None,
+        };
+        Some(Kernels {
+            level,
+            common_prefix,
+            adler32,
+        })
+    }
+
+    /// Get the fastest implementations the CPU supports.
+    fn detect() -> Kernels {
+        [SimdLevel::Avx2, SimdLevel::Neon, SimdLevel::Sse2]
+            .iter()
+            .find_map(|&level| Kernels::for_level(level))
+            .unwrap_or(Kernels {
+                level: SimdLevel::Scalar,
+                common_prefix: common_prefix_scalar,
+                adler32: adler32_scalar,
+            })
+    }
+}
+
+static KERNELS: OnceLock<Kernels> = OnceLock::new();
+
+/// Get the implementations to use on this CPU.
+#[inline]
+pub fn kernels() -> &'static Kernels {
+    KERNELS.get_or_init(Kernels::detect)
+}
+
+/// Get the set of instruction set extensions the compressor uses on this CPU, e.g for logging
+/// which code paths are used on the different machines in a fleet.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{simd_level, SimdLevel};
+///
+/// if simd_level() == SimdLevel::Scalar {
+///     println!("Not using any vector instructions");
+/// }
+/// ```
+pub fn simd_level() -> SimdLevel {
+    kernels().level
+}
+
+fn common_prefix_scalar(a: &[u8], b: &[u8]) -> usize {
+    let b = &b[..a.len()];
+    let mut n = 0;
+    for (x, y) in a.chunks_exact(8).zip(b.chunks_exact(8)) {
+        let x = u64::from_le_bytes([x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7]]);
+        let y = u64::from_le_bytes([y[0], y[1], y[2], y[3], y[4], y[5], y[6], y[7]]);
+        let diff = x ^ y;
+        if diff != 0 {
+            return n + (diff.trailing_zeros() / 8) as usize;
+        }
+        n += 8;
+    }
+    n + a[n..]
+        .iter()
+        .zip(&b[n..])
+        .take_while(|&(x, y)| x == y)
+        .count()
+}
+
+fn adler32_scalar(adler: u32, data: &[u8]) -> u32 {
+    let mut hash = RollingAdler32::from_value(adler);
+    hash.update_buffer(data);
+    hash.hash()
+}
+
+/// The modulus used for the Adler-32 sums.
+const ADLER_MOD: u64 = 65521;
+
+/// The largest number of bytes that can be added to the Adler-32 sums before they have to be
+/// reduced, as in zlib.
+const ADLER_NMAX: usize = 5552;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::{common_prefix_scalar, ADLER_MOD, ADLER_NMAX};
+
+    // The safe wrappers are only ever put in a `Kernels` after checking that the CPU supports
+    // the instructions used.
+
+    pub fn common_prefix_sse2(a: &[u8], b: &[u8]) -> usize {
+        unsafe { common_prefix_sse2_impl(a, b) }
+    }
+
+    pub fn common_prefix_avx2(a: &[u8], b: &[u8]) -> usize {
+        unsafe { common_prefix_avx2_impl(a, b) }
+    }
+
+    pub fn adler32_sse2(adler: u32, data: &[u8]) -> u32 {
+        unsafe { adler32_sse2_impl(adler, data) }
+    }
+
+    pub fn adler32_avx2(adler: u32, data: &[u8]) -> u32 {
+        unsafe { adler32_avx2_impl(adler, data) }
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn common_prefix_sse2_impl(a: &[u8], b: &[u8]) -> usize {
+        assert!(b.len() >= a.len());
+        let mut n = 0;
+        while n + 16 <= a.len() {
+            // Both loads are in bounds, as `n + 16 <= a.len() <= b.len()`.
+            let x = _mm_loadu_si128(a.as_ptr().add(n) as *const __m128i);
+            let y = _mm_loadu_si128(b.as_ptr().add(n) as *const __m128i);
+            let equal = _mm_movemask_epi8(_mm_cmpeq_epi8(x, y)) as u32;
+            if equal != 0xffff {
+                return n + (!equal).trailing_zeros() as usize;
+            }
+            n += 16;
+        }
+        n + common_prefix_scalar(&a[n..], &b[n..])
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn common_prefix_avx2_impl(a: &[u8], b: &[u8]) -> usize {
+        assert!(b.len() >= a.len());
+        let mut n = 0;
+        while n + 32 <= a.len() {
+            // Both loads are in bounds, as `n + 32 <= a.len() <= b.len()`.
+            let x = _mm256_loadu_si256(a.as_ptr().add(n) as *const __m256i);
+            let y = _mm256_loadu_si256(b.as_ptr().add(n) as *const __m256i);
+            let equal = _mm256_movemask_epi8(_mm256_cmpeq_epi8(x, y)) as u32;
+            if equal != u32::MAX {
+                return n + (!equal).trailing_zeros() as usize;
+            }
+            n += 32;
+        }
+        n + common_prefix_sse2_impl(&a[n..], &b[n..])
+    }
+
+    /// Add up the 32-bit lanes of `v`.
+    #[target_feature(enable = "sse2")]
+    unsafe fn sum_epi32(v: __m128i) -> u64 {
+        let mut lanes = [0u32; 4];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, v);
+        lanes.iter().map(|&l| u64::from(l)).sum()
+    }
+
+    /// Add up the 32-bit lanes of `v`.
+    #[target_feature(enable = "avx2")]
+    unsafe fn sum_epi32_256(v: __m256i) -> u64 {
+        let mut lanes = [0u32; 8];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, v);
+        lanes.iter().map(|&l| u64::from(l)).sum()
+    }
+
+    // For a block of bytes `x[0..len]`, the Adler-32 sums are updated as
+    //
+    //     a' = a + sum(x[i])
+    //     b' = b + len * a + sum((len - i) * x[i])
+    //
+    // The vector versions go through the block in chunks, keeping the sum of the bytes and the
+    // sum of the bytes weighted by their position in the chunk. The weight of a byte in the
+    // whole block is its weight in the chunk plus the chunk size times the number of chunks
+    // after it, which is accounted for by adding up the byte sums of the preceding chunks at
+    // each chunk.
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn adler32_sse2_impl(adler: u32, data: &[u8]) -> u32 {
+        let mut a = u64::from(adler & 0xffff);
+        let mut b = u64::from(adler >> 16);
+        let zero = _mm_setzero_si128();
+        let weights_low = _mm_setr_epi16(16, 15, 14, 13, 12, 11, 10, 9);
+        let weights_high = _mm_setr_epi16(8, 7, 6, 5, 4, 3, 2, 1);
+        for block in data.chunks(ADLER_NMAX) {
+            let (chunks, rest) = block.split_at(block.len() & !15);
+            b += a * chunks.len() as u64;
+            let mut sums = zero;
+            let mut preceding_sums = zero;
+            let mut weighted_sums = zero;
+            for chunk in chunks.chunks_exact(16) {
+                let v = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+                preceding_sums = _mm_add_epi32(preceding_sums, sums);
+                sums = _mm_add_epi32(sums, _mm_sad_epu8(v, zero));
+                let low = _mm_unpacklo_epi8(v, zero);
+                let high = _mm_unpackhi_epi8(v, zero);
+                weighted_sums = _mm_add_epi32(weighted_sums, _mm_madd_epi16(low, weights_low));
+                weighted_sums = _mm_add_epi32(weighted_sums, _mm_madd_epi16(high, weights_high));
+            }
+            a += sum_epi32(sums);
+            b += sum_epi32(weighted_sums) + 16 * sum_epi32(preceding_sums);
+            for &byte in rest {
+                a += u64::from(byte);
+                b += a;
+            }
+            a %= ADLER_MOD;
+            b %= ADLER_MOD;
+        }
+        ((b as u32) << 16) | a as u32
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn adler32_avx2_impl(adler: u32, data: &[u8]) -> u32 {
+        let mut a = u64::from(adler & 0xffff);
+        let mut b = u64::from(adler >> 16);
+        let zero = _mm256_setzero_si256();
+        let ones = _mm256_set1_epi16(1);
+        let weights = _mm256_setr_epi8(
+            32, 31, 30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 20, 19, 18, 17, 16, 15, 14, 13, 12, 11,
+            10, 9, 8, 7, 6, 5, 4, 3, 2, 1,
+        );
+        for block in data.chunks(ADLER_NMAX) {
+            let (chunks, rest) = block.split_at(block.len() & !31);
+            b += a * chunks.len() as u64;
+            let mut sums = zero;
+            let mut preceding_sums = zero;
+            let mut weighted_sums = zero;
+            for chunk in chunks.chunks_exact(32) {
+                let v = _mm256_loadu_si256(chunk.as_ptr() as *const __m256i);
+                preceding_sums = _mm256_add_epi32(preceding_sums, sums);
+                sums = _mm256_add_epi32(sums, _mm256_sad_epu8(v, zero));
+                // The products of adjacent bytes and weights are added in pairs, which is at
+                // most 255 * (32 + 31), so it fits in an i16.
+                let products = _mm256_maddubs_epi16(v, weights);
+                weighted_sums = _mm256_add_epi32(weighted_sums, _mm256_madd_epi16(products, ones));
+            }
+            a += sum_epi32_256(sums);
+            b += sum_epi32_256(weighted_sums) + 32 * sum_epi32_256(preceding_sums);
+            for &byte in rest {
+                a += u64::from(byte);
+                b += a;
+            }
+            a %= ADLER_MOD;
+            b %= ADLER_MOD;
+        }
+        ((b as u32) << 16) | a as u32
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use super::common_prefix_scalar;
+
+    // Only put in a `Kernels` after checking that the CPU supports NEON.
+    pub fn common_prefix_neon(a: &[u8], b: &[u8]) -> usize {
+        unsafe { common_prefix_neon_impl(a, b) }
+    }
+
+    #[target_feature(enable = "neon")]
+    unsafe fn common_prefix_neon_impl(a: &[u8], b: &[u8]) -> usize {
+        assert!(b.len() >= a.len());
+        let mut n = 0;
+        while n + 16 <= a.len() {
+            // Both loads are in bounds, as `n + 16 <= a.len() <= b.len()`.
+            let x = vld1q_u8(a.as_ptr().add(n));
+            let y = vld1q_u8(b.as_ptr().add(n));
+            let equal = vceqq_u8(x, y);
+            // Narrow the comparison result to 4 bits per byte, as there is no movemask.
+            let narrowed = vshrn_n_u16::<4>(vreinterpretq_u16_u8(equal));
+            let mask = vget_lane_u64::<0>(vreinterpret_u64_u8(narrowed));
+            if mask != u64::MAX {
+                return n + ((!mask).trailing_zeros() / 4) as usize;
+            }
+            n += 16;
+        }
+        n + common_prefix_scalar(&a[n..], &b[n..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{get_random_data, get_test_data};
+
+    fn supported() -> Vec<Kernels> {
+        [
+            SimdLevel::Scalar,
+            SimdLevel::Sse2,
+            SimdLevel::Avx2,
+            SimdLevel::Neon,
+        ]
+        .iter()
+        .filter_map(|&level| Kernels::for_level(level))
+        .collect()
+    }
+
+    #[test]
+    fn detected_level_supported() {
+        assert!(Kernels::for_level(simd_level()).is_some());
+        assert!(Kernels::for_level(SimdLevel::Scalar).is_some());
+    }
+
+    #[test]
+    fn common_prefix() {
+        let data = get_random_data(300);
+        for kernels in supported() {
+            for len in 0..100 {
+                for mismatch in 0..=len {
+                    let a = &data[..len];
+                    let mut b = data[..len + 3].to_vec();
+                    if mismatch < len {
+                        b[mismatch] ^= 1;
+                    }
+                    assert_eq!(
+                        (kernels.common_prefix)(a, &b),
+                        mismatch,
+                        "{:?}, len {}",
+                        kernels.level,
+                        len
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn adler32() {
+        let mut data = get_test_data();
+        // Long runs of 0xff give the largest sums.
+        data.resize(data.len() + 3 * ADLER_NMAX + 17, 0xff);
+        let expected = adler32_scalar(1, &data);
+        for kernels in supported() {
+            assert_eq!((kernels.adler32)(1, &data), expected, "{:?}", kernels.level);
+            for len in 0..200 {
+                let (first, second) = data[data.len() - len - 100..].split_at(len);
+                let start = adler32_scalar(1, &data[..data.len() - len - 100]);
+                let hash = (kernels.adler32)((kernels.adler32)(start, first), second);
+                assert_eq!(hash, expected, "{:?}, len {}", kernels.level, len);
+            }
+        }
+    }
+}
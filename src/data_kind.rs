@@ -0,0 +1,151 @@
+//! Guessing what kind of data is being compressed, used to pick the compression level for
+//! [`Compression::Auto`](../enum.Compression.html#variant.Auto).
+
+use crate::compression_options::CompressionOptions;
+
+/// The number of bytes at the start of the data that are looked at.
+const SAMPLE_LENGTH: usize = 4096;
+
+/// Samples shorter than this are too short for the byte frequencies to say much.
+const MIN_ENTROPY_SAMPLE: usize = 256;
+
+/// Data with more bits of entropy per byte than this is assumed to be compressed already.
+const COMPRESSED_ENTROPY: f64 = 7.5;
+
+/// Signatures at the start of common compressed file formats.
+const COMPRESSED_SIGNATURES: [&[u8]; 11] = [
+    // gzip
+    b"\x1f\x8b",
+    // zip, and formats based on it
+    b"PK\x03\x04",
+    // PNG
+    b"\x89PNG\r\n\x1a\n",
+    // JPEG
+    b"\xff\xd8\xff",
+    // GIF
+    b"GIF8",
+    // bzip2
+    b"BZh",
+    // xz
+    b"\xfd7zXZ\x00",
+    // 7z
+    b"7z\xbc\xaf\x27\x1c",
+    // zstd
+    b"\x28\xb5\x2f\xfd",
+    // Ogg
+    b"OggS",
+    // RAR
+    b"Rar!\x1a\x07",
+];
+
+/// The kind of data being compressed, as guessed from the start of it by
+/// [`detect`](#method.detect).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum DataKind {
+    /// Text, which usually has many long matches, so searching harder for them pays off.
+    Text,
+    /// Other uncompressed data.
+    Binary,
+    /// Data that is already compressed, like images and archives, which won't compress much
+    /// further.
+    Compressed,
+}
+
+impl DataKind {
+    /// Guess the kind of data from the first 4 KiB of `data`.
+    ///
+    /// Data that starts with the signature of a common compressed format, or looks random, is
+    /// taken to be compressed. Otherwise, it's taken to be text if it doesn't contain any control
+    /// characters other than whitespace, and binary if it does.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use deflate::DataKind;
+    ///
+    /// assert_eq!(DataKind::detect(b"Some text.\n"), DataKind::Text);
+    /// assert_eq!(DataKind::detect(&[0, 1, 2, 3, 0, 0, 0, 0]), DataKind::Binary);
+    /// assert_eq!(DataKind::detect(b"\x1f\x8b\x08\x00"), DataKind::Compressed);
+    /// ```
+    pub fn detect(data: &[u8]) -> DataKind {
+        let sample = &data[..data.len().min(SAMPLE_LENGTH)];
+        if COMPRESSED_SIGNATURES
+            .iter()
+            .any(|signature| sample.starts_with(signature))
+        {
+            return DataKind::Compressed;
+        }
+
+        let mut counts = [0usize; 256];
+        for &b in sample {
+            counts[usize::from(b)] += 1;
+        }
+        if sample.len() >= MIN_ENTROPY_SAMPLE && entropy(&counts, sample.len()) > COMPRESSED_ENTROPY
+        {
+            return DataKind::Compressed;
+        }
+
+        // Tab, line feed, form feed, carriage return and escape (for terminal colours).
+        let is_text_control = |b: usize| b == 9 || b == 10 || b == 12 || b == 13 || b == 27;
+        let binary = counts[..32]
+            .iter()
+            .enumerate()
+            .any(|(b, &count)| count > 0 && !is_text_control(b));
+        if binary || counts[127] > 0 {
+            DataKind::Binary
+        } else {
+            DataKind::Text
+        }
+    }
+
+    /// The compression options used for this kind of data with `Compression::Auto`.
+    ///
+    /// This is `CompressionOptions::high()` for text, `CompressionOptions::default()` for
+    /// binary data and `CompressionOptions::huffman_only()` for compressed data, as looking for
+    /// matches in it is mostly wasted effort.
+    pub fn options(self) -> CompressionOptions {
+        match self {
+            DataKind::Text => CompressionOptions::high(),
+            DataKind::Binary => CompressionOptions::default(),
+            DataKind::Compressed => CompressionOptions::huffman_only(),
+        }
+    }
+}
+
+/// The entropy in bits per byte of data with the byte frequencies `counts`.
+fn entropy(counts: &[usize; 256], total: usize) -> f64 {
+    let total = total as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{get_random_data, get_test_data};
+
+    #[test]
+    fn detect() {
+        assert_eq!(DataKind::detect(&get_test_data()), DataKind::Text);
+        assert_eq!(DataKind::detect(b""), DataKind::Text);
+        assert_eq!(DataKind::detect("Ünïcödé\r\n".as_bytes()), DataKind::Text);
+        assert_eq!(
+            DataKind::detect(&get_random_data(10000)),
+            DataKind::Compressed
+        );
+        // Too short to tell from the byte frequencies.
+        assert_eq!(DataKind::detect(&get_random_data(100)), DataKind::Binary);
+
+        let numbers: Vec<u8> = (0..1000u32).flat_map(|n| n.to_le_bytes()).collect();
+        assert_eq!(DataKind::detect(&numbers), DataKind::Binary);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend_from_slice(&numbers);
+        assert_eq!(DataKind::detect(&png), DataKind::Compressed);
+    }
+}
@@ -1,15 +1,19 @@
+use std::io;
 use std::io::Write;
-use std::{cmp, io, mem};
 
+use crate::allocator::BufferAllocator;
 use crate::compress::Flush;
-use crate::compression_options::{CompressionOptions, MAX_HASH_CHECKS};
+use crate::compression_options::{CompressionOptions, SpecialOptions};
+use crate::data_kind::DataKind;
+use crate::encoder_metrics::EncoderMetrics;
 use crate::encoder_state::EncoderState;
-pub use crate::huffman_table::MAX_MATCH;
-use crate::huffman_table::NUM_LITERALS_AND_LENGTHS;
+use crate::flush_coalescing::{CoalescingState, FlushCoalescing};
 use crate::input_buffer::InputBuffer;
-use crate::length_encode::{EncodedLength, LeafVec};
+use crate::length_encode::EncodedLengths;
 use crate::lz77::LZ77State;
 use crate::output_writer::DynamicWriter;
+use crate::pre_filter::PreFilterState;
+use crate::verify::Verifier;
 
 /// A counter used for checking values in debug mode.
 /// Does nothing when debug assertions are disabled.
@@ -48,16 +52,14 @@ impl DebugCounter {
 }
 
 pub struct LengthBuffers {
-    pub leaf_buf: LeafVec,
-    pub length_buf: Vec<EncodedLength>,
+    pub length_buf: EncodedLengths,
 }
 
 impl LengthBuffers {
     #[inline]
-    fn new() -> LengthBuffers {
+    pub fn new() -> LengthBuffers {
         LengthBuffers {
-            leaf_buf: Vec::with_capacity(NUM_LITERALS_AND_LENGTHS),
-            length_buf: Vec::with_capacity(19),
+            length_buf: EncodedLengths::new(),
         }
     }
 }
@@ -74,6 +76,8 @@ pub struct DeflateState<W: Write> {
     pub lz77_writer: DynamicWriter,
     /// Buffers used when generating huffman code lengths.
     pub length_buffers: LengthBuffers,
+    /// State of the filters applied to the input before compressing it, if any.
+    pub pre_filter: PreFilterState,
     /// Total number of bytes consumed/written to the input buffer.
     pub bytes_written: u64,
     /// Wrapped writer.
@@ -85,6 +89,29 @@ pub struct DeflateState<W: Write> {
     /// writer.
     pub output_buf_pos: usize,
     pub flush_mode: Flush,
+    /// Whether input has been received since the last sync flush (or the start of the stream).
+    pub pending_input: bool,
+    /// Whether the last operation was a sync flush, in which case another one would be redundant.
+    pub synced: bool,
+    /// An error from the wrapped writer that happened after the input of a call was consumed, to
+    /// be returned by the next call.
+    pub deferred_error: Option<io::Error>,
+    /// State for skipping flushes done in quick succession, if enabled.
+    pub flush_coalescing: Option<CoalescingState>,
+    /// State for checking the output as it's written, if enabled.
+    pub verifier: Option<Verifier>,
+    /// The kind of data detected at the start of the input, when using `SpecialOptions::Auto`.
+    pub data_kind: Option<DataKind>,
+    /// Pad the end of the stream so its length is a multiple of this many bytes, if more than 1.
+    pub output_alignment: u16,
+    /// The number of bytes the wrapper format adds after the compressed data, which is counted
+    /// when aligning the output.
+    pub trailer_len: u8,
+    /// End every block but the last one with a sync marker, so all blocks start on a byte
+    /// boundary.
+    pub aligned_blocks: bool,
+    /// Handles of the metrics reported by the encoder.
+    pub metrics: EncoderMetrics,
     /// Number of bytes written as calculated by sum of block input lengths.
     /// Used to check that they are correct when `debug_assertions` are enabled.
     pub bytes_written_control: DebugCounter,
@@ -92,30 +119,160 @@ pub struct DeflateState<W: Write> {
 
 impl<W: Write> DeflateState<W> {
     pub fn new(compression_options: CompressionOptions, writer: W) -> DeflateState<W> {
+        DeflateState::new_in(compression_options, writer, &BufferAllocator::default())
+    }
+
+    /// Create a new state, allocating the large internal buffers using `alloc`.
+    pub fn new_in(
+        compression_options: CompressionOptions,
+        writer: W,
+        alloc: &BufferAllocator,
+    ) -> DeflateState<W> {
+        let compression_options = compression_options.resolved();
+        let mut lz77_state = LZ77State::new(
+            compression_options.max_hash_checks,
+            compression_options.lazy_if_less_than,
+            compression_options.matching_type,
+            alloc,
+        );
+        lz77_state.set_nice_match(compression_options.nice_match);
+        lz77_state.set_match_hardening(compression_options.match_hardening);
+        lz77_state.set_chain_decay(compression_options.chain_decay);
+        lz77_state.set_prefetch(compression_options.prefetch);
         DeflateState {
-            input_buffer: InputBuffer::empty(),
-            lz77_state: LZ77State::new(
-                compression_options.max_hash_checks,
-                cmp::min(compression_options.lazy_if_less_than, MAX_HASH_CHECKS),
-                compression_options.matching_type,
-            ),
+            input_buffer: InputBuffer::empty_in(alloc),
+            lz77_state,
             encoder_state: EncoderState::new(Vec::with_capacity(1024 * 32)),
-            lz77_writer: DynamicWriter::new(),
+            lz77_writer: DynamicWriter::with_limits(compression_options.block_limits, alloc),
             length_buffers: LengthBuffers::new(),
+            pre_filter: PreFilterState::new(compression_options.pre_filter),
             compression_options,
             bytes_written: 0,
             inner: Some(writer),
             output_buf_pos: 0,
             flush_mode: Flush::None,
+            pending_input: false,
+            synced: false,
+            deferred_error: None,
+            flush_coalescing: None,
+            verifier: if compression_options.verify {
+                Some(Verifier::new())
+            } else {
+                None
+            },
+            data_kind: None,
+            output_alignment: 0,
+            trailer_len: 0,
+            aligned_blocks: false,
+            metrics: EncoderMetrics::new(),
             bytes_written_control: DebugCounter::default(),
         }
     }
 
+    /// Use the provided data as a preset dictionary.
+    ///
+    /// This has to be done before compressing any data.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        self.lz77_state
+            .set_dictionary(&mut self.input_buffer, dictionary);
+        if let Some(ref mut verifier) = self.verifier {
+            verifier.add_history(dictionary);
+        }
+    }
+
+    /// Add the provided data to the end of the history that matches can refer to.
+    ///
+    /// This has to be done before compressing any data.
+    pub fn add_history(&mut self, data: &[u8]) {
+        self.lz77_state.add_history(&mut self.input_buffer, data);
+        if let Some(ref mut verifier) = self.verifier {
+            verifier.add_history(data);
+        }
+    }
+
+    /// With `SpecialOptions::Auto`, pick the matching settings for the kind of data that
+    /// starts with `data`, if it hasn't been done yet.
+    pub fn detect_data_kind(&mut self, data: &[u8]) {
+        if self.compression_options.special == SpecialOptions::Auto
+            && self.data_kind.is_none()
+            && !data.is_empty()
+        {
+            let kind = DataKind::detect(data);
+            self.data_kind = Some(kind);
+            self.set_matching(kind.options());
+        }
+    }
+
+    /// Use the matching settings from `options`.
+    fn set_matching(&mut self, options: CompressionOptions) {
+        let options = options.resolved();
+        self.lz77_state.set_matching(
+            options.max_hash_checks,
+            options.lazy_if_less_than,
+            options.matching_type,
+        );
+        self.lz77_state.set_nice_match(options.nice_match);
+    }
+
+    /// Pad the output to a multiple of `alignment` bytes when finishing, counting the
+    /// `trailer_len` bytes that will be added after the compressed data.
+    pub fn set_output_alignment(&mut self, alignment: u16, trailer_len: u8) {
+        self.output_alignment = alignment;
+        self.trailer_len = trailer_len;
+    }
+
+    /// Mark that input has been received, so the next sync flush has something to flush.
+    pub fn set_pending_input(&mut self) {
+        self.pending_input = true;
+        self.synced = false;
+    }
+
+    /// Enable or disable coalescing flushes.
+    pub fn set_flush_coalescing(&mut self, settings: Option<FlushCoalescing>) {
+        self.flush_coalescing = settings.map(|s| CoalescingState::new(s, self.bytes_written));
+    }
+
+    /// Whether a flush requested by the user should be done now, or skipped to combine it with a
+    /// later one.
+    pub fn flush_due(&self) -> bool {
+        // Don't hold back an error that has to be reported.
+        self.deferred_error.is_some()
+            || self
+                .flush_coalescing
+                .as_ref()
+                .is_none_or(|c| c.flush_due(self.bytes_written))
+    }
+
+    /// Returns `true` if input has been received since the last sync flush.
+    pub fn has_pending(&self) -> bool {
+        self.pending_input
+    }
+
+    /// The output that has been produced but not written to the wrapped writer yet.
+    pub fn pending_output(&self) -> &[u8] {
+        &self.encoder_state.writer.w[self.output_buf_pos..]
+    }
+
     #[inline]
     pub fn output_buf(&mut self) -> &mut Vec<u8> {
         self.encoder_state.inner_vec()
     }
 
+    /// Forget the data compressed so far, so the data that follows is compressed without
+    /// referring back to it, while continuing the same output stream.
+    ///
+    /// This should only be done right after a sync flush, so no input is left in the buffers.
+    pub fn reset_context(&mut self) {
+        debug_assert!(!self.pending_input);
+        self.input_buffer.clear();
+        self.lz77_writer.clear();
+        self.lz77_state.reset();
+        self.pre_filter.reset();
+        if let Some(ref mut verifier) = self.verifier {
+            verifier.reset_context();
+        }
+    }
+
     /// Resets the status of the decoder, leaving the compression options intact
     ///
     /// If flushing the current writer succeeds, it is replaced with the provided one,
@@ -124,22 +281,37 @@ impl<W: Write> DeflateState<W> {
     ///
     /// If flushing fails, the rest of the writer is not cleared.
     pub fn reset(&mut self, writer: W) -> io::Result<W> {
-        self.encoder_state.flush();
+        self.encoder_state.flush()?;
         self.inner
             .as_mut()
             .expect("Missing writer!")
             .write_all(self.encoder_state.inner_vec())?;
-        self.encoder_state.inner_vec().clear();
-        self.input_buffer = InputBuffer::empty();
+        self.encoder_state.clear_output();
+        self.encoder_state.reset_offsets();
+        self.input_buffer.clear();
         self.lz77_writer.clear();
         self.lz77_state.reset();
+        self.pre_filter.reset();
         self.bytes_written = 0;
         self.output_buf_pos = 0;
         self.flush_mode = Flush::None;
+        self.pending_input = false;
+        self.synced = false;
+        self.deferred_error = None;
+        if let Some(ref mut coalescing) = self.flush_coalescing {
+            coalescing.flushed(0);
+        }
+        if let Some(ref mut verifier) = self.verifier {
+            verifier.reset();
+        }
+        if self.data_kind.take().is_some() {
+            self.set_matching(self.compression_options);
+        }
         if cfg!(debug_assertions) {
             self.bytes_written_control.reset();
         }
-        mem::replace(&mut self.inner, Some(writer))
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Missing writer"))
+        self.inner
+            .replace(writer)
+            .ok_or_else(|| io::Error::other("Missing writer"))
     }
 }
@@ -0,0 +1,371 @@
+//! Utilities for building preset dictionaries.
+//!
+//! Small messages compress poorly on their own, as there is little earlier data for matches to
+//! refer to. If many similar messages are compressed, a preset dictionary containing data
+//! common to them can be used to improve compression considerably.
+//! See [`ZlibEncoder::with_dictionary`](../write/struct.ZlibEncoder.html#method.with_dictionary).
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::chained_hash_table::WINDOW_SIZE;
+
+/// The length of the substrings counted when training, about the length of a short match.
+const KMER_LENGTH: usize = 8;
+
+/// The length of the segments of the samples the dictionary is made up of. Shorter samples are
+/// used as a whole.
+const SEGMENT_LENGTH: usize = 64;
+
+/// Build a preset dictionary of at most `max_size` bytes from a set of sample messages.
+///
+/// The dictionary is made up of segments of the samples containing substrings that occur in many
+/// different samples. Segments considered more useful are placed towards the end of the
+/// dictionary, as matches with shorter distances take less space.
+///
+/// As matches can only refer back 32 KiB, `max_size` is limited to 32768 bytes. The resulting
+/// dictionary may be shorter than `max_size`, or empty, if the samples don't have enough in
+/// common.
+///
+/// This is a simple frequency-based method, and works best when given a reasonably large number
+/// of samples that are representative of the data to be compressed.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Write;
+///
+/// use deflate::write::ZlibEncoder;
+/// use deflate::{dictionary, Compression};
+///
+/// let samples: Vec<Vec<u8>> = (0..100)
+///     .map(|n| format!("{{\"id\": {}, \"status\": \"active\"}}", n).into_bytes())
+///     .collect();
+/// let dict = dictionary::train(&samples, 1024);
+///
+/// let mut encoder = ZlibEncoder::with_dictionary(Vec::new(), Compression::Default, &dict);
+/// encoder.write_all(&samples[0]).unwrap();
+/// let compressed = encoder.finish().unwrap();
+/// # let _ = compressed;
+/// ```
+pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Vec<u8> {
+    let max_size = max_size.min(WINDOW_SIZE);
+    let segment_length = SEGMENT_LENGTH.min(max_size);
+    if segment_length < KMER_LENGTH {
+        return Vec::new();
+    }
+
+    let samples: Vec<&[u8]> = samples
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|s| s.len() >= KMER_LENGTH)
+        .collect();
+    let (kmer_ids, mut frequencies) = count_kmers(&samples);
+
+    // Split the samples into one epoch for each segment that fits in the dictionary, and pick
+    // the best segment from each of them, to avoid having to search all the samples for every
+    // segment.
+    let total: usize = kmer_ids.iter().map(Vec::len).sum();
+    let num_epochs = (max_size / segment_length).max(1);
+    let epoch_length = (total / num_epochs).max(1);
+
+    let mut segments = Vec::new();
+    let mut size = 0;
+    let mut epoch_start = 0;
+    while size + KMER_LENGTH <= max_size && epoch_start < total {
+        let epoch = epoch_start..epoch_start + epoch_length;
+        epoch_start = epoch.end;
+        let best = best_segment(&samples, &kmer_ids, &frequencies, epoch, segment_length);
+        if let Some((sample, segment)) = best {
+            if size + segment.len() > max_size {
+                continue;
+            }
+            // Don't count the substrings in this segment again in later segments.
+            let kmers = segment.start..segment.end - KMER_LENGTH + 1;
+            for &id in &kmer_ids[sample][kmers] {
+                frequencies[id] = 0;
+            }
+            size += segment.len();
+            segments.push(&samples[sample][segment]);
+        }
+    }
+
+    // The first segments picked are the most useful, so put them at the end.
+    segments.reverse();
+    segments.concat()
+}
+
+/// Build a preset dictionary of `len` bytes made up of `pattern` repeated, e.g an empty record
+/// or just zeroes, for formats made up of fixed-size records.
+///
+/// The dictionary ends with a complete copy of `pattern`, so the first byte compressed after it
+/// lines up with the start of the pattern, and the first records can refer back to it at
+/// distances that are a multiple of the record size. This helps most for small files, where
+/// there is little earlier data for matches to refer to otherwise.
+///
+/// The result can be used with
+/// [`ZlibEncoder::with_dictionary`](../write/struct.ZlibEncoder.html#method.with_dictionary), or
+/// with [`DeflateEncoder::prime_from_previous_output`](../write/struct.DeflateEncoder.html#method.prime_from_previous_output)
+/// for raw streams where the decoder fills its window the same way.
+///
+/// `len` is limited to 32768 bytes, as matches can only refer back that far. Returns an empty
+/// dictionary if `pattern` is empty.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Write;
+///
+/// use deflate::write::ZlibEncoder;
+/// use deflate::{dictionary, Compression};
+///
+/// // A few records of 32 bytes, mostly zeroes.
+/// let mut records = vec![0u8; 32 * 4];
+/// records[0] = 1;
+/// records[32] = 2;
+///
+/// let dict = dictionary::repeated(&[0], 1024);
+/// let mut encoder = ZlibEncoder::with_dictionary(Vec::new(), Compression::Default, &dict);
+/// encoder.write_all(&records).unwrap();
+/// let compressed = encoder.finish().unwrap();
+/// # let _ = compressed;
+/// ```
+pub fn repeated(pattern: &[u8], len: usize) -> Vec<u8> {
+    if pattern.is_empty() {
+        return Vec::new();
+    }
+    let len = len.min(WINDOW_SIZE);
+    // Start part way into the pattern, so the last copy is complete.
+    let start = (pattern.len() - len % pattern.len()) % pattern.len();
+    pattern
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(len)
+        .cloned()
+        .collect()
+}
+
+/// Give each distinct substring of length `KMER_LENGTH` in the samples an id.
+///
+/// Returns the id of the substring starting at each position in each sample, and the number of
+/// samples each of the substrings occurs in, indexed by id.
+fn count_kmers(samples: &[&[u8]]) -> (Vec<Vec<usize>>, Vec<u32>) {
+    let mut ids = HashMap::new();
+    // The number of samples the substring occurs in, and the last sample it was seen in.
+    let mut counts: Vec<(u32, usize)> = Vec::new();
+    let kmer_ids = samples
+        .iter()
+        .enumerate()
+        .map(|(n, sample)| {
+            sample
+                .windows(KMER_LENGTH)
+                .map(|kmer| {
+                    let id = *ids.entry(kmer).or_insert_with(|| {
+                        counts.push((0, usize::MAX));
+                        counts.len() - 1
+                    });
+                    let count = &mut counts[id];
+                    if count.1 != n {
+                        *count = (count.0 + 1, n);
+                    }
+                    id
+                })
+                .collect()
+        })
+        .collect();
+    // Substrings that only occur in one sample are not useful.
+    let frequencies = counts
+        .into_iter()
+        .map(|(count, _)| if count > 1 { count } else { 0 })
+        .collect();
+    (kmer_ids, frequencies)
+}
+
+/// Find the segment with the highest score starting within `epoch`, where positions are counted
+/// as if the samples were concatenated.
+///
+/// The score of a segment is the sum of the frequencies of the distinct substrings in it.
+/// Returns the sample and the range of the segment in it, or `None` if no segment in the epoch
+/// has a score above 0.
+fn best_segment(
+    samples: &[&[u8]],
+    kmer_ids: &[Vec<usize>],
+    frequencies: &[u32],
+    epoch: Range<usize>,
+    segment_length: usize,
+) -> Option<(usize, Range<usize>)> {
+    let mut best = None;
+    let mut best_score = 0;
+    let mut sample_start = 0;
+    for (n, ids) in kmer_ids.iter().enumerate() {
+        let sample_end = sample_start + ids.len();
+        let start = epoch.start.max(sample_start) - sample_start;
+        let end = epoch.end.min(sample_end).saturating_sub(sample_start);
+        sample_start = sample_end;
+        // Only consider segments that fit in the sample.
+        let segment_length = segment_length.min(samples[n].len());
+        let kmers_per_segment = segment_length - KMER_LENGTH + 1;
+        let last_start = samples[n].len() - segment_length;
+        if start >= end || start > last_start {
+            continue;
+        }
+
+        // Slide a window over the sample, keeping track of how many times each substring occurs
+        // in it so each one is only counted once.
+        let mut active: HashMap<usize, u32> = HashMap::new();
+        let mut score = 0;
+        for &id in &ids[start..start + kmers_per_segment] {
+            let occurrences = active.entry(id).or_insert(0);
+            if *occurrences == 0 {
+                score += frequencies[id];
+            }
+            *occurrences += 1;
+        }
+        for pos in start..end.min(last_start + 1) {
+            if score > best_score {
+                best_score = score;
+                best = Some((n, pos..pos + segment_length));
+            }
+            if pos == last_start {
+                break;
+            }
+            let removed = ids[pos];
+            let occurrences = active.get_mut(&removed).expect("Missing substring!");
+            *occurrences -= 1;
+            if *occurrences == 0 {
+                score -= frequencies[removed];
+            }
+            let added = ids[pos + kmers_per_segment];
+            let occurrences = active.entry(added).or_insert(0);
+            if *occurrences == 0 {
+                score += frequencies[added];
+            }
+            *occurrences += 1;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{
+        decompress_with_history, decompress_zlib_with_dictionary, get_random_data,
+    };
+    use crate::write::{DeflateEncoder, ZlibEncoder};
+    use crate::{deflate_bytes, deflate_bytes_zlib, Compression};
+    use std::io::Write;
+
+    fn samples() -> Vec<Vec<u8>> {
+        let noise = get_random_data(200 * 16);
+        noise
+            .chunks(16)
+            .enumerate()
+            .map(|(n, noise)| {
+                let mut sample = format!(
+                    "<request id=\"{}\"><user-agent>Mozilla/5.0 (X11; Linux x86_64)</user-agent>",
+                    n
+                )
+                .into_bytes();
+                sample.extend_from_slice(noise);
+                sample.extend_from_slice(b"<accept>text/html,application/xhtml+xml</accept>");
+                sample
+            })
+            .collect()
+    }
+
+    fn compress_with_dictionary(dictionary: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            ZlibEncoder::with_dictionary(Vec::new(), Compression::Default, dictionary);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn train_improves_compression() {
+        let samples = samples();
+        let dictionary = train(&samples[..150], 4096);
+        assert!(!dictionary.is_empty());
+        assert!(dictionary.len() <= 4096);
+
+        let mut total = 0;
+        let mut total_plain = 0;
+        for sample in &samples[150..] {
+            let compressed = compress_with_dictionary(&dictionary, sample);
+            assert!(decompress_zlib_with_dictionary(&dictionary, &compressed) == *sample);
+            total += compressed.len();
+            total_plain += deflate_bytes_zlib(sample).len();
+        }
+        assert!(total * 2 < total_plain);
+    }
+
+    #[test]
+    fn train_size_limit() {
+        let samples = samples();
+        for &max_size in &[0, 7, 8, 100, 1000, 100_000] {
+            let dictionary = train(&samples, max_size);
+            assert!(dictionary.len() <= max_size.min(WINDOW_SIZE));
+        }
+    }
+
+    #[test]
+    fn train_short_samples() {
+        let samples: Vec<Vec<u8>> = (0..100)
+            .map(|n| format!("{{\"id\": {}, \"status\": \"active\"}}", n).into_bytes())
+            .collect();
+        let dictionary = train(&samples, 1000);
+        assert!(!dictionary.is_empty());
+        let compressed = compress_with_dictionary(&dictionary, &samples[50]);
+        assert!(decompress_zlib_with_dictionary(&dictionary, &compressed) == samples[50]);
+        assert!(compressed.len() < deflate_bytes_zlib(&samples[50]).len());
+    }
+
+    #[test]
+    fn repeated_pattern() {
+        assert_eq!(repeated(b"abc", 7), b"cabcabc");
+        assert_eq!(repeated(b"abc", 6), b"abcabc");
+        assert_eq!(repeated(b"abc", 2), b"bc");
+        assert_eq!(repeated(&[0], 100_000).len(), WINDOW_SIZE);
+        assert!(repeated(b"", 100).is_empty());
+        assert!(repeated(b"abc", 0).is_empty());
+    }
+
+    #[test]
+    fn repeated_improves_compression() {
+        // A short file of records of a fixed layout, starting out as a template.
+        let mut template = vec![0u8; 48];
+        template[..8].copy_from_slice(b"RECORD01");
+        template[40..].copy_from_slice(&[0xff; 8]);
+        let mut data = Vec::new();
+        for n in 0..4u8 {
+            let mut record = template.clone();
+            record[16] = n;
+            data.extend_from_slice(&record);
+        }
+
+        let dictionary = repeated(&template, 4096);
+        let compressed = compress_with_dictionary(&dictionary, &data);
+        assert!(decompress_zlib_with_dictionary(&dictionary, &compressed) == data);
+        assert!(compressed.len() < deflate_bytes_zlib(&data).len());
+
+        // Zeroes in the window help a single record that is mostly zeroes.
+        let record = &data[48..96];
+        let zeroes = repeated(&[0], 1024);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+        encoder.prime_from_previous_output(&zeroes).unwrap();
+        encoder.write_all(record).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress_with_history(&zeroes, &compressed) == record);
+        assert!(compressed.len() < deflate_bytes(record).len());
+    }
+
+    #[test]
+    fn train_nothing_in_common() {
+        let empty: &[&[u8]] = &[];
+        assert!(train(empty, 1000).is_empty());
+        let random = get_random_data(10000);
+        let samples: Vec<&[u8]> = random.chunks(100).collect();
+        assert!(train(&samples, 1000).is_empty());
+    }
+}
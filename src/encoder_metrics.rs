@@ -0,0 +1,269 @@
+//! This module reports what the encoders do through the [`metrics`](https://docs.rs/metrics)
+//! facade when the `metrics` feature is enabled. Without it, these functions do nothing and
+//! should be optimised away entirely.
+//!
+//! The metrics are recorded by the recorder that was installed when the encoder was created.
+
+#[cfg(feature = "metrics")]
+use metrics::{Counter, Histogram};
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+
+use crate::inflate::BlockType;
+
+/// Total number of bytes of input in the blocks written.
+#[cfg(feature = "metrics")]
+pub const INPUT_BYTES: &str = "deflate_input_bytes_total";
+/// Total number of compressed bytes written to the wrapped writers.
+#[cfg(feature = "metrics")]
+pub const OUTPUT_BYTES: &str = "deflate_output_bytes_total";
+/// Number of blocks written, labelled with their `type`.
+#[cfg(feature = "metrics")]
+pub const BLOCKS: &str = "deflate_blocks_total";
+/// Histogram of the time spent choosing the codes for and writing each block, in seconds.
+#[cfg(feature = "metrics")]
+pub const BLOCK_DURATION: &str = "deflate_block_duration_seconds";
+/// Number of sync flushes that output a sync marker.
+#[cfg(feature = "metrics")]
+pub const FLUSHES: &str = "deflate_flushes_total";
+/// Number of times the output buffer had to grow to fit a block. This is the only allocation
+/// done when writing blocks, so it stops increasing once the buffer has grown to fit the
+/// largest blocks.
+#[cfg(feature = "metrics")]
+pub const OUTPUT_BUFFER_GROWTHS: &str = "deflate_output_buffer_growths_total";
+
+/// Keeps track of when encoding a block started, if the time is needed.
+#[derive(Clone, Copy)]
+pub struct BlockTimer {
+    #[cfg(feature = "metrics")]
+    start: Instant,
+}
+
+impl BlockTimer {
+    #[inline]
+    pub fn start() -> BlockTimer {
+        BlockTimer {
+            #[cfg(feature = "metrics")]
+            start: Instant::now(),
+        }
+    }
+}
+
+/// The handles of the metrics reported by an encoder.
+///
+/// They are registered with the recorder that is installed when the encoder is created, rather
+/// than looked up every time something is recorded, as building the key of a metric allocates.
+pub struct EncoderMetrics {
+    #[cfg(feature = "metrics")]
+    input_bytes: Counter,
+    #[cfg(feature = "metrics")]
+    output_bytes: Counter,
+    /// Stored, fixed and dynamic blocks, in that order.
+    #[cfg(feature = "metrics")]
+    blocks: [Counter; 3],
+    #[cfg(feature = "metrics")]
+    block_duration: Histogram,
+    #[cfg(feature = "metrics")]
+    flushes: Counter,
+    #[cfg(feature = "metrics")]
+    output_buffer_growths: Counter,
+}
+
+impl EncoderMetrics {
+    pub fn new() -> EncoderMetrics {
+        EncoderMetrics {
+            #[cfg(feature = "metrics")]
+            input_bytes: metrics::counter!(INPUT_BYTES),
+            #[cfg(feature = "metrics")]
+            output_bytes: metrics::counter!(OUTPUT_BYTES),
+            #[cfg(feature = "metrics")]
+            blocks: [
+                metrics::counter!(BLOCKS, "type" => "stored"),
+                metrics::counter!(BLOCKS, "type" => "fixed"),
+                metrics::counter!(BLOCKS, "type" => "dynamic"),
+            ],
+            #[cfg(feature = "metrics")]
+            block_duration: metrics::histogram!(BLOCK_DURATION),
+            #[cfg(feature = "metrics")]
+            flushes: metrics::counter!(FLUSHES),
+            #[cfg(feature = "metrics")]
+            output_buffer_growths: metrics::counter!(OUTPUT_BUFFER_GROWTHS),
+        }
+    }
+
+    /// Record that a block of the given type, containing `input_bytes` of input, has been
+    /// written.
+    #[inline]
+    pub fn record_block(&self, block_type: BlockType, input_bytes: u64, timer: BlockTimer) {
+        #[cfg(feature = "metrics")]
+        {
+            let index = match block_type {
+                BlockType::Stored => 0,
+                BlockType::Fixed => 1,
+                BlockType::Dynamic => 2,
+            };
+            self.input_bytes.increment(input_bytes);
+            self.blocks[index].increment(1);
+            self.block_duration.record(timer.start.elapsed());
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = (block_type, input_bytes, timer);
+    }
+
+    /// Record that `bytes` of compressed data have been written to the wrapped writer.
+    #[inline]
+    pub fn record_output(&self, bytes: usize) {
+        #[cfg(feature = "metrics")]
+        self.output_bytes.increment(bytes as u64);
+        #[cfg(not(feature = "metrics"))]
+        let _ = bytes;
+    }
+
+    /// Record that a sync flush has been done.
+    #[inline]
+    pub fn record_flush(&self) {
+        #[cfg(feature = "metrics")]
+        self.flushes.increment(1);
+    }
+
+    /// Record that the output buffer had to be reallocated to fit a block.
+    #[inline]
+    pub fn record_output_buffer_growth(&self) {
+        #[cfg(feature = "metrics")]
+        self.output_buffer_growths.increment(1);
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use super::*;
+    use crate::test_utils::get_test_data;
+    use crate::write::DeflateEncoder;
+    use crate::Compression;
+    use metrics::{
+        Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+        SharedString, Unit,
+    };
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// The values of the counters, by name and labels, and the number of histogram samples.
+    #[derive(Default)]
+    struct Recorded {
+        counters: Mutex<HashMap<String, u64>>,
+        samples: Mutex<HashMap<String, usize>>,
+    }
+
+    struct NamedCounter(String, Arc<Recorded>);
+
+    impl CounterFn for NamedCounter {
+        fn increment(&self, value: u64) {
+            *self
+                .1
+                .counters
+                .lock()
+                .unwrap()
+                .entry(self.0.clone())
+                .or_insert(0) += value;
+        }
+
+        fn absolute(&self, value: u64) {
+            self.1
+                .counters
+                .lock()
+                .unwrap()
+                .insert(self.0.clone(), value);
+        }
+    }
+
+    impl HistogramFn for NamedCounter {
+        fn record(&self, _: f64) {
+            *self
+                .1
+                .samples
+                .lock()
+                .unwrap()
+                .entry(self.0.clone())
+                .or_insert(0) += 1;
+        }
+    }
+
+    struct TestRecorder(Arc<Recorded>);
+
+    impl TestRecorder {
+        fn named(&self, key: &Key) -> Arc<NamedCounter> {
+            let mut name = key.name().to_string();
+            for label in key.labels() {
+                name += &format!("{{{}={}}}", label.key(), label.value());
+            }
+            Arc::new(NamedCounter(name, self.0.clone()))
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata) -> Counter {
+            Counter::from_arc(self.named(key))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata) -> Histogram {
+            Histogram::from_arc(self.named(key))
+        }
+    }
+
+    #[test]
+    fn encoder_metrics() {
+        let data = get_test_data();
+        let recorder = TestRecorder(Arc::default());
+        let compressed = metrics::with_local_recorder(&recorder, || {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+            encoder.write_all(&data[..10000]).unwrap();
+            encoder.flush().unwrap();
+            encoder.flush().unwrap();
+            encoder.write_all(&data[10000..]).unwrap();
+            encoder.finish().unwrap()
+        });
+
+        let counters = recorder.0.counters.lock().unwrap();
+        assert_eq!(counters[INPUT_BYTES], data.len() as u64);
+        assert_eq!(counters[OUTPUT_BYTES], compressed.len() as u64);
+        // The second flush is redundant, so it does nothing.
+        assert_eq!(counters[FLUSHES], 1);
+        let blocks: u64 = ["stored", "fixed", "dynamic"]
+            .iter()
+            .filter_map(|t| counters.get(&format!("{}{{type={}}}", BLOCKS, t)))
+            .sum();
+        assert!(counters[&format!("{}{{type=dynamic}}", BLOCKS)] >= 2);
+        assert_eq!(
+            recorder.0.samples.lock().unwrap()[BLOCK_DURATION],
+            blocks as usize
+        );
+    }
+
+    #[test]
+    fn output_buffer_growths() {
+        let data = get_test_data();
+        let recorder = TestRecorder(Arc::default());
+        let growths = metrics::with_local_recorder(&recorder, || {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+            encoder.write_all(&data).unwrap();
+            encoder.reset(Vec::new()).unwrap();
+            let growths = recorder.0.counters.lock().unwrap()[OUTPUT_BUFFER_GROWTHS];
+            assert!(growths > 0);
+            encoder.write_all(&data).unwrap();
+            encoder.finish().unwrap();
+            growths
+        });
+        // Once the buffer has grown to fit the blocks of the data, it doesn't have to grow again.
+        let counters = recorder.0.counters.lock().unwrap();
+        assert_eq!(counters[OUTPUT_BUFFER_GROWTHS], growths);
+    }
+}
@@ -1,8 +1,11 @@
-use crate::bitstream::LsbWriter;
+use std::io;
+
+use crate::bitstream::{LsbWriter, MAX_WIDE_BITS};
+use crate::entropy_backend::EntropyBackend;
 use crate::huffman_table::HuffmanTable;
-use crate::lzvalue::LZType;
-#[cfg(test)]
-use std::mem;
+use crate::inflate::BlockType;
+use crate::lzvalue::{LZValue, StoredLength};
+use crate::stored_block;
 
 // The first bits of each block, which describe the type of the block
 // `-TTF` - TT = type, 00 = stored, 01 = fixed, 10 = dynamic, 11 = reserved, F - 1 if final block
@@ -13,16 +16,48 @@ const DYNAMIC_FIRST_BYTE: u16 = 0b100;
 const DYNAMIC_FIRST_BYTE_FINAL: u16 = 0b101;
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum BType {
     NoCompression = 0b00,
     FixedHuffman = 0b01,
     DynamicHuffman = 0b10, // Reserved = 0b11, //Error
 }
 
+/// The position of the start of a block, passed to the callback set with e.g
+/// [`DeflateEncoder::set_block_callback`](write/struct.DeflateEncoder.html#method.set_block_callback).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BlockBoundary {
+    /// The number of bytes of input before the block, i.e the position in the uncompressed data
+    /// where the data in the block starts.
+    pub input_offset: u64,
+    /// The byte in the output where the block header starts.
+    ///
+    /// This is counted from the start of everything written to the wrapped writer, so for
+    /// zlib and gzip streams, it includes the header.
+    pub output_byte: u64,
+    /// The bit in `output_byte` where the block header starts, counting from the least
+    /// significant bit.
+    pub output_bit: u8,
+    /// The type of the block.
+    pub block_type: BlockType,
+}
+
+/// A callback invoked at the start of every block.
+pub type BlockCallback = Box<dyn FnMut(&BlockBoundary) + Send>;
+
 /// A struct wrapping a writer that writes data compressed using the provided huffman table
 pub struct EncoderState {
     pub huffman_table: HuffmanTable,
     pub writer: LsbWriter,
+    /// The bit position of the header of the last block written, if it's still in the buffer.
+    last_block_start: Option<usize>,
+    /// The number of bytes that have been written out and cleared from the output buffer.
+    output_base: u64,
+    /// The number of bytes of input in the blocks written so far.
+    input_offset: u64,
+    /// The number of blocks started so far.
+    blocks: u64,
+    block_callback: Option<BlockCallback>,
 }
 
 impl EncoderState {
@@ -31,15 +66,11 @@ impl EncoderState {
         EncoderState {
             huffman_table: HuffmanTable::empty(),
             writer: LsbWriter::new(writer),
-        }
-    }
-
-    #[cfg(test)]
-    /// Creates a new encoder state using the fixed huffman table
-    pub fn fixed(writer: Vec<u8>) -> EncoderState {
-        EncoderState {
-            huffman_table: HuffmanTable::fixed_table(),
-            writer: LsbWriter::new(writer),
+            last_block_start: None,
+            output_base: 0,
+            input_offset: 0,
+            blocks: 0,
+            block_callback: None,
         }
     }
 
@@ -47,42 +78,64 @@ impl EncoderState {
         &mut self.writer.w
     }
 
-    /// Encodes a literal value to the writer
-    fn write_literal(&mut self, value: u8) {
-        let code = self.huffman_table.get_literal(value);
-        debug_assert!(code.length > 0);
-        self.writer.write_bits(code.code, code.length);
+    /// Clear the output buffer, after the data in it has been written out.
+    pub fn clear_output(&mut self) {
+        self.output_base += self.writer.w.len() as u64;
+        self.writer.w.clear();
+        self.last_block_start = None;
     }
 
-    /// Write a LZvalue to the contained writer, returning Err if the write operation fails
-    pub fn write_lzvalue(&mut self, value: LZType) {
-        match value {
-            LZType::Literal(l) => self.write_literal(l),
-            LZType::StoredLengthDistance(l, d) => {
-                let (code, extra_bits_code) = self.huffman_table.get_length_huffman(l);
-                debug_assert!(
-                    code.length != 0,
-                    format!("Code: {:?}, Value: {:?}", code, value)
-                );
-                self.writer.write_bits(code.code, code.length);
-                self.writer
-                    .write_bits(extra_bits_code.code, extra_bits_code.length);
-
-                let (code, extra_bits_code) = self.huffman_table.get_distance_huffman(d);
-                debug_assert!(
-                    code.length != 0,
-                    format!("Code: {:?}, Value: {:?}", code, value)
-                );
-
-                self.writer.write_bits(code.code, code.length);
-                self.writer
-                    .write_bits(extra_bits_code.code, extra_bits_code.length)
-            }
-        };
+    /// Reset the input and output positions reported to the block callback and the number of
+    /// blocks, for starting a new stream.
+    pub fn reset_offsets(&mut self) {
+        self.output_base = 0;
+        self.input_offset = 0;
+        self.blocks = 0;
+    }
+
+    /// The number of bits output so far in the current stream, including the ones that have
+    /// been written out.
+    pub fn output_bit_position(&self) -> u64 {
+        self.output_base * 8 + self.writer.bit_position() as u64
+    }
+
+    /// The number of blocks started so far in the current stream.
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn set_block_callback(&mut self, callback: Option<BlockCallback>) {
+        self.block_callback = callback;
+    }
+
+    /// Add the number of input bytes in the block that was just written, to keep track of the
+    /// input offset of the next one.
+    pub fn add_block_input(&mut self, bytes: u64) {
+        self.input_offset += bytes;
+    }
+
+    /// Note the start of a block at the current position, and invoke the callback, if any.
+    fn start_block(&mut self, block_type: BlockType) {
+        let position = self.writer.bit_position();
+        self.last_block_start = Some(position);
+        self.blocks += 1;
+        if let Some(ref mut callback) = self.block_callback {
+            callback(&BlockBoundary {
+                input_offset: self.input_offset,
+                output_byte: self.output_base + (position / 8) as u64,
+                output_bit: (position % 8) as u8,
+                block_type,
+            });
+        }
     }
 
     /// Write the start of a block, returning Err if the write operation fails.
     pub fn write_start_of_block(&mut self, fixed: bool, final_block: bool) {
+        self.start_block(if fixed {
+            BlockType::Fixed
+        } else {
+            BlockType::Dynamic
+        });
         if final_block {
             // The final block has one bit flipped to indicate it's
             // the final one
@@ -98,33 +151,232 @@ impl EncoderState {
         }
     }
 
-    /// Write the end of block code
-    pub fn write_end_of_block(&mut self) {
-        let code = self.huffman_table.get_end_of_block();
-        self.writer.write_bits(code.code, code.length)
+    /// Write the header of a stored block, and align the output to the byte boundary.
+    pub fn write_stored_header(&mut self, final_block: bool) {
+        self.start_block(BlockType::Stored);
+        stored_block::write_stored_header(&mut self.writer, final_block);
+    }
+
+    /// Write an empty stored block to align the output to a byte boundary.
+    ///
+    /// Unlike the sync marker written on a sync flush, this is not reported to the block
+    /// callback, as it only separates the blocks around it.
+    pub fn write_sync_marker(&mut self) {
+        self.last_block_start = Some(self.writer.bit_position());
+        self.blocks += 1;
+        stored_block::write_stored_header(&mut self.writer, false);
+        stored_block::compress_block_stored(&[], &mut self.writer).expect("Write error");
+    }
+
+    /// Retroactively mark the last block that was written as the final one.
+    ///
+    /// This is only possible if the header of the block is still in the output buffer, and
+    /// at or after the byte at `min_pos`, which is the part of the buffer that has not been
+    /// written out yet. Returns `true` if this was possible.
+    pub fn set_last_block_final(&mut self, min_pos: usize) -> bool {
+        match self.last_block_start {
+            Some(position) if position / 8 >= min_pos => {
+                // The first bit of the block header is the final block flag.
+                self.writer.set_bit(position);
+                self.last_block_start = None;
+                true
+            }
+            _ => false,
+        }
     }
 
     /// Flush the contained writer and it's bitstream wrapper.
-    pub fn flush(&mut self) {
-        self.writer.flush_raw()
+    ///
+    /// Returns an error if the state of the bit writer is not consistent.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.finish()
     }
 
+    #[cfg(test)]
     pub fn set_huffman_to_fixed(&mut self) {
         self.huffman_table.set_to_fixed()
     }
+}
 
-    /// Reset the encoder state with a new writer, returning the old one if flushing
-    /// succeeds.
-    #[cfg(test)]
-    pub fn reset(&mut self, writer: Vec<u8>) -> Vec<u8> {
-        // Make sure the writer is flushed
-        // Ideally this should be done before this function is called, but we
-        // do it here just in case.
-        self.flush();
-        // Reset the huffman table
-        // This probably isn't needed, but again, we do it just in case to avoid leaking any data
-        // If this turns out to be a performance issue, it can probably be ignored later.
-        self.huffman_table = HuffmanTable::empty();
-        mem::replace(&mut self.writer.w, writer)
+/// Encodes the symbols using the huffman codes in `huffman_table`, as normal deflate output.
+impl EntropyBackend for EncoderState {
+    /// Encodes a literal value to the writer
+    #[inline]
+    fn write_literal(&mut self, value: u8) {
+        let code = self.huffman_table.get_literal(value);
+        debug_assert!(code.length > 0);
+        self.writer.write_bits(code.code, code.length);
+    }
+
+    /// Encodes literals several at a time, packing as many codes as fit in one
+    /// `write_bits_wide` call, i.e at least 3 and usually 6 or 7.
+    #[inline]
+    fn write_literals(&mut self, literals: &[LZValue]) {
+        let mut bits = 0u64;
+        let mut n = 0;
+        for literal in literals {
+            let code = self.huffman_table.get_literal(literal.literal_value());
+            debug_assert!(code.length > 0);
+            if n + code.length > MAX_WIDE_BITS {
+                self.writer.write_bits_wide(bits, n);
+                bits = 0;
+                n = 0;
+            }
+            bits |= u64::from(code.code) << n;
+            n += code.length;
+        }
+        if n > 0 {
+            self.writer.write_bits_wide(bits, n);
+        }
+    }
+
+    #[inline]
+    fn write_length_distance(&mut self, length: StoredLength, distance: u16) {
+        let (code, extra_bits_code) = self.huffman_table.get_length_huffman(length);
+        debug_assert!(code.length != 0, "Code: {:?}, Length: {:?}", code, length);
+        self.writer.write_bits(code.code, code.length);
+        self.writer
+            .write_bits(extra_bits_code.code, extra_bits_code.length);
+
+        let (code, extra_bits_code) = self.huffman_table.get_distance_huffman(distance);
+        debug_assert!(code.length != 0, "Code: {:?}, Distance: {}", code, distance);
+
+        self.writer.write_bits(code.code, code.length);
+        self.writer
+            .write_bits(extra_bits_code.code, extra_bits_code.length)
+    }
+
+    /// Write the end of block code
+    fn write_end_of_block(&mut self) {
+        let code = self.huffman_table.get_end_of_block();
+        self.writer.write_bits(code.code, code.length)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    //! Checks of the bit layout of the output against hand-computed values.
+    //!
+    //! The expected bits are written in the order they appear in the stream, which is from the
+    //! least significant bit of each byte. Huffman codes appear most significant bit first, and
+    //! all other fields least significant bit first.
+    use super::*;
+    use crate::compression_options::{CompressionOptions, SpecialOptions};
+    use crate::entropy_backend::flush_to_backend;
+    use crate::fixed_table::FixedBackend;
+    use crate::inflate::{dump, ElementKind};
+    use crate::lzvalue::LZValue;
+    use crate::stored_block::compress_block_stored;
+    use crate::test_utils::{bits_to_bytes, decompress_to_end, get_test_data};
+
+    fn output(mut state: EncoderState) -> Vec<u8> {
+        state.flush().unwrap();
+        state.writer.w
+    }
+
+    fn header(fixed: bool, final_block: bool) -> Vec<u8> {
+        let mut state = EncoderState::new(Vec::new());
+        state.write_start_of_block(fixed, final_block);
+        output(state)
+    }
+
+    #[test]
+    fn block_header_bits() {
+        // BFINAL, then BTYPE starting with the least significant bit, so fixed (01) is
+        // written as 1, 0 and dynamic (10) as 0, 1.
+        assert_eq!(header(true, true), bits_to_bytes("1 10"));
+        assert_eq!(header(true, false), bits_to_bytes("0 10"));
+        assert_eq!(header(false, true), bits_to_bytes("1 01"));
+        assert_eq!(header(false, false), bits_to_bytes("0 01"));
+    }
+
+    #[test]
+    fn stored_block_bits() {
+        // An empty fixed block, followed by a final stored block, which is aligned to the next
+        // byte after the header, with LEN and NLEN as little-endian 16-bit values.
+        let mut state = EncoderState::new(Vec::new());
+        state.write_start_of_block(true, false);
+        FixedBackend::new(&mut state.writer).write_end_of_block();
+        state.write_stored_header(true);
+        compress_block_stored(b"ab", &mut state.writer).unwrap();
+        let compressed = output(state);
+
+        let mut expected = bits_to_bytes("0 10 0000000 1 00 000");
+        expected.extend_from_slice(&[0x02, 0x00, 0xfd, 0xff, b'a', b'b']);
+        assert_eq!(compressed, expected);
+        assert_eq!(decompress_to_end(&compressed), b"ab");
+    }
+
+    #[test]
+    fn fixed_literal_bits() {
+        let options = CompressionOptions {
+            special: SpecialOptions::ForceFixed,
+            ..CompressionOptions::default()
+        };
+        // The fixed code for `a` (0x61) is 0x30 + 0x61 = 1001_0001, followed by the 7-bit end
+        // of block code 000_0000.
+        let expected = bits_to_bytes("1 10 10010001 0000000");
+        assert_eq!(expected, [0x4b, 0x04, 0x00]);
+        assert_eq!(crate::deflate_bytes_conf(b"a", options), expected);
+
+        // Literals from 144 and up have 9-bit codes, starting at 1_1001_0000.
+        let mut state = EncoderState::new(Vec::new());
+        state.set_huffman_to_fixed();
+        state.write_start_of_block(true, true);
+        state.write_literal(143);
+        state.write_literal(144);
+        state.write_literal(255);
+        state.write_end_of_block();
+        assert_eq!(
+            output(state),
+            bits_to_bytes("1 10 10111111 110010000 111111111 0000000")
+        );
+    }
+
+    #[test]
+    fn length_distance_bits() {
+        // Length 20 has code 269, which is 7 bits (0001101), followed by 2 extra bits with the
+        // value 20 - 19 = 1. Distance 800 has code 19 (10011), followed by 8 extra bits with the
+        // value 800 - 769 = 31.
+        let expected = bits_to_bytes("1 10 0001101 10 10011 11111000 0000000");
+        let values = [LZValue::length_distance(20, 800)];
+
+        let mut state = EncoderState::new(Vec::new());
+        state.set_huffman_to_fixed();
+        state.write_start_of_block(true, true);
+        flush_to_backend(&values, &mut state);
+        assert_eq!(output(state), expected);
+
+        let mut state = EncoderState::new(Vec::new());
+        state.write_start_of_block(true, true);
+        flush_to_backend(&values, &mut FixedBackend::new(&mut state.writer));
+        assert_eq!(output(state), expected);
+    }
+
+    #[test]
+    fn dynamic_header_bits() {
+        let compressed = crate::deflate_bytes(&get_test_data()[..20000]);
+        // Read the `n` bits starting at bit `pos`, least significant bit first.
+        let bits = |pos: usize, n: usize| {
+            (0..n).fold(0u16, |acc, i| {
+                let p = pos + i;
+                acc | (u16::from((compressed[p / 8] >> (p % 8)) & 1) << i)
+            })
+        };
+
+        // A final dynamic block, followed by HLIT (5 bits), HDIST (5 bits) and HCLEN (4 bits).
+        assert_eq!(bits(0, 3), 0b101);
+        let literal_lengths = bits(3, 5) + 257;
+        let distances = bits(8, 5) + 1;
+        let code_length_codes = bits(13, 4) + 4;
+        assert!(code_length_codes <= 19);
+        let dump = dump(&compressed).unwrap();
+        assert_eq!(
+            dump.elements()[1].kind,
+            ElementKind::CodeLengths {
+                literal_lengths,
+                distances,
+            }
+        );
     }
 }
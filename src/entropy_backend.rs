@@ -0,0 +1,198 @@
+//! The interface between the lz77 front-end and the code that emits the symbols it produces.
+//!
+//! The lz77 stage produces a sequence of literals and length/distance pairs for each block,
+//! which is then passed to an `EntropyBackend` as runs of literals, each followed by a match. The deflate huffman encoder in
+//! `EncoderState` is the one used for normal output, but keeping the emission behind this trait
+//! means other ways of encoding the symbols can be tried out without touching the matching code.
+
+use crate::lzvalue::{LZType, LZValue, StoredLength};
+
+/// Something that encodes the symbols of a block produced by the lz77 stage.
+///
+/// The backend is responsible for any per-block setup (like choosing and writing huffman
+/// codes) before the symbols are passed to it.
+pub trait EntropyBackend {
+    /// Encode a literal byte.
+    fn write_literal(&mut self, value: u8);
+
+    /// Encode a run of literals. All the values in `literals` have to be literals.
+    ///
+    /// Literals make up most of the symbols for a lot of data, so backends can override this to
+    /// encode several of them at a time.
+    #[inline]
+    fn write_literals(&mut self, literals: &[LZValue]) {
+        for literal in literals {
+            self.write_literal(literal.literal_value());
+        }
+    }
+
+    /// Encode a match of `length` (stored as the actual length minus 3) at `distance`.
+    fn write_length_distance(&mut self, length: StoredLength, distance: u16);
+
+    /// Encode the end of the block.
+    fn write_end_of_block(&mut self);
+}
+
+/// A run of literals from the lz77 stage, and the match following it, if any.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sequence<'a> {
+    pub literals: &'a [LZValue],
+    pub length_distance: Option<(StoredLength, u16)>,
+}
+
+/// Iterator splitting lz77 symbols into `Sequence`s.
+pub struct Sequences<'a> {
+    rest: &'a [LZValue],
+}
+
+impl<'a> Iterator for Sequences<'a> {
+    type Item = Sequence<'a>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sequence<'a>> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let rest = self.rest;
+        match rest.iter().position(|v| !v.is_literal()) {
+            Some(n) => {
+                self.rest = &rest[n + 1..];
+                let length_distance = match rest[n].value() {
+                    LZType::StoredLengthDistance(l, d) => Some((l, d)),
+                    LZType::Literal(_) => unreachable!(),
+                };
+                Some(Sequence {
+                    literals: &rest[..n],
+                    length_distance,
+                })
+            }
+            None => {
+                self.rest = &[];
+                Some(Sequence {
+                    literals: rest,
+                    length_distance: None,
+                })
+            }
+        }
+    }
+}
+
+/// Split `symbols` into runs of literals, each followed by a match, except possibly the last.
+///
+/// This is like the sequences used by libdeflate, but worked out from the symbols when they are
+/// written rather than stored in this form, as the lz77 stage and the rest of the encoder
+/// work with the symbols in order.
+pub fn sequences(symbols: &[LZValue]) -> Sequences<'_> {
+    Sequences { rest: symbols }
+}
+
+/// Write all the lz77 encoded data in the buffer using the specified backend, and finish with
+/// the end of block code.
+pub fn flush_to_backend<B>(buffer: &[LZValue], backend: &mut B)
+where
+    B: EntropyBackend + ?Sized,
+{
+    for sequence in sequences(buffer) {
+        backend.write_literals(sequence.literals);
+        if let Some((length, distance)) = sequence.length_distance {
+            backend.write_length_distance(length, distance);
+        }
+    }
+    backend.write_end_of_block()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoder_state::EncoderState;
+    use crate::test_utils::decompress_to_end;
+
+    /// A backend that just records the symbols it's given.
+    #[derive(Default)]
+    struct Recorder {
+        symbols: Vec<LZType>,
+        blocks: usize,
+    }
+
+    impl EntropyBackend for Recorder {
+        fn write_literal(&mut self, value: u8) {
+            self.symbols.push(LZType::Literal(value));
+        }
+
+        fn write_length_distance(&mut self, length: StoredLength, distance: u16) {
+            self.symbols
+                .push(LZType::StoredLengthDistance(length, distance));
+        }
+
+        fn write_end_of_block(&mut self) {
+            self.blocks += 1;
+        }
+    }
+
+    fn test_values() -> Vec<LZValue> {
+        vec![
+            LZValue::literal(b'a'),
+            LZValue::literal(b'b'),
+            LZValue::length_distance(3, 2),
+            LZValue::length_distance(258, 1),
+            LZValue::literal(0),
+        ]
+    }
+
+    #[test]
+    fn symbols_passed_through() {
+        let values = test_values();
+        let mut recorder = Recorder::default();
+        flush_to_backend(&values, &mut recorder);
+        let expected: Vec<_> = values.iter().map(LZValue::value).collect();
+        assert_eq!(recorder.symbols, expected);
+        assert_eq!(recorder.blocks, 1);
+    }
+
+    #[test]
+    fn split_sequences() {
+        let values = test_values();
+        let split: Vec<_> = sequences(&values).collect();
+        assert_eq!(split.len(), 3);
+        assert_eq!(split[0].literals, &values[..2]);
+        assert_eq!(split[1].literals, &[]);
+        assert_eq!(split[2].literals, &values[4..]);
+        assert_eq!(split[2].length_distance, None);
+        for (sequence, &(length, distance)) in split.iter().zip(&[(3, 2), (258, 1)]) {
+            let (l, d) = sequence.length_distance.unwrap();
+            assert_eq!((l.actual_length(), d), (length, distance));
+        }
+
+        assert_eq!(sequences(&[]).count(), 0);
+        let one_match = [LZValue::length_distance(10, 5)];
+        let split: Vec<_> = sequences(&one_match).collect();
+        assert_eq!(split.len(), 1);
+        assert!(split[0].literals.is_empty() && split[0].length_distance.is_some());
+    }
+
+    #[test]
+    fn dyn_backend() {
+        // The huffman encoder should give the same output whether it's used directly or
+        // through a trait object.
+        let mut direct = EncoderState::new(Vec::new());
+        direct.set_huffman_to_fixed();
+        direct.write_start_of_block(true, true);
+        flush_to_backend(&test_values(), &mut direct);
+        direct.flush().unwrap();
+
+        let mut state = EncoderState::new(Vec::new());
+        state.set_huffman_to_fixed();
+        state.write_start_of_block(true, true);
+        {
+            let backend: &mut dyn EntropyBackend = &mut state;
+            flush_to_backend(&test_values(), backend);
+        }
+        state.flush().unwrap();
+
+        assert_eq!(direct.writer.w, state.writer.w);
+        let mut expected = b"ababa".to_vec();
+        expected.extend_from_slice(&[b'a'; 258]);
+        expected.push(0);
+        assert_eq!(decompress_to_end(&state.writer.w), expected);
+    }
+}
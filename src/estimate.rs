@@ -0,0 +1,177 @@
+//! Estimating the size of the compressed data without producing it.
+
+use std::io;
+
+use crate::compress::{Flush, LARGEST_OUTPUT_BUF_SIZE};
+use crate::compression_options::{CompressionOptions, SpecialOptions};
+use crate::deflate_state::DeflateState;
+use crate::huffman_lengths::{fixed_block_length, gen_huffman_lengths_sized};
+use crate::lz77::{lz77_compress_block, LZ77Status};
+
+/// Estimate the size of the raw DEFLATE data
+/// [`deflate_bytes_conf`](fn.deflate_bytes_conf.html) produces from `input` with `options`,
+/// without producing the output.
+///
+/// The input goes through the same lz77 matching and the same choice of block types as when
+/// compressing it, but rather than encoding each block, only the number of bits it would take up
+/// is added up. This is quicker and uses less memory than compressing the data, and the result
+/// is normally the exact size of the output, though that isn't guaranteed. Add the size of the
+/// header and trailer to get the size of a zlib or gzip stream.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{deflate_bytes_conf, estimate_compressed_size, Compression};
+///
+/// let data = b"This is some test data, with some test data repeated.";
+/// let estimate = estimate_compressed_size(data, Compression::Default);
+/// assert_eq!(estimate, deflate_bytes_conf(data, Compression::Default).len());
+/// ```
+pub fn estimate_compressed_size<O: Into<CompressionOptions>>(input: &[u8], options: O) -> usize {
+    let options = options.into();
+    let filtered;
+    let input = if options.pre_filter.is_active() {
+        filtered = options.pre_filter.apply(input);
+        &filtered[..]
+    } else {
+        input
+    };
+
+    // Only the parts of the state used for matching and generating code lengths are used here.
+    let mut state = Box::new(DeflateState::new(options, io::sink()));
+    state.detect_data_kind(input);
+    let force_fixed = state.compression_options.special == SpecialOptions::ForceFixed;
+
+    // The number of bits output so far.
+    let mut bits = 0u64;
+    // The bit position at which the output buffer of the real encoder would start, as it's
+    // written out whenever it gets too large.
+    let mut buffer_start = 0u64;
+    // The position of the last block, if it would still be in the output buffer, so it could be
+    // made the final block rather than outputting an empty one.
+    let mut last_block_start = None;
+    let mut slice = input;
+
+    while !state.lz77_state.is_last_block() {
+        if (bits - buffer_start) / 8 > LARGEST_OUTPUT_BUF_SIZE as u64 {
+            buffer_start = bits / 8 * 8;
+            last_block_start = None;
+        }
+
+        let (written, status, _) = lz77_compress_block(
+            slice,
+            &mut state.lz77_state,
+            &mut state.input_buffer,
+            &mut state.lz77_writer,
+            Flush::Finish,
+        );
+        slice = &slice[written..];
+
+        let last_block = state.lz77_state.is_last_block();
+        let block_input_bytes = state.lz77_state.current_block_input_bytes();
+        let skip_block = block_input_bytes == 0 && (!last_block || last_block_start.is_some());
+        if !skip_block {
+            let (l_freqs, d_freqs) = state.lz77_writer.get_frequencies();
+            last_block_start = Some(bits);
+            bits += if force_fixed {
+                fixed_block_length(l_freqs, d_freqs)
+            } else {
+                let (l_lengths, d_lengths) = state.encoder_state.huffman_table.get_lengths_mut();
+                gen_huffman_lengths_sized(
+                    l_freqs,
+                    d_freqs,
+                    block_input_bytes,
+                    (bits % 8) as u8,
+                    state.compression_options.max_code_length,
+                    l_lengths,
+                    d_lengths,
+                    &mut state.length_buffers,
+                )
+                .1
+            };
+        }
+
+        state.lz77_writer.clear();
+        state.lz77_state.reset_input_bytes();
+
+        if status == LZ77Status::Finished {
+            if !state.lz77_state.is_last_block() && last_block_start.is_none() {
+                // The real encoder ends the stream with an empty fixed block in this case, made
+                // up of the 3-bit block header and the 7-bit end of block code.
+                bits += 10;
+            }
+            break;
+        }
+    }
+
+    bits.div_ceil(8) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression_options::Compression;
+    use crate::test_utils::{get_random_data, get_test_data};
+    use crate::{deflate_bytes_conf, BlockLimits, PreFilter};
+    use std::convert::TryFrom;
+
+    fn check_exact(data: &[u8], options: CompressionOptions) {
+        assert_eq!(
+            estimate_compressed_size(data, options),
+            deflate_bytes_conf(data, options).len(),
+            "{:?}",
+            options
+        );
+    }
+
+    #[test]
+    fn exact_for_levels() {
+        let test_data = get_test_data();
+        let random = get_random_data(100_000);
+        let runs: Vec<u8> = (0..200_000u32).map(|n| (n / 1000) as u8).collect();
+        for data in [&test_data[..], &random, &runs, b"", b"a", b"abcabcabcabc"] {
+            for level in 1..=9 {
+                check_exact(data, CompressionOptions::try_from(level).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn exact_for_options() {
+        let data = get_test_data();
+        let options = [
+            CompressionOptions::huffman_only(),
+            CompressionOptions::rle(),
+            CompressionOptions {
+                special: SpecialOptions::ForceFixed,
+                ..Default::default()
+            },
+            CompressionOptions {
+                special: SpecialOptions::Auto,
+                ..Default::default()
+            },
+            CompressionOptions {
+                block_limits: BlockLimits {
+                    max_symbols: 1000,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            CompressionOptions {
+                max_code_length: 11,
+                ..Default::default()
+            },
+            CompressionOptions {
+                pre_filter: PreFilter {
+                    delta: 2,
+                    transpose: 0,
+                },
+                ..Default::default()
+            },
+        ];
+        for &options in &options {
+            check_exact(&data, options);
+        }
+        check_exact(&data, Compression::Fast.into());
+    }
+}
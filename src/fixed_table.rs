@@ -0,0 +1,256 @@
+//! Precomputed bit patterns for writing blocks using the fixed huffman codes.
+//!
+//! As the fixed codes never change, the (bit reversed) code for each symbol can be computed at
+//! compile time, along with the number of extra bits and the base value for each length and
+//! distance code. Encoding a fixed block is then just table lookups, rather than first filling
+//! in a `HuffmanTable` and going through it for each symbol, which makes a noticeable
+//! difference for the fastest compression levels, which output mostly fixed blocks.
+
+use crate::bit_reverse::reverse_bits;
+use crate::bitstream::{LsbWriter, MAX_WIDE_BITS};
+use crate::entropy_backend::EntropyBackend;
+use crate::huffman_table::{
+    get_distance_code, num_extra_bits_for_distance_code, num_extra_bits_for_length_code,
+    HuffmanCode, BASE_LENGTH, DISTANCE_BASE, END_OF_BLOCK_POSITION, LENGTH_BITS_START, LENGTH_CODE,
+    NUM_DISTANCE_CODES, NUM_LENGTH_CODES,
+};
+use crate::lzvalue::{LZValue, StoredLength};
+
+/// The code for a length or distance symbol, and how to add the extra bits after it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct FixedMatchCode {
+    /// The huffman code, reversed so it can be written LSB first.
+    code: u16,
+    /// The length of the huffman code.
+    length: u8,
+    /// The number of extra bits following the code.
+    extra_bits: u8,
+    /// The value subtracted from the stored length, or the distance minus one, to get the
+    /// value of the extra bits.
+    base: u16,
+}
+
+/// The codes for all the literals.
+const FIXED_LITERAL_CODES: [HuffmanCode; 256] = literal_codes();
+
+/// The code for the end of block symbol.
+const FIXED_END_OF_BLOCK: HuffmanCode = fixed_ll_code(END_OF_BLOCK_POSITION as u16);
+
+/// The codes for the length symbols, indexed by length code number (starting at 0 rather than
+/// 257).
+const FIXED_LENGTH_CODES: [FixedMatchCode; NUM_LENGTH_CODES] = length_codes();
+
+/// The codes for the distance symbols.
+const FIXED_DISTANCE_CODES: [FixedMatchCode; NUM_DISTANCE_CODES] = distance_codes();
+
+/// Get the fixed code for literal/length symbol `symbol`, as defined in section 3.2.6 of
+/// RFC 1951.
+const fn fixed_ll_code(symbol: u16) -> HuffmanCode {
+    let (code, length) = match symbol {
+        0..=143 => (0b0011_0000 + symbol, 8),
+        144..=255 => (0b1_1001_0000 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0b1100_0000 + symbol - 280, 8),
+    };
+    HuffmanCode {
+        code: reverse_bits(code, length),
+        length,
+    }
+}
+
+const fn literal_codes() -> [HuffmanCode; 256] {
+    let mut table = [HuffmanCode { code: 0, length: 0 }; 256];
+    let mut n = 0;
+    while n < 256 {
+        table[n] = fixed_ll_code(n as u16);
+        n += 1;
+    }
+    table
+}
+
+const fn length_codes() -> [FixedMatchCode; NUM_LENGTH_CODES] {
+    let mut table = [FixedMatchCode {
+        code: 0,
+        length: 0,
+        extra_bits: 0,
+        base: 0,
+    }; NUM_LENGTH_CODES];
+    let mut n = 0;
+    while n < NUM_LENGTH_CODES {
+        let code = fixed_ll_code(LENGTH_BITS_START + n as u16);
+        table[n] = FixedMatchCode {
+            code: code.code,
+            length: code.length,
+            extra_bits: num_extra_bits_for_length_code(n as u8),
+            base: BASE_LENGTH[n] as u16,
+        };
+        n += 1;
+    }
+    table
+}
+
+const fn distance_codes() -> [FixedMatchCode; NUM_DISTANCE_CODES] {
+    let mut table = [FixedMatchCode {
+        code: 0,
+        length: 0,
+        extra_bits: 0,
+        base: 0,
+    }; NUM_DISTANCE_CODES];
+    let mut n = 0;
+    while n < NUM_DISTANCE_CODES {
+        // All the fixed distance codes are 5 bits long.
+        table[n] = FixedMatchCode {
+            code: reverse_bits(n as u16, 5),
+            length: 5,
+            extra_bits: num_extra_bits_for_distance_code(n as u8),
+            base: DISTANCE_BASE[n],
+        };
+        n += 1;
+    }
+    table
+}
+
+/// Get the bits to write for a length, with the extra bits packed in after the code, and the
+/// total number of bits. This is at most 8 + 5 bits.
+#[inline]
+fn length_bits(length: StoredLength) -> (u16, u8) {
+    let stored = length.stored_length();
+    let entry = FIXED_LENGTH_CODES[usize::from(LENGTH_CODE[usize::from(stored)])];
+    let extra = u16::from(stored) - entry.base;
+    (
+        entry.code | (extra << entry.length),
+        entry.length + entry.extra_bits,
+    )
+}
+
+/// Get the bits to write for a distance, with the extra bits packed in after the code, and the
+/// total number of bits. This is at most 5 + 13 bits.
+#[inline]
+fn distance_bits(distance: u16) -> (u32, u8) {
+    let entry = FIXED_DISTANCE_CODES[usize::from(get_distance_code(distance))];
+    let extra = u32::from(distance - 1 - entry.base);
+    (
+        u32::from(entry.code) | (extra << entry.length),
+        entry.length + entry.extra_bits,
+    )
+}
+
+/// Writes the symbols of a block using the fixed huffman codes, straight from the precomputed
+/// tables.
+///
+/// The block header has to be written separately.
+pub struct FixedBackend<'a> {
+    writer: &'a mut LsbWriter,
+}
+
+impl<'a> FixedBackend<'a> {
+    pub fn new(writer: &'a mut LsbWriter) -> FixedBackend<'a> {
+        FixedBackend { writer }
+    }
+}
+
+impl EntropyBackend for FixedBackend<'_> {
+    #[inline]
+    fn write_literal(&mut self, value: u8) {
+        let code = FIXED_LITERAL_CODES[usize::from(value)];
+        self.writer.write_bits(code.code, code.length);
+    }
+
+    /// Encodes literals several at a time, packing as many codes (8 or 9 bits each) as fit in
+    /// one `write_bits_wide` call.
+    #[inline]
+    fn write_literals(&mut self, literals: &[LZValue]) {
+        let mut bits = 0u64;
+        let mut n = 0;
+        for literal in literals {
+            let code = FIXED_LITERAL_CODES[usize::from(literal.literal_value())];
+            if n + code.length > MAX_WIDE_BITS {
+                self.writer.write_bits_wide(bits, n);
+                bits = 0;
+                n = 0;
+            }
+            bits |= u64::from(code.code) << n;
+            n += code.length;
+        }
+        if n > 0 {
+            self.writer.write_bits_wide(bits, n);
+        }
+    }
+
+    #[inline]
+    fn write_length_distance(&mut self, length: StoredLength, distance: u16) {
+        debug_assert!(distance > 0);
+        let (bits, n) = length_bits(length);
+        self.writer.write_bits(bits, n);
+        let (bits, n) = distance_bits(distance);
+        self.writer.write_bits_long(bits, n);
+    }
+
+    #[inline]
+    fn write_end_of_block(&mut self) {
+        self.writer
+            .write_bits(FIXED_END_OF_BLOCK.code, FIXED_END_OF_BLOCK.length);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoder_state::EncoderState;
+    use crate::entropy_backend::flush_to_backend;
+    use crate::huffman_table::{HuffmanTable, MAX_MATCH, MIN_MATCH};
+    use crate::lzvalue::LZValue;
+
+    #[test]
+    fn same_as_huffman_table() {
+        let table = HuffmanTable::fixed_table();
+        for value in 0..=255 {
+            assert_eq!(
+                FIXED_LITERAL_CODES[usize::from(value)],
+                table.get_literal(value)
+            );
+        }
+        assert_eq!(FIXED_END_OF_BLOCK, table.get_end_of_block());
+
+        for length in MIN_MATCH..=MAX_MATCH {
+            let (code, extra) = table.get_length_huffman(StoredLength::new((length - 3) as u8));
+            let (bits, n) = length_bits(StoredLength::new((length - 3) as u8));
+            assert_eq!(n, code.length + extra.length, "length {}", length);
+            assert_eq!(bits, code.code | (extra.code << code.length));
+        }
+
+        for distance in 1..=32768 {
+            let (code, extra) = table.get_distance_huffman(distance);
+            let (bits, n) = distance_bits(distance);
+            assert_eq!(n, code.length + extra.length, "distance {}", distance);
+            assert_eq!(
+                bits,
+                u32::from(code.code) | (u32::from(extra.code) << code.length)
+            );
+        }
+    }
+
+    #[test]
+    fn same_output_as_encoder_state() {
+        let values: Vec<_> = (0..=255u8)
+            .map(LZValue::literal)
+            .chain((MIN_MATCH..=MAX_MATCH).map(|l| LZValue::length_distance(l, l)))
+            .chain(
+                (1..=32768)
+                    .step_by(97)
+                    .map(|d| LZValue::length_distance(258, d)),
+            )
+            .collect();
+
+        let mut state = EncoderState::new(Vec::new());
+        state.set_huffman_to_fixed();
+        flush_to_backend(&values, &mut state);
+        state.flush().unwrap();
+
+        let mut fixed = EncoderState::new(Vec::new());
+        flush_to_backend(&values, &mut FixedBackend::new(&mut fixed.writer));
+        fixed.flush().unwrap();
+
+        assert_eq!(state.writer.w, fixed.writer.w);
+    }
+}
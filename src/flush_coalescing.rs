@@ -0,0 +1,76 @@
+//! This module contains the settings and state for combining flushes done in quick succession.
+
+use std::time::{Duration, Instant};
+
+/// Settings for combining flushes done in quick succession, set with e.g
+/// [`DeflateEncoder::set_flush_coalescing`](write/struct.DeflateEncoder.html#method.set_flush_coalescing).
+///
+/// Every flush ends the current block and outputs an empty stored block as a sync marker, which
+/// for many small writes that are each followed by a flush, as in chatty RPC protocols, can add
+/// up to more than the compressed data itself. With coalescing, a flush is only done if enough
+/// input has been written or enough time has passed since the last one, and skipped otherwise,
+/// so the data is output by a later flush instead.
+///
+/// The time is only checked when flushing, so data held back by a skipped flush stays in the
+/// encoder until a later flush is done. Use e.g
+/// [`DeflateEncoder::flush_now`](write/struct.DeflateEncoder.html#method.flush_now) where the
+/// data has to be output right away.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::io::Write;
+/// use std::time::Duration;
+///
+/// use deflate::write::DeflateEncoder;
+/// use deflate::{Compression, FlushCoalescing};
+///
+/// let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+/// encoder.set_flush_coalescing(Some(FlushCoalescing {
+///     min_bytes: 4096,
+///     max_delay: Duration::from_millis(10),
+/// }));
+/// for n in 0..100 {
+///     write!(encoder, "Message {}", n).unwrap();
+///     encoder.flush().unwrap();
+/// }
+/// encoder.flush_now().unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct FlushCoalescing {
+    /// Flush if at least this many bytes of input have been written since the last flush.
+    pub min_bytes: u64,
+    /// Flush if at least this much time has passed since the last flush.
+    pub max_delay: Duration,
+}
+
+/// Keeps track of when the last flush was done.
+pub struct CoalescingState {
+    settings: FlushCoalescing,
+    last_flush: Instant,
+    last_flush_bytes: u64,
+}
+
+impl CoalescingState {
+    /// Start coalescing flushes, counting from the current point in time and number of bytes of
+    /// input.
+    pub fn new(settings: FlushCoalescing, bytes_written: u64) -> CoalescingState {
+        CoalescingState {
+            settings,
+            last_flush: Instant::now(),
+            last_flush_bytes: bytes_written,
+        }
+    }
+
+    /// Whether enough input or time has passed since the last flush for a flush to be done.
+    pub fn flush_due(&self, bytes_written: u64) -> bool {
+        bytes_written.saturating_sub(self.last_flush_bytes) >= self.settings.min_bytes
+            || self.last_flush.elapsed() >= self.settings.max_delay
+    }
+
+    /// Note that a flush was done after `bytes_written` bytes of input.
+    pub fn flushed(&mut self, bytes_written: u64) {
+        self.last_flush = Instant::now();
+        self.last_flush_bytes = bytes_written;
+    }
+}
@@ -0,0 +1,358 @@
+use std::{cmp, fmt, io};
+
+use crate::compress::Flush;
+use crate::compression_options::{CompressionOptions, ConfigError};
+use crate::deflate_state::DeflateState;
+use crate::writer::{compress_all, compress_until_done};
+
+/// Worst-case number of bytes added on top of the input when a frame ends up as a single stored
+/// block: up to one byte for the block header, four bytes for the length fields, and another five
+/// bytes for the empty stored block that makes up the sync marker at the end of the frame.
+const FRAME_OVERHEAD: usize = 10;
+
+/// How many times larger than the maximum frame length the input is allowed to be on the first
+/// attempt at compressing a frame.
+const MAX_EXPECTED_RATIO: usize = 4;
+
+/// The smallest `max_len` accepted by [`FrameEncoder::next_frame`].
+///
+/// [`FrameEncoder::next_frame`]: struct.FrameEncoder.html#method.next_frame
+pub const MIN_FRAME_LEN: usize = FRAME_OVERHEAD + 1;
+
+/// A chunk of DEFLATE-compressed data produced by a [`FrameEncoder`].
+///
+/// [`FrameEncoder`]: struct.FrameEncoder.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    data: Vec<u8>,
+    input_len: usize,
+}
+
+impl Frame {
+    /// The compressed data of this frame.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Consume the frame, returning the compressed data.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The number of bytes of input data contained in this frame.
+    pub fn input_len(&self) -> usize {
+        self.input_len
+    }
+}
+
+/// An encoder that splits the input into self-contained frames of bounded size.
+///
+/// Each frame is compressed independently of the previous ones, starts on a block boundary and
+/// ends with a sync marker (an empty stored block), the same way zlib does on `Z_FULL_FLUSH`.
+/// This means each frame can be decompressed on its own by a raw DEFLATE decoder, and that the
+/// frames concatenated in order also make up a valid (unterminated) DEFLATE stream.
+/// This is useful for datagram transports that can not fragment messages.
+///
+/// As each frame is compressed on its own, smaller frames will give a worse compression ratio.
+///
+/// The `pre_filter` compression option is not supported by this encoder, and neither are
+/// `block_limits.max_blocks` and `verify`, as `next_frame` has no way to report the errors
+/// they can cause.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::io::Write;
+///
+/// use deflate::{Compression, FrameEncoder};
+///
+/// let data = b"This is some test data";
+/// let mut encoder = FrameEncoder::new(Compression::Default);
+/// encoder.write_all(data).unwrap();
+/// while let Some(frame) = encoder.next_frame(1200) {
+///     assert!(frame.data().len() <= 1200);
+///     # let _ = frame;
+/// }
+/// ```
+pub struct FrameEncoder {
+    deflate_state: Box<DeflateState<Vec<u8>>>,
+    /// Input data, of which the bytes from `start` on have not been output in a frame yet.
+    pending: Vec<u8>,
+    start: usize,
+}
+
+impl FrameEncoder {
+    /// Create a new `FrameEncoder` using the provided compression options.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a `pre_filter` is set in the options, as the frames would not be independent
+    /// of each other if the filters were applied across them. Also panics if
+    /// `block_limits.max_blocks` is set or `verify` is enabled.
+    pub fn new<O: Into<CompressionOptions>>(options: O) -> FrameEncoder {
+        let options = options.into();
+        assert!(
+            !options.pre_filter.is_active(),
+            "FrameEncoder does not support pre-filters!"
+        );
+        assert!(
+            options.block_limits.max_blocks == 0 && !options.verify,
+            "FrameEncoder does not support max_blocks or verify!"
+        );
+        FrameEncoder {
+            deflate_state: Box::new(DeflateState::new(options, Vec::new())),
+            pending: Vec::new(),
+            start: 0,
+        }
+    }
+
+    /// Create a new `FrameEncoder` using the provided compression options, after checking them
+    /// with [`CompressionOptions::validate`](struct.CompressionOptions.html#method.validate).
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from `validate`, or `ConfigError::Unsupported` if a `pre_filter`,
+    /// `block_limits.max_blocks` or `verify` is set in the options.
+    pub fn try_new<O: Into<CompressionOptions>>(options: O) -> Result<FrameEncoder, ConfigError> {
+        let options = options.into();
+        options.validate()?;
+        if options.pre_filter.is_active() {
+            return Err(ConfigError::Unsupported("pre_filter with FrameEncoder"));
+        }
+        if options.block_limits.max_blocks != 0 {
+            return Err(ConfigError::Unsupported("max_blocks with FrameEncoder"));
+        }
+        if options.verify {
+            return Err(ConfigError::Unsupported("verify with FrameEncoder"));
+        }
+        Ok(FrameEncoder::new(options))
+    }
+
+    /// The number of bytes of input that have not been output in a frame yet.
+    pub fn pending_len(&self) -> usize {
+        self.pending.len() - self.start
+    }
+
+    /// Compress as much of the pending input as will fit into a frame of at most `max_len` bytes.
+    ///
+    /// The input may be compressed several times to find out how much of it will fit.
+    ///
+    /// Returns `None` if there is no pending input.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_len` is less than [`MIN_FRAME_LEN`].
+    ///
+    /// [`MIN_FRAME_LEN`]: constant.MIN_FRAME_LEN.html
+    pub fn next_frame(&mut self, max_len: usize) -> Option<Frame> {
+        assert!(
+            max_len >= MIN_FRAME_LEN,
+            "Maximum frame length must be at least {} bytes!",
+            MIN_FRAME_LEN
+        );
+
+        if self.pending_len() == 0 {
+            return None;
+        }
+
+        // Amount of input that is guaranteed to fit even if it ends up in a stored block.
+        let safe_len = max_len - FRAME_OVERHEAD;
+        // Optimistically start with more input than that, as it will usually compress, and
+        // scale it down by how much we overshoot until the output fits.
+        let mut input_len = cmp::min(
+            self.pending_len(),
+            max_len.saturating_mul(MAX_EXPECTED_RATIO),
+        );
+
+        loop {
+            // Writing to a Vec can't fail, and the options that can make compressing fail are
+            // rejected when creating the encoder.
+            let data = self
+                .compress_frame(input_len)
+                .expect("Write error when writing to a Vec!");
+            if data.len() <= max_len || input_len == 1 {
+                debug_assert!(data.len() <= max_len);
+                self.start += input_len;
+                return Some(Frame { data, input_len });
+            }
+            let scaled = input_len * safe_len / data.len();
+            input_len = if input_len > safe_len {
+                cmp::max(scaled, safe_len)
+            } else {
+                // Shouldn't happen, but make sure we still make progress if it somehow does.
+                cmp::max(scaled, 1)
+            };
+        }
+    }
+
+    /// Compress the first `input_len` bytes of the pending data with a fresh state.
+    fn compress_frame(&mut self, input_len: usize) -> io::Result<Vec<u8>> {
+        let input = &self.pending[self.start..self.start + input_len];
+        compress_all(input, &mut self.deflate_state)?;
+        compress_until_done(&[], &mut self.deflate_state, Flush::Sync)?;
+        self.deflate_state.reset(Vec::new())
+    }
+}
+
+impl fmt::Debug for FrameEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FrameEncoder")
+            .field("options", &self.deflate_state.compression_options)
+            .field("pending_len", &self.pending_len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl io::Write for FrameEncoder {
+    /// Add data to be compressed by subsequent calls to `next_frame`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Drop the data that has been output once it makes up at least half of the buffer, so
+        // it's not moved for every frame.
+        if self.start > self.pending.len() / 2 {
+            self.pending.drain(..self.start);
+            self.start = 0;
+        }
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_sync_flushed, get_random_data, get_test_data};
+    use crate::Compression;
+    use std::io::Write;
+
+    fn frames(data: &[u8], max_len: usize) -> Vec<Frame> {
+        let mut encoder = FrameEncoder::new(Compression::Default);
+        encoder.write_all(data).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = encoder.next_frame(max_len) {
+            frames.push(frame);
+        }
+        assert_eq!(encoder.pending_len(), 0);
+        frames
+    }
+
+    #[test]
+    fn frames_independent() {
+        let data = get_test_data();
+        for &(max_len, len) in &[
+            (MIN_FRAME_LEN, 300),
+            (100, 3000),
+            (1200, 20000),
+            (65535, 20000),
+        ] {
+            let frames = frames(&data[..len], max_len);
+            let mut pos = 0;
+            for frame in &frames {
+                assert!(frame.data().len() <= max_len);
+                let decompressed = decompress_sync_flushed(frame.data());
+                assert!(decompressed[..] == data[pos..pos + frame.input_len()]);
+                pos += frame.input_len();
+            }
+            assert_eq!(pos, len);
+        }
+    }
+
+    #[test]
+    fn frames_concatenated() {
+        let data = get_test_data();
+        let frames = frames(&data, 1400);
+        let stream: Vec<u8> = frames.into_iter().flat_map(Frame::into_data).collect();
+        let decompressed = decompress_sync_flushed(&stream);
+        assert!(decompressed == data);
+    }
+
+    #[test]
+    fn frames_incompressible() {
+        let data = get_random_data(5000);
+        let frames = frames(&data, 512);
+        for frame in &frames {
+            assert!(frame.data().len() <= 512);
+        }
+        let stream: Vec<u8> = frames.into_iter().flat_map(Frame::into_data).collect();
+        assert!(decompress_sync_flushed(&stream) == data);
+    }
+
+    #[test]
+    fn frames_interleaved_writes() {
+        // Write more data while frames are being taken out, so the buffer is compacted.
+        let data = get_test_data();
+        let mut encoder = FrameEncoder::new(Compression::Fast);
+        let mut stream = Vec::new();
+        for chunk in data.chunks(3000) {
+            encoder.write_all(chunk).unwrap();
+            let frame = encoder.next_frame(500).unwrap();
+            stream.extend_from_slice(frame.data());
+        }
+        while let Some(frame) = encoder.next_frame(500) {
+            stream.extend_from_slice(frame.data());
+        }
+        assert_eq!(encoder.pending_len(), 0);
+        assert!(decompress_sync_flushed(&stream) == data);
+    }
+
+    #[test]
+    #[should_panic(expected = "FrameEncoder does not support pre-filters!")]
+    fn pre_filter_rejected() {
+        let options = CompressionOptions {
+            pre_filter: crate::PreFilter {
+                delta: 1,
+                transpose: 0,
+            },
+            ..CompressionOptions::default()
+        };
+        assert_eq!(
+            FrameEncoder::try_new(options).unwrap_err(),
+            ConfigError::Unsupported("pre_filter with FrameEncoder")
+        );
+        let _ = FrameEncoder::new(options);
+    }
+
+    #[test]
+    #[should_panic(expected = "FrameEncoder does not support max_blocks or verify!")]
+    fn failing_options_rejected() {
+        let options = CompressionOptions {
+            verify: true,
+            ..CompressionOptions::default()
+        };
+        assert_eq!(
+            FrameEncoder::try_new(options).unwrap_err(),
+            ConfigError::Unsupported("verify with FrameEncoder")
+        );
+        let options = CompressionOptions {
+            block_limits: crate::BlockLimits {
+                max_blocks: 1,
+                ..crate::BlockLimits::default()
+            },
+            ..CompressionOptions::default()
+        };
+        assert_eq!(
+            FrameEncoder::try_new(options).unwrap_err(),
+            ConfigError::Unsupported("max_blocks with FrameEncoder")
+        );
+        let _ = FrameEncoder::new(options);
+    }
+
+    #[test]
+    fn huge_max_len() {
+        let data = get_test_data();
+        let mut encoder = FrameEncoder::new(Compression::Fast);
+        encoder.write_all(&data[..10000]).unwrap();
+        let frame = encoder.next_frame(usize::MAX / 2).unwrap();
+        assert_eq!(frame.input_len(), 10000);
+        assert!(decompress_sync_flushed(frame.data()) == data[..10000]);
+    }
+
+    #[test]
+    fn no_pending_data() {
+        let mut encoder = FrameEncoder::new(Compression::Fast);
+        assert!(encoder.next_frame(1000).is_none());
+    }
+}
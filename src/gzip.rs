@@ -0,0 +1,424 @@
+//! Helpers for filling in the gzip header, so callers don't have to assemble the raw bytes of the
+//! OS and extra fields themselves.
+
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use gzip_header::{FileSystemType, GzBuilder};
+
+use crate::compression_options::CompressionOptions;
+use crate::pipeline::{compress_pipelined_gzip, PipelineOptions};
+use crate::writer::gzip::GzEncoder;
+
+/// The maximum length of the extra field, as its length is stored in two bytes.
+const MAX_EXTRA_LEN: usize = u16::MAX as usize;
+
+/// Size of the header of a subfield in the extra field. (Two id bytes and a two byte length.)
+const SUBFIELD_HEADER_LEN: usize = 4;
+
+/// Get the value for the OS field of the gzip header for the platform the crate is compiled for.
+///
+/// The values follow what recent versions of zlib use. This is used for headers where the OS has
+/// not been set explicitly in the `GzBuilder`.
+pub fn gzip_os() -> FileSystemType {
+    if cfg!(any(target_os = "macos", target_os = "ios")) {
+        FileSystemType::Apple
+    } else if cfg!(windows) {
+        FileSystemType::Tops20OrNTFS
+    } else if cfg!(unix) {
+        FileSystemType::Unix
+    } else {
+        FileSystemType::Unknown
+    }
+}
+
+/// Create the raw header from a `GzBuilder`.
+///
+/// `GzBuilder` only knows the OS of a few platforms, so if it leaves the OS byte as unknown
+/// without that having been set explicitly, it is replaced by the value from
+/// [`gzip_os`](fn.gzip_os.html).
+pub(crate) fn header_from_builder(builder: GzBuilder) -> Vec<u8> {
+    let explicitly_unknown = builder.clone().os(FileSystemType::Unknown) == builder;
+    let mut header = builder.into_header();
+    if header[9] == FileSystemType::Unknown.as_u8() && !explicitly_unknown {
+        header[9] = gzip_os().as_u8();
+    }
+    header
+}
+
+/// A builder for the extra field of a gzip header.
+///
+/// The extra field consists of a list of subfields, each identified by two bytes. This adds
+/// helpers for the subfields used by some common formats built on top of gzip, as well as
+/// arbitrary ones. The resulting bytes can be passed to `GzBuilder::extra`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate deflate;
+/// # extern crate gzip_header;
+/// # fn main() {
+/// use deflate::GzExtra;
+/// use gzip_header::GzBuilder;
+///
+/// let extra = GzExtra::new()
+///     .subfield(*b"Ex", b"some data")
+///     .into_bytes()
+///     .unwrap();
+/// let compressed = deflate::deflate_bytes_gzip_conf(
+///     b"Some data",
+///     deflate::Compression::Default,
+///     GzBuilder::new().extra(extra),
+/// );
+/// # let _ = compressed;
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GzExtra {
+    subfields: Vec<([u8; 2], Vec<u8>)>,
+}
+
+impl GzExtra {
+    /// Create an empty extra field.
+    pub fn new() -> GzExtra {
+        GzExtra::default()
+    }
+
+    /// Add a subfield with the id `id` containing `data`.
+    ///
+    /// The length of the data and the id is checked in [`into_bytes`](#method.into_bytes).
+    pub fn subfield(mut self, id: [u8; 2], data: &[u8]) -> GzExtra {
+        self.subfields.push((id, data.to_vec()));
+        self
+    }
+
+    /// Add the `BC` subfield used by the BGZF format (used for e.g BAM files), containing the
+    /// total size of the block (the gzip member) this header is part of minus one.
+    ///
+    /// As the size includes the compressed data and the trailer, the data has to be compressed
+    /// before the header is created.
+    pub fn bgzf_block_size(self, block_size_minus_one: u16) -> GzExtra {
+        self.subfield(*b"BC", &block_size_minus_one.to_le_bytes())
+    }
+
+    /// Add the `RA` subfield used by the dictzip format for random access, containing the
+    /// uncompressed length of each chunk, and the compressed size of each of the chunks.
+    ///
+    /// For the index to be usable, each chunk has to be decodable without the data in the
+    /// previous ones, i.e ending in a full flush.
+    pub fn random_access(self, chunk_len: u16, compressed_sizes: &[u16]) -> GzExtra {
+        // Version 1 is the only one defined.
+        let mut data = vec![1, 0];
+        data.extend_from_slice(&chunk_len.to_le_bytes());
+        data.extend_from_slice(&(compressed_sizes.len() as u16).to_le_bytes());
+        for size in compressed_sizes {
+            data.extend_from_slice(&size.to_le_bytes());
+        }
+        self.subfield(*b"RA", &data)
+    }
+
+    /// Create the raw bytes of the extra field.
+    ///
+    /// Returns an error of the kind `InvalidInput` if the second byte of an id is zero,
+    /// (which is reserved), or if a subfield or the whole extra field is too long.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for (id, data) in self.subfields {
+            if id[1] == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Reserved gzip extra subfield id",
+                ));
+            }
+            if data.len() > MAX_EXTRA_LEN - SUBFIELD_HEADER_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Gzip extra subfield too long",
+                ));
+            }
+            output.extend_from_slice(&id);
+            output.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            output.extend_from_slice(&data);
+        }
+
+        if output.len() > MAX_EXTRA_LEN {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Gzip extra field too long",
+            ))
+        } else {
+            Ok(output)
+        }
+    }
+}
+
+/// Create a `GzBuilder` with the name and modification time of the file at `path` filled in,
+/// like `gzip(1)` does.
+///
+/// Only the last component of the path is used as the name. On Unix, the bytes of the name are
+/// stored as they are, like `gzip(1)` does, even if they are not valid UTF-8. Elsewhere, the
+/// name is left out if it's not valid Unicode. The time is left as 0 (no time stamp) if it's
+/// not available, or can't be represented in the header, which can only hold times between
+/// 1970 and 2106.
+pub fn gz_builder_for_file<P: AsRef<Path>>(path: P) -> io::Result<GzBuilder> {
+    let path = path.as_ref();
+    let mtime = path
+        .metadata()?
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .and_then(|since_epoch| u32::try_from(since_epoch.as_secs()).ok())
+        .unwrap_or(0);
+    let mut builder = GzBuilder::new().mtime(mtime);
+    if let Some(name) = path.file_name().and_then(file_name_bytes) {
+        builder = builder.filename(name);
+    }
+    Ok(builder)
+}
+
+/// Get the bytes to store in the name field of the header for a file name.
+#[cfg(unix)]
+fn file_name_bytes(name: &OsStr) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(name.as_bytes().to_vec())
+}
+
+/// Get the bytes to store in the name field of the header for a file name.
+#[cfg(not(unix))]
+fn file_name_bytes(name: &OsStr) -> Option<Vec<u8>> {
+    name.to_str().map(|name| name.as_bytes().to_vec())
+}
+
+/// Compress the file at `source` to a gzip file at `destination`.
+///
+/// If `file_info` is `true`, the name and modification time of the source file are stored in
+/// the header, see [`gz_builder_for_file`](fn.gz_builder_for_file.html), as `gzip(1)` does by
+/// default. Otherwise, they are left out, as with `gzip -n`, which is useful for reproducible
+/// output.
+///
+/// # Examples
+///
+/// ```no_run
+/// use deflate::{compress_file, Compression};
+///
+/// compress_file("data.txt", "data.txt.gz", Compression::Default, true).unwrap();
+/// ```
+pub fn compress_file<P, Q, O>(
+    source: P,
+    destination: Q,
+    options: O,
+    file_info: bool,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    O: Into<CompressionOptions>,
+{
+    let source = source.as_ref();
+    let builder = if file_info {
+        gz_builder_for_file(source)?
+    } else {
+        GzBuilder::new()
+    };
+    let mut input = File::open(source)?;
+    let output = BufWriter::new(File::create(destination)?);
+    let mut encoder = GzEncoder::from_builder(builder, output, options);
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.flush()
+}
+
+/// Compress the file at `source` to a gzip file at `destination`, reading, compressing and
+/// writing the data at the same time on different threads.
+///
+/// This works like [`compress_file`](fn.compress_file.html), but uses the pipelined mode
+/// described in [`compress_pipelined`](fn.compress_pipelined.html), so the output differs
+/// slightly from the single threaded version.
+///
+/// # Examples
+///
+/// ```no_run
+/// use deflate::{compress_file_pipelined, Compression, PipelineOptions};
+///
+/// compress_file_pipelined(
+///     "data.txt",
+///     "data.txt.gz",
+///     Compression::Default,
+///     true,
+///     PipelineOptions::default(),
+/// )
+/// .unwrap();
+/// ```
+pub fn compress_file_pipelined<P, Q, O>(
+    source: P,
+    destination: Q,
+    options: O,
+    file_info: bool,
+    pipeline: PipelineOptions,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    O: Into<CompressionOptions>,
+{
+    let source = source.as_ref();
+    let builder = if file_info {
+        gz_builder_for_file(source)?
+    } else {
+        GzBuilder::new()
+    };
+    let input = File::open(source)?;
+    let output = BufWriter::new(File::create(destination)?);
+    compress_pipelined_gzip(input, output, options, pipeline, builder)?.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::decompress_gzip;
+    use crate::{deflate_bytes_gzip, deflate_bytes_gzip_conf, Compression};
+
+    #[test]
+    fn os_detected() {
+        let compressed = deflate_bytes_gzip(b"data");
+        assert_eq!(compressed[9], gzip_os().as_u8());
+        if cfg!(target_os = "linux") {
+            assert_eq!(gzip_os(), FileSystemType::Unix);
+        }
+
+        let explicit = header_from_builder(GzBuilder::new().os(FileSystemType::Unknown));
+        assert_eq!(explicit[9], FileSystemType::Unknown.as_u8());
+        let explicit = header_from_builder(GzBuilder::new().os(FileSystemType::Amiga));
+        assert_eq!(explicit[9], FileSystemType::Amiga.as_u8());
+    }
+
+    #[test]
+    fn extra_subfields() {
+        let extra = GzExtra::new()
+            .bgzf_block_size(1000)
+            .random_access(8, &[3, 300])
+            .subfield(*b"Zz", &[])
+            .into_bytes()
+            .unwrap();
+        assert_eq!(
+            extra,
+            [
+                b'B', b'C', 2, 0, 232, 3, // BGZF
+                b'R', b'A', 10, 0, 1, 0, 8, 0, 2, 0, 3, 0, 44, 1, // dictzip
+                b'Z', b'z', 0, 0,
+            ]
+        );
+
+        let data = b"Some data with an extra field";
+        let compressed = deflate_bytes_gzip_conf(
+            data,
+            Compression::Default,
+            GzBuilder::new().extra(extra.clone()),
+        );
+        let (header, decompressed) = decompress_gzip(&compressed);
+        assert_eq!(decompressed, data);
+        assert_eq!(header.header().unwrap().extra(), Some(&extra[..]));
+    }
+
+    #[test]
+    fn extra_invalid() {
+        assert!(GzExtra::new()
+            .subfield([b'A', 0], b"")
+            .into_bytes()
+            .is_err());
+        let long = vec![0; MAX_EXTRA_LEN - SUBFIELD_HEADER_LEN];
+        assert!(GzExtra::new().subfield(*b"AB", &long).into_bytes().is_ok());
+        assert!(GzExtra::new()
+            .subfield(*b"AB", &long[1..])
+            .subfield(*b"CD", &[])
+            .into_bytes()
+            .is_err());
+        let err = GzExtra::new()
+            .subfield(*b"AB", &[0; MAX_EXTRA_LEN])
+            .into_bytes()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn compress_file_info() {
+        use std::fs;
+        use std::time::Duration;
+
+        let data = crate::test_utils::get_test_data();
+        let dir =
+            std::env::temp_dir().join(format!("deflate-compress-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.txt");
+        let destination = dir.join("source.txt.gz");
+        fs::write(&source, &data).unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        File::options()
+            .write(true)
+            .open(&source)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        compress_file(&source, &destination, Compression::Default, true).unwrap();
+        let compressed = fs::read(&destination).unwrap();
+        let (header, decompressed) = decompress_gzip(&compressed);
+        assert!(decompressed == data);
+        let header = header.header().unwrap();
+        assert_eq!(header.filename(), Some(&b"source.txt"[..]));
+        assert_eq!(header.mtime(), 1_500_000_000);
+
+        compress_file(&source, &destination, Compression::Default, false).unwrap();
+        let compressed = fs::read(&destination).unwrap();
+        let (header, decompressed) = decompress_gzip(&compressed);
+        assert!(decompressed == data);
+        let header = header.header().unwrap();
+        assert_eq!(header.filename(), None);
+        assert_eq!(header.mtime(), 0);
+
+        compress_file_pipelined(
+            &source,
+            &destination,
+            Compression::Default,
+            true,
+            PipelineOptions {
+                threads: 2,
+                chunk_size: 32768,
+                queue_len: 0,
+            },
+        )
+        .unwrap();
+        let compressed = fs::read(&destination).unwrap();
+        let (header, decompressed) = decompress_gzip(&compressed);
+        assert!(decompressed == data);
+        let header = header.header().unwrap();
+        assert_eq!(header.filename(), Some(&b"source.txt"[..]));
+        assert_eq!(header.mtime(), 1_500_000_000);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_name_not_utf8() {
+        use std::fs;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = std::env::temp_dir().join(format!("deflate-file-name-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = b"caf\xe9.txt";
+        let source = dir.join(OsStr::from_bytes(name));
+        fs::write(&source, b"data").unwrap();
+
+        let builder = gz_builder_for_file(&source).unwrap();
+        let compressed = crate::deflate_bytes_gzip_conf(b"data", Compression::Default, builder);
+        let (header, decompressed) = decompress_gzip(&compressed);
+        assert_eq!(decompressed, b"data");
+        assert_eq!(header.header().unwrap().filename(), Some(&name[..]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,8 +2,8 @@ use crate::bitstream::LsbWriter;
 use crate::deflate_state::LengthBuffers;
 use crate::huffman_table::{
     create_codes_in_place, num_extra_bits_for_distance_code, num_extra_bits_for_length_code,
-    HuffmanTable, FIXED_CODE_LENGTHS, LENGTH_BITS_START, MAX_CODE_LENGTH, NUM_DISTANCE_CODES,
-    NUM_LITERALS_AND_LENGTHS,
+    HuffmanTable, END_OF_BLOCK_POSITION, FIXED_CODE_LENGTHS, FIXED_CODE_LENGTHS_DISTANCE,
+    LENGTH_BITS_START, MAX_CODE_LENGTH, NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS,
 };
 use crate::length_encode::{
     encode_lengths_m, huffman_lengths_from_frequency_m, EncodedLength, COPY_PREVIOUS,
@@ -24,7 +24,7 @@ const NUM_HUFFMAN_LENGTHS: usize = 19;
 // The output ordering of the lengths for the huffman codes used to encode the lengths
 // used to build the full huffman tree for length/literal codes.
 // http://www.gzip.org/zlib/rfc-deflate.html#dyn
-const HUFFMAN_LENGTH_ORDER: [u8; NUM_HUFFMAN_LENGTHS] = [
+pub const HUFFMAN_LENGTH_ORDER: [u8; NUM_HUFFMAN_LENGTHS] = [
     16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
 ];
 
@@ -49,7 +49,8 @@ pub fn remove_trailing_zeroes<T: From<u8> + PartialEq>(input: &[T], min_length:
 /// How many extra bits the huffman length code uses to represent a value.
 fn extra_bits_for_huffman_length_code(code: u8) -> u8 {
     match code {
-        16..=17 => 3,
+        16 => 2,
+        17 => 3,
         18 => 7,
         _ => 0,
     }
@@ -67,8 +68,8 @@ fn calculate_huffman_length(frequencies: &[FrequencyType], code_lengths: &[u8])
         })
 }
 
-/// Calculate how many bits data with the given frequencies will use when compressed with dynamic
-/// code lengths (first return value) and static code lengths (second return value).
+/// Calculate how many bits data with the given frequencies will use when compressed with the
+/// dynamic code lengths `dyn_code_lengths`.
 ///
 /// Parameters:
 /// Frequencies, length of dynamic codes, and a function to get how many extra bits in addition
@@ -77,33 +78,17 @@ fn calculate_block_length<F>(
     frequencies: &[FrequencyType],
     dyn_code_lengths: &[u8],
     get_num_extra_bits: &F,
-) -> (u64, u64)
+) -> u64
 where
     F: Fn(usize) -> u64,
 {
-    // Length of data represented by dynamic codes.
-    let mut d_ll_length = 0u64;
-    // length of data represented by static codes.
-    let mut s_ll_length = 0u64;
-
-    let iter = frequencies
+    frequencies
         .iter()
-        .zip(dyn_code_lengths.iter().zip(FIXED_CODE_LENGTHS.iter()))
-        .enumerate();
-
-    // This could maybe be optimised a bit by splitting the iteration of codes using extra bits and
-    // codes not using extra bits, but the extra complexity may not be worth it.
-    for (c, (&f, (&l, &fl))) in iter {
-        // Frequency
-        let f = u64::from(f);
-        // How many extra bits the current code number needs.
-        let extra_bits_for_code = get_num_extra_bits(c);
-
-        d_ll_length += f * (u64::from(l) + extra_bits_for_code);
-        s_ll_length += f * (u64::from(fl) + extra_bits_for_code);
-    }
-
-    (d_ll_length, s_ll_length)
+        .zip(dyn_code_lengths.iter())
+        .enumerate()
+        .fold(0, |acc, (c, (&f, &l))| {
+            acc + u64::from(f) * (u64::from(l) + get_num_extra_bits(c))
+        })
 }
 
 /// Get how extra padding bits after a block start header a stored block would use.
@@ -139,9 +124,10 @@ fn stored_length(input_bytes: u64) -> u64 {
         + 1;
     // The length will be the input length and the headers for each block. (Excluding the start
     // of block code for the first one)
-    (input_bytes + (STORED_BLOCK_HEADER_LENGTH as u64 * num_blocks) + (num_blocks - 1)) * 8
+    (input_bytes + (STORED_BLOCK_HEADER_LENGTH * num_blocks) + (num_blocks - 1)) * 8
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BlockType {
     Stored,
     Fixed,
@@ -152,32 +138,86 @@ pub enum BlockType {
 ///
 /// The code lengths are stored directly in the `HuffmanTable` struct.
 /// TODO: Do the same for other things here.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DynamicBlockHeader {
     /// Length of the run-length encoding symbols.
-    pub huffman_table_lengths: Vec<u8>,
+    pub huffman_table_lengths: [u8; NUM_HUFFMAN_LENGTHS],
     /// Number of lengths for values describing the huffman table that encodes the length values
     /// of the main huffman tables.
     pub used_hclens: usize,
 }
 
+/// Calculate how many bits a fixed block with the given frequencies takes up, including the
+/// block header.
+pub fn fixed_block_length(l_freqs: &[FrequencyType], d_freqs: &[FrequencyType]) -> u64 {
+    let ll_length = l_freqs
+        .iter()
+        .zip(FIXED_CODE_LENGTHS.iter())
+        .enumerate()
+        .fold(0, |acc, (c, (&f, &l))| {
+            let extra =
+                num_extra_bits_for_length_code(c.saturating_sub(LENGTH_BITS_START as usize) as u8);
+            acc + u64::from(f) * u64::from(l + extra)
+        });
+    let dist_length = d_freqs
+        .iter()
+        .zip(FIXED_CODE_LENGTHS_DISTANCE.iter())
+        .enumerate()
+        .fold(0, |acc, (c, (&f, &l))| {
+            acc + u64::from(f) * u64::from(l + num_extra_bits_for_distance_code(c as u8))
+        });
+    u64::from(BLOCK_MARKER_LENGTH) + ll_length + dist_length
+}
+
 /// Generate the lengths of the huffman codes we will be using, using the
 /// frequency of the different symbols/lengths/distances, and determine what block type will give
 /// the shortest representation.
+///
+/// The huffman codes are limited to `max_code_length` bits, which has to be in the range
+/// `9...15`.
 /// TODO: This needs a test
+#[allow(clippy::too_many_arguments)]
 pub fn gen_huffman_lengths(
     l_freqs: &[FrequencyType],
     d_freqs: &[FrequencyType],
     num_input_bytes: u64,
     pending_bits: u8,
+    max_code_length: u8,
     l_lengths: &mut [u8; 288],
     d_lengths: &mut [u8; 32],
     length_buffers: &mut LengthBuffers,
 ) -> BlockType {
+    gen_huffman_lengths_sized(
+        l_freqs,
+        d_freqs,
+        num_input_bytes,
+        pending_bits,
+        max_code_length,
+        l_lengths,
+        d_lengths,
+        length_buffers,
+    )
+    .0
+}
+
+/// Like `gen_huffman_lengths`, but also return the number of bits the block will take up with
+/// the chosen block type, including the block header.
+#[allow(clippy::too_many_arguments)]
+pub fn gen_huffman_lengths_sized(
+    l_freqs: &[FrequencyType],
+    d_freqs: &[FrequencyType],
+    num_input_bytes: u64,
+    pending_bits: u8,
+    max_code_length: u8,
+    l_lengths: &mut [u8; 288],
+    d_lengths: &mut [u8; 32],
+    length_buffers: &mut LengthBuffers,
+) -> (BlockType, u64) {
     // Avoid corner cases and issues if this is called for an empty block.
     // For blocks this short, a fixed block will be the shortest.
     // TODO: Find the minimum value it's worth doing calculations for.
     if num_input_bytes <= 4 {
-        return BlockType::Fixed;
+        return (BlockType::Fixed, fixed_block_length(l_freqs, d_freqs));
     };
 
     let l_freqs = remove_trailing_zeroes(l_freqs, MIN_NUM_LITERALS_AND_LENGTHS);
@@ -191,18 +231,9 @@ pub fn gen_huffman_lengths(
     // There is however a minimum number of values we have to keep
     // according to the deflate spec.
     // TODO: We could probably compute some of this in parallel.
-    huffman_lengths_from_frequency_m(
-        l_freqs,
-        MAX_CODE_LENGTH,
-        &mut length_buffers.leaf_buf,
-        l_lengths,
-    );
-    huffman_lengths_from_frequency_m(
-        d_freqs,
-        MAX_CODE_LENGTH,
-        &mut length_buffers.leaf_buf,
-        d_lengths,
-    );
+    debug_assert!((9..=MAX_CODE_LENGTH).contains(&usize::from(max_code_length)));
+    huffman_lengths_from_frequency_m(l_freqs, usize::from(max_code_length), l_lengths);
+    huffman_lengths_from_frequency_m(d_freqs, usize::from(max_code_length), d_lengths);
 
     let used_lengths = l_freqs.len();
     let used_distances = d_freqs.len();
@@ -218,13 +249,8 @@ pub fn gen_huffman_lengths(
     );
 
     // Create huffman lengths for the length/distance code lengths
-    let mut huffman_table_lengths = vec![0; freqs.len()];
-    huffman_lengths_from_frequency_m(
-        &freqs,
-        MAX_HUFFMAN_CODE_LENGTH,
-        &mut length_buffers.leaf_buf,
-        huffman_table_lengths.as_mut_slice(),
-    );
+    let mut huffman_table_lengths = [0; NUM_HUFFMAN_LENGTHS];
+    huffman_lengths_from_frequency_m(&freqs, MAX_HUFFMAN_CODE_LENGTH, &mut huffman_table_lengths);
 
     // Count how many of these lengths we use.
     let used_hclens = HUFFMAN_LENGTH_ORDER.len()
@@ -240,13 +266,15 @@ pub fn gen_huffman_lengths(
     // Calculate how many bytes of space this block will take up with the different block types
     // (excluding the 3-bit block header since it's used in all block types).
 
-    // Total length of the compressed literals/lengths.
-    let (d_ll_length, s_ll_length) = calculate_block_length(l_freqs, l_lengths, &|c| {
+    // Total length of the compressed literals/lengths, including the end of block code, which
+    // is always counted once in the frequencies.
+    debug_assert_eq!(l_freqs[END_OF_BLOCK_POSITION], 1);
+    let d_ll_length = calculate_block_length(l_freqs, l_lengths, &|c| {
         num_extra_bits_for_length_code(c.saturating_sub(LENGTH_BITS_START as usize) as u8).into()
     });
 
     // Total length of the compressed distances.
-    let (d_dist_length, s_dist_length) = calculate_block_length(d_freqs, d_lengths, &|c| {
+    let d_dist_length = calculate_block_length(d_freqs, d_lengths, &|c| {
         num_extra_bits_for_distance_code(c as u8).into()
     });
 
@@ -263,7 +291,8 @@ pub fn gen_huffman_lengths(
         + u64::from(HCLEN_BITS);
 
     // Static blocks don't have any extra header data.
-    let static_length = s_ll_length + s_dist_length;
+    let header_length = u64::from(BLOCK_MARKER_LENGTH);
+    let static_length = fixed_block_length(l_freqs, d_freqs) - header_length;
 
     // Calculate how many bits it will take to store the data in uncompressed (stored) block(s).
     let stored_length = stored_length(num_input_bytes) + stored_padding(pending_bits % 8);
@@ -275,14 +304,17 @@ pub fn gen_huffman_lengths(
     // already compressed), we want to output a stored(uncompressed) block instead to avoid wasting
     // space.
     if used_length == static_length {
-        BlockType::Fixed
+        (BlockType::Fixed, header_length + static_length)
     } else if used_length == stored_length {
-        BlockType::Stored
+        (BlockType::Stored, header_length + stored_length)
     } else {
-        BlockType::Dynamic(DynamicBlockHeader {
-            huffman_table_lengths,
-            used_hclens,
-        })
+        (
+            BlockType::Dynamic(DynamicBlockHeader {
+                huffman_table_lengths,
+                used_hclens,
+            }),
+            header_length + dynamic_length,
+        )
     }
 }
 
@@ -382,4 +414,121 @@ mod test {
         assert_eq!(stored_padding(6), 7);
         assert_eq!(stored_padding(7), 6);
     }
+
+    /// Strict decoders like zlib reject headers declaring the reserved literal/length and
+    /// distance codes, even if their lengths are zero, so make sure we never do that.
+    #[test]
+    fn header_code_counts() {
+        use crate::huffman_table::{NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS};
+        use crate::inflate::{dump, ElementKind};
+        use crate::test_utils::{decompress_to_end, get_random_data, get_test_data};
+        use crate::{deflate_bytes_conf, CompressionOptions};
+
+        let mut data = get_test_data();
+        data.extend(get_random_data(5000));
+        // Runs of all byte values, to use every literal and match length.
+        data.extend((0..=255u8).flat_map(|b| vec![b; usize::from(b) + 3]));
+        let options = [
+            CompressionOptions::fast(),
+            CompressionOptions::high(),
+            CompressionOptions::rle(),
+            CompressionOptions::huffman_only(),
+        ];
+        for &options in &options {
+            let compressed = deflate_bytes_conf(&data, options);
+            assert!(decompress_to_end(&compressed) == data);
+            for element in dump(&compressed).unwrap().elements() {
+                if let ElementKind::CodeLengths {
+                    literal_lengths,
+                    distances,
+                } = element.kind
+                {
+                    assert!(usize::from(literal_lengths) <= NUM_LITERALS_AND_LENGTHS);
+                    assert!(usize::from(distances) <= NUM_DISTANCE_CODES);
+                }
+            }
+        }
+    }
+
+    /// The fixed and dynamic block sizes should be compared exactly, including the end of block
+    /// code and the 5-bit distance codes of fixed blocks, so a fixed block is used whenever it's
+    /// the smallest.
+    #[test]
+    fn smallest_block_type() {
+        use super::{fixed_block_length, gen_huffman_lengths_sized};
+        use crate::deflate_state::LengthBuffers;
+        use crate::output_writer::DynamicWriter;
+
+        let mut length_buffers = LengthBuffers::new();
+        let mut seed = 1u32;
+        for num_matches in 0..200u16 {
+            let mut writer = DynamicWriter::new();
+            let mut input_bytes = 0;
+            for n in 0..(num_matches % 13) * 3 {
+                writer.write_literal(b'a' + (n % 7) as u8);
+                input_bytes += 1;
+            }
+            for _ in 0..num_matches {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let length = 3 + (seed >> 16) as u16 % 10;
+                writer.write_length_distance(length, 1 + (seed >> 8) as u16 % 4);
+                input_bytes += u64::from(length);
+            }
+            let (l_freqs, d_freqs) = writer.get_frequencies();
+            let (mut l_lengths, mut d_lengths) = ([0; 288], [0; 32]);
+            let (_, size) = gen_huffman_lengths_sized(
+                l_freqs,
+                d_freqs,
+                input_bytes,
+                0,
+                15,
+                &mut l_lengths,
+                &mut d_lengths,
+                &mut length_buffers,
+            );
+            assert!(
+                size <= fixed_block_length(l_freqs, d_freqs),
+                "{}",
+                num_matches
+            );
+        }
+    }
+
+    #[test]
+    fn max_code_length() {
+        use crate::inflate::{dump, ElementKind};
+        use crate::test_utils::{decompress_to_end, get_test_data};
+        use crate::{deflate_bytes_conf, CompressionOptions};
+
+        let mut data = get_test_data();
+        // Add some rare byte values, which get long codes unless limited.
+        data.extend(0..=255u8);
+        let longest_literal = |compressed: &[u8]| {
+            dump(compressed)
+                .unwrap()
+                .elements()
+                .iter()
+                .filter(|e| matches!(e.kind, ElementKind::Literal(_) | ElementKind::EndOfBlock))
+                .map(|e| e.end_bit - e.start_bit)
+                .max()
+                .unwrap()
+        };
+
+        let full = deflate_bytes_conf(&data, CompressionOptions::default());
+        assert!(longest_literal(&full) > 12);
+        for max_code_length in 9..=15 {
+            let options = CompressionOptions {
+                max_code_length,
+                ..CompressionOptions::default()
+            };
+            let compressed = deflate_bytes_conf(&data, options);
+            assert!(decompress_to_end(&compressed) == data);
+            assert!(longest_literal(&compressed) <= u64::from(max_code_length));
+            if max_code_length == 15 {
+                assert!(compressed == full);
+            } else if max_code_length == 9 {
+                assert!(compressed.len() > full.len());
+            }
+        }
+    }
 }
@@ -46,7 +46,7 @@ const LENGTH_EXTRA_BITS_LENGTH: [u8; NUM_LENGTH_CODES] = [
 ];
 
 // Table used to get a code from a length value (see get_distance_code_and_extra_bits)
-const LENGTH_CODE: [u8; 256] = [
+pub const LENGTH_CODE: [u8; 256] = [
     0, 1, 2, 3, 4, 5, 6, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 12, 12, 13, 13, 13, 13, 14, 14, 14,
     14, 15, 15, 15, 15, 16, 16, 16, 16, 16, 16, 16, 16, 17, 17, 17, 17, 17, 17, 17, 17, 18, 18, 18,
     18, 18, 18, 18, 18, 19, 19, 19, 19, 19, 19, 19, 19, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20,
@@ -61,7 +61,7 @@ const LENGTH_CODE: [u8; 256] = [
 ];
 
 // Base values to calculate the value of the bits in length codes
-const BASE_LENGTH: [u8; NUM_LENGTH_CODES] = [
+pub const BASE_LENGTH: [u8; NUM_LENGTH_CODES] = [
     0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 16, 20, 24, 28, 32, 40, 48, 56, 64, 80, 96, 112, 128,
     160, 192, 224, 255,
 ]; // 258 - MIN_MATCh
@@ -104,19 +104,19 @@ const DISTANCE_EXTRA_BITS: [u8; NUM_DISTANCE_CODES] = [
     13,
 ];
 
-const DISTANCE_BASE: [u16; NUM_DISTANCE_CODES] = [
+pub const DISTANCE_BASE: [u16; NUM_DISTANCE_CODES] = [
     0, 1, 2, 3, 4, 6, 8, 12, 16, 24, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536,
     2048, 3072, 4096, 6144, 8192, 12288, 16384, 24576,
 ];
 
-pub fn num_extra_bits_for_length_code(code: u8) -> u8 {
+pub const fn num_extra_bits_for_length_code(code: u8) -> u8 {
     LENGTH_EXTRA_BITS_LENGTH[code as usize]
 }
 
 /// Get the number of extra bits used for a distance code.
 /// (Code numbers above `NUM_DISTANCE_CODES` will give some garbage
 /// value.)
-pub fn num_extra_bits_for_distance_code(code: u8) -> u8 {
+pub const fn num_extra_bits_for_distance_code(code: u8) -> u8 {
     // This can be easily calculated without a lookup.
     //
     let mut c = code >> 1;
@@ -145,6 +145,27 @@ pub fn get_length_code(length: u16) -> usize {
         + LENGTH_BITS_START as usize
 }
 
+/// Get the number of bits needed to output a length/distance pair using the fixed huffman codes,
+/// including the extra bits.
+pub fn fixed_match_cost(length: u16, distance: u16) -> u32 {
+    let length_code = get_length_code(length);
+    let distance_code = get_distance_code(distance);
+    u32::from(FIXED_CODE_LENGTHS[length_code])
+        + u32::from(num_extra_bits_for_length_code(
+            (length_code - LENGTH_BITS_START as usize) as u8,
+        ))
+        + u32::from(FIXED_CODE_LENGTHS_DISTANCE[usize::from(distance_code)])
+        + u32::from(num_extra_bits_for_distance_code(distance_code))
+}
+
+/// Get the number of bits needed to output `literals` using the fixed huffman codes.
+pub fn fixed_literals_cost(literals: &[u8]) -> u32 {
+    literals
+        .iter()
+        .map(|&l| u32::from(FIXED_CODE_LENGTHS[usize::from(l)]))
+        .sum()
+}
+
 /// Get the code for the huffman table and the extra bits for the requested length.
 fn get_length_code_and_extra_bits(length: StoredLength) -> ExtraBits {
     // Length values are stored as unsigned bytes, where the actual length is the value - 3
@@ -192,7 +213,7 @@ fn get_distance_code_and_extra_bits(distance: u16) -> ExtraBits {
     }
 }
 
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
 pub struct HuffmanCode {
     pub code: u16,
     pub length: u8,
@@ -217,6 +238,7 @@ impl HuffmanCode {
 }
 
 #[cfg(test)]
+#[allow(dead_code)]
 pub struct LengthAndDistanceBits {
     pub length_code: HuffmanCode,
     pub length_extra_bits: HuffmanCode,
@@ -249,18 +271,20 @@ fn build_length_count_table(table: &[u8], len_counts: &mut [u16; 16]) -> (usize,
 
 /// Generats a vector of huffman codes given a table of bit lengths
 /// Returns an error if any of the lengths are > 15
+///
+/// This is done for every dynamic block, so the working data is kept on the stack to avoid
+/// allocating.
 pub fn create_codes_in_place(code_table: &mut [u16], length_table: &[u8]) {
     let mut len_counts = [0; 16];
     let (max_length, max_length_pos) = build_length_count_table(length_table, &mut len_counts);
     let lengths = len_counts;
 
     let mut code = 0u16;
-    let mut next_code = Vec::with_capacity(length_table.len());
-    next_code.push(code);
+    let mut next_code = [0u16; MAX_CODE_LENGTH + 1];
 
     for bits in 1..=max_length {
         code = (code + lengths[bits - 1]) << 1;
-        next_code.push(code);
+        next_code[bits] = code;
     }
 
     for n in 0..=max_length_pos {
@@ -335,6 +359,7 @@ impl HuffmanTable {
         );
     }
 
+    #[cfg(test)]
     pub fn set_to_fixed(&mut self) {
         self.code_lengths = FIXED_CODE_LENGTHS;
         self.distance_code_lengths = FIXED_CODE_LENGTHS_DISTANCE;
@@ -388,7 +413,7 @@ impl HuffmanTable {
     /// Returns None if distance is 0 or above 32768
     #[inline]
     pub fn get_distance_huffman(&self, distance: u16) -> (HuffmanCode, HuffmanCode) {
-        debug_assert!(distance >= MIN_DISTANCE && distance <= MAX_DISTANCE);
+        debug_assert!((MIN_DISTANCE..=MAX_DISTANCE).contains(&distance));
 
         let distance_data = get_distance_code_and_extra_bits(distance);
 
@@ -410,7 +435,7 @@ impl HuffmanTable {
 
     #[cfg(test)]
     pub fn get_length_distance_code(&self, length: u16, distance: u16) -> LengthAndDistanceBits {
-        assert!(length >= MIN_MATCH && length < MAX_DISTANCE);
+        assert!((MIN_MATCH..MAX_DISTANCE).contains(&length));
         let l_codes = self.get_length_huffman(StoredLength::from_actual_length(length));
         let d_codes = self.get_distance_huffman(distance);
         LengthAndDistanceBits {
@@ -525,15 +550,66 @@ mod test {
         assert_eq!(ld.distance_extra_bits.code, 0);
     }
 
+    /// Check the fixed codes against the table in section 3.2.6 of RFC 1951, bit for bit.
+    #[test]
+    fn fixed_table_matches_rfc() {
+        // (First symbol, last symbol, code length, first code), codes written most significant
+        // bit first as in the RFC.
+        const RFC_LITERAL_LENGTH_CODES: [(usize, usize, u8, u16); 4] = [
+            (0, 143, 8, 0b0011_0000),
+            (144, 255, 9, 0b1_1001_0000),
+            (256, 279, 7, 0b000_0000),
+            (280, 287, 8, 0b1100_0000),
+        ];
+        let table = HuffmanTable::fixed_table();
+        let (lengths, distance_lengths) = table.get_lengths();
+        for &(first, last, length, first_code) in &RFC_LITERAL_LENGTH_CODES {
+            let codes = table.codes[first..=last].iter().zip(&lengths[first..=last]);
+            for (symbol, (&code, &code_length)) in (first..).zip(codes) {
+                let expected = first_code + (symbol - first) as u16;
+                assert_eq!(code_length, length, "symbol {}", symbol);
+                assert_eq!(reverse_bits(code, length), expected, "symbol {}", symbol);
+            }
+        }
+
+        // The fixed distance codes include 30 and 31, even though they can't occur in the data,
+        // as that is needed for the code to be complete.
+        assert_eq!(distance_lengths.len(), 32);
+        for (code, &length) in distance_lengths.iter().enumerate() {
+            assert_eq!(length, 5);
+            assert_eq!(reverse_bits(table.distance_codes[code], 5), code as u16);
+        }
+    }
+
+    /// Make sure the symbols that are reserved in the format are never used.
+    #[test]
+    fn reserved_symbols_unused() {
+        for length in MIN_MATCH..=MAX_MATCH {
+            let code = get_length_code_and_extra_bits(l(length)).code_number;
+            assert!(usize::from(code) < NUM_LITERALS_AND_LENGTHS);
+        }
+        for distance in MIN_DISTANCE..=MAX_DISTANCE {
+            let code = get_distance_code_and_extra_bits(distance).code_number;
+            assert!(usize::from(code) < NUM_DISTANCE_CODES);
+        }
+    }
+
     #[test]
     fn extra_bits_distance() {
         use std::mem::size_of;
-        for i in 0..NUM_DISTANCE_CODES {
-            assert_eq!(
-                num_extra_bits_for_distance_code(i as u8),
-                DISTANCE_EXTRA_BITS[i]
-            );
+        for (i, &extra) in DISTANCE_EXTRA_BITS.iter().enumerate() {
+            assert_eq!(num_extra_bits_for_distance_code(i as u8), extra);
         }
         println!("Size of huffmanCode struct: {}", size_of::<HuffmanCode>());
     }
+
+    #[test]
+    fn fixed_costs() {
+        assert_eq!(fixed_match_cost(3, 1), 7 + 5);
+        assert_eq!(fixed_match_cost(10, 5), 7 + 5 + 1);
+        assert_eq!(fixed_match_cost(258, 32768), 8 + 5 + 13);
+        assert_eq!(fixed_match_cost(257, 1), 8 + 5 + 5);
+        assert_eq!(fixed_literals_cost(&[0, 143, 144, 255]), 8 + 8 + 9 + 9);
+        assert_eq!(fixed_literals_cost(&[]), 0);
+    }
 }
@@ -0,0 +1,1434 @@
+//! A minimal DEFLATE decoder.
+//!
+//! This is a straightforward implementation of the decoding side of
+//! [RFC 1951](https://tools.ietf.org/html/rfc1951), meant for verifying compressed data and
+//! inspecting the structure of streams, not for speed. It handles all valid streams, not just
+//! ones produced by this library, including the less common edge cases like blocks with no
+//! distance codes, dynamic headers with a single distance code and code length repeats crossing
+//! from the literal/length code lengths into the distance code lengths.
+//!
+//! For decompressing data in general, a dedicated decoder like the ones in
+//! [`flate2`](https://crates.io/crates/flate2) or
+//! [`miniz_oxide`](https://crates.io/crates/miniz_oxide) is a better choice.
+
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hasher;
+use std::io;
+
+use crate::checksum::{Adler32, Crc32};
+use crate::huffman_lengths::HUFFMAN_LENGTH_ORDER;
+use crate::huffman_table::{
+    num_extra_bits_for_distance_code, num_extra_bits_for_length_code, BASE_LENGTH, DISTANCE_BASE,
+    END_OF_BLOCK_POSITION, FIXED_CODE_LENGTHS, FIXED_CODE_LENGTHS_DISTANCE, LENGTH_BITS_START,
+    MAX_CODE_LENGTH, MIN_MATCH, NUM_DISTANCE_CODES, NUM_LENGTH_CODES, NUM_LITERALS_AND_LENGTHS,
+};
+
+/// The number of code length codes used to describe the lengths in a dynamic block header.
+const NUM_CODE_LENGTH_CODES: usize = 19;
+
+/// An error encountered when decoding a compressed stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum InflateError {
+    /// The input ended before the end of the stream.
+    UnexpectedEnd,
+    /// A block header had the reserved block type `3`.
+    InvalidBlockType,
+    /// The length of a stored block did not match the one's complement stored after it.
+    InvalidStoredLength,
+    /// The code lengths in a dynamic block header don't describe a valid set of Huffman codes.
+    InvalidCodeLengths,
+    /// A literal/length or distance symbol that is not used by the format (e.g 286, 287 or a
+    /// distance code of 30 or 31) was found, or one without a code was needed.
+    InvalidSymbol,
+    /// A match referred back further than the start of the data.
+    InvalidDistance,
+    /// The zlib or gzip header was invalid, or specified a preset dictionary that was not
+    /// provided.
+    InvalidHeader,
+    /// The checksum in the trailer did not match the decompressed data.
+    ChecksumMismatch,
+    /// The size stored in a gzip trailer did not match the size of the decompressed data.
+    SizeMismatch,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            InflateError::UnexpectedEnd => "unexpected end of compressed data",
+            InflateError::InvalidBlockType => "invalid block type",
+            InflateError::InvalidStoredLength => "stored block length did not match its complement",
+            InflateError::InvalidCodeLengths => "invalid Huffman code lengths",
+            InflateError::InvalidSymbol => "invalid symbol",
+            InflateError::InvalidDistance => "match distance too far back",
+            InflateError::InvalidHeader => "invalid zlib or gzip header",
+            InflateError::ChecksumMismatch => "checksum mismatch",
+            InflateError::SizeMismatch => "size mismatch",
+        };
+        f.write_str(description)
+    }
+}
+
+impl Error for InflateError {}
+
+impl From<InflateError> for io::Error {
+    fn from(error: InflateError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// The type of a block in a DEFLATE stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum BlockType {
+    /// Uncompressed data.
+    Stored,
+    /// Data compressed using the fixed Huffman codes defined by the format.
+    Fixed,
+    /// Data compressed using Huffman codes stored in the block header.
+    Dynamic,
+}
+
+/// Information about a single block in a DEFLATE stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BlockInfo {
+    /// The type of the block.
+    pub block_type: BlockType,
+    /// Whether this block had the final block bit set.
+    pub is_final: bool,
+    /// The position of the first bit of the block header in the compressed data.
+    pub start_bit: u64,
+    /// The position of the bit after the end of the block in the compressed data.
+    pub end_bit: u64,
+    /// The position of the first byte produced by the block in the decompressed data.
+    pub output_start: u64,
+    /// The number of bytes the block decompresses to.
+    pub output_len: u64,
+}
+
+/// The kind of an element of a compressed stream recorded by [`dump`](fn.dump.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ElementKind {
+    /// The three bits at the start of a block with the final block flag and the block type.
+    BlockHeader {
+        is_final: bool,
+        block_type: BlockType,
+    },
+    /// The code lengths at the start of a dynamic block, with the number of literal/length and
+    /// distance code lengths.
+    CodeLengths {
+        literal_lengths: u16,
+        distances: u16,
+    },
+    /// The padding up to the next byte and the length fields of a stored block, with the
+    /// number of bytes in the block.
+    StoredLength(u16),
+    /// The data in a stored block, with its length.
+    StoredData(u16),
+    /// A literal byte, including the code for it.
+    Literal(u8),
+    /// A match, including the length and distance codes and their extra bits.
+    Match { length: u16, distance: u16 },
+    /// The end of block code.
+    EndOfBlock,
+}
+
+impl fmt::Display for ElementKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ElementKind::BlockHeader {
+                is_final,
+                block_type,
+            } => {
+                let block_type = match block_type {
+                    BlockType::Stored => "stored",
+                    BlockType::Fixed => "fixed",
+                    BlockType::Dynamic => "dynamic",
+                };
+                let is_final = if is_final { "final " } else { "" };
+                write!(f, "{}{} block", is_final, block_type)
+            }
+            ElementKind::CodeLengths {
+                literal_lengths,
+                distances,
+            } => write!(
+                f,
+                "code lengths: {} literal/length, {} distance",
+                literal_lengths, distances
+            ),
+            ElementKind::StoredLength(len) => write!(f, "stored length {}", len),
+            ElementKind::StoredData(len) => write!(f, "{} stored bytes", len),
+            ElementKind::Literal(b) if b.is_ascii_graphic() || b == b' ' => {
+                write!(f, "literal {:?}", char::from(b))
+            }
+            ElementKind::Literal(b) => write!(f, "literal 0x{:02x}", b),
+            ElementKind::Match { length, distance } => {
+                write!(f, "match length {}, distance {}", length, distance)
+            }
+            ElementKind::EndOfBlock => f.write_str("end of block"),
+        }
+    }
+}
+
+/// An element of a compressed stream recorded by [`dump`](fn.dump.html), and where in the
+/// stream it is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct Element {
+    /// The position of the first bit of the element in the compressed data.
+    pub start_bit: u64,
+    /// The position of the bit after the element in the compressed data.
+    pub end_bit: u64,
+    /// What the element is.
+    pub kind: ElementKind,
+}
+
+/// The maximum number of bits of an element shown when displaying a `Dump`.
+const MAX_DUMP_BITS: u64 = 32;
+
+/// A listing of all the elements of a compressed stream, created by [`dump`](fn.dump.html).
+///
+/// The `Display` implementation shows one element per line, with the position of its first
+/// bit, the bits themselves in the order they are in the stream (least significant bit of each
+/// byte first) and what they represent. The bits of long elements like the code lengths of a
+/// dynamic block are cut off.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Dump {
+    input: Vec<u8>,
+    elements: Vec<Element>,
+}
+
+impl Dump {
+    /// The elements of the stream, in the order they appear in it.
+    pub fn elements(&self) -> &[Element] {
+        &self.elements
+    }
+
+    fn bit(&self, position: u64) -> u8 {
+        (self.input[(position / 8) as usize] >> (position % 8)) & 1
+    }
+}
+
+impl fmt::Display for Dump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>7}  {:<35}  element", "bit", "bits")?;
+        for element in &self.elements {
+            let shown_end = cmp::min(element.end_bit, element.start_bit + MAX_DUMP_BITS);
+            let mut bits: String = (element.start_bit..shown_end)
+                .map(|n| char::from(b'0' + self.bit(n)))
+                .collect();
+            if shown_end < element.end_bit {
+                bits.push_str("...");
+            }
+            writeln!(
+                f,
+                "{:>7}  {:<35}  {}",
+                element.start_bit, bits, element.kind
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The checksum and size stored in the trailer of a zlib or gzip stream, as returned by
+/// [`verify_zlib_trailer`](fn.verify_zlib_trailer.html) and
+/// [`verify_gzip_trailer`](fn.verify_gzip_trailer.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct Trailer {
+    /// The Adler-32 (zlib) or CRC-32 (gzip) checksum of the uncompressed data.
+    pub checksum: u32,
+    /// The size of the uncompressed data modulo 2^32, which is only stored by gzip.
+    pub size: Option<u32>,
+}
+
+/// Decompress a raw DEFLATE stream.
+///
+/// Any data after the end of the final block is ignored.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes, inflate};
+///
+/// let data = b"This is some test data";
+/// let compressed = deflate_bytes(data);
+/// assert_eq!(inflate::inflate(&compressed).unwrap(), data);
+/// ```
+pub fn inflate(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    let mut output = Vec::with_capacity(input.len() * 2);
+    decode(input, &[], &mut output, None, None)?;
+    Ok(output)
+}
+
+/// Decompress a zlib stream, checking the header and the checksum in the trailer.
+///
+/// Fails with `InvalidHeader` if the stream needs a preset dictionary, use
+/// [`inflate_zlib_with_dictionary`](fn.inflate_zlib_with_dictionary.html) for those.
+pub fn inflate_zlib(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_zlib_with_dictionary(input, &[])
+}
+
+/// Decompress a zlib stream that may have been compressed using a preset dictionary, checking
+/// the header and the checksum in the trailer.
+pub fn inflate_zlib_with_dictionary(
+    input: &[u8],
+    dictionary: &[u8],
+) -> Result<Vec<u8>, InflateError> {
+    decode_zlib(input, dictionary, None).map(|(output, _)| output)
+}
+
+/// Decompress a zlib stream and check its header and trailer, returning the trailer.
+///
+/// This is meant for end-to-end integrity checks of compressed data, e.g after storing or
+/// sending it. Any data after the trailer is ignored.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes_zlib, inflate, Adler32};
+/// use std::hash::Hasher;
+///
+/// let data = b"This is some test data";
+/// let trailer = inflate::verify_zlib_trailer(&deflate_bytes_zlib(data)).unwrap();
+/// let mut adler32 = Adler32::new();
+/// adler32.write(data);
+/// assert_eq!(u64::from(trailer.checksum), adler32.finish());
+/// ```
+pub fn verify_zlib_trailer(input: &[u8]) -> Result<Trailer, InflateError> {
+    decode_zlib(input, &[], None).map(|(_, trailer)| trailer)
+}
+
+/// Decompress a gzip stream, checking the header and the checksum and size in the trailer.
+///
+/// Only the first member of the stream is decompressed, any data after it is ignored.
+pub fn inflate_gzip(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    decode_gzip(input, None).map(|(output, _)| output)
+}
+
+/// Decompress a gzip stream and check its header and trailer, returning the trailer.
+///
+/// Like [`verify_zlib_trailer`](fn.verify_zlib_trailer.html), but for gzip streams. Only the
+/// first member of the stream is checked.
+pub fn verify_gzip_trailer(input: &[u8]) -> Result<Trailer, InflateError> {
+    decode_gzip(input, None).map(|(_, trailer)| trailer)
+}
+
+fn decode_zlib(
+    input: &[u8],
+    dictionary: &[u8],
+    blocks: Option<&mut Vec<BlockInfo>>,
+) -> Result<(Vec<u8>, Trailer), InflateError> {
+    if input.len() < 2 {
+        return Err(InflateError::UnexpectedEnd);
+    }
+    let (cmf, flg) = (input[0], input[1]);
+    let valid =
+        (cmf & 0x0f) == 8 && (cmf >> 4) <= 7 && (u16::from(cmf) << 8 | u16::from(flg)) % 31 == 0;
+    if !valid {
+        return Err(InflateError::InvalidHeader);
+    }
+
+    let mut start = 2;
+    if flg & 0b0010_0000 != 0 {
+        let dict_id = read_u32_be(input, start)?;
+        if dictionary.is_empty() || dict_id != adler32(dictionary) {
+            return Err(InflateError::InvalidHeader);
+        }
+        start += 4;
+    }
+
+    let mut output = Vec::with_capacity(input.len() * 2);
+    let end = start + decode(&input[start..], dictionary, &mut output, blocks, None)?;
+    let checksum = read_u32_be(input, end)?;
+    if checksum != adler32(&output) {
+        return Err(InflateError::ChecksumMismatch);
+    }
+    Ok((
+        output,
+        Trailer {
+            checksum,
+            size: None,
+        },
+    ))
+}
+
+fn decode_gzip(
+    input: &[u8],
+    blocks: Option<&mut Vec<BlockInfo>>,
+) -> Result<(Vec<u8>, Trailer), InflateError> {
+    let start = gzip_header_len(input)?;
+    let mut output = Vec::with_capacity(input.len() * 2);
+    let end = start + decode(&input[start..], &[], &mut output, blocks, None)?;
+    let checksum = read_u32_le(input, end)?;
+    let size = read_u32_le(input, end + 4)?;
+    if checksum != crc32(&output) {
+        return Err(InflateError::ChecksumMismatch);
+    }
+    if size != output.len() as u32 {
+        return Err(InflateError::SizeMismatch);
+    }
+    Ok((
+        output,
+        Trailer {
+            checksum,
+            size: Some(size),
+        },
+    ))
+}
+
+/// Check the header of a gzip stream, and return its length.
+pub(crate) fn gzip_header_len(input: &[u8]) -> Result<usize, InflateError> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+    const RESERVED: u8 = 0b1110_0000;
+
+    let header = input.get(..10).ok_or(InflateError::UnexpectedEnd)?;
+    let flags = header[3];
+    if header[..3] != [0x1f, 0x8b, 8] || flags & RESERVED != 0 {
+        return Err(InflateError::InvalidHeader);
+    }
+
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        pos += 2 + usize::from(read_u16_le(input, pos)?);
+    }
+    for &field in &[FNAME, FCOMMENT] {
+        if flags & field != 0 {
+            // The file name and comment are zero-terminated.
+            let rest = input.get(pos..).ok_or(InflateError::UnexpectedEnd)?;
+            pos += rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or(InflateError::UnexpectedEnd)?
+                + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        // The header checksum is the lower 16 bits of the CRC-32 of the rest of the header.
+        if read_u16_le(input, pos)? != crc32(&input[..pos]) as u16 {
+            return Err(InflateError::InvalidHeader);
+        }
+        pos += 2;
+    }
+    if pos > input.len() {
+        return Err(InflateError::UnexpectedEnd);
+    }
+    Ok(pos)
+}
+
+/// List the blocks in a raw DEFLATE stream, decoding it to check that it is valid.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::inflate;
+/// use deflate::deflate_bytes;
+///
+/// let blocks = inflate::analyze(&deflate_bytes(b"This is some test data")).unwrap();
+/// assert_eq!(blocks.len(), 1);
+/// assert!(blocks[0].is_final);
+/// assert_eq!(blocks[0].output_len, 22);
+/// ```
+pub fn analyze(input: &[u8]) -> Result<Vec<BlockInfo>, InflateError> {
+    let mut output = Vec::with_capacity(input.len() * 2);
+    let mut blocks = Vec::new();
+    decode(input, &[], &mut output, Some(&mut blocks), None)?;
+    Ok(blocks)
+}
+
+/// List the blocks in a zlib stream without a preset dictionary, decoding it to check that it
+/// is valid, and return them along with the trailer. The positions in the blocks are relative to
+/// the start of the DEFLATE data, after the 2-byte header.
+pub(crate) fn analyze_zlib(input: &[u8]) -> Result<(Vec<BlockInfo>, Trailer), InflateError> {
+    let mut blocks = Vec::new();
+    let (_, trailer) = decode_zlib(input, &[], Some(&mut blocks))?;
+    Ok((blocks, trailer))
+}
+
+/// Like `analyze_zlib`, but for the first member of a gzip stream. The positions in the blocks
+/// are relative to the end of the header, see `gzip_header_len`.
+pub(crate) fn analyze_gzip(input: &[u8]) -> Result<(Vec<BlockInfo>, Trailer), InflateError> {
+    let mut blocks = Vec::new();
+    let (_, trailer) = decode_gzip(input, Some(&mut blocks))?;
+    Ok((blocks, trailer))
+}
+
+/// Decode a raw DEFLATE stream, recording every block header, literal, match and other element
+/// in it along with where it is in the stream.
+///
+/// This is meant for inspecting small streams, e.g when reporting or debugging a problem with
+/// the compressed output, as it records an element for each symbol.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes, inflate};
+///
+/// let dump = inflate::dump(&deflate_bytes(b"Deflate late")).unwrap();
+/// println!("{}", dump);
+/// assert_eq!(dump.elements().len(), 11);
+/// ```
+pub fn dump(input: &[u8]) -> Result<Dump, InflateError> {
+    let mut output = Vec::with_capacity(input.len() * 2);
+    let mut elements = Vec::new();
+    let used = decode(input, &[], &mut output, None, Some(&mut elements))?;
+    Ok(Dump {
+        input: input[..used].to_vec(),
+        elements,
+    })
+}
+
+/// Record an element that started at `start_bit` and ends at the current position of `reader`.
+fn record(
+    elements: &mut Option<&mut Vec<Element>>,
+    reader: &BitReader,
+    start_bit: u64,
+    kind: ElementKind,
+) {
+    if let Some(ref mut elements) = *elements {
+        elements.push(Element {
+            start_bit,
+            end_bit: reader.bit_position(),
+            kind,
+        });
+    }
+}
+
+fn read_u32_be(input: &[u8], pos: usize) -> Result<u32, InflateError> {
+    input
+        .get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(InflateError::UnexpectedEnd)
+}
+
+fn read_u16_le(input: &[u8], pos: usize) -> Result<u16, InflateError> {
+    input
+        .get(pos..pos + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(InflateError::UnexpectedEnd)
+}
+
+fn read_u32_le(input: &[u8], pos: usize) -> Result<u32, InflateError> {
+    input
+        .get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(InflateError::UnexpectedEnd)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = Crc32::new();
+    hasher.write(data);
+    hasher.finish() as u32
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut hasher = Adler32::new();
+    hasher.write(data);
+    hasher.finish() as u32
+}
+
+/// Decode a raw DEFLATE stream, appending the output to `output`, and optionally recording
+/// information about the blocks and the elements in them.
+///
+/// Matches may refer back into `history`, which is not included in the output.
+///
+/// Returns the number of bytes of input used, including the padding after the final block.
+fn decode(
+    input: &[u8],
+    history: &[u8],
+    output: &mut Vec<u8>,
+    mut blocks: Option<&mut Vec<BlockInfo>>,
+    mut elements: Option<&mut Vec<Element>>,
+) -> Result<usize, InflateError> {
+    let mut reader = BitReader::new(input);
+    // Keep the history in the output while decoding, so matches can refer to it.
+    let output_start = output.len();
+    output.extend_from_slice(history);
+    let data_start = output.len();
+
+    let fixed = fixed_codes()?;
+    loop {
+        let start_bit = reader.bit_position();
+        let block_output_start = output.len();
+        let (block_type, is_final) =
+            decode_block(&mut reader, output, output_start, &fixed, &mut elements)?;
+
+        if let Some(ref mut blocks) = blocks {
+            blocks.push(BlockInfo {
+                block_type,
+                is_final,
+                start_bit,
+                end_bit: reader.bit_position(),
+                output_start: (block_output_start - data_start) as u64,
+                output_len: (output.len() - block_output_start) as u64,
+            });
+        }
+
+        if is_final {
+            break;
+        }
+    }
+
+    output.drain(output_start..data_start);
+    Ok(reader.bytes_used())
+}
+
+/// Decode the blocks from bit `start_bit` up to bit `end_bit` of `input`, appending the output
+/// to `output`. Unlike `decode`, this doesn't stop at the final block, or need one.
+///
+/// This is used to check each block as it's output when verification is enabled, with the data
+/// before the blocks as `history`.
+pub(crate) fn decode_blocks(
+    input: &[u8],
+    start_bit: u8,
+    end_bit: u64,
+    history: &[u8],
+    output: &mut Vec<u8>,
+) -> Result<(), InflateError> {
+    let mut reader = BitReader::new(input);
+    reader.bits(start_bit)?;
+    let output_start = output.len();
+    output.extend_from_slice(history);
+    let data_start = output.len();
+
+    let fixed = fixed_codes()?;
+    while reader.bit_position() < end_bit {
+        decode_block(&mut reader, output, output_start, &fixed, &mut None)?;
+    }
+    // The last block went past the end of the data.
+    if reader.bit_position() != end_bit {
+        return Err(InflateError::UnexpectedEnd);
+    }
+
+    output.drain(output_start..data_start);
+    Ok(())
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman), InflateError> {
+    Ok((
+        Huffman::new(&FIXED_CODE_LENGTHS)?,
+        Huffman::new(&FIXED_CODE_LENGTHS_DISTANCE)?,
+    ))
+}
+
+/// Decode a block, appending the output to `output`. Matches can not refer back further than
+/// `window_start` in `output`.
+///
+/// Returns the type of the block and whether it was the final one.
+fn decode_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    window_start: usize,
+    fixed: &(Huffman, Huffman),
+    elements: &mut Option<&mut Vec<Element>>,
+) -> Result<(BlockType, bool), InflateError> {
+    let start_bit = reader.bit_position();
+    let is_final = reader.bits(1)? == 1;
+    let block_type = match reader.bits(2)? {
+        0 => BlockType::Stored,
+        1 => BlockType::Fixed,
+        2 => BlockType::Dynamic,
+        _ => return Err(InflateError::InvalidBlockType),
+    };
+    let kind = ElementKind::BlockHeader {
+        is_final,
+        block_type,
+    };
+    record(elements, reader, start_bit, kind);
+
+    match block_type {
+        BlockType::Stored => stored_block(reader, output, elements)?,
+        BlockType::Fixed => compressed_block(reader, output, window_start, fixed, elements)?,
+        BlockType::Dynamic => {
+            let header_start = reader.bit_position();
+            let (codes, literal_lengths, distances) = dynamic_header(reader)?;
+            let kind = ElementKind::CodeLengths {
+                literal_lengths,
+                distances,
+            };
+            record(elements, reader, header_start, kind);
+            compressed_block(reader, output, window_start, &codes, elements)?
+        }
+    }
+    Ok((block_type, is_final))
+}
+
+fn stored_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    elements: &mut Option<&mut Vec<Element>>,
+) -> Result<(), InflateError> {
+    let start_bit = reader.bit_position();
+    reader.align_to_byte();
+    let len = reader.bits(16)?;
+    let nlen = reader.bits(16)?;
+    if len != !nlen & 0xffff {
+        return Err(InflateError::InvalidStoredLength);
+    }
+    record(
+        elements,
+        reader,
+        start_bit,
+        ElementKind::StoredLength(len as u16),
+    );
+    let start_bit = reader.bit_position();
+    output.extend_from_slice(reader.bytes(len as usize)?);
+    record(
+        elements,
+        reader,
+        start_bit,
+        ElementKind::StoredData(len as u16),
+    );
+    Ok(())
+}
+
+/// Decode the data in a fixed or dynamic block using the provided literal/length and distance
+/// codes. Matches can not refer back further than `window_start` in `output`.
+fn compressed_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    window_start: usize,
+    codes: &(Huffman, Huffman),
+    elements: &mut Option<&mut Vec<Element>>,
+) -> Result<(), InflateError> {
+    let (literal_lengths, distances) = codes;
+    loop {
+        let start_bit = reader.bit_position();
+        let symbol = usize::from(literal_lengths.decode(reader)?);
+        if symbol < END_OF_BLOCK_POSITION {
+            output.push(symbol as u8);
+            record(
+                elements,
+                reader,
+                start_bit,
+                ElementKind::Literal(symbol as u8),
+            );
+            continue;
+        } else if symbol == END_OF_BLOCK_POSITION {
+            record(elements, reader, start_bit, ElementKind::EndOfBlock);
+            return Ok(());
+        }
+
+        let length_code = symbol - usize::from(LENGTH_BITS_START);
+        if length_code >= NUM_LENGTH_CODES {
+            return Err(InflateError::InvalidSymbol);
+        }
+        let extra = reader.bits(num_extra_bits_for_length_code(length_code as u8))?;
+        let length =
+            usize::from(BASE_LENGTH[length_code]) + usize::from(MIN_MATCH) + extra as usize;
+
+        let distance_code = usize::from(distances.decode(reader)?);
+        if distance_code >= NUM_DISTANCE_CODES {
+            return Err(InflateError::InvalidSymbol);
+        }
+        let extra = reader.bits(num_extra_bits_for_distance_code(distance_code as u8))?;
+        let distance = usize::from(DISTANCE_BASE[distance_code]) + 1 + extra as usize;
+        if distance > output.len() - window_start {
+            return Err(InflateError::InvalidDistance);
+        }
+
+        let kind = ElementKind::Match {
+            length: length as u16,
+            distance: distance as u16,
+        };
+        record(elements, reader, start_bit, kind);
+
+        // The match may overlap the data it produces, so copy one byte at a time.
+        let start = output.len() - distance;
+        for n in start..start + length {
+            let byte = output[n];
+            output.push(byte);
+        }
+    }
+}
+
+/// Read the code lengths in a dynamic block header, and build the literal/length and distance
+/// codes from them. Also returns the number of literal/length and distance code lengths.
+fn dynamic_header(reader: &mut BitReader) -> Result<((Huffman, Huffman), u16, u16), InflateError> {
+    let mut lengths = [0u8; NUM_LITERALS_AND_LENGTHS + NUM_DISTANCE_CODES];
+    let (num_literal_lengths, num_distances) = code_lengths(reader, &mut lengths)?;
+    let total = num_literal_lengths + num_distances;
+
+    let literal_lengths = Huffman::new(&lengths[..num_literal_lengths])?;
+    let distances = Huffman::new(&lengths[num_literal_lengths..total])?;
+    // A distance code with no codes at all is fine as long as the block only has literals.
+    if !literal_lengths.is_usable() || !distances.is_usable() {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+    Ok((
+        (literal_lengths, distances),
+        num_literal_lengths as u16,
+        num_distances as u16,
+    ))
+}
+
+/// Read the code lengths in a dynamic block header into `lengths`, the literal/length code
+/// lengths followed by the distance code lengths. Returns the number of each.
+fn code_lengths(
+    reader: &mut BitReader,
+    lengths: &mut [u8; NUM_LITERALS_AND_LENGTHS + NUM_DISTANCE_CODES],
+) -> Result<(usize, usize), InflateError> {
+    let num_literal_lengths = reader.bits(5)? as usize + 257;
+    let num_distances = reader.bits(5)? as usize + 1;
+    let num_code_lengths = reader.bits(4)? as usize + 4;
+    // The fields can describe more codes than the format actually uses.
+    if num_literal_lengths > NUM_LITERALS_AND_LENGTHS || num_distances > NUM_DISTANCE_CODES {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let mut code_length_lengths = [0; NUM_CODE_LENGTH_CODES];
+    for &n in &HUFFMAN_LENGTH_ORDER[..num_code_lengths] {
+        code_length_lengths[usize::from(n)] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_length_lengths)?;
+    if code_length_code.incomplete() {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    // The literal/length and distance code lengths are encoded as one sequence, so runs can
+    // cross from one to the other.
+    let total = num_literal_lengths + num_distances;
+    let mut n = 0;
+    while n < total {
+        let symbol = code_length_code.decode(reader)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if n == 0 {
+                    // There is no previous length to repeat.
+                    return Err(InflateError::InvalidCodeLengths);
+                }
+                (lengths[n - 1], 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if n + repeat > total {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+        lengths[n..n + repeat].iter_mut().for_each(|l| *l = length);
+        n += repeat;
+    }
+
+    // Without an end of block code, the block can't end.
+    if lengths[END_OF_BLOCK_POSITION] == 0 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+    Ok((num_literal_lengths, num_distances))
+}
+
+/// Read the header of the dynamic block starting at bit `start_bit` of `input`, returning the
+/// literal/length and distance code lengths it describes, with the lengths left out of the
+/// header set to zero.
+///
+/// This is used to check each dynamic block header right after it's written when verification
+/// is enabled, before the symbols encoded with the codes follow.
+pub(crate) fn read_dynamic_header(
+    input: &[u8],
+    start_bit: u8,
+) -> Result<([u8; NUM_LITERALS_AND_LENGTHS], [u8; NUM_DISTANCE_CODES]), InflateError> {
+    let mut reader = BitReader::new(input);
+    reader.bits(start_bit)?;
+    // Skip the final block flag.
+    reader.bits(1)?;
+    if reader.bits(2)? != 2 {
+        return Err(InflateError::InvalidBlockType);
+    }
+
+    let mut lengths = [0u8; NUM_LITERALS_AND_LENGTHS + NUM_DISTANCE_CODES];
+    let (num_literal_lengths, num_distances) = code_lengths(&mut reader, &mut lengths)?;
+    let mut literal_lengths = [0u8; NUM_LITERALS_AND_LENGTHS];
+    let mut distance_lengths = [0u8; NUM_DISTANCE_CODES];
+    literal_lengths[..num_literal_lengths].copy_from_slice(&lengths[..num_literal_lengths]);
+    distance_lengths[..num_distances]
+        .copy_from_slice(&lengths[num_literal_lengths..num_literal_lengths + num_distances]);
+    Ok((literal_lengths, distance_lengths))
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length and the symbols
+/// sorted by code.
+struct Huffman {
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    symbols: Vec<u16>,
+    /// The number of unused codes of the maximum length.
+    left: i32,
+}
+
+impl Huffman {
+    /// Build a code from the code lengths of each symbol.
+    ///
+    /// Fails if the lengths describe more codes than there are room for.
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+
+        // Check that the code is not over-subscribed.
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+        }
+
+        let mut offsets = [0u16; MAX_CODE_LENGTH + 2];
+        for length in 1..=MAX_CODE_LENGTH {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; usize::from(offsets[MAX_CODE_LENGTH + 1])];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                let offset = &mut offsets[usize::from(length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+
+        Ok(Huffman {
+            counts,
+            symbols,
+            left,
+        })
+    }
+
+    /// Whether there are unused codes. A code with no symbols at all is not counted as
+    /// incomplete.
+    fn incomplete(&self) -> bool {
+        self.left > 0 && !self.symbols.is_empty()
+    }
+
+    /// Whether the code can be used to encode data. Incomplete codes are only allowed if there
+    /// is a single code, which is then 1 bit long.
+    fn is_usable(&self) -> bool {
+        !self.incomplete() || (self.symbols.len() == 1 && self.counts[1] == 1)
+    }
+
+    /// Decode one symbol, reading the code one bit at a time.
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        // The first code of the current length, and the index of its symbol.
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        // Ran out of code lengths, so this is one of the unused codes of an incomplete code.
+        Err(InflateError::InvalidSymbol)
+    }
+}
+
+/// Reads bits from a byte slice, least significant bit first.
+struct BitReader<'a> {
+    input: &'a [u8],
+    /// The position of the next byte to load into `acc`.
+    pos: usize,
+    acc: u32,
+    bits: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            input,
+            pos: 0,
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Read `n` (at most 16) bits.
+    fn bits(&mut self, n: u8) -> Result<u32, InflateError> {
+        while self.bits < n {
+            let byte = *self
+                .input
+                .get(self.pos)
+                .ok_or(InflateError::UnexpectedEnd)?;
+            self.acc |= u32::from(byte) << self.bits;
+            self.pos += 1;
+            self.bits += 8;
+        }
+        let value = self.acc & ((1 << n) - 1);
+        self.acc >>= n;
+        self.bits -= n;
+        Ok(value)
+    }
+
+    /// Skip to the start of the next byte.
+    fn align_to_byte(&mut self) {
+        self.acc >>= self.bits % 8;
+        self.bits -= self.bits % 8;
+    }
+
+    /// Read `n` bytes, which have to start at a byte boundary.
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], InflateError> {
+        debug_assert!(self.bits % 8 == 0);
+        // Give back any whole bytes that have been loaded already.
+        self.pos -= usize::from(self.bits / 8);
+        self.acc = 0;
+        self.bits = 0;
+        let bytes = self
+            .input
+            .get(self.pos..self.pos + n)
+            .ok_or(InflateError::UnexpectedEnd)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// The number of bits read so far.
+    fn bit_position(&self) -> u64 {
+        self.pos as u64 * 8 - u64::from(self.bits)
+    }
+
+    /// The number of bytes read so far, including any partially read byte.
+    fn bytes_used(&self) -> usize {
+        self.pos - usize::from(self.bits / 8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitstream::LsbWriter;
+    use crate::huffman_table::create_codes_in_place;
+    use crate::test_utils::{get_random_data, get_test_data};
+    use crate::write::ZlibEncoder;
+    use crate::{deflate_bytes_conf, deflate_bytes_zlib_conf, Compression, CompressionOptions};
+    use std::io::Write;
+
+    /// Write a symbol using the code built from `lengths`.
+    fn write_symbol(writer: &mut LsbWriter, lengths: &[u8], symbol: usize) {
+        let mut codes = vec![0; lengths.len()];
+        create_codes_in_place(&mut codes, lengths);
+        writer.write_bits(codes[symbol], lengths[symbol]);
+    }
+
+    /// Write the header of a final dynamic block, using code length code lengths of 5 for the
+    /// code length symbols 9 to 14 and 4 for the rest, and encoding the code lengths using the
+    /// provided code length symbols and extra bits.
+    fn dynamic_header(
+        writer: &mut LsbWriter,
+        num_literal_lengths: usize,
+        num_distances: usize,
+        code_lengths: &[(usize, u16, u8)],
+    ) {
+        let mut code_length_lengths = [4u8; NUM_CODE_LENGTH_CODES];
+        code_length_lengths[9..15].iter_mut().for_each(|l| *l = 5);
+        writer.write_bits(0b101, 3);
+        writer.write_bits((num_literal_lengths - 257) as u16, 5);
+        writer.write_bits((num_distances - 1) as u16, 5);
+        writer.write_bits((NUM_CODE_LENGTH_CODES - 4) as u16, 4);
+        for &n in &HUFFMAN_LENGTH_ORDER {
+            writer.write_bits(code_length_lengths[usize::from(n)].into(), 3);
+        }
+        for &(symbol, extra, extra_bits) in code_lengths {
+            write_symbol(writer, &code_length_lengths, symbol);
+            writer.write_bits(extra, extra_bits);
+        }
+    }
+
+    #[test]
+    fn inflate_own_output() {
+        let data = get_test_data();
+        let options = [
+            CompressionOptions::fast(),
+            CompressionOptions::default(),
+            CompressionOptions::high(),
+            CompressionOptions::huffman_only(),
+            CompressionOptions::rle(),
+        ];
+        for &options in &options {
+            let compressed = deflate_bytes_conf(&data, options);
+            assert!(inflate(&compressed).unwrap() == data);
+            let compressed = deflate_bytes_zlib_conf(&data, options);
+            assert!(inflate_zlib(&compressed).unwrap() == data);
+        }
+
+        let random = get_random_data(100_000);
+        let compressed = deflate_bytes_conf(&random, Compression::Default);
+        assert!(inflate(&compressed).unwrap() == random);
+        assert_eq!(
+            inflate(&deflate_bytes_conf(&[], Compression::Default)).unwrap(),
+            []
+        );
+    }
+
+    #[test]
+    fn inflate_flate2_output() {
+        use flate2::write::DeflateEncoder;
+        let data = get_test_data();
+        for level in 0..=9 {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::new(level));
+            encoder.write_all(&data[..100_000]).unwrap();
+            encoder.flush().unwrap();
+            encoder.write_all(&data[100_000..]).unwrap();
+            let compressed = encoder.finish().unwrap();
+            assert!(inflate(&compressed).unwrap() == data);
+        }
+    }
+
+    #[test]
+    fn inflate_zlib_dictionary() {
+        let dictionary = b"A dictionary with some test data in it";
+        let data = b"Some test data, compressed with a dictionary";
+        let mut encoder =
+            ZlibEncoder::with_dictionary(Vec::new(), Compression::Default, dictionary);
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(
+            inflate_zlib_with_dictionary(&compressed, dictionary).unwrap(),
+            data
+        );
+        assert_eq!(inflate_zlib(&compressed), Err(InflateError::InvalidHeader));
+
+        let mut corrupt = deflate_bytes_zlib_conf(data, Compression::Default);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert_eq!(inflate_zlib(&corrupt), Err(InflateError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn zlib_trailer() {
+        let data = get_test_data();
+        let compressed = deflate_bytes_zlib_conf(&data, Compression::Default);
+        let trailer = verify_zlib_trailer(&compressed).unwrap();
+        assert_eq!(trailer.checksum, adler32(&data));
+        assert_eq!(trailer.size, None);
+        assert_eq!(
+            verify_zlib_trailer(&compressed[..compressed.len() - 1]),
+            Err(InflateError::UnexpectedEnd)
+        );
+    }
+
+    /// Build a gzip stream by hand, with all the optional header fields.
+    fn gzip_stream(data: &[u8]) -> Vec<u8> {
+        let mut stream = vec![0x1f, 0x8b, 8, 0b1_1110, 0, 0, 0, 0, 0, 255];
+        stream.extend_from_slice(&[3, 0, b'a', b'b', b'c']);
+        stream.extend_from_slice(b"name\0comment\0");
+        let header_crc = crc32(&stream) as u16;
+        stream.extend_from_slice(&header_crc.to_le_bytes());
+        stream.extend(deflate_bytes_conf(data, Compression::Default));
+        stream.extend_from_slice(&crc32(data).to_le_bytes());
+        stream.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        stream
+    }
+
+    #[test]
+    fn gzip_trailer() {
+        let data = get_test_data();
+        let stream = gzip_stream(&data);
+        assert!(inflate_gzip(&stream).unwrap() == data);
+        assert_eq!(
+            verify_gzip_trailer(&stream),
+            Ok(Trailer {
+                checksum: crc32(&data),
+                size: Some(data.len() as u32),
+            })
+        );
+
+        let corrupt = |pos: usize| {
+            let mut stream = stream.clone();
+            stream[pos] ^= 1;
+            verify_gzip_trailer(&stream)
+        };
+        let len = stream.len();
+        assert_eq!(corrupt(len - 1), Err(InflateError::SizeMismatch));
+        assert_eq!(corrupt(len - 5), Err(InflateError::ChecksumMismatch));
+        assert_eq!(corrupt(0), Err(InflateError::InvalidHeader));
+        // The file name is covered by the header checksum.
+        assert_eq!(corrupt(16), Err(InflateError::InvalidHeader));
+        assert_eq!(
+            verify_gzip_trailer(&stream[..len - 1]),
+            Err(InflateError::UnexpectedEnd)
+        );
+        assert_eq!(
+            verify_gzip_trailer(&stream[..12]),
+            Err(InflateError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn analyze_blocks() {
+        let data = get_test_data();
+        let compressed = deflate_bytes_conf(&data, Compression::Default);
+        let blocks = analyze(&compressed).unwrap();
+        assert!(blocks.len() > 1);
+        assert!(blocks[..blocks.len() - 1].iter().all(|b| !b.is_final));
+        assert!(blocks.last().unwrap().is_final);
+        let mut bit = 0;
+        let mut output = 0;
+        for block in &blocks {
+            assert_eq!(block.start_bit, bit);
+            assert_eq!(block.output_start, output);
+            bit = block.end_bit;
+            output += block.output_len;
+        }
+        assert_eq!(output, data.len() as u64);
+        assert_eq!(bit.div_ceil(8), compressed.len() as u64);
+    }
+
+    #[test]
+    fn dump_fixed() {
+        // The example from compress::test::fixed_example.
+        let input = [
+            0x73, 0x49, 0x4d, 0xcb, 0x49, 0x2c, 0x49, 0x55, 0x00, 0x11, 0x00,
+        ];
+        let fixed = dump(&input).unwrap();
+        let kinds: Vec<_> = fixed.elements().iter().map(|e| e.kind).collect();
+        let mut expected = vec![ElementKind::BlockHeader {
+            is_final: true,
+            block_type: BlockType::Fixed,
+        }];
+        expected.extend(b"Deflate ".iter().map(|&b| ElementKind::Literal(b)));
+        expected.push(ElementKind::Match {
+            length: 4,
+            distance: 5,
+        });
+        expected.push(ElementKind::EndOfBlock);
+        assert_eq!(kinds, expected);
+        for pair in fixed.elements().windows(2) {
+            assert_eq!(pair[0].end_bit, pair[1].start_bit);
+        }
+
+        let text = fixed.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), expected.len() + 1);
+        assert_eq!(
+            lines[1],
+            "      0  110                                  final fixed block"
+        );
+        // Huffman codes are stored starting with the most significant bit.
+        assert!(lines[2].starts_with("      3  01110100  "));
+        assert!(lines[2].ends_with("literal 'D'"));
+        assert!(lines[10].ends_with("match length 4, distance 5"));
+        assert!(lines[11].starts_with("     80  0000000  "));
+    }
+
+    #[test]
+    fn dump_stored_and_dynamic() {
+        let input = [0, 0, 0, 0xff, 0xff, 1, 3, 0, 0xfc, 0xff, 1, 2, 3];
+        let stored = dump(&input).unwrap();
+        let kinds: Vec<_> = stored.elements().iter().map(|e| e.kind).collect();
+        assert_eq!(kinds[1], ElementKind::StoredLength(0));
+        assert_eq!(kinds[2], ElementKind::StoredData(0));
+        assert_eq!(kinds[4], ElementKind::StoredLength(3));
+        assert_eq!(kinds[5], ElementKind::StoredData(3));
+        assert_eq!(stored.elements()[5].end_bit, 13 * 8);
+        assert!(stored.to_string().contains("100000000100000011000000 "));
+
+        let data = get_test_data();
+        let compressed = deflate_bytes_conf(&data[..2000], Compression::Default);
+        let dynamic = dump(&compressed).unwrap();
+        match dynamic.elements()[1].kind {
+            ElementKind::CodeLengths { distances, .. } => assert!(distances > 1),
+            kind => panic!("Expected code lengths, got {:?}", kind),
+        }
+        assert!(dynamic.to_string().contains("...  code lengths: "));
+        let literals = dynamic
+            .elements()
+            .iter()
+            .filter(|e| matches!(e.kind, ElementKind::Literal(_)))
+            .count();
+        assert!(literals > 0 && literals < 2000);
+    }
+
+    #[test]
+    fn stored_blocks() {
+        // A non-final empty stored block, followed by a final one with 3 bytes.
+        let input = [0, 0, 0, 0xff, 0xff, 1, 3, 0, 0xfc, 0xff, 1, 2, 3, 0xaa];
+        assert_eq!(inflate(&input).unwrap(), [1, 2, 3]);
+        let blocks = analyze(&input).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].block_type, BlockType::Stored);
+        assert_eq!(blocks[1].start_bit, 40);
+        assert_eq!(blocks[1].end_bit, 13 * 8);
+
+        assert_eq!(
+            inflate(&[1, 3, 0, 0xfd, 0xff, 1, 2, 3]),
+            Err(InflateError::InvalidStoredLength)
+        );
+        assert_eq!(
+            inflate(&[1, 3, 0, 0xfc, 0xff, 1, 2]),
+            Err(InflateError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn invalid_streams() {
+        assert_eq!(inflate(&[]), Err(InflateError::UnexpectedEnd));
+        assert_eq!(inflate(&[0b111]), Err(InflateError::InvalidBlockType));
+        // A fixed block with a match at distance 1 before any data.
+        let mut writer = LsbWriter::new(Vec::new());
+        writer.write_bits(0b011, 3);
+        write_symbol(&mut writer, &FIXED_CODE_LENGTHS, 257);
+        write_symbol(&mut writer, &FIXED_CODE_LENGTHS_DISTANCE, 0);
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w), Err(InflateError::InvalidDistance));
+        // Literal/length symbol 286 in a fixed block.
+        let mut writer = LsbWriter::new(Vec::new());
+        writer.write_bits(0b011, 3);
+        write_symbol(&mut writer, &FIXED_CODE_LENGTHS, 286);
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w), Err(InflateError::InvalidSymbol));
+        // Distance symbol 30 in a fixed block.
+        let mut writer = LsbWriter::new(Vec::new());
+        writer.write_bits(0b011, 3);
+        write_symbol(&mut writer, &FIXED_CODE_LENGTHS, 65);
+        write_symbol(&mut writer, &FIXED_CODE_LENGTHS, 257);
+        write_symbol(&mut writer, &FIXED_CODE_LENGTHS_DISTANCE, 30);
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w), Err(InflateError::InvalidSymbol));
+    }
+
+    #[test]
+    fn dynamic_run_across_boundary() {
+        // 258 literal/length codes and 4 distance codes: 'a', 'b', end of block and length
+        // code 257 get length 2, and the repeat of the length of the end of block code covers
+        // length code 257 and all of the distance codes.
+        let mut writer = LsbWriter::new(Vec::new());
+        dynamic_header(
+            &mut writer,
+            258,
+            4,
+            &[
+                (18, 97 - 11, 7),
+                (2, 0, 0),
+                (2, 0, 0),
+                (18, 138 - 11, 7),
+                (18, 19 - 11, 7),
+                (2, 0, 0),
+                (16, 5 - 3, 2),
+            ],
+        );
+        let mut lengths = [0u8; 258];
+        lengths[97] = 2;
+        lengths[98] = 2;
+        lengths[256] = 2;
+        lengths[257] = 2;
+        let distance_lengths = [2, 2, 2, 2];
+        for &symbol in &[97, 98] {
+            write_symbol(&mut writer, &lengths, symbol);
+        }
+        // A match of length 3 at distance 2.
+        write_symbol(&mut writer, &lengths, 257);
+        write_symbol(&mut writer, &distance_lengths, 1);
+        write_symbol(&mut writer, &lengths, 256);
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w).unwrap(), b"ababa");
+        assert_eq!(
+            analyze(&writer.w).unwrap()[0].block_type,
+            BlockType::Dynamic
+        );
+    }
+
+    #[test]
+    fn dynamic_single_and_no_distance_codes() {
+        // A single distance code (HDIST = 0) of length 1, which makes the distance code
+        // incomplete, but that is allowed.
+        let mut literal_lengths = [0u8; 258];
+        literal_lengths[b'x' as usize] = 1;
+        literal_lengths[256] = 2;
+        literal_lengths[257] = 2;
+        let mut writer = LsbWriter::new(Vec::new());
+        dynamic_header(
+            &mut writer,
+            258,
+            1,
+            &[
+                (18, 120 - 11, 7),
+                (1, 0, 0),
+                (18, 135 - 11, 7),
+                (2, 0, 0),
+                (2, 0, 0),
+                (1, 0, 0),
+            ],
+        );
+        write_symbol(&mut writer, &literal_lengths, b'x' as usize);
+        write_symbol(&mut writer, &literal_lengths, 257);
+        writer.write_bits(0, 1);
+        write_symbol(&mut writer, &literal_lengths, 256);
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w).unwrap(), b"xxxx");
+
+        // No distance codes at all, which is fine as long as there are no matches.
+        let mut writer = LsbWriter::new(Vec::new());
+        dynamic_header(
+            &mut writer,
+            257,
+            1,
+            &[
+                (18, 120 - 11, 7),
+                (1, 0, 0),
+                (18, 135 - 11, 7),
+                (1, 0, 0),
+                (0, 0, 0),
+            ],
+        );
+        let mut literal_lengths = [0u8; 257];
+        literal_lengths[b'x' as usize] = 1;
+        literal_lengths[256] = 1;
+        write_symbol(&mut writer, &literal_lengths, b'x' as usize);
+        write_symbol(&mut writer, &literal_lengths, 256);
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w).unwrap(), b"x");
+    }
+
+    #[test]
+    fn dynamic_invalid_lengths() {
+        // Repeating the previous length with no previous length.
+        let mut writer = LsbWriter::new(Vec::new());
+        dynamic_header(&mut writer, 257, 1, &[(16, 0, 2)]);
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w), Err(InflateError::InvalidCodeLengths));
+
+        // A run going past the end of the distance code lengths.
+        let mut writer = LsbWriter::new(Vec::new());
+        dynamic_header(
+            &mut writer,
+            257,
+            1,
+            &[(18, 138 - 11, 7), (18, 119 - 11, 7), (18, 11 - 11, 7)],
+        );
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w), Err(InflateError::InvalidCodeLengths));
+
+        // No end of block code.
+        let mut writer = LsbWriter::new(Vec::new());
+        dynamic_header(
+            &mut writer,
+            257,
+            1,
+            &[(1, 0, 0), (1, 0, 0), (18, 138 - 11, 7), (18, 118 - 11, 7)],
+        );
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w), Err(InflateError::InvalidCodeLengths));
+
+        // Over-subscribed literal/length code.
+        let mut writer = LsbWriter::new(Vec::new());
+        dynamic_header(
+            &mut writer,
+            257,
+            1,
+            &[
+                (1, 0, 0),
+                (16, 0, 2),
+                (18, 138 - 11, 7),
+                (18, 114 - 11, 7),
+                (1, 0, 0),
+                (0, 0, 0),
+            ],
+        );
+        writer.flush_raw();
+        assert_eq!(inflate(&writer.w), Err(InflateError::InvalidCodeLengths));
+    }
+}
@@ -1,5 +1,6 @@
 use std::cmp;
 
+use crate::allocator::{BufferAllocator, BufferVec};
 use crate::chained_hash_table::WINDOW_SIZE;
 
 const MAX_MATCH: usize = crate::huffman_table::MAX_MATCH as usize;
@@ -8,23 +9,34 @@ const MAX_MATCH: usize = crate::huffman_table::MAX_MATCH as usize;
 pub const BUFFER_SIZE: usize = (WINDOW_SIZE * 2) + MAX_MATCH;
 
 pub struct InputBuffer {
-    buffer: Vec<u8>,
+    buffer: BufferVec<u8>,
 }
 
 impl InputBuffer {
     #[cfg(test)]
-    pub fn new<'a>(data: &'a [u8]) -> (InputBuffer, Option<&[u8]>) {
+    pub fn new(data: &[u8]) -> (InputBuffer, Option<&[u8]>) {
         let mut b = InputBuffer::empty();
         let rem = b.add_data(data);
         (b, rem)
     }
 
+    #[cfg(test)]
     pub fn empty() -> InputBuffer {
+        InputBuffer::empty_in(&BufferAllocator::default())
+    }
+
+    /// Create an empty buffer, allocated using `alloc`.
+    pub fn empty_in(alloc: &BufferAllocator) -> InputBuffer {
         InputBuffer {
-            buffer: Vec::with_capacity(BUFFER_SIZE),
+            buffer: alloc.vec_with_capacity(BUFFER_SIZE),
         }
     }
 
+    /// Remove all data from the buffer.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
     /// Add data to the buffer.
     ///
     /// Returns a slice of the data that was not added (including the lookahead if any).
@@ -105,7 +117,7 @@ mod test {
     pub fn buffer_add_full() {
         let data = [10u8; BUFFER_SIZE + 10];
         let (mut buf, extra) = InputBuffer::new(&data[..]);
-        assert!(extra.unwrap() == &[10; 10]);
+        assert!(extra.unwrap() == [10; 10]);
         let to_add = [2, 5, 3];
         let not_added = buf.add_data(&to_add);
         assert_eq!(not_added.unwrap(), to_add);
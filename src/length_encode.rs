@@ -3,7 +3,7 @@ use std::iter::Iterator;
 
 /// An enum representing the different types in the run-length encoded data used to encode
 /// huffman table lengths
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EncodedLength {
     // An actual length value
     Length(u8),
@@ -37,10 +37,45 @@ pub const REPEAT_ZERO_7_BITS: usize = 18;
 
 const MIN_REPEAT: u8 = 3;
 
-/// Push an `EncodedLength` to the vector and update the frequency table.
+/// The maximum number of symbols in a huffman table, which is the number of literal/length codes.
+const MAX_NUM_SYMBOLS: usize = 288;
+
+/// The maximum number of run-length encoded length values, which is when there is one for each of
+/// the literal/length and distance code lengths.
+const MAX_ENCODED_LENGTHS: usize = MAX_NUM_SYMBOLS + 32;
+
+/// A fixed-size buffer holding the run-length encoded huffman code lengths for a block.
+pub struct EncodedLengths {
+    lengths: [EncodedLength; MAX_ENCODED_LENGTHS],
+    len: usize,
+}
+
+impl EncodedLengths {
+    pub fn new() -> EncodedLengths {
+        EncodedLengths {
+            lengths: [EncodedLength::Length(0); MAX_ENCODED_LENGTHS],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, value: EncodedLength) {
+        self.lengths[self.len] = value;
+        self.len += 1;
+    }
+
+    pub fn as_slice(&self) -> &[EncodedLength] {
+        &self.lengths[..self.len]
+    }
+}
+
+/// Push an `EncodedLength` to the buffer and update the frequency table.
 fn update_out_and_freq(
     encoded: EncodedLength,
-    output: &mut Vec<EncodedLength>,
+    output: &mut EncodedLengths,
     frequencies: &mut [u16; 19],
 ) {
     let index = match encoded {
@@ -67,24 +102,21 @@ where
     I: Iterator<Item = &'a u8> + Clone,
 {
     let mut freqs = [0u16; 19];
-    let mut encoded: Vec<EncodedLength> = Vec::new();
+    let mut encoded = EncodedLengths::new();
     encode_lengths_m(lengths, &mut encoded, &mut freqs);
-    (encoded, freqs)
+    (encoded.as_slice().to_vec(), freqs)
 }
 
 /// Run-length encodes the lengths of the values in `lengths` according to the deflate
 /// specification. This is used for writing the code lengths for the huffman tables for
 /// the deflate stream.
 ///
-/// Returns a tuple containing a vec of the encoded lengths
-/// Populates the supplied array with the frequency of the different encoded length values
+/// Puts the encoded lengths in `out`, and
+/// populates the supplied array with the frequency of the different encoded length values
 /// The frequency array is taken as a parameter rather than returned to avoid
 /// excessive memcpying.
-pub fn encode_lengths_m<'a, I>(
-    lengths: I,
-    mut out: &mut Vec<EncodedLength>,
-    mut frequencies: &mut [u16; 19],
-) where
+pub fn encode_lengths_m<'a, I>(lengths: I, out: &mut EncodedLengths, frequencies: &mut [u16; 19])
+where
     I: Iterator<Item = &'a u8> + Clone,
 {
     out.clear();
@@ -104,13 +136,13 @@ pub fn encode_lengths_m<'a, I>(
                 // The previous value has been repeated enough times to write out a repeat code.
 
                 let val = EncodedLength::from_prev_and_repeat(prev, repeat);
-                update_out_and_freq(val, &mut out, &mut frequencies);
+                update_out_and_freq(val, out, frequencies);
                 repeat = 0;
                 // If we have a new length value, output l unless the last value is 0 or l is the
                 // last byte.
                 if l != prev {
                     if l != 0 || iter.peek().is_none() {
-                        update_out_and_freq(EncodedLength::Length(l), &mut out, &mut frequencies);
+                        update_out_and_freq(EncodedLength::Length(l), out, frequencies);
                         repeat = 0;
                     } else {
                         // If we have a zero, we start repeat at one instead of outputting, as
@@ -143,7 +175,7 @@ pub fn encode_lengths_m<'a, I>(
                 };
 
                 for &i in b_iter.take(repeat as usize + extra) {
-                    update_out_and_freq(EncodedLength::Length(i), &mut out, &mut frequencies);
+                    update_out_and_freq(EncodedLength::Length(i), out, frequencies);
                 }
 
                 // If the current byte is zero we start repeat at 1 as we didn't output the literal
@@ -160,33 +192,22 @@ pub fn huffman_lengths_from_frequency(frequencies: &[u16], max_len: usize) -> Ve
     in_place::gen_lengths(frequencies, max_len)
 }
 
-pub type LeafVec = Vec<in_place::Node>;
-
 /// Generate a set of canonical huffman lengths from the given frequencies, with a maximum length
 /// of `max_len`. The lengths are put in the lens slice parameter. Unused lengths are set to 0.
 ///
-/// The leaf buffer is passed in to avoid allocating it every time this function is called.
-/// The existing data contained in it is not preserved.
-pub fn huffman_lengths_from_frequency_m(
-    frequencies: &[u16],
-    max_len: usize,
-    leaf_buffer: &mut LeafVec,
-    lens: &mut [u8],
-) {
-    in_place::in_place_lengths(frequencies, max_len, leaf_buffer, lens);
+/// No heap memory is allocated, the working data is kept in a fixed-size array on the stack.
+pub fn huffman_lengths_from_frequency_m(frequencies: &[u16], max_len: usize, lens: &mut [u8]) {
+    in_place::in_place_lengths(frequencies, max_len, lens);
 }
 
 mod in_place {
+    use super::MAX_NUM_SYMBOLS;
+
     type WeightType = u32;
 
     pub fn validate_lengths(lengths: &[u8]) -> bool {
         // Avoid issue with floating point on mips: https://github.com/image-rs/deflate-rs/issues/23
-        if cfg!(any(
-            target_arch = "mips",
-            target_arch = "mipsel",
-            target_arch = "mips64",
-            target_arch = "mipsel64"
-        )) {
+        if cfg!(any(target_arch = "mips", target_arch = "mips64")) {
             true
         } else {
             let v = lengths.iter().fold(0f64, |acc, &n| {
@@ -197,14 +218,11 @@ mod in_place {
                 }
             });
 
-            match v.partial_cmp(&1.0) {
-                Some(std::cmp::Ordering::Greater) => false,
-                _ => true,
-            }
+            !matches!(v.partial_cmp(&1.0), Some(std::cmp::Ordering::Greater))
         }
     }
 
-    #[derive(Eq, PartialEq, Debug)]
+    #[derive(Clone, Copy, Default, Eq, PartialEq, Debug)]
     pub struct Node {
         value: WeightType,
         symbol: u16,
@@ -250,7 +268,7 @@ mod in_place {
             leaves[t].value = leaves[leaves[t].value as usize].value + 1;
         }
 
-        let mut available = 1 as usize;
+        let mut available = 1_usize;
         let mut used = 0;
         let mut depth = 0;
         let mut root = n as isize - 2;
@@ -275,51 +293,92 @@ mod in_place {
     const MAX_NUMBER_OF_CODES: usize = 32;
     const NUM_CODES_LENGTH: usize = MAX_NUMBER_OF_CODES + 1;
 
-    /// Checks if any of the lengths exceed `max_len`, and if that is the case, alters the length
-    /// table so that no codes exceed `max_len`.
-    /// This is ported from miniz (which is released as public domain by Rich Geldreich
-    /// https://github.com/richgel999/miniz/blob/master/miniz.c)
+    /// The maximum number of items in a list in `package_merge`: all the leaves, and a package
+    /// for each pair of items in the list below.
+    const MAX_LIST_LEN: usize = 2 * MAX_NUM_SYMBOLS;
+
+    /// Maximum code length `package_merge` is used with.
+    const MAX_PACKAGE_MERGE_LEN: usize = 15;
+
+    /// Find the optimal code lengths limited to `max_len` for the leaves with the weights in
+    /// `weights`, sorted in increasing order, and put the number of codes of each length in
+    /// `num_codes`.
     ///
-    /// This will not generate optimal (minimim-redundancy) codes, however in most cases
-    /// this won't make a large difference.
-    pub fn enforce_max_code_lengths(
-        num_codes: &mut [u16; NUM_CODES_LENGTH],
-        num_used: usize,
+    /// This uses the package-merge algorithm (Larmore and Hirschberg, A Fast Algorithm for
+    /// Optimal Length-Limited Huffman Codes), which gives codes as good as possible within the
+    /// limit, rather than just moving the longest codes up and rebalancing the tree
+    /// heuristically. The lengths are non-increasing with the weights, so only the number of
+    /// codes of each length is needed to assign them.
+    ///
+    /// For each level, from `max_len` up to 1, a list is made by merging the leaves with
+    /// packages of two items each from the list of the level below. Taking the `2n - 2` cheapest
+    /// items of the list for the top level, and following the packages down, the length of each
+    /// code is the number of levels where its leaf was taken.
+    fn package_merge(
+        weights: &[WeightType],
         max_len: usize,
+        num_codes: &mut [u16; NUM_CODES_LENGTH],
     ) {
-        debug_assert!(max_len <= 15);
+        let n = weights.len();
+        debug_assert!(n >= 2 && max_len <= MAX_PACKAGE_MERGE_LEN);
+        assert!(
+            1 << max_len >= n,
+            "Too many symbols for the maximum code length!"
+        );
 
-        if num_used <= 1 {
-            return;
-        } else {
-            let mut num_above_max = 0u16;
-            for &l in num_codes[(max_len as usize + 1)..].iter() {
-                num_above_max += l;
+        // Whether each item in the list for a level (level 0 being length 1) is a leaf rather
+        // than a package.
+        let mut is_leaf = [[false; MAX_LIST_LEN]; MAX_PACKAGE_MERGE_LEN];
+        let mut list_len = [0; MAX_PACKAGE_MERGE_LEN];
+        let mut below = [0 as WeightType; MAX_LIST_LEN];
+        let mut below_len = 0;
+        let mut current = [0 as WeightType; MAX_LIST_LEN];
+
+        for level in (0..max_len).rev() {
+            let num_packages = below_len / 2;
+            let (mut leaf, mut package, mut len) = (0, 0, 0);
+            while leaf < n || package < num_packages {
+                let package_weight = if package < num_packages {
+                    below[2 * package] + below[2 * package + 1]
+                } else {
+                    WeightType::MAX
+                };
+                // Prefer leaves on ties, which gives shorter codes to fewer symbols.
+                if leaf < n && weights[leaf] <= package_weight {
+                    current[len] = weights[leaf];
+                    is_leaf[level][len] = true;
+                    leaf += 1;
+                } else {
+                    current[len] = package_weight;
+                    package += 1;
+                }
+                len += 1;
             }
+            list_len[level] = len;
+            below[..len].copy_from_slice(&current[..len]);
+            below_len = len;
+        }
 
-            num_codes[max_len] += num_above_max;
-
-            let mut total = 0u32;
-            for i in (1..=max_len).rev() {
-                // This should be safe as max_len won't be higher than 15, and num_codes[i] can't
-                // be higher than 288,
-                // and 288 << 15 will not be anywhere close to overflowing 32 bits
-                total += (u32::from(num_codes[i])) << (max_len - i);
-            }
+        // Follow the packages taken at each level down to the level below, counting the leaves
+        // taken, which are always the ones with the lowest weights.
+        let mut leaves_taken = [0; MAX_PACKAGE_MERGE_LEN];
+        let mut take = 2 * n - 2;
+        for level in 0..max_len {
+            debug_assert!(take <= list_len[level]);
+            let leaves = is_leaf[level][..take].iter().filter(|&&l| l).count();
+            leaves_taken[level] = leaves;
+            take = 2 * (take - leaves);
+        }
 
-            // miniz uses unsigned long here. 32-bits should be sufficient though,
-            // as max_len won't be longer than 15 anyhow.
-            while total != 1u32 << max_len {
-                num_codes[max_len] -= 1;
-                for i in (1..max_len).rev() {
-                    if num_codes[i] != 0 {
-                        num_codes[i] -= 1;
-                        num_codes[i + 1] += 2;
-                        break;
-                    }
-                }
-                total -= 1;
-            }
+        for c in num_codes.iter_mut() {
+            *c = 0;
+        }
+        for leaf in 0..n {
+            let length = leaves_taken[..max_len]
+                .iter()
+                .filter(|&&t| leaf < t)
+                .count();
+            num_codes[length] += 1;
         }
     }
 
@@ -327,8 +386,7 @@ mod in_place {
     /// Convenience wrapper for tests.
     pub fn gen_lengths(frequencies: &[u16], max_len: usize) -> Vec<u8> {
         let mut lens = vec![0u8; frequencies.len()];
-        let mut leaves = Vec::new();
-        in_place_lengths(frequencies, max_len, &mut leaves, lens.as_mut_slice());
+        in_place_lengths(frequencies, max_len, lens.as_mut_slice());
         lens
     }
 
@@ -339,36 +397,31 @@ mod in_place {
     ///
     /// This is significantly faster, and seems to generally create lengths that result in length
     /// tables that are better compressible than the algorithm used previously. The downside of this
-    /// algorithm is that it's not length-limited, so if too long code lengths are generated,
-    /// it might result in a sub-optimal tables as the length-restricting function isn't optimal.
-    pub fn in_place_lengths(
-        frequencies: &[u16],
-        max_len: usize,
-        mut leaves: &mut Vec<Node>,
-        lengths: &mut [u8],
-    ) {
+    /// algorithm is that it's not length-limited, so if too long code lengths are generated, the
+    /// lengths are computed again using the slower `package_merge`.
+    pub fn in_place_lengths(frequencies: &[u16], max_len: usize, lengths: &mut [u8]) {
         debug_assert!(lengths.len() >= frequencies.len());
+        assert!(frequencies.len() <= MAX_NUM_SYMBOLS);
 
         for l in lengths.iter_mut() {
             *l = 0;
         }
 
-        // Clear any previous leaves in the leaf buffer.
-        leaves.clear();
-
         // Discard zero length nodes as they won't be given a code and thus don't need to
-        // participate in code length generation and create a new vec of the remaining
-        // symbols and weights.
-        leaves.extend(frequencies.iter().enumerate().filter_map(|(n, f)| {
-            if *f > 0 {
-                Some(Node {
-                    value: u32::from(*f),
+        // participate in code length generation and put the remaining symbols and weights
+        // in the leaf array.
+        let mut leaf_buffer = [Node::default(); MAX_NUM_SYMBOLS];
+        let mut num_leaves = 0;
+        for (n, &f) in frequencies.iter().enumerate() {
+            if f > 0 {
+                leaf_buffer[num_leaves] = Node {
+                    value: u32::from(f),
                     symbol: n as u16,
-                })
-            } else {
-                None
+                };
+                num_leaves += 1;
             }
-        }));
+        }
+        let leaves = &mut leaf_buffer[..num_leaves];
 
         // Special cases with zero or 1 value having a non-zero frequency
         if leaves.len() == 1 {
@@ -378,12 +431,19 @@ mod in_place {
             return;
         }
 
-        // Sort the leaves by value. As the sort in the standard library is stable, we don't
-        // have to worry about the symbol code here.
-        leaves.sort_by(|a, b| a.value.cmp(&b.value));
+        // Sort the leaves by value, and by symbol for equal values to keep the output
+        // deterministic. (The stable sort would need to allocate a buffer.)
+        leaves.sort_unstable_by_key(|a| (a.value, a.symbol));
 
-        step_1(&mut leaves);
-        step_2(&mut leaves);
+        // Keep the weights in case the lengths have to be limited, as the values of the leaves
+        // are replaced with the lengths.
+        let mut weights = [0 as WeightType; MAX_NUM_SYMBOLS];
+        for (w, l) in weights.iter_mut().zip(leaves.iter()) {
+            *w = l.value;
+        }
+
+        step_1(leaves);
+        step_2(leaves);
 
         // Count how many codes of each length used, for usage in the next section.
         let mut num_codes = [0u16; NUM_CODES_LENGTH];
@@ -391,9 +451,11 @@ mod in_place {
             num_codes[l.value as usize] += 1;
         }
 
-        // As the algorithm used here doesn't limit the maximum length that can be generated
-        // we need to make sure none of the lengths exceed `max_len`
-        enforce_max_code_lengths(&mut num_codes, leaves.len(), max_len);
+        // As the algorithm used here doesn't limit the maximum length that can be generated,
+        // fall back to finding the best lengths within the limit if any are too long.
+        if num_codes[max_len + 1..].iter().any(|&n| n > 0) {
+            package_merge(&weights[..leaves.len()], max_len, &mut num_codes);
+        }
 
         // Output the actual lengths
         let mut leaf_it = leaves.iter().rev();
@@ -416,7 +478,6 @@ mod in_place {
 mod test {
     use super::*;
     use crate::huffman_table::NUM_LITERALS_AND_LENGTHS;
-    use std::u16;
 
     fn lit(value: u8) -> EncodedLength {
         EncodedLength::Length(value)
@@ -434,6 +495,16 @@ mod test {
         EncodedLength::CopyPrevious(copies)
     }
 
+    #[test]
+    fn encode_lengths_max_output() {
+        // With no repeated values every length is output directly, which is the longest possible
+        // output.
+        let lengths: Vec<u8> = (0..MAX_ENCODED_LENGTHS).map(|n| (n % 2) as u8 + 1).collect();
+        let (enc, freqs) = encode_lengths(lengths.iter());
+        assert_eq!(enc.len(), MAX_ENCODED_LENGTHS);
+        assert_eq!(freqs[1] + freqs[2], MAX_ENCODED_LENGTHS as u16);
+    }
+
     #[test]
     fn test_encode_lengths() {
         use crate::huffman_table::FIXED_CODE_LENGTHS;
@@ -610,6 +681,108 @@ mod test {
         assert!(res[125] < 3);
     }
 
+    /// Straightforward package-merge, keeping the symbols in each package, to compare against.
+    fn reference_limited_cost(frequencies: &[u16], max_len: usize) -> u64 {
+        let mut leaves: Vec<(u64, Vec<usize>)> = frequencies
+            .iter()
+            .enumerate()
+            .filter(|&(_, &f)| f > 0)
+            .map(|(n, &f)| (u64::from(f), vec![n]))
+            .collect();
+        leaves.sort_by_key(|l| l.0);
+        let mut list = leaves.clone();
+        for _ in 1..max_len {
+            let packages = list
+                .chunks_exact(2)
+                .map(|p| (p[0].0 + p[1].0, [&p[0].1[..], &p[1].1[..]].concat()));
+            list = leaves.iter().cloned().chain(packages).collect();
+            list.sort_by_key(|l| l.0);
+        }
+        list[..2 * leaves.len() - 2]
+            .iter()
+            .flat_map(|item| item.1.iter())
+            .map(|&n| u64::from(frequencies[n]))
+            .sum()
+    }
+
+    fn cost(frequencies: &[u16], lengths: &[u8]) -> u64 {
+        frequencies
+            .iter()
+            .zip(lengths)
+            .map(|(&f, &l)| u64::from(f) * u64::from(l))
+            .sum()
+    }
+
+    /// Check that the lengths are within the limit, form a complete code, and are as good as
+    /// possible.
+    fn check_limited(frequencies: &[u16], max_len: usize) {
+        let lengths = huffman_lengths_from_frequency(frequencies, max_len);
+        assert!(lengths.iter().all(|&l| usize::from(l) <= max_len));
+        let kraft: u64 = lengths
+            .iter()
+            .filter(|&&l| l > 0)
+            .map(|&l| 1 << (max_len - usize::from(l)))
+            .sum();
+        assert_eq!(kraft, 1 << max_len, "{:?}", lengths);
+        assert_eq!(
+            cost(frequencies, &lengths),
+            reference_limited_cost(frequencies, max_len),
+            "{:?}",
+            frequencies
+        );
+    }
+
+    #[test]
+    fn limited_lengths() {
+        // Fibonacci frequencies give the deepest possible tree, one level per symbol.
+        let mut fibonacci = vec![1u16, 1];
+        while let Some(next) =
+            fibonacci[fibonacci.len() - 2].checked_add(fibonacci[fibonacci.len() - 1])
+        {
+            fibonacci.push(next);
+        }
+
+        // The code length alphabet, limited to 7 bits.
+        check_limited(&fibonacci[..19], 7);
+        let mut reversed = fibonacci[..19].to_vec();
+        reversed.reverse();
+        check_limited(&reversed, 7);
+        // Just enough symbols to fill all the 7-bit codes, and more than can fit in 4 bits.
+        let mut skewed = vec![1u16; 19];
+        skewed[0] = 60000;
+        skewed[18] = 30000;
+        check_limited(&skewed, 7);
+        check_limited(&skewed, 5);
+        // Zero frequencies in between.
+        let sparse: Vec<u16> = fibonacci[..19]
+            .iter()
+            .enumerate()
+            .map(|(n, &f)| if n % 3 == 1 { 0 } else { f })
+            .collect();
+        check_limited(&sparse, 7);
+
+        // Literals and lengths, limited to 15 bits.
+        let mut literals = vec![1u16; 286];
+        literals[..fibonacci.len()].copy_from_slice(&fibonacci);
+        check_limited(&literals, 15);
+        check_limited(&fibonacci, 15);
+
+        // Random-ish distributions that may or may not need limiting.
+        let mut state = 12345u32;
+        for _ in 0..200 {
+            let frequencies: Vec<u16> = (0..19)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    // Spread the values over several orders of magnitude.
+                    ((state >> 16) as u16) >> ((state >> 8) % 16)
+                })
+                .collect();
+            if frequencies.iter().filter(|&&f| f > 0).count() >= 2 {
+                check_limited(&frequencies, 7);
+            }
+        }
+    }
+
     #[test]
     /// Test if the bit lengths for a set of frequencies are optimal (give the best compression
     /// give the provided frequencies).
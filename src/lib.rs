@@ -19,6 +19,26 @@
 //! Support for the gzip wrapper (the wrapper that is used in `.gz` files) is disabled by default,
 //! but can be enabled with the `gzip` feature.
 //!
+//! With the `metrics` feature, the encoders report what they do through the
+//! [`metrics`](https://docs.rs/metrics) facade, so it can be monitored using any exporter for
+//! it, e.g for Prometheus. The metrics are:
+//!
+//! - `deflate_input_bytes_total`: the number of bytes of input in the blocks written.
+//! - `deflate_output_bytes_total`: the number of compressed bytes written to the wrapped writers.
+//! - `deflate_blocks_total`: the number of blocks written, labelled with their `type`, which is
+//!   `stored`, `fixed` or `dynamic`.
+//! - `deflate_block_duration_seconds`: a histogram of the time spent choosing the codes for and
+//!   writing each block. This does not include the time spent looking for matches.
+//! - `deflate_flushes_total`: the number of sync flushes that output a sync marker.
+//! - `deflate_output_buffer_growths_total`: the number of times the output buffer of an encoder
+//!   had to grow to fit a block, which is the only allocation done when writing blocks.
+//!
+//! The metrics are registered with the recorder that is installed when an encoder is created,
+//! and keep being reported to it for the lifetime of the encoder.
+//!
+//! The `bench` feature adds the [`bench`](bench/index.html) module, for measuring the speed and
+//! compression ratio on a given corpus on the current machine.
+//!
 //! As this library is still in development, the compression output may change slightly
 //! between versions.
 //!
@@ -48,6 +68,7 @@
 //! ```
 
 #![cfg_attr(all(feature = "benchmarks", test), feature(test))]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 
 #[cfg(all(test, feature = "benchmarks"))]
 extern crate test as test_std;
@@ -61,29 +82,60 @@ extern crate adler32;
 extern crate byteorder;
 #[cfg(feature = "gzip")]
 extern crate gzip_header;
+#[cfg(feature = "metrics")]
+extern crate metrics;
 
 mod compression_options;
 mod huffman_table;
 mod lz77;
+mod allocator;
+#[cfg(feature = "bench")]
+pub mod bench;
 mod bit_reverse;
 mod bitstream;
 mod chained_hash_table;
 mod checksum;
+mod checksum_writer;
+mod chunks;
 mod compress;
+mod concat;
+mod cpu_dispatch;
+mod data_kind;
 mod deflate_state;
+pub mod dictionary;
+mod encoder_metrics;
 mod encoder_state;
+mod estimate;
+mod entropy_backend;
+mod fixed_table;
+mod flush_coalescing;
+mod frame;
+#[cfg(feature = "gzip")]
+mod gzip;
 mod huffman_lengths;
+pub mod inflate;
 mod input_buffer;
 mod length_encode;
 mod lzvalue;
 mod matching;
+mod mszip;
 mod output_writer;
+mod page_writer;
+mod pipeline;
+mod pool;
+mod pre_filter;
+mod reader;
 mod rle;
 mod stored_block;
+mod stream_splitter;
 #[cfg(test)]
 mod test_utils;
+mod tokens;
+pub mod typestate;
+mod verify;
 mod writer;
 mod zlib;
+mod zlib_compat;
 
 use std::io;
 use std::io::Write;
@@ -100,15 +152,51 @@ use crate::checksum::RollingChecksum;
 use crate::deflate_state::DeflateState;
 
 use crate::compress::Flush;
-pub use compression_options::{Compression, CompressionOptions, SpecialOptions};
+#[cfg(feature = "allocator_api")]
+pub use allocator::BufferAllocator;
+pub use bit_reverse::reverse_bits;
+pub use checksum::{adler32_combine, crc32_combine, Adler32, Crc32};
+pub use chunks::CompressChunks;
+pub use compression_options::{
+    BlockLimits, ChainDecay, Compression, CompressionOptions, ConfigError, MatchHardening,
+    ParseCompressionError, SpecialOptions, MAX_BLOCK_SYMBOLS, MAX_COMPRESSION_LEVEL,
+};
+pub use concat::{concat_gzip_streams, concat_raw_streams, concat_zlib_streams};
+pub use cpu_dispatch::{simd_level, SimdLevel};
+pub use data_kind::DataKind;
+pub use encoder_state::BlockBoundary;
+pub use estimate::estimate_compressed_size;
+pub use flush_coalescing::FlushCoalescing;
+pub use frame::{Frame, FrameEncoder, MIN_FRAME_LEN};
+#[cfg(feature = "gzip")]
+pub use gzip::{
+    compress_file, compress_file_pipelined, gz_builder_for_file, gzip_os, GzExtra,
+};
 pub use lz77::MatchingType;
+pub use matching::MatchFinder;
+pub use mszip::{deflate_bytes_mszip, deflate_bytes_mszip_conf, MSZIP_BLOCK_SIZE};
+pub use page_writer::{deflate_bytes_paged_conf, PageWriter, DEFAULT_PAGE_SIZE};
+#[cfg(feature = "gzip")]
+pub use pipeline::compress_pipelined_gzip;
+pub use pipeline::{compress_pipelined, compress_pipelined_zlib, PipelineOptions};
+pub use pool::{CompressorPool, PooledCompressor};
+pub use pre_filter::{PreFilter, TRANSPOSE_BLOCK_RECORDS};
+pub use stream_splitter::ZlibStreamSplitter;
+pub use tokens::{record_tokens, BlockSplit, ReplayOptions, TokenStream};
+pub use zlib_compat::{compress2, compress_bound};
 
 use crate::writer::compress_until_done;
 
+/// Encoders implementing a `Read` interface, compressing the data read from a wrapped reader.
+pub mod read {
+    pub use crate::reader::DeflateEncoder;
+}
+
 /// Encoders implementing a `Write` interface.
 pub mod write {
     #[cfg(feature = "gzip")]
     pub use crate::writer::gzip::GzEncoder;
+    pub use crate::checksum_writer::ChecksumWriter;
     pub use crate::writer::{DeflateEncoder, ZlibEncoder};
 }
 
@@ -118,6 +206,13 @@ fn compress_data_dynamic<RC: RollingChecksum, W: Write>(
     mut checksum: RC,
     compression_options: CompressionOptions,
 ) -> io::Result<()> {
+    let filtered;
+    let input = if compression_options.pre_filter.is_active() {
+        filtered = compression_options.pre_filter.apply(input);
+        &filtered[..]
+    } else {
+        input
+    };
     checksum.update_from_slice(input);
     // We use a box here to avoid putting the buffers on the stack
     // It's done here rather than in the structs themselves for now to
@@ -169,11 +264,51 @@ pub fn deflate_bytes(input: &[u8]) -> Vec<u8> {
     deflate_bytes_conf(input, Compression::Default)
 }
 
+/// Compress the given slice of bytes with DEFLATE compression into `out`, replacing its contents.
+///
+/// Unlike [`deflate_bytes_conf`](fn.deflate_bytes_conf.html), this reuses the memory already
+/// allocated for `out`, so when compressing many pieces of data, e.g in a server compressing
+/// responses, the same vector can be used for each of them rather than allocating a new one
+/// every time. See [`compress_append_to_buf`](fn.compress_append_to_buf.html) for keeping the
+/// contents of `out`, and [`CompressorPool`](struct.CompressorPool.html) for reusing the memory
+/// used by the compressor as well.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{compress_to_buf, deflate_bytes_conf, Compression};
+///
+/// let mut out = Vec::new();
+/// for data in &[&b"This is some test data"[..], b"This is some more test data"] {
+///     compress_to_buf(data, Compression::Default, &mut out);
+///     assert_eq!(out, deflate_bytes_conf(data, Compression::Default));
+/// }
+/// ```
+pub fn compress_to_buf<O: Into<CompressionOptions>>(input: &[u8], options: O, out: &mut Vec<u8>) {
+    out.clear();
+    compress_append_to_buf(input, options, out);
+}
+
+/// Compress the given slice of bytes with DEFLATE compression, appending the output to `out`.
+///
+/// Like [`compress_to_buf`](fn.compress_to_buf.html), but keeps the current contents of `out`.
+pub fn compress_append_to_buf<O: Into<CompressionOptions>>(
+    input: &[u8],
+    options: O,
+    out: &mut Vec<u8>,
+) {
+    out.reserve(input.len() / 3);
+    compress_data_dynamic(input, out, checksum::NoChecksum::new(), options.into())
+        .expect("Write error!");
+}
+
 /// Compress the given slice of bytes with DEFLATE compression, including a zlib header and trailer.
 ///
 /// Returns a `Vec<u8>` of the compressed data.
 ///
-/// Zlib dictionaries are not yet suppored.
+/// To use a preset dictionary, use [`ZlibEncoder::with_dictionary`] instead.
+///
+/// [`ZlibEncoder::with_dictionary`]: write/struct.ZlibEncoder.html#method.with_dictionary
 ///
 /// # Examples
 ///
@@ -208,7 +343,9 @@ pub fn deflate_bytes_zlib_conf<O: Into<CompressionOptions>>(input: &[u8], option
 ///
 /// Returns a Vec<u8> of the compressed data.
 ///
-/// Zlib dictionaries are not yet suppored.
+/// To use a preset dictionary, use [`ZlibEncoder::with_dictionary`] instead.
+///
+/// [`ZlibEncoder::with_dictionary`]: write/struct.ZlibEncoder.html#method.with_dictionary
 ///
 /// # Examples
 ///
@@ -255,14 +392,11 @@ pub fn deflate_bytes_gzip_conf<O: Into<CompressionOptions>>(
 
     // Write header
     writer
-        .write_all(&gzip_header.into_header())
+        .write_all(&gzip::header_from_builder(gzip_header))
         .expect("Write error when writing header!");
-    let mut checksum = checksum::NoChecksum::new();
-    compress_data_dynamic(input, &mut writer, &mut checksum, options.into())
-        .expect("Write error when writing compressed data!");
-
     let mut crc = Crc::new();
-    crc.update(input);
+    compress_data_dynamic(input, &mut writer, &mut crc, options.into())
+        .expect("Write error when writing compressed data!");
 
     writer
         .write_u32::<LittleEndian>(crc.sum())
@@ -292,21 +426,117 @@ pub fn deflate_bytes_gzip(input: &[u8]) -> Vec<u8> {
     deflate_bytes_gzip_conf(input, Compression::Default, GzBuilder::new())
 }
 
+/// Write all the parts to the encoder.
+fn write_parts<'a, I, W>(mut encoder: W, parts: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+    W: Write,
+{
+    for part in parts {
+        encoder.write_all(part)?;
+    }
+    Ok(())
+}
+
+/// Compress the concatenation of the given slices of bytes with DEFLATE compression, without
+/// having to copy them into one buffer first.
+///
+/// The parts are compressed as one stream, so matches can refer back across the boundaries
+/// between them, and the output is the same as if the parts were concatenated and passed to
+/// [`deflate_bytes_conf`](fn.deflate_bytes_conf.html).
+///
+/// Returns a `Vec<u8>` of the compressed data.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes_parts_conf, Compression};
+///
+/// let header = b"Header: value\n\n";
+/// let body = b"Some test data";
+/// let compressed = deflate_bytes_parts_conf(vec![&header[..], &body[..]], Compression::Best);
+/// # let _ = compressed;
+/// ```
+pub fn deflate_bytes_parts_conf<'a, I, O>(parts: I, options: O) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+    O: Into<CompressionOptions>,
+{
+    let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+    write_parts(&mut encoder, parts).expect("Write error when writing compressed data!");
+    encoder
+        .finish()
+        .expect("Write error when finishing compression!")
+}
+
+/// Compress the concatenation of the given slices of bytes with DEFLATE compression, including a
+/// zlib header and trailer, without having to copy them into one buffer first.
+///
+/// The output is the same as if the parts were concatenated and passed to
+/// [`deflate_bytes_zlib_conf`](fn.deflate_bytes_zlib_conf.html).
+///
+/// Returns a `Vec<u8>` of the compressed data.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes_zlib_parts_conf, Compression};
+///
+/// let parts: [&[u8]; 3] = [b"header ", b"body ", b"trailer"];
+/// let compressed = deflate_bytes_zlib_parts_conf(parts.iter().cloned(), Compression::Default);
+/// # let _ = compressed;
+/// ```
+pub fn deflate_bytes_zlib_parts_conf<'a, I, O>(parts: I, options: O) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+    O: Into<CompressionOptions>,
+{
+    let mut encoder = write::ZlibEncoder::new(Vec::new(), options);
+    write_parts(&mut encoder, parts).expect("Write error when writing compressed data!");
+    encoder
+        .finish()
+        .expect("Write error when finishing compression!")
+}
+
+/// Compress the concatenation of the given slices of bytes with DEFLATE compression, including a
+/// gzip header and trailer, without having to copy them into one buffer first.
+///
+/// The output is the same as if the parts were concatenated and passed to
+/// [`deflate_bytes_gzip_conf`](fn.deflate_bytes_gzip_conf.html).
+///
+/// Returns a `Vec<u8>` of the compressed data.
+#[cfg(feature = "gzip")]
+pub fn deflate_bytes_gzip_parts_conf<'a, I, O>(
+    parts: I,
+    options: O,
+    gzip_header: GzBuilder,
+) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+    O: Into<CompressionOptions>,
+{
+    let mut encoder = write::GzEncoder::from_builder(gzip_header, Vec::new(), options);
+    write_parts(&mut encoder, parts).expect("Write error when writing compressed data!");
+    encoder
+        .finish()
+        .expect("Write error when finishing compression!")
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use std::io::Write;
 
     #[cfg(feature = "gzip")]
-    use test_utils::decompress_gzip;
-    use test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+    use crate::test_utils::decompress_gzip;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
 
     type CO = CompressionOptions;
 
     /// Write data to the writer in chunks of chunk_size.
     fn chunked_write<W: Write>(mut writer: W, data: &[u8], chunk_size: usize) {
         for chunk in data.chunks(chunk_size) {
-            writer.write_all(&chunk).unwrap();
+            writer.write_all(chunk).unwrap();
         }
     }
 
@@ -369,7 +599,7 @@ mod test {
 
         let result = decompress_zlib(&compressed);
 
-        assert!(&test_data == &result);
+        assert!(test_data == result);
         assert!(compressed.len() < test_data.len());
     }
 
@@ -408,10 +638,450 @@ mod test {
             GzBuilder::new().comment(&comment[..]),
         );
         let (dec, decompressed) = decompress_gzip(&compressed);
-        assert_eq!(dec.header().comment().unwrap(), comment);
+        assert_eq!(dec.header().unwrap().comment().unwrap(), comment);
         assert!(data == decompressed);
     }
 
+    #[test]
+    fn pre_filter_numeric() {
+        // Slowly changing 32-bit values, like sensor readings.
+        let data: Vec<u8> = (0..20000u32)
+            .flat_map(|n| (1_000_000 + n * 3 + (n % 7)).to_le_bytes().to_vec())
+            .collect();
+        let filter = PreFilter {
+            delta: 4,
+            transpose: 4,
+        };
+        let options = CompressionOptions {
+            pre_filter: filter,
+            ..CompressionOptions::default()
+        };
+
+        let compressed = deflate_bytes_zlib_conf(&data, options);
+        let decompressed = decompress_zlib(&compressed);
+        assert!(filter.reverse(&decompressed) == data);
+        assert!(compressed.len() * 2 < deflate_bytes_zlib(&data).len());
+
+        // Compressing with the writer should give the same output.
+        let mut compressor = write::ZlibEncoder::new(Vec::new(), options);
+        chunked_write(&mut compressor, &data, 3000);
+        assert!(compressor.finish().unwrap() == compressed);
+    }
+
+    #[test]
+    fn block_limits() {
+        let data = get_test_data();
+        let default_len = deflate_bytes(&data).len();
+        let limits = [
+            BlockLimits {
+                max_symbols: 1000,
+                ..BlockLimits::DEFAULT
+            },
+            BlockLimits {
+                max_input_bytes: 4096,
+                ..BlockLimits::DEFAULT
+            },
+            BlockLimits {
+                max_estimated_bits: 8 * 2048,
+                ..BlockLimits::DEFAULT
+            },
+        ];
+        for &block_limits in &limits {
+            for &base in &[CO::default(), CO::fast(), CO::rle(), CO::huffman_only()] {
+                let options = CompressionOptions {
+                    block_limits,
+                    ..base
+                };
+                let compressed = deflate_bytes_conf(&data, options);
+                assert!(decompress_to_end(&compressed) == data);
+                if base == CO::default() {
+                    // Smaller blocks means more block headers.
+                    assert!(compressed.len() > default_len);
+                }
+
+                let mut compressor = write::DeflateEncoder::new(Vec::new(), options);
+                chunked_write(&mut compressor, &data, 5000);
+                assert!(compressor.finish().unwrap() == compressed);
+            }
+        }
+    }
+
+    #[test]
+    fn max_blocks() {
+        let data = get_test_data();
+        let with_max_blocks = |max_blocks| CompressionOptions {
+            block_limits: BlockLimits {
+                max_blocks,
+                ..BlockLimits::DEFAULT
+            },
+            ..CO::default()
+        };
+        let compress = |data: &[u8], max_blocks| {
+            let mut compressor =
+                write::DeflateEncoder::new(Vec::new(), with_max_blocks(max_blocks));
+            compressor.write_all(data)?;
+            compressor.finish()
+        };
+
+        let single = deflate_bytes_conf(&data[..20000], with_max_blocks(1));
+        assert_eq!(inflate::analyze(&single).unwrap().len(), 1);
+        assert!(decompress_to_end(&single) == data[..20000]);
+
+        // The whole file needs as many blocks as it takes without a limit.
+        let blocks = inflate::analyze(&deflate_bytes(&data)).unwrap().len();
+        assert!(blocks > 1);
+        let compressed = compress(&data, blocks as u32).unwrap();
+        assert!(compressed == deflate_bytes(&data));
+        assert!(compress(&data, blocks as u32 - 1).is_err());
+        assert!(compress(&data, 1).is_err());
+
+        // The empty stored blocks output when flushing count as well.
+        for &(max_blocks, fits) in &[(2, false), (3, true)] {
+            let mut compressor =
+                write::DeflateEncoder::new(Vec::new(), with_max_blocks(max_blocks));
+            compressor.write_all(&data[..100]).unwrap();
+            compressor.flush().unwrap();
+            let res = compressor
+                .write_all(&data[100..200])
+                .and_then(|_| compressor.finish());
+            assert_eq!(res.is_ok(), fits);
+        }
+    }
+
+    #[test]
+    fn match_hardening() {
+        let data = get_test_data();
+        let hardened = CompressionOptions {
+            match_hardening: MatchHardening::untrusted(),
+            ..CompressionOptions::high()
+        };
+        let compressed = deflate_bytes_conf(&data, hardened);
+        assert!(decompress_to_end(&compressed) == data);
+        // The limits shouldn't make much of a difference for normal data.
+        assert!(compressed.len() < deflate_bytes_conf(&data, CO::fast()).len());
+
+        // Input where many positions hash to the same value, with long almost-matches.
+        let mut pathological = Vec::new();
+        for n in 0..2000u32 {
+            pathological.extend_from_slice(&[b'a'; 200]);
+            pathological.extend_from_slice(&n.to_le_bytes());
+        }
+        let compressed = deflate_bytes_conf(&pathological, hardened);
+        assert!(decompress_to_end(&compressed) == pathological);
+
+        // The same seed should give the same output.
+        let mut compressor = write::DeflateEncoder::new(Vec::new(), hardened);
+        chunked_write(&mut compressor, &data, 5000);
+        assert!(compressor.finish().unwrap() == deflate_bytes_conf(&data, hardened));
+    }
+
+    #[test]
+    fn chain_decay() {
+        use crate::test_utils::get_random_data;
+        // Text with a large incompressible part in the middle.
+        let text = get_test_data();
+        let mut data = text[..text.len() / 2].to_vec();
+        data.extend_from_slice(&get_random_data(200000));
+        data.extend_from_slice(&text[text.len() / 2..]);
+
+        for &options in &[CO::fast(), CO::default(), CO::high()] {
+            let decaying = CompressionOptions {
+                chain_decay: ChainDecay::adaptive(),
+                ..options
+            };
+            let compressed = deflate_bytes_conf(&data, decaying);
+            assert!(decompress_to_end(&compressed) == data);
+            // Fewer hash checks in the random part shouldn't cost much.
+            let full = deflate_bytes_conf(&data, options);
+            assert!(compressed.len() <= full.len() + full.len() / 100);
+        }
+    }
+
+    #[test]
+    fn nice_match() {
+        let data = get_test_data();
+        let full = deflate_bytes_conf(&data, CO::high());
+        for &nice_match in &[0, 3, 16, 128, u16::MAX] {
+            let options = CompressionOptions {
+                nice_match,
+                ..CompressionOptions::high()
+            };
+            let compressed = deflate_bytes_conf(&data, options);
+            assert!(decompress_to_end(&compressed) == data);
+            if nice_match == 0 || nice_match >= 258 {
+                assert!(compressed == full);
+            }
+        }
+    }
+
+    #[test]
+    fn compress_to_buf_reuse() {
+        let data = get_test_data();
+        let mut out = b"previous".to_vec();
+        compress_to_buf(&data, Compression::Default, &mut out);
+        assert!(out == deflate_bytes(&data));
+
+        let capacity = out.capacity();
+        compress_to_buf(&data[..5000], Compression::Fast, &mut out);
+        assert!(out == deflate_bytes_conf(&data[..5000], Compression::Fast));
+        assert_eq!(out.capacity(), capacity);
+
+        let prefix = b"prefix".to_vec();
+        let mut appended = prefix.clone();
+        compress_append_to_buf(&data[..5000], Compression::Fast, &mut appended);
+        assert_eq!(appended[..prefix.len()], prefix[..]);
+        assert!(appended[prefix.len()..] == out[..]);
+    }
+
+    #[test]
+    fn prefetch() {
+        let data = get_test_data();
+        for &options in &[CO::fast(), CO::default(), CO::high()] {
+            let prefetched = CompressionOptions {
+                prefetch: true,
+                ..options
+            };
+            assert!(deflate_bytes_conf(&data, prefetched) == deflate_bytes_conf(&data, options));
+        }
+    }
+
+    #[test]
+    fn auto_level() {
+        use crate::test_utils::get_random_data;
+        let numbers: Vec<u8> = (0..50000u32).flat_map(|n| n.to_le_bytes()).collect();
+        let inputs = [
+            (get_test_data(), DataKind::Text),
+            (numbers, DataKind::Binary),
+            (get_random_data(50000), DataKind::Compressed),
+        ];
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), Compression::Auto);
+        assert_eq!(encoder.data_kind(), None);
+        for (data, kind) in &inputs {
+            let expected = deflate_bytes_conf(data, kind.options());
+            assert!(deflate_bytes_conf(data, Compression::Auto) == expected);
+
+            // The kind is detected again after resetting.
+            encoder.write_all(data).unwrap();
+            assert_eq!(encoder.data_kind(), Some(*kind));
+            assert!(encoder.reset(Vec::new()).unwrap() == expected);
+            assert_eq!(encoder.data_kind(), None);
+        }
+
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(&inputs[0].0).unwrap();
+        assert_eq!(encoder.data_kind(), None);
+    }
+
+    /// Count the matches longer than their distance, which copy data they produce themselves,
+    /// in each block of a raw DEFLATE stream.
+    fn count_overlapping_matches(compressed: &[u8]) -> Vec<usize> {
+        let mut counts = Vec::new();
+        for element in inflate::dump(compressed).unwrap().elements() {
+            match element.kind {
+                inflate::ElementKind::BlockHeader { .. } => counts.push(0),
+                inflate::ElementKind::Match { length, distance } if length > distance => {
+                    *counts.last_mut().unwrap() += 1
+                }
+                _ => (),
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn overlapping_matches() {
+        for period in 1..4 {
+            // Starting right at the start of the stream.
+            let data: Vec<u8> = b"abc"[..period]
+                .iter()
+                .cycle()
+                .take(1000)
+                .cloned()
+                .collect();
+            let tiny_blocks = CompressionOptions {
+                block_limits: BlockLimits {
+                    max_symbols: 2,
+                    ..BlockLimits::DEFAULT
+                },
+                ..CO::default()
+            };
+            for &options in &[
+                CO::fast(),
+                CO::default(),
+                CO::high(),
+                CO::rle(),
+                tiny_blocks,
+            ] {
+                let compressed = deflate_bytes_conf(&data, options);
+                assert!(decompress_to_end(&compressed) == data);
+                assert!(inflate::inflate(&compressed).unwrap() == data);
+                let counts = count_overlapping_matches(&compressed);
+                if options == CO::rle() && period > 1 {
+                    // Only matches at distance 1 are used.
+                    assert_eq!(counts, [0]);
+                } else if options == tiny_blocks {
+                    // The matches continue the pattern from the previous blocks.
+                    assert!(counts.len() > 2);
+                    assert!(counts[1..].iter().all(|&count| count > 0), "{:?}", counts);
+                } else {
+                    assert!(counts[0] > 0);
+                }
+            }
+        }
+
+        // Across a sync flush.
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), CO::default());
+        encoder.write_all(b"xyzabc").unwrap();
+        encoder.flush().unwrap();
+        encoder.write_all(&b"abc".repeat(100)).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == [&b"xyz"[..], &b"abc".repeat(101)].concat());
+        // The 300 bytes after the flush are two matches, the first referring back before it.
+        assert_eq!(count_overlapping_matches(&compressed).last(), Some(&2));
+
+        // Into the preset dictionary at the start of the stream.
+        let data = b"abc".repeat(100);
+        let mut encoder = write::ZlibEncoder::with_dictionary(Vec::new(), CO::default(), b"abc");
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(inflate::inflate_zlib_with_dictionary(&compressed, b"abc").unwrap() == data);
+        // Header, dictionary id, a single match and the checksum.
+        assert!(compressed.len() < 20);
+    }
+
+    #[test]
+    fn verify() {
+        use crate::test_utils::get_random_data;
+        let mut data = get_test_data();
+        // Add some data that ends up in stored blocks.
+        data.extend(get_random_data(100_000));
+        data.extend(get_test_data());
+        let fixed = CompressionOptions {
+            special: SpecialOptions::ForceFixed,
+            ..CO::default()
+        };
+        for &options in &[CO::fast(), CO::default(), CO::high(), CO::rle(), fixed] {
+            let verified = CompressionOptions {
+                verify: true,
+                ..options
+            };
+            assert!(deflate_bytes_conf(&data, verified) == deflate_bytes_conf(&data, options));
+        }
+
+        // Streaming with a dictionary, flushes and resetting the context.
+        let compress = |verify| {
+            let options = CompressionOptions {
+                verify,
+                ..CO::default()
+            };
+            let dictionary = &data[..1000];
+            let mut encoder = write::ZlibEncoder::with_dictionary(Vec::new(), options, dictionary);
+            for (n, chunk) in data.chunks(30_000).enumerate() {
+                encoder.write_all(chunk).unwrap();
+                encoder.end_message(n % 2 == 0).unwrap();
+            }
+            encoder.finish().unwrap()
+        };
+        assert!(compress(true) == compress(false));
+    }
+
+    /// A writer whose output can be read while an encoder is writing to it.
+    #[derive(Clone, Default)]
+    struct SharedVec(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedVec {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Compress the same message three times, returning the output for each of them.
+    fn compress_messages(message: &[u8], retain_context: bool) -> Vec<Vec<u8>> {
+        let output = SharedVec::default();
+        let mut encoder = write::DeflateEncoder::new(output.clone(), CO::default());
+        (0..3)
+            .map(|_| {
+                encoder.write_all(message).unwrap();
+                encoder.end_message(retain_context).unwrap();
+                std::mem::take(&mut *output.0.borrow_mut())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn end_message() {
+        use crate::test_utils::decompress_sync_flushed;
+        let data = get_test_data();
+        let message = &data[..5000];
+
+        // Without the context, each message is compressed the same way, and can be
+        // decompressed on its own.
+        let independent = compress_messages(message, false);
+        assert!(independent[0] == independent[1] && independent[1] == independent[2]);
+        assert!(decompress_sync_flushed(&independent[2]) == message);
+        assert!(decompress_sync_flushed(&independent.concat()) == message.repeat(3));
+
+        // With it, the later messages can refer back to the first one.
+        let retained = compress_messages(message, true);
+        assert!(retained[0] == independent[0]);
+        assert!(retained[1].len() < independent[1].len() / 10);
+        assert!(decompress_sync_flushed(&retained.concat()) == message.repeat(3));
+
+        let mut encoder = write::ZlibEncoder::new(Vec::new(), CO::default());
+        for &retain_context in &[false, true, false] {
+            encoder.write_all(message).unwrap();
+            encoder.end_message(retain_context).unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress_zlib(&compressed) == message.repeat(3));
+    }
+
+    #[test]
+    fn flush_coalescing() {
+        use crate::test_utils::decompress_sync_flushed;
+        use std::time::Duration;
+        let data = get_test_data();
+        let messages: Vec<&[u8]> = data[..2000].chunks(20).collect();
+
+        let output = SharedVec::default();
+        let mut encoder = write::DeflateEncoder::new(output.clone(), CO::default());
+        encoder.set_flush_coalescing(Some(FlushCoalescing {
+            min_bytes: 1000,
+            max_delay: Duration::from_secs(3600),
+        }));
+        for message in &messages[..50] {
+            encoder.write_all(message).unwrap();
+            encoder.flush().unwrap();
+        }
+        // Only the flush after the first 1000 bytes is done, the others are skipped.
+        assert!(decompress_sync_flushed(&output.0.borrow()) == data[..1000]);
+        for message in &messages[50..60] {
+            encoder.write_all(message).unwrap();
+            encoder.flush().unwrap();
+        }
+        encoder.flush_now().unwrap();
+        assert!(decompress_sync_flushed(&output.0.borrow()) == data[..1200]);
+
+        // Without a delay, every flush is done as usual.
+        let mut coalesced = write::DeflateEncoder::new(Vec::new(), CO::default());
+        coalesced.set_flush_coalescing(Some(FlushCoalescing {
+            min_bytes: 1000,
+            max_delay: Duration::ZERO,
+        }));
+        let mut plain = write::DeflateEncoder::new(Vec::new(), CO::default());
+        for message in &messages {
+            for encoder in [&mut coalesced, &mut plain].iter_mut() {
+                encoder.write_all(message).unwrap();
+                encoder.flush().unwrap();
+            }
+        }
+        assert!(coalesced.finish().unwrap() == plain.finish().unwrap());
+    }
+
     fn chunk_test(chunk_size: usize, level: CompressionOptions) {
         let mut compressed = Vec::with_capacity(32000);
         let data = get_test_data();
@@ -490,4 +1160,38 @@ mod test {
         roundtrip_zlib(two, CO::fast());
         roundtrip_zlib(two, CO::default());
     }
+
+    #[test]
+    fn parts() {
+        let data = get_test_data();
+        for options in [CO::fast(), CO::default(), CO::high(), CO::rle()] {
+            let split = [0, 1, 100, 3000, 3000, 40000, 70000, data.len()];
+            let parts: Vec<&[u8]> = split.windows(2).map(|w| &data[w[0]..w[1]]).collect();
+            assert_eq!(
+                deflate_bytes_parts_conf(parts.iter().cloned(), options),
+                deflate_bytes_conf(&data, options)
+            );
+            let compressed = deflate_bytes_zlib_parts_conf(parts.iter().cloned(), options);
+            assert_eq!(compressed, deflate_bytes_zlib_conf(&data, options));
+            assert_eq!(decompress_zlib(&compressed), data);
+            #[cfg(feature = "gzip")]
+            {
+                let compressed =
+                    deflate_bytes_gzip_parts_conf(parts.iter().cloned(), options, GzBuilder::new());
+                assert_eq!(decompress_gzip(&compressed).1, data);
+            }
+        }
+
+        // A match spanning the boundary between the parts.
+        let parts: [&[u8]; 2] = [b"abcdefghij", b"klmnop abcdefghijklmnop"];
+        let compressed = deflate_bytes_parts_conf(parts.iter().cloned(), CO::default());
+        assert_eq!(compressed, deflate_bytes(&parts.concat()));
+        assert_eq!(decompress_to_end(&compressed), parts.concat());
+        let dump = inflate::dump(&compressed).unwrap();
+        assert!(dump.elements().iter().any(|e| e.kind
+            == inflate::ElementKind::Match {
+                length: 16,
+                distance: 17,
+            }));
+    }
 }
@@ -6,14 +6,17 @@ use std::iter::{self, Iterator};
 use std::ops::{Range, RangeFrom};
 use std::slice::Iter;
 
-use crate::chained_hash_table::{update_hash, ChainedHashTable};
+use crate::allocator::BufferAllocator;
+use crate::chained_hash_table::ChainedHashTable;
 use crate::compress::Flush;
+use crate::compression_options::{ChainDecay, MatchHardening};
 #[cfg(test)]
 use crate::compression_options::{HIGH_LAZY_IF_LESS_THAN, HIGH_MAX_HASH_CHECKS};
+use crate::huffman_table::{fixed_literals_cost, fixed_match_cost};
 use crate::input_buffer::InputBuffer;
 #[cfg(test)]
 use crate::lzvalue::{LZType, LZValue};
-use crate::matching::longest_match;
+use crate::matching::{longest_match, repeat_match, run_match, NO_WORK_LIMIT};
 use crate::output_writer::{BufferStatus, DynamicWriter};
 use crate::rle::process_chunk_greedy_rle;
 
@@ -59,10 +62,18 @@ pub struct LZ77State {
     current_block_input_bytes: u64,
     /// The maximum number of hash entries to search.
     max_hash_checks: u16,
+    /// The number of hash entries to search at the moment, see `ChainDecay`.
+    chain_budget: ChainBudget,
     /// Only lazy match if we have a match length less than this.
     lazy_if_less_than: u16,
     /// Whether to use greedy or lazy parsing
     matching_type: MatchingType,
+    /// The maximum amount of work to do when searching for a match at a single position.
+    max_match_work: usize,
+    /// Stop searching for a better match once we have found one at least this long.
+    nice_match: usize,
+    /// Whether to prefetch the next hash chain entry when searching for matches.
+    prefetch: bool,
     /// Keep track of the previous match and byte in case the buffer is full when lazy matching.
     match_state: ChunkState,
     /// Keep track of how many bytes in the lookahead that was part of a match, but has not been
@@ -74,21 +85,26 @@ pub struct LZ77State {
 }
 
 impl LZ77State {
-    /// Creates a new LZ77 state
+    /// Creates a new LZ77 state, allocating the hash table using `alloc`.
     pub fn new(
         max_hash_checks: u16,
         lazy_if_less_than: u16,
         matching_type: MatchingType,
+        alloc: &BufferAllocator,
     ) -> LZ77State {
         LZ77State {
-            hash_table: ChainedHashTable::new(),
+            hash_table: ChainedHashTable::new_in(alloc),
             is_first_window: true,
             is_last_block: false,
             overlap: 0,
             current_block_input_bytes: 0,
             max_hash_checks,
+            chain_budget: ChainBudget::new(max_hash_checks, ChainDecay::DEFAULT),
             lazy_if_less_than,
             matching_type,
+            max_match_work: NO_WORK_LIMIT,
+            nice_match: MAX_MATCH,
+            prefetch: false,
             match_state: ChunkState::new(),
             bytes_to_hash: 0,
             was_synced: false,
@@ -102,8 +118,111 @@ impl LZ77State {
         self.is_last_block = false;
         self.overlap = 0;
         self.current_block_input_bytes = 0;
+        self.chain_budget.restore();
         self.match_state = ChunkState::new();
-        self.bytes_to_hash = 0
+        self.bytes_to_hash = 0;
+        self.was_synced = false;
+    }
+
+    /// Use the provided data as a preset dictionary, allowing matches to refer to it without it
+    /// being output.
+    ///
+    /// This has to be done before any data is compressed. Only the last window size bytes of the
+    /// dictionary are used, and dictionaries shorter than the minimum match length are ignored.
+    pub fn set_dictionary(&mut self, buffer: &mut InputBuffer, dictionary: &[u8]) {
+        debug_assert!(self.is_first_window && buffer.current_end() == 0);
+        let dictionary = &dictionary[dictionary.len().saturating_sub(DEFAULT_WINDOW_SIZE)..];
+        if dictionary.len() < MIN_MATCH {
+            return;
+        }
+
+        buffer.add_data(dictionary);
+        self.hash_table
+            .add_initial_hash_values(dictionary[0], dictionary[1]);
+        for (n, &b) in dictionary[2..].iter().enumerate() {
+            self.hash_table.add_hash_value(n, b);
+        }
+        // Start compressing after the dictionary. The two last bytes of the dictionary can't be
+        // hashed until we have more input, which is handled the same way as after a sync flush.
+        self.overlap = dictionary.len();
+        self.was_synced = true;
+    }
+
+    /// Add the provided data to the end of the history that matches can refer to, keeping only
+    /// the last window size bytes of it in total.
+    ///
+    /// This can be called several times with consecutive pieces of the history, as long as no
+    /// data has been compressed yet. The hash chains are updated for the new data only, unless
+    /// the history outgrows the window, in which case they are rebuilt for the last window.
+    pub fn add_history(&mut self, buffer: &mut InputBuffer, data: &[u8]) {
+        let history_len = self.overlap;
+        debug_assert!(self.is_first_window && buffer.current_end() == history_len);
+        if history_len == 0 {
+            self.set_dictionary(buffer, data);
+        } else if history_len + data.len() <= DEFAULT_WINDOW_SIZE {
+            buffer.add_data(data);
+            // The hash values of the last two positions of the history so far could not be
+            // added until now.
+            for (n, &b) in data.iter().enumerate() {
+                self.hash_table.add_hash_value(history_len - 2 + n, b);
+            }
+            self.overlap += data.len();
+        } else {
+            let keep = DEFAULT_WINDOW_SIZE.saturating_sub(data.len());
+            let mut history = buffer.get_buffer()[history_len - keep..history_len].to_vec();
+            history.extend_from_slice(&data[data.len() - (DEFAULT_WINDOW_SIZE - keep)..]);
+            buffer.clear();
+            self.reset();
+            self.set_dictionary(buffer, &history);
+        }
+    }
+
+    /// Set the hash seed and work limit used when searching for matches.
+    ///
+    /// This has to be done before any data is added.
+    pub fn set_match_hardening(&mut self, hardening: MatchHardening) {
+        debug_assert!(self.is_first_window);
+        self.hash_table.set_seed(hardening.hash_seed);
+        self.max_match_work = match hardening.max_work_per_position {
+            0 => NO_WORK_LIMIT,
+            n => n as usize,
+        };
+    }
+
+    /// Set the number of hash checks and the type of matching to use.
+    ///
+    /// This has to be done before any data is added.
+    pub fn set_matching(
+        &mut self,
+        max_hash_checks: u16,
+        lazy_if_less_than: u16,
+        matching_type: MatchingType,
+    ) {
+        debug_assert!(self.is_first_window);
+        self.max_hash_checks = max_hash_checks;
+        self.chain_budget = ChainBudget::new(max_hash_checks, self.chain_budget.settings);
+        self.lazy_if_less_than = lazy_if_less_than;
+        self.matching_type = matching_type;
+    }
+
+    /// Set how to reduce the number of hash checks while no matches are found.
+    pub fn set_chain_decay(&mut self, settings: ChainDecay) {
+        self.chain_budget = ChainBudget::new(self.max_hash_checks, settings);
+    }
+
+    /// Set the match length at which to stop searching for longer matches.
+    ///
+    /// `0` means only stopping at the maximum match length.
+    pub fn set_nice_match(&mut self, nice_match: u16) {
+        self.nice_match = match nice_match {
+            0 => MAX_MATCH,
+            n => cmp::min(usize::from(n), MAX_MATCH),
+        };
+    }
+
+    /// Set whether to issue prefetch hints when searching for matches.
+    pub fn set_prefetch(&mut self, prefetch: bool) {
+        self.prefetch = prefetch;
     }
 
     pub fn set_last(&mut self) {
@@ -120,9 +239,11 @@ impl LZ77State {
         self.current_block_input_bytes
     }
 
-    /// Sets the number of input bytes for the current block to 0.
+    /// Sets the number of input bytes for the current block to 0, and goes back to the full
+    /// number of hash checks for the next block.
     pub fn reset_input_bytes(&mut self) {
         self.current_block_input_bytes = 0;
+        self.chain_budget.restore();
     }
 
     /// Is there a buffered byte that has not been output yet?
@@ -144,7 +265,7 @@ impl LZ77State {
 
 const DEFAULT_WINDOW_SIZE: usize = 32768;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 /// Status after calling `process_chunk`.
 pub enum ProcessStatus {
     /// All the input data was processed.
@@ -155,6 +276,60 @@ pub enum ProcessStatus {
     BufferFull(usize),
 }
 
+/// Keeps track of the number of hash checks to use, reducing it while searches don't find any
+/// matches, as described in `ChainDecay`.
+#[derive(Debug)]
+pub struct ChainBudget {
+    settings: ChainDecay,
+    /// The configured number of hash checks.
+    max_checks: u16,
+    /// The number of hash checks to use for the next search.
+    checks: u16,
+    /// The number of searches without a match since the last match or reduction.
+    misses: u16,
+}
+
+impl ChainBudget {
+    pub fn new(max_checks: u16, settings: ChainDecay) -> ChainBudget {
+        ChainBudget {
+            settings,
+            max_checks,
+            checks: max_checks,
+            misses: 0,
+        }
+    }
+
+    /// The number of hash checks to use for the next search.
+    #[inline]
+    pub fn checks(&self) -> u16 {
+        self.checks
+    }
+
+    /// Note the outcome of a search.
+    #[inline]
+    pub fn record(&mut self, found_match: bool) {
+        if self.settings.miss_window == 0 {
+            return;
+        }
+        if found_match {
+            self.restore();
+        } else {
+            self.misses += 1;
+            if self.misses >= self.settings.miss_window {
+                self.misses = 0;
+                let min_checks = cmp::min(cmp::max(self.settings.min_checks, 1), self.max_checks);
+                self.checks = cmp::max(self.checks / 2, min_checks);
+            }
+        }
+    }
+
+    /// Go back to the full number of hash checks.
+    pub fn restore(&mut self) {
+        self.checks = self.max_checks;
+        self.misses = 0;
+    }
+}
+
 #[derive(Debug)]
 /// A struct to keep track of status between calls of `process_chunk_lazy`
 ///
@@ -192,10 +367,14 @@ pub fn buffer_full(position: usize) -> ProcessStatus {
 fn process_chunk(
     data: &[u8],
     iterated_data: &Range<usize>,
-    mut match_state: &mut ChunkState,
+    match_state: &mut ChunkState,
     hash_table: &mut ChainedHashTable,
     writer: &mut DynamicWriter,
     max_hash_checks: u16,
+    budget: &mut ChainBudget,
+    max_match_work: usize,
+    nice_match: usize,
+    prefetch: bool,
     lazy_if_less_than: usize,
     matching_type: MatchingType,
 ) -> (usize, ProcessStatus) {
@@ -209,18 +388,28 @@ fn process_chunk(
         false
     };
     match matching_type {
-        MatchingType::Greedy => {
-            process_chunk_greedy(data, iterated_data, hash_table, writer, max_hash_checks)
-        }
+        MatchingType::Greedy => process_chunk_greedy(
+            data,
+            iterated_data,
+            hash_table,
+            writer,
+            budget,
+            max_match_work,
+            nice_match,
+            prefetch,
+        ),
         MatchingType::Lazy => {
             if max_hash_checks > 0 || avoid_rle {
                 process_chunk_lazy(
                     data,
                     iterated_data,
-                    &mut match_state,
+                    match_state,
                     hash_table,
                     writer,
-                    max_hash_checks,
+                    budget,
+                    max_match_work,
+                    nice_match,
+                    prefetch,
                     lazy_if_less_than,
                 )
             } else {
@@ -247,7 +436,7 @@ fn add_to_hash_table(
     // checksum
     for (ipos, _) in taker {
         if let Some(&i_hash_byte) = hash_taker.next() {
-            hash = update_hash(hash, i_hash_byte);
+            hash = hash_table.update_hash(hash, i_hash_byte);
             hash_table.add_with_hash(ipos, hash);
         }
     }
@@ -255,6 +444,38 @@ fn add_to_hash_table(
     hash_table.set_hash(hash);
 }
 
+/// Advance the iterators past `bytes_to_skip` bytes like `add_to_hash_table`, but only update the
+/// hash, without adding the positions to the hash chains.
+///
+/// This is used inside long runs of the same byte, where all the positions have the same hash,
+/// so adding them would only make the hash chain longer and slower to search.
+fn skip_in_hash_table(
+    bytes_to_skip: usize,
+    insert_it: &mut iter::Zip<RangeFrom<usize>, Iter<u8>>,
+    hash_it: &mut Iter<u8>,
+    hash_table: &mut ChainedHashTable,
+) {
+    if bytes_to_skip == 0 {
+        return;
+    }
+    insert_it.nth(bytes_to_skip - 1);
+    let mut hash = hash_table.current_hash();
+    for &hash_byte in hash_it.by_ref().take(bytes_to_skip) {
+        hash = hash_table.update_hash(hash, hash_byte);
+    }
+    hash_table.set_hash(hash);
+}
+
+/// Check whether a match starting at `start` is part of a run of the same byte that goes on
+/// past the end of it, so the positions it covers don't have to be added to the hash chains.
+///
+/// The end of each run is still added, so the data following a run can be matched against the end
+/// of an earlier one.
+#[inline]
+fn run_continues(data: &[u8], start: usize, match_len: usize, match_dist: usize) -> bool {
+    match_dist == 1 && match_len == MAX_MATCH && data.get(start + match_len) == Some(&data[start])
+}
+
 /// Write the specified literal `byte` to the writer `w`, and return
 /// `ProcessStatus::BufferFull($pos)` if the buffer is full after writing.
 ///
@@ -270,12 +491,18 @@ macro_rules! write_literal {
     };
 }
 
-/// If the match is only 3 bytes long and the distance is more than 8 * 1024, it's likely to take
-/// up more space than it would save.
+/// Check whether a match starting at `position` is likely to take up less space than the
+/// literals it replaces.
+///
+/// The code lengths of the huffman codes aren't known until the block is done, so the lengths of
+/// the fixed codes are used as a static estimate of the cost of each symbol instead. Short
+/// matches far back need a lot of extra bits for the distance, so they may not be worth it.
 #[inline]
-fn match_too_far(match_len: usize, match_dist: usize) -> bool {
-    const TOO_FAR: usize = 8 * 1024;
-    match_len == MIN_MATCH && match_dist > TOO_FAR
+fn match_worth_it(data: &[u8], position: usize, match_len: usize, match_dist: usize) -> bool {
+    // With the fixed codes, matches longer than 3 bytes always take up less space.
+    match_len > MIN_MATCH
+        || fixed_match_cost(match_len as u16, match_dist as u16)
+            < fixed_literals_cost(&data[position..position + match_len])
 }
 
 ///Create the iterators used when processing through a chunk of data.
@@ -298,18 +525,22 @@ fn create_iterators<'a>(
         } else {
             data.len()
         };
-        (&data[hash_start..]).iter()
+        data[hash_start..].iter()
     };
     (end, insert_it, hash_it)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_chunk_lazy(
     data: &[u8],
     iterated_data: &Range<usize>,
     state: &mut ChunkState,
-    mut hash_table: &mut ChainedHashTable,
+    hash_table: &mut ChainedHashTable,
     writer: &mut DynamicWriter,
-    max_hash_checks: u16,
+    budget: &mut ChainBudget,
+    max_match_work: usize,
+    nice_match: usize,
+    prefetch: bool,
     lazy_if_less_than: usize,
 ) -> (usize, ProcessStatus) {
     let (end, mut insert_it, mut hash_it) = create_iterators(data, iterated_data);
@@ -333,6 +564,9 @@ fn process_chunk_lazy(
     // If we had a good match, carry this over from the previous call.
     let mut ignore_next = prev_length as usize >= lazy_if_less_than;
 
+    // The distance of the last match that was output, if it was not followed by a literal.
+    let mut repeat_distance = 0;
+
     // This is to output the correct byte in case there is one pending to be output
     // from the previous call.
     state.prev_byte = state.cur_byte;
@@ -346,13 +580,32 @@ fn process_chunk_lazy(
             // Only lazy match if we have a match shorter than a set value
             // TODO: This should be cleaned up a bit
             if !ignore_next {
-                let (mut match_len, match_dist) = {
+                // If the previous match can be continued at the same distance with the longest
+                // possible length, there is no point in searching the hash chains.
+                let repeat_len = if prev_length == NO_LENGTH {
+                    repeat_match(data, position, repeat_distance)
+                } else {
+                    0
+                };
+
+                // Matching is disabled entirely when there are no hash checks (huffman only).
+                let run_len = if repeat_len > 0 || budget.checks() == 0 {
+                    0
+                } else {
+                    run_match(data, position)
+                };
+
+                let (mut match_len, match_dist) = if repeat_len > 0 {
+                    (repeat_len, repeat_distance)
+                } else if run_len > 0 {
+                    (run_len, 1)
+                } else {
                     // If there already was a decent match at the previous byte
                     // and we are lazy matching, do less match checks in this step.
                     let max_hash_checks = if prev_length >= 32 {
-                        max_hash_checks >> 2
+                        budget.checks() >> 2
                     } else {
-                        max_hash_checks
+                        budget.checks()
                     };
 
                     // Check if we can find a better match here than the one we had at
@@ -363,14 +616,18 @@ fn process_chunk_lazy(
                         position,
                         prev_length as usize,
                         max_hash_checks,
+                        max_match_work,
+                        nice_match,
+                        prefetch,
                     )
                 };
 
-                // If the match is only 3 bytes long and very far back, it's probably not worth
-                // outputting.
-                if match_too_far(match_len, match_dist) {
+                // If the match is short and very far back, it's probably not worth outputting.
+                if match_len >= MIN_MATCH && !match_worth_it(data, position, match_len, match_dist)
+                {
                     match_len = NO_LENGTH as usize;
                 };
+                budget.record(match_len >= MIN_MATCH || prev_length >= MIN_MATCH as u16);
 
                 if match_len >= lazy_if_less_than {
                     // We found a decent match, so we won't check for a better one at the next byte.
@@ -390,20 +647,34 @@ fn process_chunk_lazy(
                 // The previous match was better so we add it.
                 // Casting note: length and distance is already bounded by the longest match
                 // function. Usize is just used for convenience.
-                let b_status =
-                    writer.write_length_distance(prev_length as u16, prev_distance as u16);
+                let b_status = writer.write_length_distance(prev_length, prev_distance);
+                repeat_distance = prev_distance as usize;
 
                 // We add the bytes to the hash table and checksum.
                 // Since we've already added two of them, we need to add two less than
                 // the length.
                 let bytes_to_add = prev_length - 2;
 
-                add_to_hash_table(
-                    bytes_to_add as usize,
-                    &mut insert_it,
-                    &mut hash_it,
-                    &mut hash_table,
-                );
+                if run_continues(
+                    data,
+                    position - 1,
+                    prev_length as usize,
+                    prev_distance as usize,
+                ) {
+                    skip_in_hash_table(
+                        bytes_to_add as usize,
+                        &mut insert_it,
+                        &mut hash_it,
+                        hash_table,
+                    );
+                } else {
+                    add_to_hash_table(
+                        bytes_to_add as usize,
+                        &mut insert_it,
+                        &mut hash_it,
+                        hash_table,
+                    );
+                }
 
                 // If the match is longer than the current window, we have note how many
                 // bytes we overlap, since we don't need to do any matching on these bytes
@@ -432,6 +703,7 @@ fn process_chunk_lazy(
                 // We found a better match (or there was no previous match)
                 // so output the previous byte.
                 // BETTER OR NO MATCH
+                repeat_distance = 0;
                 write_literal!(writer, state.prev_byte, position + 1);
             } else {
                 state.add = true
@@ -443,8 +715,7 @@ fn process_chunk_lazy(
         } else {
             // If there is a match at this point, it will not have been added, so we need to add it.
             if prev_length >= MIN_MATCH as u16 {
-                let b_status =
-                    writer.write_length_distance(prev_length as u16, prev_distance as u16);
+                let b_status = writer.write_length_distance(prev_length, prev_distance);
 
                 state.current_length = 0;
                 state.current_distance = 0;
@@ -481,12 +752,16 @@ fn process_chunk_lazy(
     (overlap, ProcessStatus::Ok)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_chunk_greedy(
     data: &[u8],
     iterated_data: &Range<usize>,
-    mut hash_table: &mut ChainedHashTable,
+    hash_table: &mut ChainedHashTable,
     writer: &mut DynamicWriter,
-    max_hash_checks: u16,
+    budget: &mut ChainBudget,
+    max_match_work: usize,
+    nice_match: usize,
+    prefetch: bool,
 ) -> (usize, ProcessStatus) {
     let (end, mut insert_it, mut hash_it) = create_iterators(data, iterated_data);
 
@@ -496,25 +771,57 @@ fn process_chunk_greedy(
     // the lookahead window.
     let mut overlap = 0;
 
+    // The distance of the last match that was output, if it was not followed by a literal.
+    let mut repeat_distance = 0;
+
     // Iterate through the slice, adding literals or length/distance pairs.
     while let Some((position, &b)) = insert_it.next() {
         if let Some(&hash_byte) = hash_it.next() {
             hash_table.add_hash_value(position, hash_byte);
 
-            // TODO: This should be cleaned up a bit.
-            let (match_len, match_dist) =
-                { longest_match(data, hash_table, position, NO_LENGTH, max_hash_checks) };
+            // If the previous match can be continued at the same distance with the longest
+            // possible length, there is no point in searching the hash chains.
+            let repeat_len = repeat_match(data, position, repeat_distance);
+            let run_len = if repeat_len > 0 || budget.checks() == 0 {
+                0
+            } else {
+                run_match(data, position)
+            };
+            let (match_len, match_dist) = if repeat_len > 0 {
+                (repeat_len, repeat_distance)
+            } else if run_len > 0 {
+                (run_len, 1)
+            } else {
+                longest_match(
+                    data,
+                    hash_table,
+                    position,
+                    NO_LENGTH,
+                    budget.checks(),
+                    max_match_work,
+                    nice_match,
+                    prefetch,
+                )
+            };
 
-            if match_len >= MIN_MATCH as usize && !match_too_far(match_len, match_dist) {
+            let found_match =
+                match_len >= MIN_MATCH && match_worth_it(data, position, match_len, match_dist);
+            budget.record(found_match);
+            if found_match {
                 // Casting note: length and distance is already bounded by the longest match
                 // function. Usize is just used for convenience.
                 let b_status = writer.write_length_distance(match_len as u16, match_dist as u16);
+                repeat_distance = match_dist;
 
                 // We add the bytes to the hash table and checksum.
                 // Since we've already added one of them, we need to add one less than
                 // the length.
                 let bytes_to_add = match_len - 1;
-                add_to_hash_table(bytes_to_add, &mut insert_it, &mut hash_it, &mut hash_table);
+                if run_continues(data, position, match_len, match_dist) {
+                    skip_in_hash_table(bytes_to_add, &mut insert_it, &mut hash_it, hash_table);
+                } else {
+                    add_to_hash_table(bytes_to_add, &mut insert_it, &mut hash_it, hash_table);
+                }
 
                 // If the match is longer than the current window, we have note how many
                 // bytes we overlap, since we don't need to do any matching on these bytes
@@ -530,6 +837,7 @@ fn process_chunk_greedy(
                 }
             } else {
                 // NO MATCH
+                repeat_distance = 0;
                 write_literal!(writer, b, position + 1);
             }
         } else {
@@ -558,10 +866,9 @@ pub fn lz77_compress_block_finish(
     data: &[u8],
     state: &mut LZ77State,
     buffer: &mut InputBuffer,
-    mut writer: &mut DynamicWriter,
+    writer: &mut DynamicWriter,
 ) -> (usize, LZ77Status) {
-    let (consumed, status, _) =
-        lz77_compress_block(data, state, buffer, &mut writer, Flush::Finish);
+    let (consumed, status, _) = lz77_compress_block(data, state, buffer, writer, Flush::Finish);
     (consumed, status)
 }
 
@@ -578,7 +885,7 @@ pub fn lz77_compress_block(
     data: &[u8],
     state: &mut LZ77State,
     buffer: &mut InputBuffer,
-    mut writer: &mut DynamicWriter,
+    writer: &mut DynamicWriter,
     flush: Flush,
 ) -> (usize, LZ77Status, usize) {
     // Currently we only support the maximum window size
@@ -645,8 +952,12 @@ pub fn lz77_compress_block(
                     &(start..first_chunk_end),
                     &mut state.match_state,
                     &mut state.hash_table,
-                    &mut writer,
+                    writer,
                     state.max_hash_checks,
+                    &mut state.chain_budget,
+                    state.max_match_work,
+                    state.nice_match,
+                    state.prefetch,
                     state.lazy_if_less_than as usize,
                     state.matching_type,
                 );
@@ -721,8 +1032,12 @@ pub fn lz77_compress_block(
                 &(start..end),
                 &mut state.match_state,
                 &mut state.hash_table,
-                &mut writer,
+                writer,
                 state.max_hash_checks,
+                &mut state.chain_budget,
+                state.max_match_work,
+                state.nice_match,
+                state.prefetch,
                 state.lazy_if_less_than as usize,
                 state.matching_type,
             );
@@ -886,7 +1201,12 @@ impl TestStruct {
         matching_type: MatchingType,
     ) -> TestStruct {
         TestStruct {
-            state: LZ77State::new(max_hash_checks, lazy_if_less_than, matching_type),
+            state: LZ77State::new(
+                max_hash_checks,
+                lazy_if_less_than,
+                matching_type,
+                &BufferAllocator::default(),
+            ),
             buffer: InputBuffer::empty(),
             writer: DynamicWriter::new(),
         }
@@ -978,6 +1298,20 @@ mod test {
     }
 
     /// Test that a short string from an example on SO compresses correctly
+    #[test]
+    fn match_cost() {
+        let data = [0x10, 0x20, 0x30, 0xf0, 0xf1, 0xf2];
+        // Close matches are always worth it.
+        assert!(match_worth_it(&data, 0, 3, 100));
+        assert!(match_worth_it(&data, 0, 3, 8192));
+        // The literals with lower values have shorter codes, so a short match far back would
+        // take up as much space as the literals.
+        assert!(!match_worth_it(&data, 0, 3, 8193));
+        assert!(match_worth_it(&data, 3, 3, 8193));
+        assert!(match_worth_it(&data, 3, 3, 32768));
+        assert!(match_worth_it(&data, 0, 4, 32768));
+    }
+
     #[test]
     fn compress_short() {
         let test_bytes = String::from("Deflate late").into_bytes();
@@ -1007,7 +1341,7 @@ mod test {
             }
         }
         assert_eq!(input.len(), decompressed.len());
-        assert!(&decompressed == &input);
+        assert!(decompressed == input);
     }
 
     /// Check that lazy matching is working as intended
@@ -1026,8 +1360,117 @@ mod test {
         }
     }
 
+    /// Compress `data` in blocks of at most `max_symbols` symbols (or as many as fit in the
+    /// buffer if 0), returning the symbols of all of the blocks.
+    fn lz77_compress_blocks(
+        data: &[u8],
+        matching_type: MatchingType,
+        max_symbols: u16,
+    ) -> Vec<LZValue> {
+        use crate::compression_options::BlockLimits;
+        let mut test = Box::new(TestStruct::with_config(
+            HIGH_MAX_HASH_CHECKS,
+            HIGH_LAZY_IF_LESS_THAN,
+            matching_type,
+        ));
+        test.writer = DynamicWriter::with_limits(
+            BlockLimits {
+                max_symbols,
+                ..BlockLimits::DEFAULT
+            },
+            &BufferAllocator::default(),
+        );
+        let mut out = Vec::new();
+        let mut slice = data;
+        while !test.state.is_last_block {
+            let (written, _, _) = test.compress_block(slice, true);
+            slice = &slice[written..];
+            out.extend(test.writer.drain());
+        }
+        out
+    }
+
+    /// Check that `data` is compressed to exactly the symbols in `expected` with both lazy and
+    /// greedy matching, in blocks of at most `max_symbols` symbols.
+    fn check_symbols(data: &[u8], max_symbols: u16, expected: &[LZValue]) {
+        for &matching_type in &[MatchingType::Lazy, MatchingType::Greedy] {
+            let compressed = lz77_compress_blocks(data, matching_type, max_symbols);
+            assert!(
+                compressed == expected,
+                "{:?} matching of {:?} gave {:?}",
+                matching_type,
+                String::from_utf8_lossy(data),
+                compressed
+            );
+            assert!(decompress_lz77(&compressed) == data);
+        }
+    }
+
+    /// Check the exact symbols the parser outputs for inputs exercising the corner cases of
+    /// matching, independent of how they are encoded afterwards.
+    #[test]
+    fn synthetic_matches() {
+        // A match overlapping the data it copies.
+        check_symbols(
+            b"abcabcabcabc",
+            0,
+            &[lit(b'a'), lit(b'b'), lit(b'c'), ld(9, 3)],
+        );
+        // A match with a distance equal to its length.
+        check_symbols(
+            b"abcdabcd",
+            0,
+            &[lit(b'a'), lit(b'b'), lit(b'c'), lit(b'd'), ld(4, 4)],
+        );
+        // A run, which is a match at distance 1.
+        check_symbols(b"xaaaaaaaaaa", 0, &[lit(b'x'), lit(b'a'), ld(9, 1)]);
+        // A run longer than the maximum match length.
+        let mut run = vec![b'a'; 300];
+        run.push(b'b');
+        check_symbols(&run, 0, &[lit(b'a'), ld(258, 1), ld(41, 1), lit(b'b')]);
+        // Runs continuing across the end of a block refer back into the previous one.
+        check_symbols(
+            b"xyzaaaaaaaaaaaaaaaaaaaa",
+            4,
+            &[lit(b'x'), lit(b'y'), lit(b'z'), lit(b'a'), ld(19, 1)],
+        );
+        check_symbols(
+            b"abcabcabcabcabcabc",
+            2,
+            &[lit(b'a'), lit(b'b'), lit(b'c'), ld(15, 3)],
+        );
+    }
+
+    /// Check the symbols output for data repeating the start of the window at exactly the
+    /// maximum distance, and at one byte past it, where it can't be referred to.
+    #[test]
+    fn synthetic_window_edge() {
+        use crate::test_utils::get_random_data;
+        for &offset in &[0, 1] {
+            let mut data = get_random_data(WINDOW_SIZE + offset);
+            let repeated = data[..20].to_vec();
+            data.extend_from_slice(&repeated);
+            let tail: Vec<LZValue> = if offset == 0 {
+                vec![ld(20, WINDOW_SIZE as u16)]
+            } else {
+                repeated.iter().map(|&b| lit(b)).collect()
+            };
+            for &matching_type in &[MatchingType::Lazy, MatchingType::Greedy] {
+                let compressed = lz77_compress_blocks(&data, matching_type, 0);
+                assert!(
+                    compressed.ends_with(&tail),
+                    "{:?} matching, offset {}: {:?}",
+                    matching_type,
+                    offset,
+                    &compressed[compressed.len() - tail.len()..]
+                );
+                assert!(decompress_lz77(&compressed) == data);
+            }
+        }
+    }
+
     fn roundtrip(data: &[u8]) {
-        let compressed = super::lz77_compress(&data).unwrap();
+        let compressed = super::lz77_compress(data).unwrap();
         let decompressed = decompress_lz77(&compressed);
         assert!(decompressed == data);
     }
@@ -1040,11 +1483,11 @@ mod test {
         let mut data = vec![0; WINDOW_SIZE];
         roundtrip(&data);
         {
-            data.write(&[22; WINDOW_SIZE]);
+            data.write_all(&[22; WINDOW_SIZE]).unwrap();
         }
         roundtrip(&data);
         {
-            data.write(&[55; WINDOW_SIZE]);
+            data.write_all(&[55; WINDOW_SIZE]).unwrap();
         }
         roundtrip(&data);
     }
@@ -1075,6 +1518,159 @@ mod test {
         assert!(decompressed == data);
     }
 
+    /// Test that matches at exactly the maximum distance are found across window slides.
+    #[test]
+    fn max_distance_matches() {
+        use crate::test_utils::get_random_data;
+        // Incompressible data repeated with a period of exactly the window size, so the only
+        // useful matches are the ones at the maximum distance.
+        let window = get_random_data(WINDOW_SIZE);
+        let mut data = Vec::with_capacity(WINDOW_SIZE * 4);
+        for _ in 0..4 {
+            data.extend_from_slice(&window);
+        }
+
+        let compressed = lz77_compress(&data).unwrap();
+        let mut max_distance_bytes = 0;
+        for v in &compressed {
+            if let LZType::StoredLengthDistance(l, d) = v.value() {
+                assert!(d as usize <= WINDOW_SIZE);
+                if d as usize == WINDOW_SIZE {
+                    max_distance_bytes += l.actual_length() as usize;
+                }
+            }
+        }
+        // Everything after the first window should be covered by matches, save for some
+        // bytes where a shorter, closer match happened to be picked.
+        assert!(max_distance_bytes > WINDOW_SIZE * 3 - 1024);
+
+        let decompressed = decompress_lz77(&compressed);
+        assert!(decompressed == data);
+    }
+
+    /// Test that periodic data is compressed to runs of maximum length matches at the period.
+    #[test]
+    fn chain_budget() {
+        let settings = ChainDecay {
+            miss_window: 4,
+            min_checks: 10,
+        };
+        let mut budget = ChainBudget::new(128, settings);
+        for _ in 0..3 {
+            budget.record(false);
+        }
+        assert_eq!(budget.checks(), 128);
+        budget.record(false);
+        assert_eq!(budget.checks(), 64);
+        // A match restores the full number of checks and starts the count over.
+        budget.record(true);
+        assert_eq!(budget.checks(), 128);
+        for _ in 0..100 {
+            budget.record(false);
+        }
+        assert_eq!(budget.checks(), 10);
+        budget.restore();
+        assert_eq!(budget.checks(), 128);
+
+        // No decay by default.
+        let mut budget = ChainBudget::new(128, ChainDecay::DEFAULT);
+        for _ in 0..1000 {
+            budget.record(false);
+        }
+        assert_eq!(budget.checks(), 128);
+
+        // Never goes below one check, or above the configured number.
+        let mut budget = ChainBudget::new(
+            2,
+            ChainDecay {
+                miss_window: 1,
+                min_checks: 0,
+            },
+        );
+        budget.record(false);
+        budget.record(false);
+        assert_eq!(budget.checks(), 1);
+        let mut budget = ChainBudget::new(2, settings);
+        budget.record(false);
+        budget.record(false);
+        budget.record(false);
+        budget.record(false);
+        assert_eq!(budget.checks(), 2);
+    }
+
+    #[test]
+    fn periodic_data() {
+        // An array of "structs" of 12 bytes each.
+        let mut data = Vec::new();
+        for i in 0..2000u32 {
+            data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            data.extend_from_slice(&(i & 1).to_le_bytes());
+        }
+
+        for &matching_type in &[MatchingType::Lazy, MatchingType::Greedy] {
+            let compressed = lz77_compress_conf(&data, 128, 32, matching_type).unwrap();
+            let max_len_matches = compressed
+                .iter()
+                .filter(|v| match v.value() {
+                    LZType::StoredLengthDistance(l, d) => {
+                        l.actual_length() as usize == MAX_MATCH && d == 24
+                    }
+                    _ => false,
+                })
+                .count();
+            // Everything save for the first two periods and the end should be covered by
+            // maximum length matches.
+            assert!(max_len_matches >= (data.len() - 48) / MAX_MATCH - 1);
+            assert!(decompress_lz77(&compressed) == data);
+        }
+    }
+
+    #[test]
+    fn sparse_data() {
+        // Long zero runs with a few bytes of data between them, as in a sparse file.
+        let mut data = vec![0u8; 100_000];
+        for i in (0..data.len()).step_by(7919) {
+            data[i] = (i % 251) as u8 + 1;
+        }
+
+        for &matching_type in &[MatchingType::Lazy, MatchingType::Greedy] {
+            let compressed = lz77_compress_conf(&data, 128, 32, matching_type).unwrap();
+            // The runs should be covered by maximum length matches one byte back.
+            let run_matches = compressed
+                .iter()
+                .filter(|v| match v.value() {
+                    LZType::StoredLengthDistance(l, d) => {
+                        l.actual_length() as usize == MAX_MATCH && d == 1
+                    }
+                    _ => false,
+                })
+                .count();
+            assert!(run_matches >= data.len() / MAX_MATCH - 2 * (data.len() / 7919 + 1));
+            assert!(decompress_lz77(&compressed) == data);
+        }
+
+        // Greedy matching without any hash checks is used for huffman only compression, so no
+        // matches should be emitted at all.
+        let compressed = lz77_compress_conf(&data, 0, 0, MatchingType::Greedy).unwrap();
+        assert!(compressed.iter().all(|v| v.is_literal()));
+    }
+
+    /// Test that matches can refer to data in a preset dictionary.
+    #[test]
+    fn dictionary() {
+        let dictionary = b"A dictionary with some data in it";
+        let data = b"some data in the dictionary";
+        let mut state = TestStruct::new();
+        state.state.set_dictionary(&mut state.buffer, dictionary);
+        let (consumed, status, _) = state.compress_block(data, true);
+        assert_eq!(consumed, data.len());
+        assert_eq!(status, LZ77Status::Finished);
+
+        let compressed = state.writer.get_buffer();
+        assert_eq!(compressed[0], ld(13, 15));
+        assert!(decompress_lz77_with_backbuffer(compressed, dictionary) == data);
+    }
+
     #[test]
     fn compress_block_status() {
         use crate::input_buffer::InputBuffer;
@@ -1083,7 +1679,12 @@ mod test {
         let mut writer = DynamicWriter::new();
 
         let mut buffer = InputBuffer::empty();
-        let mut state = LZ77State::new(4096, DEFAULT_LAZY_IF_LESS_THAN, MatchingType::Lazy);
+        let mut state = LZ77State::new(
+            4096,
+            DEFAULT_LAZY_IF_LESS_THAN,
+            MatchingType::Lazy,
+            &BufferAllocator::default(),
+        );
         let status = lz77_compress_block_finish(data, &mut state, &mut buffer, &mut writer);
         assert_eq!(status.1, LZ77Status::Finished);
         assert!(&buffer.get_buffer()[..data.len()] == data);
@@ -1100,7 +1701,12 @@ mod test {
         let mut writer = DynamicWriter::new();
 
         let mut buffer = InputBuffer::empty();
-        let mut state = LZ77State::new(0, DEFAULT_LAZY_IF_LESS_THAN, MatchingType::Lazy);
+        let mut state = LZ77State::new(
+            0,
+            DEFAULT_LAZY_IF_LESS_THAN,
+            MatchingType::Lazy,
+            &BufferAllocator::default(),
+        );
         let (bytes_consumed, status) =
             lz77_compress_block_finish(&data, &mut state, &mut buffer, &mut writer);
         assert_eq!(
@@ -1168,7 +1774,7 @@ mod test {
     /// Test buffer fill when a byte is added due to no match being found.
     fn buffer_test_literals(data: &[u8]) {
         let mut state = TestStruct::with_config(0, NO_RLE, MatchingType::Lazy);
-        let (bytes_consumed, status, position) = state.compress_block(&data, false);
+        let (bytes_consumed, status, position) = state.compress_block(data, false);
 
         // There should be enough data for the block to have ended.
         assert_eq!(status, LZ77Status::EndBlock);
@@ -1195,7 +1801,7 @@ mod test {
 
         let _ = state.compress_block(&data[bytes_consumed..], false);
         // We should have some new data in the buffer at this point.
-        assert!(state.writer.get_buffer().len() > 0);
+        assert!(!state.writer.get_buffer().is_empty());
         assert_eq!(
             state.state.current_block_input_bytes() as usize,
             MAX_BUFFER_LENGTH
@@ -1209,14 +1815,14 @@ mod test {
     fn buffer_test_last_bytes(matching_type: MatchingType, data: &[u8]) {
         const BYTES_USED: usize = MAX_BUFFER_LENGTH;
         assert!(
-            &data[..BYTES_USED]
-                == &decompress_lz77(
+            data[..BYTES_USED]
+                == decompress_lz77(
                     &lz77_compress_conf(&data[..BYTES_USED], 0, NO_RLE, matching_type,).unwrap()
                 )[..]
         );
         assert!(
-            &data[..BYTES_USED + 1]
-                == &decompress_lz77(
+            data[..BYTES_USED + 1]
+                == decompress_lz77(
                     &lz77_compress_conf(&data[..BYTES_USED + 1], 0, NO_RLE, matching_type,)
                         .unwrap()
                 )[..]
@@ -1,6 +1,4 @@
-#[cfg(test)]
-use crate::huffman_table::MAX_MATCH;
-use crate::huffman_table::{MAX_DISTANCE, MIN_MATCH};
+use crate::huffman_table::{MAX_DISTANCE, MAX_MATCH, MIN_MATCH};
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct StoredLength {
@@ -10,7 +8,7 @@ pub struct StoredLength {
 impl StoredLength {
     #[cfg(test)]
     pub fn from_actual_length(length: u16) -> StoredLength {
-        assert!(length <= MAX_MATCH && length >= MIN_MATCH);
+        assert!((MIN_MATCH..=MAX_MATCH).contains(&length));
         StoredLength {
             length: (length - MIN_MATCH) as u8,
         }
@@ -26,7 +24,6 @@ impl StoredLength {
         self.length
     }
 
-    #[cfg(test)]
     pub fn actual_length(&self) -> u16 {
         u16::from(self.length) + MIN_MATCH
     }
@@ -55,6 +52,14 @@ impl LZValue {
 
     #[inline]
     pub fn length_distance(length: u16, distance: u16) -> LZValue {
+        // These are bounded by the matching functions, so an invalid value would be a bug.
+        // Checking this here means the invalid match is caught where it's created, rather than
+        // ending up as a corrupt symbol when it's encoded.
+        debug_assert!(
+            (MIN_MATCH..=MAX_MATCH).contains(&length),
+            "Invalid match length {}!",
+            length
+        );
         debug_assert!(distance > 0 && distance <= MAX_DISTANCE);
         let stored_length = (length - MIN_MATCH) as u8;
         LZValue {
@@ -63,6 +68,19 @@ impl LZValue {
         }
     }
 
+    /// Returns `true` if this is a literal rather than a match.
+    #[inline]
+    pub fn is_literal(&self) -> bool {
+        self.distance == 0
+    }
+
+    /// The byte of a literal value.
+    #[inline]
+    pub fn literal_value(&self) -> u8 {
+        debug_assert!(self.is_literal());
+        self.litlen
+    }
+
     #[inline]
     pub fn value(&self) -> LZType {
         if self.distance != 0 {
@@ -86,10 +104,10 @@ pub fn ld(l: u16, d: u16) -> LZValue {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::huffman_table::{MAX_DISTANCE, MAX_MATCH, MIN_DISTANCE, MIN_MATCH};
+    use crate::huffman_table::MIN_DISTANCE;
     #[test]
     fn lzvalue() {
-        for i in 0..255 as usize + 1 {
+        for i in 0..256 {
             let v = LZValue::literal(i as u8);
             if let LZType::Literal(n) = v.value() {
                 assert_eq!(n as usize, i);
@@ -117,4 +135,17 @@ mod test {
             }
         }
     }
+
+    /// The symbol buffer holds a lot of these, so make sure they stay packed.
+    #[test]
+    fn size() {
+        assert_eq!(::std::mem::size_of::<LZValue>(), 4);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Invalid match length 259!")]
+    fn length_distance_too_long() {
+        let _ = LZValue::length_distance(MAX_MATCH + 1, 1);
+    }
 }
@@ -1,75 +1,107 @@
-use std::cmp;
+use std::{cmp, fmt};
 
+use crate::allocator::BufferAllocator;
 use crate::chained_hash_table::{ChainedHashTable, WINDOW_SIZE};
+use crate::compression_options::MAX_HASH_CHECKS;
+use crate::cpu_dispatch::kernels;
 
 const MAX_MATCH: usize = crate::huffman_table::MAX_MATCH as usize;
-#[cfg(test)]
 const MIN_MATCH: usize = crate::huffman_table::MIN_MATCH as usize;
 
+/// Value for `max_work` in `longest_match` meaning the amount of work is only limited by the
+/// number of hash checks.
+pub const NO_WORK_LIMIT: usize = usize::MAX;
+
+/// Hint to the CPU that `value` will be read soon, so it can start loading it into the cache.
+///
+/// This is a no-op on architectures other than x86 and x86-64.
+#[inline(always)]
+pub fn prefetch_read<T>(value: &T) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::{_mm_prefetch, _MM_HINT_T0};
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        // Prefetching is only a hint and can't fault, so this is fine for any address.
+        #[allow(unused_unsafe)]
+        unsafe {
+            _mm_prefetch::<_MM_HINT_T0>(value as *const T as *const i8)
+        }
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let _ = value;
+}
+
 /// Get the length of the checked match
 /// The function returns number of bytes at and including `current_pos` that are the same as the
 /// ones at `pos_to_check`
+///
+/// The first `known_length` bytes are assumed to already have been checked, and are not
+/// compared again.
 #[inline]
-pub fn get_match_length(data: &[u8], current_pos: usize, pos_to_check: usize) -> usize {
-    // Unsafe version using unaligned loads for comparison.
-    // Faster when benching the matching function alone,
-    // but not as significant when running the full thing.
-    /*
-        type Comp = u64;
+pub fn get_match_length(
+    data: &[u8],
+    current_pos: usize,
+    pos_to_check: usize,
+    known_length: usize,
+) -> usize {
+    let a = &data[current_pos + known_length..];
+    let b = &data[pos_to_check + known_length..];
+    let len = cmp::min(cmp::min(a.len(), b.len()), MAX_MATCH - known_length);
+    known_length + (kernels().common_prefix)(&a[..len], b)
+}
 
-        use std::mem::size_of;
+/// Check whether the data at `position` continues with a match of the longest possible length at
+/// `distance` bytes back.
+///
+/// Periodic data (e.g arrays of fixed-size structs) tends to produce long runs of maximum length
+/// matches with the same distance, so checking the distance of the previous match first lets us
+/// skip searching the hash chains for these.
+///
+/// # Returns
+/// The length of the match, or 0 if there is no match of the longest possible length.
+#[inline]
+pub fn repeat_match(data: &[u8], position: usize, distance: usize) -> usize {
+    if distance == 0 || distance > position || position >= data.len() {
+        return 0;
+    }
 
-        let max = cmp::min(data.len() - current_pos, MAX_MATCH);
-        let mut left = max;
-        let s = size_of::<Comp>();
+    let max_length = cmp::min(data.len() - position, MAX_MATCH);
+    let length = get_match_length(data, position, position - distance, 0);
+    if length == max_length && length >= MIN_MATCH {
+        length
+    } else {
+        0
+    }
+}
 
-        unsafe {
-            let mut cur = data.as_ptr().offset(current_pos as isize);
-            let mut tc = data.as_ptr().offset(pos_to_check as isize);
-            while left >= s &&
-                  (*(cur as *const Comp) == *(tc as *const Comp)) {
-                      left -= s;
-                      cur = cur.offset(s as isize);
-                      tc = tc.offset(s as isize);
-                  }
-            while left > 0 && *cur == *tc {
-                left -= 1;
-                cur = cur.offset(1);
-                tc = tc.offset(1);
-            }
-        }
+/// The minimum length of a run of the same byte for `run_match` to report it.
+pub const MIN_RUN_LENGTH: usize = 32;
 
-        max - left
-    */
-
-    // Slightly faster than naive in single bench.
-    // Does not use unaligned loads.
-    // let l = cmp::min(MAX_MATCH, data.len() - current_pos);
-
-    // let a = unsafe{&data.get_unchecked(current_pos..current_pos + l)};
-    // let b = unsafe{&data.get_unchecked(pos_to_check..)};
-
-    // let mut len = 0;
-
-    // for (l, r) in a
-    //     .iter()
-    //     .zip(b.iter()) {
-    //         if *l == *r {
-    //             len += 1;
-    //             continue;
-    //         } else {
-    //             break;
-    //         }
-    //     }
-    // len as usize
-
-    // Naive version
-    data[current_pos..]
-        .iter()
-        .zip(data[pos_to_check..].iter())
-        .take(MAX_MATCH)
-        .take_while(|&(&a, &b)| a == b)
-        .count()
+/// Check whether the data at `position` is a run of at least `MIN_RUN_LENGTH` copies of the byte
+/// before it, which is encoded as a match at distance 1.
+///
+/// Long runs of the same byte, like the zeroes in sparse files and disk images, are slow to
+/// search for matches in, as every position in a run has the same hash, and near the end of a
+/// run every candidate in the hash chain gives the same length, so the search can't stop early.
+/// A match of the rest of the run is also almost always the best one there is, so runs are
+/// checked for before searching the hash chains.
+///
+/// # Returns
+/// The length of the run, up to the maximum match length, or 0 if there is no run this long.
+#[inline]
+pub fn run_match(data: &[u8], position: usize) -> usize {
+    if position == 0 || position >= data.len() || data[position] != data[position - 1] {
+        return 0;
+    }
+
+    let length = get_match_length(data, position, position - 1, 0);
+    if length >= MIN_RUN_LENGTH {
+        length
+    } else {
+        0
+    }
 }
 
 /// Try finding the position and length of the longest match in the input data.
@@ -84,12 +116,21 @@ pub fn get_match_length(data: &[u8], current_pos: usize, pos_to_check: usize) ->
 /// `position`: The position in the data to match against.
 /// `prev_length`: The length of the previous `longest_match` check to compare against.
 /// `max_hash_checks`: The maximum number of matching hash chain positions to check.
+/// `max_work`: Stop searching once about this many hash chain steps and byte comparisons have
+/// been done in total.
+/// `nice_length`: Stop searching once a match at least this long has been found.
+/// `prefetch`: Prefetch the next entry in the hash chain and the data it refers to while
+/// checking the current one.
+#[allow(clippy::too_many_arguments)]
 pub fn longest_match(
     data: &[u8],
     hash_table: &ChainedHashTable,
     position: usize,
     prev_length: usize,
     max_hash_checks: u16,
+    max_work: usize,
+    nice_length: usize,
+    prefetch: bool,
 ) -> (usize, usize) {
     // debug_assert_eq!(position, hash_table.current_head() as usize);
 
@@ -99,17 +140,16 @@ pub fn longest_match(
         return (0, 0);
     }
 
-    let limit = if position > WINDOW_SIZE {
-        position - WINDOW_SIZE
-    } else {
-        0
-    };
+    let limit = position.saturating_sub(WINDOW_SIZE);
 
     // Make sure the length is at least one to simplify the matching code, as
     // otherwise the matching code might underflow.
     let prev_length = cmp::max(prev_length, 1);
 
     let max_length = cmp::min(data.len() - position, MAX_MATCH);
+    // Stop once we've found a match that's long enough that continuing to search for a longer one
+    // isn't worth the time.
+    let nice_length = cmp::min(max_length, nice_length);
 
     // The position in the hash chain we are currently checking.
     let mut current_head = position;
@@ -121,6 +161,9 @@ pub fn longest_match(
     // The position of the previous value in the hash chain.
     let mut prev_head;
 
+    // The number of hash chain steps and byte comparisons done so far.
+    let mut work = 0;
+
     for _ in 0..max_hash_checks {
         prev_head = current_head;
         current_head = hash_table.get_prev(current_head) as usize;
@@ -131,31 +174,48 @@ pub fn longest_match(
             break;
         }
 
+        if prefetch {
+            // Start loading the next entry in the chain and the byte we will compare first
+            // at the position it refers to, so they are hopefully in the cache by the time we
+            // get to them.
+            let next_head = hash_table.get_prev(current_head) as usize;
+            prefetch_read(hash_table.prev_entry(next_head));
+            if let Some(b) = data.get(next_head + best_length) {
+                prefetch_read(b);
+            }
+        }
+
         // We only check further if the match length can actually increase
-        // Checking if the end byte and the potential next byte matches is generally
-        // more likely to give a quick answer rather than checking from the start first, given
-        // that the hashes match.
-        // If there is no previous match, best_length will be 1 and the two first bytes will
-        // be checked instead.
+        // Checking if the byte that would make the match longer than the best one and the one
+        // before it match is generally more likely to give a quick answer rather than checking
+        // from the start first, given that the hashes match. The first two bytes are checked
+        // next, as different strings can still have the same hash.
+        // If there is no previous match, best_length will be 1 and only the two first bytes will
+        // be checked.
         // Since we've made sure best_length is always at least 1, this shouldn't underflow.
-        if data[position + best_length - 1..=position + best_length]
-            == data[current_head + best_length - 1..=current_head + best_length]
+        if data[position + best_length] == data[current_head + best_length]
+            && data[position + best_length - 1] == data[current_head + best_length - 1]
+            && data[position..position + 2] == data[current_head..current_head + 2]
         {
-            // Actually check how many bytes match.
-            // At the moment this will check the two bytes we just checked again,
-            // though adding code for skipping these bytes may not result in any speed
-            // gain due to the added complexity.
-            let length = get_match_length(data, position, current_head);
+            // Actually check how many bytes match, skipping the two first bytes as we've
+            // already checked those.
+            let length = get_match_length(data, position, current_head, 2);
+            work += length;
             if length > best_length {
                 best_length = length;
                 best_distance = position - current_head;
-                if length == max_length {
-                    // We are at the max length, so there is no point
-                    // searching any longer
+                if length >= nice_length {
+                    // We are at the max length (or a length that is good enough), so there is
+                    // no point searching any longer
                     break;
                 }
             }
         }
+
+        work += 1;
+        if work >= max_work {
+            break;
+        }
     }
 
     if best_length > prev_length {
@@ -165,6 +225,180 @@ pub fn longest_match(
     }
 }
 
+/// Find the matches at `position` that could be worth using, for parsers that pick between
+/// matches based on their cost rather than just using the longest one.
+///
+/// The hash chain is searched from the closest position and back, and a match is only included
+/// if it's longer than all of the closer ones, as a closer match of the same length is never
+/// more expensive. The greedy and lazy parsers only need the longest match, so this is not used
+/// by them.
+///
+/// # Returns
+/// Up to `max_results` (length, distance) pairs, sorted by decreasing length (and so also by
+/// decreasing distance). The longest of them is the same match `longest_match` would find.
+///
+/// # Arguments:
+/// `data`: The data to search in.
+/// `hash_table`: Hash table to use for searching.
+/// `position`: The position in the data to match against.
+/// `max_hash_checks`: The maximum number of matching hash chain positions to check.
+/// `max_results`: The maximum number of matches to return, the longest ones are kept.
+pub fn matches_at(
+    data: &[u8],
+    hash_table: &ChainedHashTable,
+    position: usize,
+    max_hash_checks: u16,
+    max_results: usize,
+) -> Vec<(usize, usize)> {
+    let mut matches = Vec::new();
+    if max_results == 0 || position + MIN_MATCH > data.len() {
+        return matches;
+    }
+
+    let limit = position.saturating_sub(WINDOW_SIZE);
+    let max_length = cmp::min(data.len() - position, MAX_MATCH);
+    let mut best_length = MIN_MATCH - 1;
+    let mut current_head = position;
+
+    for _ in 0..max_hash_checks {
+        let prev_head = current_head;
+        current_head = hash_table.get_prev(current_head) as usize;
+        if current_head >= prev_head || current_head < limit {
+            break;
+        }
+
+        // As in `longest_match`, check the byte that would make the match longer than the
+        // best one so far first.
+        if data[position + best_length] == data[current_head + best_length] {
+            let length = get_match_length(data, position, current_head, 0);
+            if length > best_length {
+                best_length = length;
+                matches.push((length, position - current_head));
+                if length == max_length {
+                    break;
+                }
+            }
+        }
+    }
+
+    matches.reverse();
+    matches.truncate(max_results);
+    matches
+}
+
+/// Finds the matches the encoder could use at each position of some data.
+///
+/// The matches are found by searching the same hash chains as the encoder does, but all of the
+/// matches that could be worth using are returned rather than only the longest one. This is
+/// meant for parsers that pick between matches based on their cost, such as a zopfli-style
+/// optimal parser, and for tools looking into how well some data can be compressed.
+///
+/// The positions have to be passed in increasing order, as the hash chains are only built up
+/// to the position last passed, and only cover the last 32 KiB before it, like in the encoder.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::MatchFinder;
+///
+/// let data = b"abcdefXabcdYabcdeZabcdef";
+/// let mut finder = MatchFinder::new(data, 4096);
+/// // The longest match comes first, followed by the shorter but closer ones.
+/// assert_eq!(finder.matches_at(18, 10), [(6, 18), (5, 6)]);
+/// ```
+pub struct MatchFinder<'a> {
+    data: &'a [u8],
+    hash_table: ChainedHashTable,
+    /// The position in `data` that the positions in the hash table are relative to.
+    base: usize,
+    /// The number of positions that have been added to the hash table.
+    added: usize,
+    /// The last position matches were found at.
+    last_position: usize,
+    max_hash_checks: u16,
+}
+
+impl<'a> MatchFinder<'a> {
+    /// Create a new `MatchFinder` for finding matches in `data`, checking at most
+    /// `max_hash_checks` positions in the hash chain for each position.
+    ///
+    /// Values higher than `32768` are treated as `32768`. The compression levels use the same
+    /// limit through
+    /// [`CompressionOptions::max_hash_checks`](struct.CompressionOptions.html#structfield.max_hash_checks),
+    /// e.g `128` for the default level.
+    pub fn new(data: &'a [u8], max_hash_checks: u16) -> MatchFinder<'a> {
+        let mut hash_table = ChainedHashTable::new_in(&BufferAllocator::default());
+        if data.len() >= MIN_MATCH {
+            hash_table.add_initial_hash_values(data[0], data[1]);
+        }
+        MatchFinder {
+            data,
+            hash_table,
+            base: 0,
+            added: 0,
+            last_position: 0,
+            max_hash_checks: cmp::min(max_hash_checks, MAX_HASH_CHECKS),
+        }
+    }
+
+    /// Find the matches at `position` that could be worth using.
+    ///
+    /// A match is only included if it's longer than all of the closer ones, as a closer match
+    /// of the same length is never more expensive to encode.
+    ///
+    /// # Returns
+    /// Up to `max_results` (length, distance) pairs, sorted by decreasing length (and so also by
+    /// decreasing distance). The longest ones are kept, so the first one is the longest match
+    /// found.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `position` is before the position passed in the previous call.
+    pub fn matches_at(&mut self, position: usize, max_results: usize) -> Vec<(usize, usize)> {
+        assert!(
+            position >= self.last_position,
+            "Positions have to be passed in increasing order!"
+        );
+        self.last_position = position;
+
+        // Add the positions up to and including this one to the hash chains, as far as there
+        // are enough bytes left to hash.
+        let end = cmp::min(position + 1, self.data.len().saturating_sub(MIN_MATCH - 1));
+        while self.added < end {
+            if self.added - self.base == WINDOW_SIZE * 2 {
+                // Keep the positions in the table within the range it can store, like the
+                // encoder does when sliding its buffer.
+                self.hash_table.slide(WINDOW_SIZE);
+                self.base += WINDOW_SIZE;
+            }
+            self.hash_table
+                .add_hash_value(self.added - self.base, self.data[self.added + 2]);
+            self.added += 1;
+        }
+
+        if position >= end {
+            return Vec::new();
+        }
+        matches_at(
+            &self.data[self.base..],
+            &self.hash_table,
+            position - self.base,
+            self.max_hash_checks,
+            max_results,
+        )
+    }
+}
+
+impl fmt::Debug for MatchFinder<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MatchFinder")
+            .field("len", &self.data.len())
+            .field("last_position", &self.last_position)
+            .field("max_hash_checks", &self.max_hash_checks)
+            .finish_non_exhaustive()
+    }
+}
+
 /// Try finding the position and length of the longest match in the input data using fast zlib
 /// hash skipping algorithm.
 /// # Returns
@@ -194,11 +428,7 @@ pub fn longest_match_fast(
         return (0, 0);
     }
 
-    let limit = if position > WINDOW_SIZE {
-        position - WINDOW_SIZE
-    } else {
-        0
-    };
+    let limit = position.saturating_sub(WINDOW_SIZE);
 
     // Make sure the length is at least one to simplify the matching code, as
     // otherwise the matching code might underflow.
@@ -244,7 +474,7 @@ pub fn longest_match_fast(
             // At the moment this will check the two bytes we just checked again,
             // though adding code for skipping these bytes may not result in any speed
             // gain due to the added complexity.
-            let length = get_match_length(data, position, offset_head);
+            let length = get_match_length(data, position, offset_head, 0);
             if length > best_length {
                 best_length = length;
                 best_distance = position - offset_head;
@@ -283,26 +513,37 @@ pub fn longest_match_current(data: &[u8], hash_table: &ChainedHashTable) -> (usi
         data,
         hash_table,
         hash_table.current_head() as usize,
-        MIN_MATCH as usize - 1,
+        MIN_MATCH - 1,
         MAX_HASH_CHECKS,
+        NO_WORK_LIMIT,
+        MAX_MATCH,
+        false,
     )
 }
 
 #[cfg(test)]
 mod test {
-    use super::{get_match_length, longest_match, longest_match_fast};
-    use crate::chained_hash_table::{filled_hash_table, ChainedHashTable, HASH_BYTES};
+    use super::{get_match_length, longest_match, longest_match_fast, MAX_MATCH, NO_WORK_LIMIT};
+    use crate::chained_hash_table::{filled_hash_table, ChainedHashTable, HASH_BYTES, WINDOW_SIZE};
 
     /// Test that match lengths are calculated correctly
     #[test]
     fn match_length() {
         let test_arr = [5u8, 5, 5, 5, 5, 9, 9, 2, 3, 5, 5, 5, 5, 5];
-        let l = get_match_length(&test_arr, 9, 0);
+        let l = get_match_length(&test_arr, 9, 0, 0);
         assert_eq!(l, 5);
-        let l2 = get_match_length(&test_arr, 9, 7);
+        let l2 = get_match_length(&test_arr, 9, 7, 0);
         assert_eq!(l2, 0);
-        let l3 = get_match_length(&test_arr, 10, 0);
+        let l3 = get_match_length(&test_arr, 10, 0, 0);
         assert_eq!(l3, 4);
+        // Skipping bytes that are known to match shouldn't change the result.
+        assert_eq!(get_match_length(&test_arr, 9, 0, 2), 5);
+        assert_eq!(get_match_length(&test_arr, 10, 0, 4), 4);
+
+        // The length is still limited to the maximum match length.
+        let long = [7u8; 600];
+        assert_eq!(get_match_length(&long, 1, 0, 0), MAX_MATCH);
+        assert_eq!(get_match_length(&long, 1, 0, 2), MAX_MATCH);
     }
 
     /// Test that we get the longest of the matches
@@ -326,6 +567,39 @@ mod test {
         assert_eq!(length, 4);
     }
 
+    /// Test that all matches longer than the closer ones are returned, longest first.
+    #[test]
+    fn all_matches() {
+        use super::matches_at;
+        let data = b"abcdefXabcdYabcdeZabcdef";
+        let position = 18;
+        let hash_table = filled_hash_table(&data[..position + HASH_BYTES]);
+        assert_eq!(hash_table.current_head() as usize, position);
+
+        let matches = matches_at(data, &hash_table, position, 4096, 10);
+        assert_eq!(matches, [(6, 18), (5, 6)]);
+        assert_eq!(
+            longest_match(
+                data,
+                &hash_table,
+                position,
+                0,
+                4096,
+                NO_WORK_LIMIT,
+                MAX_MATCH,
+                false
+            ),
+            matches[0]
+        );
+        // The longest matches are kept.
+        assert_eq!(matches_at(data, &hash_table, position, 4096, 1), [(6, 18)]);
+        assert!(matches_at(data, &hash_table, position, 4096, 0).is_empty());
+        // Only the closest match is checked.
+        assert_eq!(matches_at(data, &hash_table, position, 1, 10), [(5, 6)]);
+        // Too close to the end for a match.
+        assert!(matches_at(data, &hash_table, data.len() - 2, 4096, 10).is_empty());
+    }
+
     /// Make sure we can get a match at index zero
     #[test]
     fn match_index_zero() {
@@ -336,12 +610,232 @@ mod test {
             hash_table.add_hash_value(n, b);
         }
 
-        let (match_length, match_dist) = longest_match(test_data, &hash_table, 1, 0, 4096);
+        let (match_length, match_dist) = longest_match(
+            test_data,
+            &hash_table,
+            1,
+            0,
+            4096,
+            NO_WORK_LIMIT,
+            MAX_MATCH,
+            false,
+        );
 
         assert_eq!(match_dist, 1);
         assert!(match_length == 6);
     }
 
+    /// Test that repeated matches are only reported if they have the longest possible length.
+    #[test]
+    fn repeat_match_max_length() {
+        use super::{repeat_match, MAX_MATCH};
+
+        let mut data = b"0123456789".to_vec();
+        for _ in 0..40 {
+            data.extend_from_slice(b"0123456789");
+        }
+        assert_eq!(repeat_match(&data, 10, 10), MAX_MATCH);
+        // Shorter than the maximum length, as we are close to the end of the data.
+        assert_eq!(repeat_match(&data, data.len() - 20, 10), 20);
+        // Not a match at this distance.
+        assert_eq!(repeat_match(&data, 10, 9), 0);
+        // A match that is not as long as it could be.
+        data[200] = b'x';
+        assert_eq!(repeat_match(&data, 100, 10), 0);
+        // Distance further back than the start of the data.
+        assert_eq!(repeat_match(&data, 5, 10), 0);
+        assert_eq!(repeat_match(&data, 10, 0), 0);
+    }
+
+    #[test]
+    fn run_match_length() {
+        use super::{run_match, MAX_MATCH, MIN_RUN_LENGTH};
+
+        let mut data = b"abc".to_vec();
+        data.resize(1000, 0);
+        data.extend_from_slice(b"abc");
+        assert_eq!(run_match(&data, 4), MAX_MATCH);
+        // The byte at the position has to be the same as the one before it.
+        assert_eq!(run_match(&data, 3), 0);
+        assert_eq!(run_match(&data, 0), 0);
+        // The rest of the run at the end of it.
+        assert_eq!(run_match(&data, 1000 - 50), 50);
+        assert_eq!(run_match(&data, 1000 - MIN_RUN_LENGTH), MIN_RUN_LENGTH);
+        assert_eq!(run_match(&data, 1000 - MIN_RUN_LENGTH + 1), 0);
+        // Runs at the end of the data.
+        data.resize(2000, b'c');
+        assert_eq!(run_match(&data, 2000 - 100), 100);
+        assert_eq!(run_match(&data, 2000), 0);
+    }
+
+    /// Create data containing `PATTERN` at the start and at `distance`, with zeroes in between,
+    /// and return it along with a hash table filled up to the second occurence.
+    fn window_edge_data(distance: usize) -> (Vec<u8>, ChainedHashTable) {
+        const PATTERN: &[u8] = b"window edge";
+        let mut data = PATTERN.to_vec();
+        data.resize(distance, 0);
+        data.extend_from_slice(PATTERN);
+        let hash_table = filled_hash_table(&data[..distance + HASH_BYTES]);
+        assert_eq!(hash_table.current_head() as usize, distance);
+        (data, hash_table)
+    }
+
+    /// Make sure a match exactly at the maximum distance is found.
+    #[test]
+    fn match_max_distance() {
+        let (data, hash_table) = window_edge_data(WINDOW_SIZE);
+
+        let (length, distance) = longest_match(
+            &data,
+            &hash_table,
+            WINDOW_SIZE,
+            0,
+            4096,
+            NO_WORK_LIMIT,
+            MAX_MATCH,
+            false,
+        );
+        assert_eq!(distance, WINDOW_SIZE);
+        assert_eq!(length, 11);
+
+        let (length, distance) = longest_match_fast(&data, &hash_table, WINDOW_SIZE, 0, 4096);
+        assert_eq!(distance, WINDOW_SIZE);
+        assert_eq!(length, 11);
+    }
+
+    /// Make sure we don't output a match that is further back than the maximum distance.
+    #[test]
+    fn no_match_past_max_distance() {
+        let (data, hash_table) = window_edge_data(WINDOW_SIZE + 1);
+
+        let res = longest_match(
+            &data,
+            &hash_table,
+            WINDOW_SIZE + 1,
+            0,
+            4096,
+            NO_WORK_LIMIT,
+            MAX_MATCH,
+            false,
+        );
+        assert_eq!(res, (0, 0));
+
+        let res = longest_match_fast(&data, &hash_table, WINDOW_SIZE + 1, 0, 4096);
+        assert_eq!(res, (0, 0));
+    }
+
+    /// Make sure chains referring to data that was slid out of the window are not followed.
+    #[test]
+    fn no_match_in_slid_out_data() {
+        let (mut data, mut hash_table) = window_edge_data(WINDOW_SIZE);
+        hash_table.slide(WINDOW_SIZE);
+        let data = data.split_off(WINDOW_SIZE);
+
+        // The previous occurence of the pattern is no longer in the data, so there should not
+        // be any match at all.
+        let res = longest_match(
+            &data,
+            &hash_table,
+            0,
+            0,
+            4096,
+            NO_WORK_LIMIT,
+            MAX_MATCH,
+            false,
+        );
+        assert_eq!(res, (0, 0));
+    }
+
+    #[test]
+    fn work_limit() {
+        use crate::test_utils::get_test_data;
+        let data = get_test_data();
+        for start_pos in (10000..20000).step_by(97) {
+            let hash_table = filled_hash_table(&data[..start_pos + 1]);
+            let pos = hash_table.current_head() as usize;
+
+            // The first chain step counts as work, so no more than one position is checked.
+            assert_eq!(
+                longest_match(&data, &hash_table, pos, 0, 4096, 1, MAX_MATCH, false),
+                longest_match(
+                    &data,
+                    &hash_table,
+                    pos,
+                    0,
+                    1,
+                    NO_WORK_LIMIT,
+                    MAX_MATCH,
+                    false
+                ),
+            );
+            let limited = longest_match(&data, &hash_table, pos, 0, 4096, 300, MAX_MATCH, false);
+            let unlimited = longest_match(
+                &data,
+                &hash_table,
+                pos,
+                0,
+                4096,
+                NO_WORK_LIMIT,
+                MAX_MATCH,
+                false,
+            );
+            assert!(limited.0 <= unlimited.0);
+        }
+    }
+
+    #[test]
+    fn nice_length() {
+        use crate::test_utils::get_test_data;
+        let data = get_test_data();
+        for start_pos in (10000..20000).step_by(97) {
+            let hash_table = filled_hash_table(&data[..start_pos + 1]);
+            let pos = hash_table.current_head() as usize;
+
+            let full = longest_match(
+                &data,
+                &hash_table,
+                pos,
+                0,
+                4096,
+                NO_WORK_LIMIT,
+                MAX_MATCH,
+                false,
+            );
+            for &nice_length in &[3, 8, 32] {
+                let nice = longest_match(
+                    &data,
+                    &hash_table,
+                    pos,
+                    0,
+                    4096,
+                    NO_WORK_LIMIT,
+                    nice_length,
+                    false,
+                );
+                assert!(nice.0 <= full.0);
+                // Searching should only stop early once a long enough match has been found.
+                assert!(nice.0 >= full.0.min(nice_length));
+            }
+        }
+    }
+
+    /// Prefetching is only a hint, so it should not change which match is found.
+    #[test]
+    fn prefetch_same_match() {
+        use crate::test_utils::get_test_data;
+        let data = get_test_data();
+        for start_pos in (10000..20000).step_by(97) {
+            let hash_table = filled_hash_table(&data[..start_pos + 1]);
+            let pos = hash_table.current_head() as usize;
+            for &prev_length in &[0, 4, 20] {
+                assert_eq!(
+                    longest_match(&data, &hash_table, pos, prev_length, 4096, 300, 128, false),
+                    longest_match(&data, &hash_table, pos, prev_length, 4096, 300, 128, true),
+                );
+            }
+        }
+    }
+
     /// Test for fast_zlib algorithm.
     /// Check that it doesn't give worse matches than the default one.
     /// ignored by default as it's slow, and best ran in release mode.
@@ -355,7 +849,16 @@ mod test {
             let hash_table = filled_hash_table(&data[..start_pos + 1]);
             let pos = hash_table.current_head() as usize;
 
-            let naive_match = longest_match(&data[..], &hash_table, pos, 0, NUM_CHECKS);
+            let naive_match = longest_match(
+                &data[..],
+                &hash_table,
+                pos,
+                0,
+                NUM_CHECKS,
+                NO_WORK_LIMIT,
+                MAX_MATCH,
+                false,
+            );
             let fast_match = longest_match_fast(&data[..], &hash_table, pos, 0, NUM_CHECKS);
 
             if fast_match.0 > naive_match.0 {
@@ -382,7 +885,7 @@ mod test {
 
 #[cfg(all(test, feature = "benchmarks"))]
 mod bench {
-    use super::{longest_match, longest_match_fast};
+    use super::{longest_match, longest_match_fast, MAX_MATCH, NO_WORK_LIMIT};
     use chained_hash_table::filled_hash_table;
     use test_std::Bencher;
     use test_utils::get_test_data;
@@ -394,9 +897,29 @@ mod bench {
         let pos = hash_table.current_head() as usize;
         println!(
             "M: {:?}",
-            longest_match(&data[..], &hash_table, pos, 0, 4096)
+            longest_match(
+                &data[..],
+                &hash_table,
+                pos,
+                0,
+                4096,
+                NO_WORK_LIMIT,
+                MAX_MATCH,
+                false
+            )
         );
-        b.iter(|| longest_match(&data[..], &hash_table, pos, 0, 4096));
+        b.iter(|| {
+            longest_match(
+                &data[..],
+                &hash_table,
+                pos,
+                0,
+                4096,
+                NO_WORK_LIMIT,
+                MAX_MATCH,
+                false,
+            )
+        });
     }
 
     #[bench]
@@ -0,0 +1,135 @@
+//! This module contains functions for producing MSZIP compressed data, as used in the
+//! Microsoft CAB file format.
+//!
+//! In the MSZIP format, the input is split into blocks of up to 32 KiB, each compressed to a
+//! complete DEFLATE stream prefixed with the signature `CK`. While the blocks are separate
+//! streams, the decoder keeps the history between them, so matches may refer to the data in the
+//! previous block.
+
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::writer::compress_until_done;
+use crate::Compression;
+
+/// The maximum number of uncompressed bytes in an MSZIP block.
+pub const MSZIP_BLOCK_SIZE: usize = 32 * 1024;
+
+/// The signature at the start of each MSZIP block.
+const MSZIP_SIGNATURE: &[u8; 2] = b"CK";
+
+/// Compress the given slice of bytes into MSZIP blocks using the provided compression options.
+///
+/// Returns one `Vec<u8>` for each block, each containing the signature and the compressed data
+/// for up to [`MSZIP_BLOCK_SIZE`](constant.MSZIP_BLOCK_SIZE.html) bytes of input. The blocks
+/// are not wrapped in any other headers, so the caller has to write these for the container
+/// format used.
+///
+/// If the input is empty, no blocks are returned.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes_mszip_conf, Compression};
+///
+/// let data = b"This is some test data";
+/// let blocks = deflate_bytes_mszip_conf(data, Compression::Best);
+/// assert_eq!(blocks.len(), 1);
+/// assert_eq!(&blocks[0][..2], b"CK");
+/// ```
+pub fn deflate_bytes_mszip_conf<O: Into<CompressionOptions>>(
+    input: &[u8],
+    options: O,
+) -> Vec<Vec<u8>> {
+    let options = options.into();
+    let filtered;
+    let input = if options.pre_filter.is_active() {
+        filtered = options.pre_filter.apply(input);
+        &filtered[..]
+    } else {
+        input
+    };
+
+    let mut deflate_state = Box::new(DeflateState::new(options, Vec::new()));
+    let mut previous: &[u8] = &[];
+    input
+        .chunks(MSZIP_BLOCK_SIZE)
+        .map(|chunk| {
+            let mut block = MSZIP_SIGNATURE.to_vec();
+            block.reserve(chunk.len() / 2);
+            let block = deflate_state
+                .reset(block)
+                .and_then(|_| {
+                    // Carry over the history from the previous block.
+                    deflate_state.set_dictionary(previous);
+                    compress_until_done(chunk, &mut deflate_state, Flush::Finish)
+                })
+                .and_then(|_| deflate_state.reset(Vec::new()))
+                .expect("Write error!");
+            previous = chunk;
+            block
+        })
+        .collect()
+}
+
+/// Compress the given slice of bytes into MSZIP blocks using the default compression level.
+///
+/// See [`deflate_bytes_mszip_conf`](fn.deflate_bytes_mszip_conf.html).
+///
+/// # Examples
+///
+/// ```
+/// use deflate::deflate_bytes_mszip;
+///
+/// let data = b"This is some test data";
+/// let blocks = deflate_bytes_mszip(data);
+/// # let _ = blocks;
+/// ```
+pub fn deflate_bytes_mszip(input: &[u8]) -> Vec<Vec<u8>> {
+    deflate_bytes_mszip_conf(input, Compression::Default)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_with_history, get_test_data};
+
+    #[test]
+    fn mszip_roundtrip() {
+        let data = get_test_data();
+        let blocks = deflate_bytes_mszip(&data);
+        assert_eq!(blocks.len(), data.len().div_ceil(MSZIP_BLOCK_SIZE));
+
+        let mut decompressed: Vec<u8> = Vec::with_capacity(data.len());
+        for block in &blocks {
+            assert_eq!(&block[..2], MSZIP_SIGNATURE);
+            let history_start = decompressed.len().saturating_sub(MSZIP_BLOCK_SIZE);
+            let res = decompress_with_history(&decompressed[history_start..], &block[2..]);
+            assert!(res.len() <= MSZIP_BLOCK_SIZE);
+            decompressed.extend_from_slice(&res);
+        }
+        assert!(decompressed == data);
+    }
+
+    #[test]
+    fn mszip_uses_history() {
+        // The second block is a copy of the first one, so it should compress to almost nothing
+        // if the previous block is used as history.
+        let data = get_test_data();
+        let mut input = data[..MSZIP_BLOCK_SIZE].to_vec();
+        input.extend_from_slice(&data[..MSZIP_BLOCK_SIZE]);
+        let blocks = deflate_bytes_mszip(&input);
+        assert_eq!(blocks.len(), 2);
+        // About two bytes for each maximum length match.
+        assert!(blocks[1].len() < 300);
+        assert!(
+            decompress_with_history(&input[..MSZIP_BLOCK_SIZE], &blocks[1][2..])
+                == data[..MSZIP_BLOCK_SIZE]
+        );
+    }
+
+    #[test]
+    fn mszip_empty() {
+        assert!(deflate_bytes_mszip(&[]).is_empty());
+    }
+}
@@ -1,8 +1,11 @@
-use std::u16;
+use std::cmp;
 
+use crate::allocator::{BufferAllocator, BufferBox, Zeroable};
+use crate::compression_options::BlockLimits;
 use crate::huffman_table::{
-    get_distance_code, get_length_code, END_OF_BLOCK_POSITION, NUM_DISTANCE_CODES,
-    NUM_LITERALS_AND_LENGTHS,
+    get_distance_code, get_length_code, num_extra_bits_for_distance_code,
+    num_extra_bits_for_length_code, END_OF_BLOCK_POSITION, FIXED_CODE_LENGTHS, LENGTH_BITS_START,
+    NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS,
 };
 use crate::lzvalue::LZValue;
 
@@ -18,42 +21,98 @@ pub type FrequencyType = u16;
 /// overflowing (which would degrade, or in the worst case break compression).
 pub const MAX_BUFFER_LENGTH: usize = 1024 * 31;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum BufferStatus {
     NotFull,
     Full,
 }
 
+/// Fixed size storage for the lz77 symbols of a block.
+struct Symbols([LZValue; MAX_BUFFER_LENGTH]);
+
+impl Default for Symbols {
+    #[inline]
+    fn default() -> Symbols {
+        Symbols([LZValue::literal(0); MAX_BUFFER_LENGTH])
+    }
+}
+
+// SAFETY: The literal 0 is represented by all zeroes.
+unsafe impl Zeroable for Symbols {}
+
 /// Struct that buffers lz77 data and keeps track of the usage of different codes
 pub struct DynamicWriter {
-    buffer: Vec<LZValue>,
+    /// The symbols of the current block, which are the first `len` entries. They are stored in a
+    /// boxed array rather than a vector so adding one is just a store and an increment, and the
+    /// same memory is reused for every block.
+    buffer: BufferBox<Symbols>,
+    len: usize,
     // The two last length codes are not actually used, but only participates in code construction
     // Therefore, we ignore them to get the correct number of lengths
     frequencies: [FrequencyType; NUM_LITERALS_AND_LENGTHS],
     distance_frequencies: [FrequencyType; NUM_DISTANCE_CODES],
+    /// The maximum number of values in the buffer before the block should end.
+    max_symbols: usize,
+    /// The number of input bytes covered by the buffer, and the maximum before the block should
+    /// end, or `u32::MAX` if there is no limit.
+    input_bytes: u32,
+    max_input_bytes: u32,
+    /// The estimated number of bits the buffer will take up when compressed, and the maximum
+    /// before the block should end, or `u32::MAX` if there is no limit.
+    estimated_bits: u32,
+    max_estimated_bits: u32,
+}
+
+/// Get the length of the fixed huffman code for a literal/length code and the number of extra
+/// bits, as an estimate of the size of the value.
+#[inline]
+fn estimate_length_bits(l_code_num: usize) -> u32 {
+    let extra = if l_code_num > END_OF_BLOCK_POSITION {
+        num_extra_bits_for_length_code((l_code_num - LENGTH_BITS_START as usize) as u8)
+    } else {
+        0
+    };
+    u32::from(FIXED_CODE_LENGTHS[l_code_num]) + u32::from(extra)
 }
 
+/// All distance codes are 5 bits long in the fixed huffman table.
+const FIXED_DISTANCE_CODE_LENGTH: u32 = 5;
+
+/// The length of the end of block code in the fixed huffman table, which every block ends with.
+const FIXED_END_OF_BLOCK_LENGTH: u32 = FIXED_CODE_LENGTHS[END_OF_BLOCK_POSITION] as u32;
+
 impl DynamicWriter {
     #[inline]
     pub fn check_buffer_length(&self) -> BufferStatus {
-        if self.buffer.len() >= MAX_BUFFER_LENGTH {
+        if self.len >= self.max_symbols
+            || self.input_bytes >= self.max_input_bytes
+            || self.estimated_bits >= self.max_estimated_bits
+        {
             BufferStatus::Full
         } else {
             BufferStatus::NotFull
         }
     }
 
+    #[inline]
+    fn push(&mut self, value: LZValue) {
+        debug_assert!(self.len < MAX_BUFFER_LENGTH);
+        self.buffer.0[self.len] = value;
+        self.len += 1;
+    }
+
     #[inline]
     pub fn write_literal(&mut self, literal: u8) -> BufferStatus {
-        debug_assert!(self.buffer.len() < MAX_BUFFER_LENGTH);
-        self.buffer.push(LZValue::literal(literal));
+        self.push(LZValue::literal(literal));
         self.frequencies[usize::from(literal)] += 1;
+        self.input_bytes += 1;
+        self.estimated_bits += u32::from(FIXED_CODE_LENGTHS[usize::from(literal)]);
         self.check_buffer_length()
     }
 
     #[inline]
     pub fn write_length_distance(&mut self, length: u16, distance: u16) -> BufferStatus {
-        self.buffer.push(LZValue::length_distance(length, distance));
+        self.push(LZValue::length_distance(length, distance));
         let l_code_num = get_length_code(length);
         // As we limit the buffer to 2^16 values, this should be safe from overflowing.
         self.frequencies[l_code_num] += 1;
@@ -61,22 +120,55 @@ impl DynamicWriter {
         let d_code_num = get_distance_code(distance);
         // The compiler seems to be able to evade the bounds check here somehow.
         self.distance_frequencies[usize::from(d_code_num)] += 1;
+
+        self.input_bytes += u32::from(length);
+        self.estimated_bits += estimate_length_bits(l_code_num)
+            + FIXED_DISTANCE_CODE_LENGTH
+            + u32::from(num_extra_bits_for_distance_code(d_code_num));
         self.check_buffer_length()
     }
 
     pub fn buffer_length(&self) -> usize {
-        self.buffer.len()
+        self.len
     }
 
+    #[cfg(test)]
     pub fn get_buffer(&self) -> &[LZValue] {
-        &self.buffer
+        &self.buffer.0[..self.len]
+    }
+
+    /// Take the symbols out of the buffer in order, leaving it empty for the next block.
+    ///
+    /// The frequencies are kept until `clear_frequencies` is called.
+    pub fn drain(&mut self) -> &[LZValue] {
+        let len = self.len;
+        self.clear_data();
+        &self.buffer.0[..len]
     }
 
+    #[cfg(test)]
     pub fn new() -> DynamicWriter {
+        DynamicWriter::with_limits(BlockLimits::DEFAULT, &BufferAllocator::default())
+    }
+
+    /// Create a new writer, which signals that the block should end when any of `limits` have
+    /// been reached, allocating the buffer using `alloc`.
+    pub fn with_limits(limits: BlockLimits, alloc: &BufferAllocator) -> DynamicWriter {
+        let max_symbols = match usize::from(limits.max_symbols) {
+            0 => MAX_BUFFER_LENGTH,
+            n => cmp::min(n, MAX_BUFFER_LENGTH),
+        };
+        let no_limit_if_zero = |limit| if limit == 0 { u32::MAX } else { limit };
         let mut w = DynamicWriter {
-            buffer: Vec::with_capacity(MAX_BUFFER_LENGTH),
+            buffer: alloc.boxed_default(),
+            len: 0,
             frequencies: [0; NUM_LITERALS_AND_LENGTHS],
             distance_frequencies: [0; NUM_DISTANCE_CODES],
+            max_symbols,
+            input_bytes: 0,
+            max_input_bytes: no_limit_if_zero(limits.max_input_bytes),
+            estimated_bits: FIXED_END_OF_BLOCK_LENGTH,
+            max_estimated_bits: no_limit_if_zero(limits.max_estimated_bits),
         };
         // This will always be 1,
         // since there will always only be one end of block marker in each block
@@ -88,7 +180,7 @@ impl DynamicWriter {
     /// that avoids bothering to lookup a distance code.
     #[inline]
     pub fn write_length_rle(&mut self, length: u16) -> BufferStatus {
-        self.buffer.push(LZValue::length_distance(length, 1));
+        self.push(LZValue::length_distance(length, 1));
         let l_code_num = get_length_code(length);
         // As we limit the buffer to 2^16 values, this should be safe from overflowing.
         if cfg!(debug_assertions) {
@@ -103,9 +195,16 @@ impl DynamicWriter {
             }
         }
         self.distance_frequencies[0] += 1;
+        self.input_bytes += u32::from(length);
+        self.estimated_bits += estimate_length_bits(l_code_num) + FIXED_DISTANCE_CODE_LENGTH;
         self.check_buffer_length()
     }
 
+    /// Get the number of times each literal/length code and each distance code is used in the
+    /// current block.
+    ///
+    /// The end of block code (`256`) is always counted once, as every block ends with it, so the
+    /// frequencies can be used to work out the exact size of the block.
     pub fn get_frequencies(&self) -> (&[u16], &[u16]) {
         (&self.frequencies, &self.distance_frequencies)
     }
@@ -117,7 +216,9 @@ impl DynamicWriter {
     }
 
     pub fn clear_data(&mut self) {
-        self.buffer.clear()
+        self.len = 0;
+        self.input_bytes = 0;
+        self.estimated_bits = FIXED_END_OF_BLOCK_LENGTH;
     }
 
     pub fn clear(&mut self) {
@@ -136,12 +237,110 @@ mod test {
     fn array_bounds() {
         let w = DynamicWriter::new();
 
-        for i in 0..u16::max_value() {
+        for i in 0..u16::MAX {
             assert!(get_length_code(i) < w.frequencies.len());
         }
 
-        for i in 0..u16::max_value() {
+        for i in 0..u16::MAX {
             assert!(get_distance_code(i) < w.distance_frequencies.len() as u8);
         }
     }
+
+    #[test]
+    fn block_limits() {
+        let mut w = DynamicWriter::with_limits(
+            BlockLimits {
+                max_symbols: 3,
+                ..BlockLimits::DEFAULT
+            },
+            &BufferAllocator::default(),
+        );
+        assert_eq!(w.write_literal(1), BufferStatus::NotFull);
+        assert_eq!(w.write_length_distance(100, 1), BufferStatus::NotFull);
+        assert_eq!(w.write_literal(1), BufferStatus::Full);
+        w.clear();
+        assert_eq!(w.write_literal(1), BufferStatus::NotFull);
+
+        let mut w = DynamicWriter::with_limits(
+            BlockLimits {
+                max_input_bytes: 100,
+                ..BlockLimits::DEFAULT
+            },
+            &BufferAllocator::default(),
+        );
+        assert_eq!(w.write_literal(1), BufferStatus::NotFull);
+        assert_eq!(w.write_length_distance(98, 1), BufferStatus::NotFull);
+        assert_eq!(w.write_length_rle(3), BufferStatus::Full);
+
+        // Literals 0-143 use 8 bits in the fixed huffman table, and the end of block code 7.
+        let mut w = DynamicWriter::with_limits(
+            BlockLimits {
+                max_estimated_bits: 8 * 10 + 7,
+                ..BlockLimits::DEFAULT
+            },
+            &BufferAllocator::default(),
+        );
+        for _ in 0..2 {
+            for _ in 0..9 {
+                assert_eq!(w.write_literal(b'a'), BufferStatus::NotFull);
+            }
+            assert_eq!(w.write_literal(b'a'), BufferStatus::Full);
+            w.clear();
+        }
+    }
+
+    #[test]
+    fn end_of_block_frequency() {
+        let mut w = DynamicWriter::new();
+        assert_eq!(w.get_frequencies().0[END_OF_BLOCK_POSITION], 1);
+        w.write_literal(1);
+        w.write_length_distance(10, 1);
+        w.drain();
+        assert_eq!(w.get_frequencies().0[END_OF_BLOCK_POSITION], 1);
+        w.clear();
+        assert_eq!(w.get_frequencies().0[END_OF_BLOCK_POSITION], 1);
+        assert_eq!(w.get_frequencies().0.iter().sum::<u16>(), 1);
+    }
+
+    #[test]
+    fn block_limits_max_symbols_clamped() {
+        for &max_symbols in &[0, u16::MAX] {
+            let w = DynamicWriter::with_limits(
+                BlockLimits {
+                    max_symbols,
+                    ..BlockLimits::DEFAULT
+                },
+                &BufferAllocator::default(),
+            );
+            assert_eq!(w.max_symbols, MAX_BUFFER_LENGTH);
+        }
+    }
+
+    #[test]
+    fn drain() {
+        let mut w = DynamicWriter::new();
+        for _ in 0..2 {
+            w.write_literal(b'a');
+            w.write_length_distance(10, 1);
+            w.write_length_rle(3);
+            let expected = [
+                LZValue::literal(b'a'),
+                LZValue::length_distance(10, 1),
+                LZValue::length_distance(3, 1),
+            ];
+            assert_eq!(w.get_buffer(), expected);
+            assert_eq!(w.drain(), &expected[..]);
+            // The buffer is empty for the next block, but the frequencies are kept.
+            assert_eq!(w.buffer_length(), 0);
+            assert_eq!(w.input_bytes, 0);
+            assert_eq!(w.get_frequencies().0[usize::from(b'a')], 1);
+            w.clear_frequencies();
+        }
+
+        // The whole buffer can be filled.
+        for _ in 0..MAX_BUFFER_LENGTH {
+            w.write_literal(0);
+        }
+        assert_eq!(w.drain().len(), MAX_BUFFER_LENGTH);
+    }
 }
@@ -0,0 +1,210 @@
+//! This module contains a writer that scatters the compressed output into a list of fixed-size
+//! pages rather than one contiguous buffer, for storage that works on pages, like file systems
+//! and databases, so the output doesn't have to be copied into page-sized storage afterwards.
+
+use std::io::{self, Write};
+
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::writer::compress_until_done;
+
+/// The default size of the pages used by `PageWriter`.
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+
+/// A writer that stores the data written to it in a list of owned, fixed-size pages.
+///
+/// Every page is exactly [`page_size`](#method.page_size) bytes long. The last page is padded
+/// with zeroes if the data does not fill it completely, so [`len`](#method.len) has to be used
+/// to find where the data ends.
+///
+/// This can be used as the wrapped writer of any of the encoders in
+/// [`write`](write/index.html).
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Write;
+///
+/// use deflate::write::DeflateEncoder;
+/// use deflate::{Compression, PageWriter};
+///
+/// let mut encoder = DeflateEncoder::new(PageWriter::with_page_size(512), Compression::Default);
+/// encoder.write_all(b"This is some test data").unwrap();
+/// let output = encoder.finish().unwrap();
+/// assert_eq!(output.pages().len(), 1);
+/// assert!(output.len() < 512);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PageWriter {
+    page_size: usize,
+    pages: Vec<Box<[u8]>>,
+    len: usize,
+}
+
+impl PageWriter {
+    /// Create a new writer using pages of [`DEFAULT_PAGE_SIZE`](constant.DEFAULT_PAGE_SIZE.html)
+    /// bytes.
+    pub fn new() -> PageWriter {
+        PageWriter::with_page_size(DEFAULT_PAGE_SIZE)
+    }
+
+    /// Create a new writer using pages of `page_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page_size` is 0.
+    pub fn with_page_size(page_size: usize) -> PageWriter {
+        assert!(page_size > 0, "Page size can't be 0!");
+        PageWriter {
+            page_size,
+            pages: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// The size of each of the pages.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// The total number of bytes written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if nothing has been written.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The pages written so far.
+    pub fn pages(&self) -> &[Box<[u8]>] {
+        &self.pages
+    }
+
+    /// Consume the writer, returning the pages.
+    pub fn into_pages(self) -> Vec<Box<[u8]>> {
+        self.pages
+    }
+}
+
+impl Default for PageWriter {
+    fn default() -> PageWriter {
+        PageWriter::new()
+    }
+}
+
+impl Write for PageWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            let pos = self.len % self.page_size;
+            if pos == 0 {
+                self.pages.push(vec![0; self.page_size].into_boxed_slice());
+            }
+            let page = self.pages.last_mut().expect("No pages!");
+            let n = remaining.len().min(self.page_size - pos);
+            page[pos..pos + n].copy_from_slice(&remaining[..n]);
+            self.len += n;
+            remaining = &remaining[n..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compress the given slice of bytes with DEFLATE compression into pages of `page_size` bytes.
+///
+/// # Panics
+///
+/// Panics if `page_size` is 0.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes_paged_conf, Compression};
+///
+/// let data = vec![5; 100000];
+/// let output = deflate_bytes_paged_conf(&data, Compression::Fast, 64);
+/// assert!(output.pages().iter().all(|page| page.len() == 64));
+/// ```
+pub fn deflate_bytes_paged_conf<O: Into<CompressionOptions>>(
+    input: &[u8],
+    options: O,
+    page_size: usize,
+) -> PageWriter {
+    let options = options.into();
+    let filtered;
+    let input = if options.pre_filter.is_active() {
+        filtered = options.pre_filter.apply(input);
+        &filtered[..]
+    } else {
+        input
+    };
+
+    let mut deflate_state = Box::new(DeflateState::new(
+        options,
+        PageWriter::with_page_size(page_size),
+    ));
+    compress_until_done(input, &mut deflate_state, Flush::Finish).expect("Write error!");
+    deflate_state.inner.take().expect("Missing writer!")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+    use crate::{deflate_bytes_conf, Compression};
+
+    /// Join the pages together, dropping the padding at the end.
+    fn join(output: &PageWriter) -> Vec<u8> {
+        let mut joined = output.pages().concat();
+        joined.truncate(output.len());
+        joined
+    }
+
+    #[test]
+    fn page_writer_write() {
+        let mut w = PageWriter::with_page_size(4);
+        assert!(w.is_empty());
+        w.write_all(&[1, 2, 3]).unwrap();
+        w.write_all(&[4, 5, 6, 7, 8, 9, 10, 11, 12]).unwrap();
+        w.write_all(&[]).unwrap();
+        w.write_all(&[13]).unwrap();
+        assert_eq!(w.len(), 13);
+        let pages = w.into_pages();
+        assert_eq!(
+            pages,
+            vec![
+                vec![1, 2, 3, 4].into_boxed_slice(),
+                vec![5, 6, 7, 8].into_boxed_slice(),
+                vec![9, 10, 11, 12].into_boxed_slice(),
+                vec![13, 0, 0, 0].into_boxed_slice(),
+            ]
+        );
+    }
+
+    #[test]
+    fn paged_matches_contiguous() {
+        let data = get_test_data();
+        for &page_size in &[1, 100, DEFAULT_PAGE_SIZE] {
+            let output = deflate_bytes_paged_conf(&data, Compression::Default, page_size);
+            assert!(output.pages().iter().all(|page| page.len() == page_size));
+            assert_eq!(output.pages().len(), output.len().div_ceil(page_size));
+            let joined = join(&output);
+            assert!(joined == deflate_bytes_conf(&data, Compression::Default));
+            assert!(decompress_to_end(&joined) == data);
+        }
+    }
+
+    #[test]
+    fn paged_empty() {
+        let output = deflate_bytes_paged_conf(&[], Compression::Default, DEFAULT_PAGE_SIZE);
+        assert_eq!(output.pages().len(), 1);
+        assert!(decompress_to_end(&join(&output)).is_empty());
+    }
+}
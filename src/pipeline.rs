@@ -0,0 +1,519 @@
+//! This module contains a pipelined mode for compressing data from a reader, where reading the
+//! input, compressing it and writing the output happen at the same time on different threads.
+//!
+//! The input is split into chunks, which are compressed independently by a number of
+//! compressor threads, each using the end of the previous chunk as history so matches can still
+//! refer back across the chunk boundaries. Each chunk except the last one ends with a sync
+//! flush, which aligns the output to a byte boundary, so the compressed chunks can simply be
+//! written one after another to make up a single stream. (This is the same approach `pigz`
+//! uses.) The threads are connected by bounded channels, so memory use stays bounded no matter
+//! how far ahead reading gets compared to writing.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::{panic, thread};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::chained_hash_table::WINDOW_SIZE;
+use crate::checksum::{Adler32Checksum, NoChecksum, RollingChecksum};
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::writer::compress_until_done;
+use crate::zlib::{write_zlib_header, CompressionLevel};
+
+/// The smallest chunk size used, so the history for each chunk can always be taken from the
+/// previous one alone.
+const MIN_CHUNK_SIZE: usize = WINDOW_SIZE;
+
+/// Settings for the pipelined mode, see [`compress_pipelined`](fn.compress_pipelined.html).
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::PipelineOptions;
+///
+/// let pipeline = PipelineOptions {
+///     threads: 2,
+///     ..PipelineOptions::default()
+/// };
+/// # let _ = pipeline;
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct PipelineOptions {
+    /// The number of threads compressing the data, in addition to the reader thread and the
+    /// calling thread, which writes the output.
+    ///
+    /// * `0`: Use the available parallelism of the system.
+    /// * Default value: `0`
+    pub threads: usize,
+    /// The number of bytes of input in each chunk compressed by a compressor thread.
+    ///
+    /// Smaller chunks give slightly worse compression, as the compressed data for each chunk
+    /// has to end on a byte boundary, and the block boundaries can't be chosen freely.
+    ///
+    /// * Values below 32 KiB are treated as 32 KiB.
+    /// * Default value: 128 KiB
+    pub chunk_size: usize,
+    /// The number of chunks that can be waiting to be compressed, and the number of compressed
+    /// chunks that can be waiting to be written.
+    ///
+    /// This bounds how far reading can get ahead of compressing, and compressing ahead of
+    /// writing, and thus the memory used.
+    ///
+    /// * `0`: Twice the number of compressor threads.
+    /// * Default value: `0`
+    pub queue_len: usize,
+}
+
+impl PipelineOptions {
+    /// The default settings.
+    pub const DEFAULT: PipelineOptions = PipelineOptions {
+        threads: 0,
+        chunk_size: 128 * 1024,
+        queue_len: 0,
+    };
+
+    /// The number of compressor threads to use.
+    fn threads(&self) -> usize {
+        if self.threads == 0 {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            self.threads
+        }
+    }
+}
+
+impl Default for PipelineOptions {
+    fn default() -> PipelineOptions {
+        PipelineOptions::DEFAULT
+    }
+}
+
+/// A chunk of input to be compressed.
+struct Job {
+    index: u64,
+    /// The end of the previous chunk, which matches can refer back to.
+    history: Vec<u8>,
+    data: Vec<u8>,
+    last: bool,
+}
+
+type JobResult = (u64, io::Result<Vec<u8>>);
+
+/// Read until `buf` has `len` bytes or the end of the input is reached.
+fn read_chunk<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read the input in chunks and send them to the compressor threads, updating the checksum
+/// with the data as it's read.
+fn read_chunks<R: Read, C: RollingChecksum>(
+    mut reader: R,
+    mut checksum: C,
+    chunk_size: usize,
+    jobs: SyncSender<Job>,
+) -> io::Result<C> {
+    let mut history = Vec::new();
+    let mut current = read_chunk(&mut reader, chunk_size)?;
+    for index in 0.. {
+        // Read ahead to find out whether this is the last chunk, as that one has to be
+        // finished rather than flushed.
+        let next = if current.len() < chunk_size {
+            Vec::new()
+        } else {
+            read_chunk(&mut reader, chunk_size)?
+        };
+        let last = next.is_empty();
+        checksum.update_from_slice(&current);
+        let next_history = current[current.len().saturating_sub(WINDOW_SIZE)..].to_vec();
+        let job = Job {
+            index,
+            history,
+            data: current,
+            last,
+        };
+        if jobs.send(job).is_err() || last {
+            // If the compressor threads have stopped, the writer has run into an error, which
+            // it will report.
+            break;
+        }
+        history = next_history;
+        current = next;
+    }
+    Ok(checksum)
+}
+
+/// Compress one chunk, leaving `deflate_state` ready for the next one.
+fn compress_job(deflate_state: &mut DeflateState<Vec<u8>>, job: &Job) -> io::Result<Vec<u8>> {
+    deflate_state.add_history(&job.history);
+    let flush = if job.last { Flush::Finish } else { Flush::Sync };
+    compress_until_done(&job.data, deflate_state, flush)?;
+    deflate_state.reset(Vec::new())
+}
+
+/// Compress the chunks from `jobs` until there are no more, and send the results to the writer.
+fn compress_chunks(
+    options: CompressionOptions,
+    jobs: &Mutex<Receiver<Job>>,
+    results: SyncSender<JobResult>,
+) {
+    let mut deflate_state = Box::new(DeflateState::new(options, Vec::new()));
+    loop {
+        // Only hold the lock while waiting for a job, not while compressing it.
+        let job = match jobs.lock().map(|jobs| jobs.recv()) {
+            Ok(Ok(job)) => job,
+            _ => return,
+        };
+        let result = compress_job(&mut deflate_state, &job);
+        let failed = result.is_err();
+        if results.send((job.index, result)).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Compress the data from `reader` to `writer` using the pipeline, returning the checksum of the
+/// input.
+fn compress_pipeline<R, W, C>(
+    reader: R,
+    writer: &mut W,
+    options: CompressionOptions,
+    pipeline: PipelineOptions,
+    checksum: C,
+) -> io::Result<C>
+where
+    R: Read + Send,
+    W: Write,
+    C: RollingChecksum + Send,
+{
+    let threads = pipeline.threads();
+    let queue_len = if pipeline.queue_len == 0 {
+        threads * 2
+    } else {
+        pipeline.queue_len
+    };
+    let chunk_size = pipeline.chunk_size.max(MIN_CHUNK_SIZE);
+
+    let (job_sender, job_receiver) = mpsc::sync_channel(queue_len);
+    let (result_sender, result_receiver) = mpsc::sync_channel(queue_len);
+    // Shared by the compressor threads, so it's dropped once they have all stopped, which
+    // makes the reader stop as well.
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+    thread::scope(|s| {
+        let reader = s.spawn(move || read_chunks(reader, checksum, chunk_size, job_sender));
+        for _ in 0..threads {
+            let results = result_sender.clone();
+            let jobs = Arc::clone(&job_receiver);
+            s.spawn(move || compress_chunks(options, &jobs, results));
+        }
+        // Only the compressor threads should keep the channels open.
+        drop(result_sender);
+        drop(job_receiver);
+
+        // Write the compressed chunks in order as they come in. Returning early drops the
+        // receiver, which makes the other threads stop.
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (index, result) in result_receiver {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&next) {
+                writer.write_all(&result?)?;
+                next += 1;
+            }
+        }
+
+        // The compressor threads have stopped, so either all the chunks have been written,
+        // or reading failed.
+        match reader.join() {
+            Ok(res) => res,
+            Err(e) => panic::resume_unwind(e),
+        }
+    })
+}
+
+/// Compress the data from `reader` with DEFLATE compression to `writer`, reading, compressing
+/// and writing the data at the same time on different threads.
+///
+/// This is meant for large inputs where both the I/O and the compression take a while, such as
+/// when reading from or writing to a slow disk or a network file system, so the time spent
+/// waiting for I/O is not added on top of the time spent compressing. The input is read by a
+/// separate thread, compressed in chunks by the number of threads given by `pipeline`, and the
+/// output is written by the calling thread. See
+/// [`PipelineOptions`](struct.PipelineOptions.html) for the settings.
+///
+/// The output is a single DEFLATE stream, but it is not the same as what the other functions
+/// produce, as each chunk ends with a sync flush. The `pre_filter` compression option is not
+/// supported.
+///
+/// Returns the writer once all of the input has been compressed and written to it.
+///
+/// # Errors
+///
+/// Returns the first error from reading the input, compressing it or writing the output, or an
+/// error of kind `InvalidInput` if `block_limits.max_blocks` or `pre_filter` is set in the
+/// options.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{compress_pipelined, Compression, PipelineOptions};
+///
+/// let data = vec![b'a'; 1000000];
+/// let compressed = compress_pipelined(
+///     &data[..],
+///     Vec::new(),
+///     Compression::Default,
+///     PipelineOptions::default(),
+/// )
+/// .unwrap();
+/// # let _ = compressed;
+/// ```
+pub fn compress_pipelined<R, W, O>(
+    reader: R,
+    mut writer: W,
+    options: O,
+    pipeline: PipelineOptions,
+) -> io::Result<W>
+where
+    R: Read + Send,
+    W: Write,
+    O: Into<CompressionOptions>,
+{
+    compress_pipeline(
+        reader,
+        &mut writer,
+        pipeline_options(options.into())?,
+        pipeline,
+        NoChecksum::new(),
+    )?;
+    Ok(writer)
+}
+
+/// Compress the data from `reader` with DEFLATE compression to `writer`, including a zlib header
+/// and trailer, reading, compressing and writing the data at the same time on different threads.
+///
+/// See [`compress_pipelined`](fn.compress_pipelined.html) for details.
+pub fn compress_pipelined_zlib<R, W, O>(
+    reader: R,
+    mut writer: W,
+    options: O,
+    pipeline: PipelineOptions,
+) -> io::Result<W>
+where
+    R: Read + Send,
+    W: Write,
+    O: Into<CompressionOptions>,
+{
+    write_zlib_header(&mut writer, CompressionLevel::Default)?;
+    let checksum = compress_pipeline(
+        reader,
+        &mut writer,
+        pipeline_options(options.into())?,
+        pipeline,
+        Adler32Checksum::new(),
+    )?;
+    writer.write_u32::<BigEndian>(checksum.current_hash())?;
+    Ok(writer)
+}
+
+/// Compress the data from `reader` with DEFLATE compression to `writer`, including a gzip header
+/// and trailer, reading, compressing and writing the data at the same time on different threads.
+///
+/// See [`compress_pipelined`](fn.compress_pipelined.html) for details.
+#[cfg(feature = "gzip")]
+pub fn compress_pipelined_gzip<R, W, O>(
+    reader: R,
+    mut writer: W,
+    options: O,
+    pipeline: PipelineOptions,
+    gzip_header: gzip_header::GzBuilder,
+) -> io::Result<W>
+where
+    R: Read + Send,
+    W: Write,
+    O: Into<CompressionOptions>,
+{
+    use byteorder::LittleEndian;
+
+    writer.write_all(&crate::gzip::header_from_builder(gzip_header))?;
+    let crc = compress_pipeline(
+        reader,
+        &mut writer,
+        pipeline_options(options.into())?,
+        pipeline,
+        gzip_header::Crc::new(),
+    )?;
+    writer.write_u32::<LittleEndian>(crc.sum())?;
+    writer.write_u32::<LittleEndian>(crc.amt_as_u32())?;
+    Ok(writer)
+}
+
+/// Check that the options can be used in a pipeline. The pre-filters need to see the input as
+/// a whole, and the block count would be per chunk rather than for the whole stream.
+fn pipeline_options(options: CompressionOptions) -> io::Result<CompressionOptions> {
+    let unsupported = if options.pre_filter.is_active() {
+        "pre_filter"
+    } else if options.block_limits.max_blocks != 0 {
+        "max_blocks"
+    } else {
+        return Ok(options);
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} is not supported when compressing in a pipeline",
+            unsupported
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_random_data, get_test_data};
+    use crate::Compression;
+
+    fn pipeline(threads: usize, chunk_size: usize) -> PipelineOptions {
+        PipelineOptions {
+            threads,
+            chunk_size,
+            queue_len: 0,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut data = get_test_data();
+        data.extend_from_slice(&get_random_data(50000));
+        for &len in &[0, 1, 32768, 65536, 100000, data.len()] {
+            let data = &data[..len];
+            for &threads in &[1, 3] {
+                let compressed =
+                    compress_pipelined(data, Vec::new(), Compression::Fast, pipeline(threads, 0))
+                        .unwrap();
+                assert_eq!(decompress_to_end(&compressed), data, "len {}", len);
+
+                let compressed = compress_pipelined_zlib(
+                    data,
+                    Vec::new(),
+                    Compression::Fast,
+                    pipeline(threads, 0),
+                )
+                .unwrap();
+                assert_eq!(decompress_zlib(&compressed), data, "len {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_across_chunks() {
+        // Each chunk is the same as the previous one, so they should all be one long match.
+        let chunk = get_random_data(MIN_CHUNK_SIZE);
+        let data = chunk.repeat(8);
+        let compressed = compress_pipelined(
+            &data[..],
+            Vec::new(),
+            Compression::Default,
+            pipeline(4, MIN_CHUNK_SIZE),
+        )
+        .unwrap();
+        assert_eq!(decompress_to_end(&compressed), data);
+        assert!(compressed.len() < chunk.len() + 2000);
+    }
+
+    /// A reader that fails after giving `len` bytes.
+    struct FailingReader {
+        len: usize,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.len == 0 {
+                return Err(io::Error::other("read failed"));
+            }
+            let n = buf.len().min(self.len);
+            buf[..n].iter_mut().for_each(|b| *b = 1);
+            self.len -= n;
+            Ok(n)
+        }
+    }
+
+    /// A writer that fails after taking `len` bytes.
+    #[derive(Debug)]
+    struct FailingWriter {
+        len: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.len == 0 {
+                return Err(io::Error::other("write failed"));
+            }
+            let n = buf.len().min(self.len);
+            self.len -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = compress_pipelined(
+            FailingReader { len: 1000000 },
+            Vec::new(),
+            Compression::Fast,
+            pipeline(2, 0),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "read failed");
+
+        let data = get_random_data(1000000);
+        let err = compress_pipelined(
+            &data[..],
+            FailingWriter { len: 100000 },
+            Compression::Fast,
+            pipeline(2, 0),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "write failed");
+
+        let options = crate::CompressionOptions {
+            block_limits: crate::BlockLimits {
+                max_blocks: 1,
+                ..crate::BlockLimits::default()
+            },
+            ..crate::CompressionOptions::default()
+        };
+        let err = compress_pipelined(&data[..], Vec::new(), options, pipeline(2, 0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn pre_filter_rejected() {
+        let data = get_test_data();
+        let options = crate::CompressionOptions {
+            pre_filter: crate::PreFilter {
+                delta: 1,
+                transpose: 0,
+            },
+            ..crate::CompressionOptions::default()
+        };
+        let err =
+            compress_pipelined_zlib(&data[..], Vec::new(), options, pipeline(2, 0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "pre_filter is not supported when compressing in a pipeline"
+        );
+    }
+}
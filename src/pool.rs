@@ -0,0 +1,385 @@
+//! This module contains a pool of compressors that can be shared between threads.
+//!
+//! Setting up a compressor involves allocating and initialising a fairly large amount of memory
+//! for the hash tables and buffers, which can end up taking more time than compressing the data
+//! when compressing many small pieces of data, e.g in a server compressing responses. Reusing
+//! compressors avoids this.
+
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::checksum::{Adler32Checksum, NoChecksum, RollingChecksum};
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::writer::compress_until_done;
+use crate::zlib;
+
+/// The maximum number of separately locked lists of idle compressors in a pool.
+const NUM_SHARDS: usize = 8;
+
+type State = Box<DeflateState<Vec<u8>>>;
+
+/// A thread-safe pool of compressors using the same compression options.
+///
+/// Compressors are checked out with [`get`](#method.get), and returned to the pool when the
+/// [`PooledCompressor`](struct.PooledCompressor.html) is dropped. If there is no idle compressor
+/// in the pool, a new one is created. At most `max_idle` compressors are kept in the pool,
+/// any compressors returned when it's full are dropped.
+///
+/// The idle compressors are split between several separately locked lists to reduce
+/// contention, so a new compressor may occasionally be created even if there is an idle one in
+/// a list that is currently locked by another thread.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{Compression, CompressorPool};
+///
+/// let pool = CompressorPool::new(Compression::Default, 16);
+/// let compressed = pool.get().compress_zlib(b"This is some test data");
+/// // The compressor was returned to the pool.
+/// assert_eq!(pool.idle(), 1);
+/// # let _ = compressed;
+/// ```
+pub struct CompressorPool {
+    options: CompressionOptions,
+    /// The preset dictionary used by all the compressors, if any, and its adler32 checksum.
+    dictionary: Option<(Arc<[u8]>, u32)>,
+    shard_capacity: usize,
+    shards: Vec<Mutex<Vec<State>>>,
+    next_shard: AtomicUsize,
+}
+
+impl CompressorPool {
+    /// Create a new, empty, pool of compressors using the provided compression options, keeping
+    /// at most `max_idle` idle compressors.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_limits.max_blocks` is set or `verify` is enabled in the options, as the
+    /// compressors have no way to report the errors they can cause.
+    pub fn new<O: Into<CompressionOptions>>(options: O, max_idle: usize) -> CompressorPool {
+        let options = options.into();
+        assert!(
+            options.block_limits.max_blocks == 0 && !options.verify,
+            "CompressorPool does not support max_blocks or verify!"
+        );
+        let num_shards = max_idle.clamp(1, NUM_SHARDS);
+        CompressorPool {
+            options,
+            dictionary: None,
+            shard_capacity: max_idle.div_ceil(num_shards),
+            shards: (0..num_shards).map(|_| Mutex::new(Vec::new())).collect(),
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+
+    /// Create a new, empty, pool of compressors using the provided compression options and
+    /// preset dictionary, keeping at most `max_idle` idle compressors.
+    ///
+    /// Panics under the same conditions as [`new`](#method.new).
+    ///
+    /// The dictionary is shared by all the compressors rather than copied into each of them,
+    /// and loaded into the window of the compressor at the start of each compression, see
+    /// [`ZlibEncoder::with_dictionary`](write/struct.ZlibEncoder.html#method.with_dictionary).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    ///
+    /// use deflate::{Compression, CompressorPool};
+    ///
+    /// let dictionary: Arc<[u8]> = Arc::from(&b"{\"id\": 0, \"status\": \"active\"}"[..]);
+    /// let pool = CompressorPool::with_dictionary(Compression::Default, 16, dictionary);
+    /// let compressed = pool.get().compress_zlib(b"{\"id\": 1, \"status\": \"active\"}");
+    /// # let _ = compressed;
+    /// ```
+    pub fn with_dictionary<O: Into<CompressionOptions>>(
+        options: O,
+        max_idle: usize,
+        dictionary: Arc<[u8]>,
+    ) -> CompressorPool {
+        let mut checksum = Adler32Checksum::new();
+        checksum.update_from_slice(&dictionary);
+        CompressorPool {
+            dictionary: Some((dictionary, checksum.current_hash())),
+            ..CompressorPool::new(options, max_idle)
+        }
+    }
+
+    /// The compression options used by the compressors in the pool.
+    pub fn options(&self) -> CompressionOptions {
+        self.options
+    }
+
+    /// The number of idle compressors currently in the pool.
+    pub fn idle(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).len()).sum()
+    }
+
+    /// Check out a compressor from the pool, or create a new one if there are no idle ones.
+    pub fn get(&self) -> PooledCompressor<'_> {
+        let start = self.next_shard.fetch_add(1, Ordering::Relaxed);
+        let state = self
+            .shards_from(start)
+            .find_map(|shard| shard.try_lock().ok().and_then(|mut idle| idle.pop()))
+            .unwrap_or_else(|| Box::new(DeflateState::new(self.options, Vec::new())));
+        PooledCompressor {
+            pool: self,
+            state: Some(state),
+        }
+    }
+
+    /// Return a compressor to the pool, dropping it if the pool is full.
+    fn put(&self, state: State) {
+        let start = self.next_shard.fetch_add(1, Ordering::Relaxed);
+        let shard = self
+            .shards_from(start)
+            .filter_map(|shard| shard.try_lock().ok())
+            .find(|idle| idle.len() < self.shard_capacity);
+        // If all the shards are busy, wait for one of them.
+        let mut idle = shard.unwrap_or_else(|| lock(&self.shards[start % self.shards.len()]));
+        if idle.len() < self.shard_capacity {
+            idle.push(state);
+        }
+    }
+
+    /// Iterate over all the shards, starting at the one with index `start` (wrapping around).
+    fn shards_from(&self, start: usize) -> impl Iterator<Item = &Mutex<Vec<State>>> {
+        let start = start % self.shards.len();
+        self.shards[start..].iter().chain(&self.shards[..start])
+    }
+}
+
+impl fmt::Debug for CompressorPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompressorPool")
+            .field("options", &self.options)
+            .field("idle", &self.idle())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Lock a shard, ignoring poisoning, as the lists are never left in an invalid state.
+fn lock(shard: &Mutex<Vec<State>>) -> MutexGuard<'_, Vec<State>> {
+    shard.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A compressor checked out from a [`CompressorPool`](struct.CompressorPool.html).
+///
+/// The compressor is reset after each use, and returned to the pool when dropped.
+pub struct PooledCompressor<'a> {
+    pool: &'a CompressorPool,
+    state: Option<State>,
+}
+
+impl PooledCompressor<'_> {
+    /// Compress `input` with DEFLATE compression.
+    pub fn compress(&mut self, input: &[u8]) -> Vec<u8> {
+        let output = Vec::with_capacity(input.len() / 3);
+        self.compress_with(input, output, NoChecksum::new()).0
+    }
+
+    /// Compress `input` with DEFLATE compression into `out`, replacing its contents, so the
+    /// memory allocated for the output can be reused along with the compressor.
+    pub fn compress_to_buf(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        let mut output = mem::take(out);
+        output.clear();
+        output.reserve(input.len() / 3);
+        *out = self.compress_with(input, output, NoChecksum::new()).0;
+    }
+
+    /// Compress `input` with DEFLATE compression, including a zlib header and trailer.
+    pub fn compress_zlib(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(input.len() / 3);
+        match self.pool.dictionary {
+            Some((_, dict_id)) => {
+                zlib::write_zlib_header_dict(&mut output, zlib::CompressionLevel::Default, dict_id)
+            }
+            None => zlib::write_zlib_header(&mut output, zlib::CompressionLevel::Default),
+        }
+        .expect("Write error when writing zlib header!");
+        let (mut output, hash) = self.compress_with(input, output, Adler32Checksum::new());
+        output
+            .write_u32::<BigEndian>(hash)
+            .expect("Write error when writing checksum!");
+        output
+    }
+
+    /// Compress `input`, appending the output to `output`, and return it along with the
+    /// checksum of the (filtered) input.
+    fn compress_with<RC: RollingChecksum>(
+        &mut self,
+        input: &[u8],
+        output: Vec<u8>,
+        mut checksum: RC,
+    ) -> (Vec<u8>, u32) {
+        let deflate_state = self.state.as_mut().expect("Missing compressor!");
+        let filtered;
+        let input = if deflate_state.compression_options.pre_filter.is_active() {
+            filtered = deflate_state.compression_options.pre_filter.apply(input);
+            &filtered[..]
+        } else {
+            input
+        };
+        checksum.update_from_slice(input);
+
+        // The state is always reset after use, so it holds an empty writer at this point.
+        deflate_state.inner = Some(output);
+        if let Some((ref dictionary, _)) = self.pool.dictionary {
+            deflate_state.set_dictionary(dictionary);
+        }
+        // Writing to a Vec can't fail, and the options that can make compressing fail are
+        // rejected when creating the pool.
+        let output = compress_until_done(input, deflate_state, Flush::Finish)
+            .and_then(|_| deflate_state.reset(Vec::new()))
+            .expect("Write error!");
+        (output, checksum.current_hash())
+    }
+}
+
+impl Drop for PooledCompressor<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            // If we panicked while compressing, the state may not have been reset.
+            if !thread::panicking() {
+                self.pool.put(state);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PooledCompressor<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PooledCompressor")
+            .field("options", &self.pool.options)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{
+        decompress_to_end, decompress_with_history, decompress_zlib,
+        decompress_zlib_with_dictionary, get_test_data,
+    };
+    use crate::{deflate_bytes_conf, deflate_bytes_zlib_conf, Compression, PreFilter};
+
+    #[test]
+    fn pool_reuse() {
+        let data = get_test_data();
+        let pool = CompressorPool::new(Compression::Default, 4);
+        assert_eq!(pool.idle(), 0);
+        for chunk in data.chunks(50000).take(4) {
+            let mut compressor = pool.get();
+            let compressed = compressor.compress(chunk);
+            assert!(compressed == deflate_bytes_conf(chunk, Compression::Default));
+            let compressed = compressor.compress_zlib(chunk);
+            assert!(compressed == deflate_bytes_zlib_conf(chunk, Compression::Default));
+            drop(compressor);
+            assert_eq!(pool.idle(), 1);
+        }
+        assert!(decompress_to_end(&pool.get().compress(&[])).is_empty());
+    }
+
+    #[test]
+    fn pool_compress_to_buf() {
+        let data = get_test_data();
+        let pool = CompressorPool::new(Compression::Fast, 1);
+        let mut out = Vec::new();
+        for chunk in data.chunks(30000).take(3) {
+            pool.get().compress_to_buf(chunk, &mut out);
+            assert!(out == deflate_bytes_conf(chunk, Compression::Fast));
+        }
+        let capacity = out.capacity();
+        pool.get().compress_to_buf(&data[..1000], &mut out);
+        assert!(decompress_to_end(&out) == data[..1000]);
+        assert_eq!(out.capacity(), capacity);
+    }
+
+    #[test]
+    fn pool_max_idle() {
+        let pool = CompressorPool::new(Compression::Fast, 2);
+        let compressors: Vec<_> = (0..5).map(|_| pool.get()).collect();
+        assert_eq!(pool.idle(), 0);
+        drop(compressors);
+        assert_eq!(pool.idle(), 2);
+
+        let pool = CompressorPool::new(Compression::Fast, 0);
+        pool.get().compress(b"data");
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "CompressorPool does not support max_blocks or verify!")]
+    fn pool_verify_rejected() {
+        let options = CompressionOptions {
+            verify: true,
+            ..CompressionOptions::default()
+        };
+        let _ = CompressorPool::new(options, 1);
+    }
+
+    #[test]
+    fn pool_pre_filter() {
+        let data: Vec<u8> = (0..10000u32)
+            .flat_map(|n| n.to_le_bytes().to_vec())
+            .collect();
+        let filter = PreFilter {
+            delta: 4,
+            transpose: 0,
+        };
+        let options = CompressionOptions {
+            pre_filter: filter,
+            ..CompressionOptions::default()
+        };
+        let pool = CompressorPool::new(options, 1);
+        for _ in 0..2 {
+            let compressed = pool.get().compress_zlib(&data);
+            assert!(compressed == deflate_bytes_zlib_conf(&data, options));
+        }
+    }
+
+    #[test]
+    fn pool_dictionary() {
+        let data = get_test_data();
+        let dictionary: Arc<[u8]> = Arc::from(&data[..20000]);
+        let pool = CompressorPool::with_dictionary(Compression::Default, 2, dictionary.clone());
+        for chunk in data[20000..].chunks(10000).take(3) {
+            let mut compressor = pool.get();
+            let compressed = compressor.compress(chunk);
+            assert!(decompress_with_history(&dictionary, &compressed) == chunk);
+            assert!(compressed.len() < deflate_bytes_conf(chunk, Compression::Default).len());
+            let compressed = compressor.compress_zlib(chunk);
+            assert!(decompress_zlib_with_dictionary(&dictionary, &compressed) == chunk);
+        }
+        // The compressors don't hold on to the dictionary.
+        assert_eq!(Arc::strong_count(&dictionary), 2);
+    }
+
+    #[test]
+    fn pool_threads() {
+        let data = get_test_data();
+        let pool = CompressorPool::new(Compression::Default, 8);
+        thread::scope(|s| {
+            for chunk in data.chunks(data.len() / 8 + 1) {
+                let pool = &pool;
+                s.spawn(move || {
+                    for _ in 0..3 {
+                        let compressed = pool.get().compress_zlib(chunk);
+                        assert!(decompress_zlib(&compressed) == chunk);
+                    }
+                });
+            }
+        });
+        assert!(pool.idle() >= 1 && pool.idle() <= 8);
+    }
+}
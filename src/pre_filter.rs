@@ -0,0 +1,252 @@
+//! This module contains the filters that can be applied to the input data before compressing it.
+//!
+//! These filters don't compress anything by themselves, but rearrange some kinds of data, like
+//! arrays of numbers, so that it compresses much better. As the output of the compressor
+//! will be the filtered data, the caller has to reverse the filters after decompressing it,
+//! which can be done using [`PreFilter::reverse`](struct.PreFilter.html#method.reverse).
+
+/// The number of records in each block transposed by the transpose filter.
+pub const TRANSPOSE_BLOCK_RECORDS: usize = 4096;
+
+/// Filters to apply to the input data before compressing it.
+///
+/// If both filters are enabled, delta encoding is applied first.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{deflate_bytes_conf, CompressionOptions, PreFilter};
+///
+/// // Some 4-byte little-endian counter values.
+/// let data: Vec<u8> = (0..1000u32).flat_map(|n| n.to_le_bytes().to_vec()).collect();
+/// let filter = PreFilter {
+///     delta: 4,
+///     transpose: 4,
+/// };
+/// let options = CompressionOptions {
+///     pre_filter: filter,
+///     ..CompressionOptions::default()
+/// };
+/// let compressed = deflate_bytes_conf(&data, options);
+/// # let _ = compressed;
+/// // After decompressing, the data has to be passed to `filter.reverse()`.
+/// assert_eq!(filter.reverse(&filter.apply(&data)), data);
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct PreFilter {
+    /// Delta encode the data, replacing each byte with the (wrapping) difference between it and
+    /// the byte this many positions before it. Bytes before the start of the data are treated as
+    /// zero.
+    ///
+    /// Setting this to the size of the values in the data (or the size of a record) usually
+    /// works best.
+    ///
+    /// * `0`: Don't delta encode.
+    /// * Default value: `0`
+    pub delta: u16,
+    /// Transpose the data as records of this many bytes, so the first bytes of each record are
+    /// output first, followed by the second bytes and so on.
+    ///
+    /// The transposing is done in blocks of up to
+    /// [`TRANSPOSE_BLOCK_RECORDS`](constant.TRANSPOSE_BLOCK_RECORDS.html) records,
+    /// and any trailing bytes at the end of the data that don't make up a whole record are
+    /// left as is.
+    ///
+    /// When used with the writers, up to a block of the input will be held back until
+    /// the next block is complete or the writer is finished, also when flushing.
+    ///
+    /// * `0`: Don't transpose.
+    /// * Default value: `0`
+    pub transpose: u16,
+}
+
+impl PreFilter {
+    /// No filtering.
+    pub const NONE: PreFilter = PreFilter {
+        delta: 0,
+        transpose: 0,
+    };
+
+    /// Returns `true` if any of the filters are enabled.
+    pub fn is_active(&self) -> bool {
+        self.delta != 0 || self.transpose != 0
+    }
+
+    /// Apply the filters to the provided data, returning the filtered data.
+    pub fn apply(&self, data: &[u8]) -> Vec<u8> {
+        let mut state = PreFilterState::new(*self);
+        state.push(data);
+        state.finish();
+        state.output
+    }
+
+    /// Reverse the filters on data that was filtered using the same filters.
+    pub fn reverse(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = if self.transpose != 0 {
+            let record_size = usize::from(self.transpose);
+            let mut out = Vec::with_capacity(data.len());
+            for block in data.chunks(record_size * TRANSPOSE_BLOCK_RECORDS) {
+                transpose_block(block, record_size, block.len() / record_size, &mut out);
+            }
+            out
+        } else {
+            data.to_vec()
+        };
+
+        let delta = usize::from(self.delta);
+        if delta != 0 {
+            for n in delta..out.len() {
+                out[n] = out[n].wrapping_add(out[n - delta]);
+            }
+        }
+        out
+    }
+}
+
+/// Transpose the first `rows * columns` bytes of `block`, as a matrix stored row by row,
+/// outputting any remaining bytes after them as is.
+fn transpose_block(block: &[u8], rows: usize, columns: usize, out: &mut Vec<u8>) {
+    let (matrix, rest) = block.split_at(rows * columns);
+    for column in 0..columns {
+        out.extend(matrix.iter().skip(column).step_by(columns));
+    }
+    out.extend_from_slice(rest);
+}
+
+/// Keeps track of the state of the pre-filters when data is input in multiple steps.
+pub struct PreFilterState {
+    filter: PreFilter,
+    /// The last `delta` bytes of unfiltered input.
+    history: Vec<u8>,
+    /// The position in `history` of the byte `delta` bytes back.
+    history_pos: usize,
+    /// Delta-encoded data waiting for a full block to be transposed.
+    block: Vec<u8>,
+    /// Filtered data that is ready to be compressed.
+    pub output: Vec<u8>,
+}
+
+impl PreFilterState {
+    pub fn new(filter: PreFilter) -> PreFilterState {
+        PreFilterState {
+            filter,
+            history: vec![0; usize::from(filter.delta)],
+            history_pos: 0,
+            block: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.filter.is_active()
+    }
+
+    fn record_size(&self) -> usize {
+        usize::from(self.filter.transpose)
+    }
+
+    /// Filter the input data, adding the result to `output` when it's ready.
+    pub fn push(&mut self, data: &[u8]) {
+        let out = if self.filter.transpose != 0 {
+            &mut self.block
+        } else {
+            &mut self.output
+        };
+
+        if self.filter.delta != 0 {
+            out.reserve(data.len());
+            for &b in data {
+                out.push(b.wrapping_sub(self.history[self.history_pos]));
+                self.history[self.history_pos] = b;
+                self.history_pos += 1;
+                if self.history_pos == self.history.len() {
+                    self.history_pos = 0;
+                }
+            }
+        } else {
+            out.extend_from_slice(data);
+        }
+
+        if self.filter.transpose != 0 {
+            let record_size = self.record_size();
+            let block_len = record_size * TRANSPOSE_BLOCK_RECORDS;
+            let mut start = 0;
+            while self.block.len() - start >= block_len {
+                transpose_block(
+                    &self.block[start..start + block_len],
+                    TRANSPOSE_BLOCK_RECORDS,
+                    record_size,
+                    &mut self.output,
+                );
+                start += block_len;
+            }
+            self.block.drain(..start);
+        }
+    }
+
+    /// Output any data that is held back waiting for a complete block.
+    pub fn finish(&mut self) {
+        if self.filter.transpose != 0 {
+            let record_size = self.record_size();
+            let records = self.block.len() / record_size;
+            transpose_block(&self.block, records, record_size, &mut self.output);
+            self.block.clear();
+        }
+    }
+
+    /// Reset the state, discarding any pending data.
+    pub fn reset(&mut self) {
+        *self = PreFilterState::new(self.filter);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::get_test_data;
+
+    fn check_roundtrip(filter: PreFilter, data: &[u8]) {
+        let filtered = filter.apply(data);
+        assert_eq!(filtered.len(), data.len());
+        assert!(filter.reverse(&filtered) == data);
+
+        // Filtering in multiple steps should give the same result.
+        let mut state = PreFilterState::new(filter);
+        for chunk in data.chunks(1000) {
+            state.push(chunk);
+        }
+        state.finish();
+        assert!(state.output == filtered);
+    }
+
+    #[test]
+    fn delta() {
+        let filter = PreFilter {
+            delta: 2,
+            transpose: 0,
+        };
+        assert_eq!(filter.apply(&[1, 2, 4, 7, 3, 5]), [1, 2, 3, 5, 255, 254]);
+        check_roundtrip(filter, &get_test_data());
+    }
+
+    #[test]
+    fn transpose() {
+        let filter = PreFilter {
+            delta: 0,
+            transpose: 3,
+        };
+        assert_eq!(
+            filter.apply(&[1, 2, 3, 4, 5, 6, 7, 8]),
+            [1, 4, 2, 5, 3, 6, 7, 8]
+        );
+        check_roundtrip(filter, &get_test_data());
+    }
+
+    #[test]
+    fn delta_transpose() {
+        let data = get_test_data();
+        for &(delta, transpose) in &[(1, 1), (4, 4), (3, 7), (16, 8)] {
+            check_roundtrip(PreFilter { delta, transpose }, &data);
+        }
+    }
+}
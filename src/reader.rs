@@ -0,0 +1,325 @@
+//! This module contains an encoder that is read from rather than written to, compressing the data
+//! read from a wrapped reader, for plugging compression into code that pulls data from a
+//! `Read`, like an HTTP client sending a request body.
+
+use std::io::{self, Read};
+use std::{cmp, fmt};
+
+use crate::checksum::NoChecksum;
+use crate::compress::Flush;
+use crate::compression_options::{CompressionOptions, ConfigError};
+use crate::deflate_state::DeflateState;
+use crate::writer::{compress_filtered, compress_until_done_filtered};
+
+const ERR_STR: &str = "Write error when writing to a Vec!";
+
+/// The default size of the buffer the data from the wrapped reader is read into.
+const DEFAULT_CHUNK_SIZE: usize = 32 * 1024;
+
+/// A DEFLATE encoder that compresses the data read from a wrapped reader, producing the
+/// compressed data when read from.
+///
+/// Input is only read from the wrapped reader when the compressed data already produced has
+/// all been read out, so memory use stays bounded no matter how long the input is. The stream
+/// is finished once the wrapped reader runs out of data.
+///
+/// The compressor keeps the window and lookahead from earlier reads, so the compressed data
+/// does not depend on how the input is split up by the wrapped reader or the chunk size used.
+///
+/// Errors from the wrapped reader are passed on as they are, and reading can be retried
+/// afterwards, e.g on errors of kind `Interrupted` or `WouldBlock`. Errors from compressing the
+/// data, e.g when it doesn't fit in
+/// [`BlockLimits::max_blocks`](../struct.BlockLimits.html#structfield.max_blocks) blocks or
+/// `verify` finds a problem, are returned as well, but can't be recovered from: every read
+/// after them fails with an error of the same kind.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::io::Read;
+///
+/// use deflate::read::DeflateEncoder;
+/// use deflate::Compression;
+///
+/// let data = b"This is some test data";
+/// let mut encoder = DeflateEncoder::new(&data[..], Compression::Default);
+/// let mut compressed = Vec::new();
+/// encoder.read_to_end(&mut compressed).unwrap();
+/// # let _ = compressed;
+/// ```
+pub struct DeflateEncoder<R: Read> {
+    reader: R,
+    deflate_state: Box<DeflateState<Vec<u8>>>,
+    input: Box<[u8]>,
+    /// The position in the compressed data that has not been read out yet.
+    output_pos: usize,
+    finished: bool,
+    /// The kind of the error compressing the data failed with, if it did.
+    failed: Option<io::ErrorKind>,
+}
+
+impl<R: Read> DeflateEncoder<R> {
+    /// Creates a new encoder compressing the data read from `reader` using the provided
+    /// compression options.
+    pub fn new<O: Into<CompressionOptions>>(reader: R, options: O) -> DeflateEncoder<R> {
+        DeflateEncoder::with_chunk_size(reader, options, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Creates a new encoder compressing the data read from `reader`, after checking the
+    /// compression options, see
+    /// [`write::DeflateEncoder::try_new`](../write/struct.DeflateEncoder.html#method.try_new).
+    pub fn try_new<O: Into<CompressionOptions>>(
+        reader: R,
+        options: O,
+    ) -> Result<DeflateEncoder<R>, ConfigError> {
+        let options = options.into();
+        options.validate()?;
+        Ok(DeflateEncoder::new(reader, options))
+    }
+
+    /// Creates a new encoder compressing the data read from `reader` using the provided
+    /// compression options, reading at most `chunk_size` bytes from `reader` at a time.
+    ///
+    /// The default chunk size is 32 KiB. A smaller one lowers the memory used and the amount
+    /// of input read ahead of the compressed data, at the cost of more calls to `reader`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn with_chunk_size<O: Into<CompressionOptions>>(
+        reader: R,
+        options: O,
+        chunk_size: usize,
+    ) -> DeflateEncoder<R> {
+        assert!(chunk_size > 0, "The chunk size can't be 0!");
+        DeflateEncoder {
+            reader,
+            deflate_state: Box::new(DeflateState::new(options.into(), Vec::new())),
+            input: vec![0; chunk_size].into_boxed_slice(),
+            output_pos: 0,
+            finished: false,
+            failed: None,
+        }
+    }
+
+    /// Get a reference to the wrapped reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Get a mutable reference to the wrapped reader.
+    ///
+    /// Note that reading from it directly means the data read is not compressed.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Consume the encoder, returning the wrapped reader.
+    ///
+    /// Any compressed data that has not been read out yet is discarded.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Compress all of `input`, putting the compressed data in the output buffer.
+    fn compress(deflate_state: &mut DeflateState<Vec<u8>>, mut input: &[u8]) -> io::Result<()> {
+        while !input.is_empty() {
+            match compress_filtered(input, deflate_state, NoChecksum::new()) {
+                Ok(n) => input = &input[n..],
+                // Only means that the output buffer was full.
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DeflateEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if let Some(kind) = self.failed {
+            return Err(io::Error::new(kind, "Compressing the data failed earlier."));
+        }
+
+        loop {
+            let output = self.deflate_state.inner.as_mut().expect(ERR_STR);
+            if self.output_pos < output.len() {
+                let n = cmp::min(buf.len(), output.len() - self.output_pos);
+                buf[..n].copy_from_slice(&output[self.output_pos..self.output_pos + n]);
+                self.output_pos += n;
+                if self.output_pos == output.len() {
+                    // Everything has been read out, so the buffer can be reused.
+                    output.clear();
+                    self.output_pos = 0;
+                }
+                return Ok(n);
+            }
+
+            if self.finished {
+                return Ok(0);
+            }
+
+            let n = self.reader.read(&mut self.input)?;
+            let res = if n == 0 {
+                self.finished = true;
+                compress_until_done_filtered(
+                    &mut self.deflate_state,
+                    NoChecksum::new(),
+                    Flush::Finish,
+                )
+            } else {
+                Self::compress(&mut self.deflate_state, &self.input[..n])
+            };
+            if let Err(e) = res {
+                self.failed = Some(e.kind());
+                return Err(e);
+            }
+        }
+    }
+}
+
+impl<R: Read + fmt::Debug> fmt::Debug for DeflateEncoder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeflateEncoder")
+            .field("reader", &self.reader)
+            .field("options", &self.deflate_state.compression_options)
+            .field("finished", &self.finished)
+            .field("failed", &self.failed)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_random_data, get_test_data};
+    use crate::{deflate_bytes_conf, BlockLimits, Compression, CompressionOptions};
+
+    /// A reader returning at most `max_read` bytes at a time, and an `Interrupted` error every
+    /// other call.
+    struct SlowReader<'a> {
+        data: &'a [u8],
+        max_read: usize,
+        interrupt: bool,
+    }
+
+    impl<'a> Read for SlowReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let n = cmp::min(cmp::min(buf.len(), self.max_read), self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_roundtrip() {
+        let data = get_test_data();
+        let mut encoder = DeflateEncoder::new(&data[..], Compression::Default);
+        let mut compressed = Vec::new();
+        encoder.read_to_end(&mut compressed).unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+        // Reading the whole input at once gives the same output as compressing it in one go.
+        assert_eq!(compressed, deflate_bytes_conf(&data, Compression::Default));
+        assert_eq!(encoder.read(&mut [0; 10]).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_small_pieces() {
+        let data = get_test_data();
+        let reader = SlowReader {
+            data: &data,
+            max_read: 1000,
+            interrupt: false,
+        };
+        let mut encoder = DeflateEncoder::new(reader, Compression::Fast);
+        let mut compressed = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match encoder.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => compressed.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert!(decompress_to_end(&compressed) == data);
+        assert!(encoder.get_ref().data.is_empty());
+    }
+
+    #[test]
+    fn read_one_byte_at_a_time() {
+        let data = get_test_data();
+        let data = &data[..100_000];
+        for &chunk_size in &[1, 7, DEFAULT_CHUNK_SIZE] {
+            let reader = SlowReader {
+                data,
+                max_read: 1,
+                interrupt: false,
+            };
+            let mut encoder =
+                DeflateEncoder::with_chunk_size(reader, Compression::Default, chunk_size);
+            let mut compressed = Vec::new();
+            loop {
+                match encoder.read_to_end(&mut compressed) {
+                    Ok(_) => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            // Matches aren't cut off where the reads end.
+            assert_eq!(compressed, deflate_bytes_conf(data, Compression::Default));
+        }
+    }
+
+    #[test]
+    fn read_chunk_size() {
+        let data = get_test_data();
+        let mut encoder = DeflateEncoder::with_chunk_size(&data[..], Compression::Fast, 100);
+        let mut compressed = Vec::new();
+        encoder.read_to_end(&mut compressed).unwrap();
+        assert_eq!(compressed, deflate_bytes_conf(&data, Compression::Fast));
+    }
+
+    #[test]
+    #[should_panic]
+    fn read_zero_chunk_size() {
+        let _ = DeflateEncoder::with_chunk_size(io::empty(), Compression::Default, 0);
+    }
+
+    #[test]
+    fn read_empty() {
+        let mut encoder = DeflateEncoder::new(io::empty(), Compression::Default);
+        let mut compressed = Vec::new();
+        encoder.read_to_end(&mut compressed).unwrap();
+        assert!(!compressed.is_empty());
+        assert!(decompress_to_end(&compressed).is_empty());
+    }
+
+    #[test]
+    fn read_max_blocks_exceeded() {
+        let data = get_random_data(200_000);
+        let options = CompressionOptions {
+            block_limits: BlockLimits {
+                max_blocks: 1,
+                ..BlockLimits::default()
+            },
+            ..CompressionOptions::default()
+        };
+        let mut encoder = DeflateEncoder::new(&data[..], options);
+        let mut compressed = Vec::new();
+        let err = encoder.read_to_end(&mut compressed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        // The encoder can't be used after the error.
+        let err = encoder.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+}
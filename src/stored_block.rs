@@ -1,8 +1,8 @@
 use crate::bitstream::LsbWriter;
 use byteorder::{LittleEndian, WriteBytesExt};
+use std::convert::TryFrom;
 use std::io;
 use std::io::Write;
-use std::u16;
 
 #[cfg(test)]
 const BLOCK_SIZE: u16 = 32000;
@@ -25,19 +25,17 @@ pub fn write_stored_header(writer: &mut LsbWriter, final_block: bool) {
 
 // Compress one stored block (excluding the header)
 pub fn compress_block_stored<W: Write>(input: &[u8], writer: &mut W) -> io::Result<usize> {
-    if input.len() > u16::max_value() as usize {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Stored block too long!",
-        ));
-    };
+    // The length has to fit in the 16-bit LEN field.
+    let len = u16::try_from(input.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Stored block too long!"))?;
     // The header is written before this function.
     // The next two bytes indicates the length
-    writer.write_u16::<LittleEndian>(input.len() as u16)?;
+    writer.write_u16::<LittleEndian>(len)?;
     // the next two after the length is the ones complement of the length
-    writer.write_u16::<LittleEndian>(!input.len() as u16)?;
+    writer.write_u16::<LittleEndian>(!len)?;
     // After this the data is written directly with no compression
-    writer.write(input)
+    writer.write_all(input)?;
+    Ok(input.len())
 }
 
 #[cfg(test)]
@@ -45,7 +43,11 @@ pub fn compress_data_stored(input: &[u8]) -> Vec<u8> {
     let block_length = BLOCK_SIZE as usize;
 
     let mut output = Vec::with_capacity(input.len() + 2);
-    let mut i = input.chunks(block_length).peekable();
+    // An empty input still needs a (final) block.
+    let mut i = input
+        .chunks(block_length)
+        .chain(input.is_empty().then_some(&[][..]))
+        .peekable();
     while let Some(chunk) = i.next() {
         let last_chunk = i.peek().is_none();
         // First bit tells us if this is the final chunk
@@ -55,7 +57,7 @@ pub fn compress_data_stored(input: &[u8]) -> Vec<u8> {
         } else {
             STORED_FIRST_BYTE
         };
-        output.write(&[first_byte]).unwrap();
+        output.write_all(&[first_byte]).unwrap();
 
         compress_block_stored(chunk, &mut output).unwrap();
     }
@@ -83,6 +85,81 @@ mod test {
         assert_eq!(test_data, result);
     }
 
+    #[test]
+    fn no_compression_block_size_boundaries() {
+        let block_size = BLOCK_SIZE as usize;
+        for &len in &[
+            0,
+            1,
+            block_size - 1,
+            block_size,
+            block_size + 1,
+            block_size * 3,
+        ] {
+            let test_data: Vec<u8> = (0..len).map(|n| n as u8).collect();
+            let compressed = compress_data_stored(&test_data);
+            // A five byte header for each block.
+            assert_eq!(
+                compressed.len(),
+                len + len.div_ceil(block_size).max(1) * 5,
+                "{}",
+                len
+            );
+            assert_eq!(decompress_to_end(&compressed), test_data);
+        }
+    }
+
+    #[test]
+    fn max_length_block() {
+        let max = u16::MAX as usize;
+        let test_data = vec![7u8; max + 1];
+
+        let mut compressed = vec![STORED_FIRST_BYTE_FINAL];
+        assert_eq!(
+            compress_block_stored(&test_data[..max], &mut compressed).unwrap(),
+            max
+        );
+        assert_eq!(compressed[1..5], [0xff, 0xff, 0, 0]);
+        assert_eq!(decompress_to_end(&compressed), &test_data[..max]);
+
+        // One more byte doesn't fit in the length field, and nothing is written.
+        let mut output = Vec::new();
+        let err = compress_block_stored(&test_data, &mut output).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn write_stored_block_boundaries() {
+        use crate::compress::write_stored_block;
+        use crate::encoder_state::EncoderState;
+
+        let max = MAX_STORED_BLOCK_LENGTH;
+        let data: Vec<u8> = (0..max * 4 + 1).map(|n| (n % 251) as u8).collect();
+        for &len in &[
+            0,
+            max - 1,
+            max,
+            max + 1,
+            max * 2,
+            u16::MAX as usize,
+            u16::MAX as usize + 1,
+            max * 4 + 1,
+        ] {
+            let mut state = EncoderState::new(Vec::new());
+            write_stored_block(&data[..len], &mut state, true);
+            state.flush().unwrap();
+            let compressed = state.writer.w;
+            assert_eq!(
+                compressed.len(),
+                len + len.div_ceil(max).max(1) * 5,
+                "{}",
+                len
+            );
+            assert_eq!(decompress_to_end(&compressed), &data[..len], "{}", len);
+        }
+    }
+
     #[test]
     fn no_compression_string() {
         let test_data = String::from(
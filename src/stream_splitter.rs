@@ -0,0 +1,156 @@
+//! This module contains an encoder that compresses a sequence of inputs into separate zlib
+//! streams, where each stream uses the previous input as a preset dictionary.
+//!
+//! This is mainly useful for formats that store many similar pieces of data as separate
+//! zlib streams, like the frames of an animated PNG, where compressing each frame on its own
+//! would redundantly encode the data that is the same as in the previous frame.
+
+use std::io::Write;
+
+use crate::chained_hash_table::WINDOW_SIZE;
+use crate::compression_options::CompressionOptions;
+use crate::writer::ZlibEncoder;
+
+/// An encoder that compresses each input into a separate zlib stream, using the last 32 KiB of
+/// the previous input as a preset dictionary.
+///
+/// The first stream is compressed without a dictionary. For the following streams, the
+/// decompressor has to be given the dictionary returned by
+/// [`dictionary`](#method.dictionary) before the stream was compressed, which is simply the
+/// end of the previous input.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{Compression, ZlibStreamSplitter};
+///
+/// let frames: [&[u8]; 2] = [b"This is some test data", b"This is some more test data"];
+/// let mut splitter = ZlibStreamSplitter::new(Compression::Default);
+/// for frame in frames.iter() {
+///     // A decompressor needs to use this as the dictionary for the stream.
+///     let dictionary = splitter.dictionary().to_vec();
+///     let stream = splitter.compress_stream(frame);
+///     # let _ = (dictionary, stream);
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ZlibStreamSplitter {
+    options: CompressionOptions,
+    dictionary: Vec<u8>,
+}
+
+impl ZlibStreamSplitter {
+    /// Create a new `ZlibStreamSplitter` using the provided compression options.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_limits.max_blocks` is set or `verify` is enabled in the options, as
+    /// `compress_stream` has no way to report the errors they can cause.
+    pub fn new<O: Into<CompressionOptions>>(options: O) -> ZlibStreamSplitter {
+        let options = options.into();
+        assert!(
+            options.block_limits.max_blocks == 0 && !options.verify,
+            "ZlibStreamSplitter does not support max_blocks or verify!"
+        );
+        ZlibStreamSplitter {
+            options,
+            dictionary: Vec::new(),
+        }
+    }
+
+    /// The dictionary that will be used for the next stream.
+    ///
+    /// This is empty if no dictionary will be used.
+    pub fn dictionary(&self) -> &[u8] {
+        &self.dictionary
+    }
+
+    /// Compress `input` into a complete zlib stream using the current dictionary, then use the
+    /// end of `input` as the dictionary for the next stream.
+    ///
+    /// If `input` is empty, the dictionary is kept for the next stream.
+    pub fn compress_stream(&mut self, input: &[u8]) -> Vec<u8> {
+        let writer = Vec::with_capacity(input.len() / 3);
+        let mut encoder = if self.dictionary.is_empty() {
+            ZlibEncoder::new(writer, self.options)
+        } else {
+            ZlibEncoder::with_dictionary(writer, self.options, &self.dictionary)
+        };
+        encoder
+            .write_all(input)
+            .expect("Write error when writing compressed data!");
+        let compressed = encoder
+            .finish()
+            .expect("Write error when finishing stream!");
+
+        if !input.is_empty() {
+            let start = input.len().saturating_sub(WINDOW_SIZE);
+            self.dictionary.clear();
+            self.dictionary.extend_from_slice(&input[start..]);
+        }
+        compressed
+    }
+
+    /// Discard the current dictionary, so the next stream is compressed without one.
+    pub fn reset_dictionary(&mut self) {
+        self.dictionary.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_zlib, decompress_zlib_with_dictionary, get_test_data};
+    use crate::Compression;
+
+    #[test]
+    fn splitter_roundtrip() {
+        let data = get_test_data();
+        let mut splitter = ZlibStreamSplitter::new(Compression::Default);
+        let mut chunks = data.chunks(20000);
+
+        let first = chunks.next().unwrap();
+        let compressed = splitter.compress_stream(first);
+        assert!(decompress_zlib(&compressed) == first);
+
+        for chunk in chunks {
+            let dictionary = splitter.dictionary().to_vec();
+            let compressed = splitter.compress_stream(chunk);
+            assert!(decompress_zlib_with_dictionary(&dictionary, &compressed) == chunk);
+        }
+    }
+
+    #[test]
+    fn splitter_similar_frames() {
+        // A frame that is identical to the previous one should compress to almost nothing.
+        let data = get_test_data();
+        let frame = &data[..WINDOW_SIZE];
+        let mut splitter = ZlibStreamSplitter::new(Compression::Default);
+        let first = splitter.compress_stream(frame);
+        let second = splitter.compress_stream(frame);
+        assert!(second.len() < 300);
+        assert!(second.len() * 10 < first.len());
+        assert!(decompress_zlib_with_dictionary(frame, &second) == frame);
+    }
+
+    #[test]
+    #[should_panic(expected = "ZlibStreamSplitter does not support max_blocks or verify!")]
+    fn splitter_verify_rejected() {
+        let _ = ZlibStreamSplitter::new(CompressionOptions {
+            verify: true,
+            ..CompressionOptions::default()
+        });
+    }
+
+    #[test]
+    fn splitter_reset() {
+        let mut splitter = ZlibStreamSplitter::new(Compression::Fast);
+        splitter.compress_stream(b"Some data");
+        assert_eq!(splitter.dictionary(), b"Some data");
+        splitter.compress_stream(&[]);
+        assert_eq!(splitter.dictionary(), b"Some data");
+        splitter.reset_dictionary();
+        let compressed = splitter.compress_stream(b"Some data");
+        assert_eq!(decompress_zlib(&compressed), b"Some data");
+    }
+}
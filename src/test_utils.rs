@@ -19,6 +19,14 @@ pub fn get_test_data() -> Vec<u8> {
     get_test_file_data(&path)
 }
 
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+/// Generate `len` bytes of pseudo-random, incompressible data using a simple xorshift generator.
+pub fn get_random_data(len: usize) -> Vec<u8> {
+    common::random_data(len)
+}
+
 /// Helper function to decompress into a `Vec<u8>`
 pub fn decompress_to_end(input: &[u8]) -> Vec<u8> {
     // use std::str;
@@ -45,15 +53,47 @@ pub fn decompress_to_end(input: &[u8]) -> Vec<u8> {
     use std::io::Read;
 
     let mut result = Vec::new();
-    let i = &input[..];
-    let mut e = DeflateDecoder::new(i);
+    let mut e = DeflateDecoder::new(input);
 
     let res = e.read_to_end(&mut result);
-    if let Ok(_) = res {
-        //        println!("{} bytes decompressed successfully", n);
-    } else {
+    if let Err(e) = res {
         println!("result size: {}", result.len());
-        res.unwrap();
+        panic!("{:?}", e);
+    }
+    result
+}
+
+/// Helper function to decompress a raw deflate stream that may refer to data in `history` that
+/// came before it, as when using a preset dictionary.
+pub fn decompress_with_history(history: &[u8], input: &[u8]) -> Vec<u8> {
+    use crate::stored_block::compress_block_stored;
+    // Put the history in a stored block in front of the data, so the decoder has it in it's
+    // window. Stored blocks end on a byte boundary, so the data can be appended directly.
+    let mut stream = vec![0];
+    compress_block_stored(history, &mut stream).unwrap();
+    stream.extend_from_slice(input);
+    let mut result = decompress_to_end(&stream);
+    result.drain(..history.len());
+    result
+}
+
+/// Helper function to decompress a raw deflate stream that ends with a sync flush rather than
+/// a final block.
+pub fn decompress_sync_flushed(input: &[u8]) -> Vec<u8> {
+    use flate2::{Decompress, FlushDecompress, Status};
+
+    let mut d = Decompress::new(false);
+    let mut result = Vec::with_capacity(input.len() * 2);
+    loop {
+        let consumed = d.total_in() as usize;
+        let status = d
+            .decompress_vec(&input[consumed..], &mut result, FlushDecompress::Sync)
+            .unwrap();
+        if d.total_in() as usize == input.len() && result.len() < result.capacity() {
+            break;
+        }
+        assert_eq!(status, Status::Ok, "Unexpected end of stream!");
+        result.reserve(input.len() * 2);
     }
     result
 }
@@ -61,19 +101,114 @@ pub fn decompress_to_end(input: &[u8]) -> Vec<u8> {
 #[cfg(feature = "gzip")]
 pub fn decompress_gzip(compressed: &[u8]) -> (GzDecoder<&[u8]>, Vec<u8>) {
     use std::io::Read;
-    let mut e = GzDecoder::new(&compressed[..]).unwrap();
+    let mut e = GzDecoder::new(compressed);
 
     let mut result = Vec::new();
     e.read_to_end(&mut result).unwrap();
+    let trailer = crate::inflate::verify_gzip_trailer(compressed).unwrap();
+    assert_eq!(trailer.size, Some(result.len() as u32));
     (e, result)
 }
 
 pub fn decompress_zlib(compressed: &[u8]) -> Vec<u8> {
     use flate2::read::ZlibDecoder;
     use std::io::Read;
-    let mut e = ZlibDecoder::new(&compressed[..]);
+    let mut e = ZlibDecoder::new(compressed);
 
     let mut result = Vec::new();
     e.read_to_end(&mut result).unwrap();
+    crate::inflate::verify_zlib_trailer(compressed).unwrap();
+    result
+}
+
+/// Helper function to decompress a zlib stream compressed using the preset dictionary
+/// `dictionary`, checking the dictionary id in the header and the checksum in the trailer.
+pub fn decompress_zlib_with_dictionary(dictionary: &[u8], compressed: &[u8]) -> Vec<u8> {
+    use adler32::RollingAdler32;
+    use byteorder::{BigEndian, ByteOrder};
+
+    let (header, rest) = compressed.split_at(6);
+    assert_eq!(header[1] & (1 << 5), 1 << 5, "FDICT flag not set!");
+    assert_eq!(
+        BigEndian::read_u32(&header[2..]),
+        RollingAdler32::from_buffer(dictionary).hash()
+    );
+    let (data, trailer) = rest.split_at(rest.len() - 4);
+    let history_start = dictionary.len().saturating_sub(32768);
+    let result = decompress_with_history(&dictionary[history_start..], data);
+    assert_eq!(
+        BigEndian::read_u32(trailer),
+        RollingAdler32::from_buffer(&result).hash()
+    );
     result
 }
+
+/// A writer for testing how the encoders deal with the wrapped writer misbehaving.
+///
+/// By default it behaves like a `Vec<u8>`, the fields can be set to make it fail in different
+/// ways.
+#[derive(Debug, Default)]
+pub struct FaultyWriter {
+    /// The data written so far.
+    pub data: Vec<u8>,
+    /// Accept at most this many bytes in each call to `write`, if set.
+    pub max_write: Option<usize>,
+    /// Return `ErrorKind::Interrupted` on every n-th call to `write`, if set.
+    pub interrupt_every: Option<usize>,
+    /// Once this many bytes have been written, fail with an error...
+    pub fail_at: Option<usize>,
+    /// ...this many times, before accepting data again.
+    pub failures: usize,
+    /// Fail with an error on the n-th call to `write`, if set.
+    pub fail_call: Option<usize>,
+    /// The number of calls to `write` so far.
+    pub calls: usize,
+}
+
+impl std::io::Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        self.calls += 1;
+        if self.interrupt_every.is_some_and(|n| self.calls % n == 0) {
+            return Err(Error::new(ErrorKind::Interrupted, "Interrupted!"));
+        }
+        if self.fail_call == Some(self.calls) {
+            return Err(Error::other("Write failed!"));
+        }
+        let mut len = buf.len().min(self.max_write.unwrap_or(usize::MAX));
+        if let Some(fail_at) = self.fail_at {
+            if self.data.len() >= fail_at && self.failures > 0 {
+                self.failures -= 1;
+                return Err(Error::other("Write failed!"));
+            }
+            if self.failures > 0 {
+                len = len.min(fail_at - self.data.len());
+            }
+        }
+        self.data.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Pack a string of `0`s and `1`s, in the order the bits appear in a DEFLATE stream, into bytes
+/// starting with the least significant bit of each byte. Whitespace is ignored, so the string can
+/// be split up into the fields of the stream, and the last byte is padded with zeroes.
+pub fn bits_to_bytes(bits: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (n, bit) in bits.chars().filter(|c| !c.is_whitespace()).enumerate() {
+        if n % 8 == 0 {
+            bytes.push(0);
+        }
+        match bit {
+            '0' => (),
+            '1' => *bytes.last_mut().unwrap() |= 1 << (n % 8),
+            _ => panic!("Invalid bit: {:?}", bit),
+        }
+    }
+    bytes
+}
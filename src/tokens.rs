@@ -0,0 +1,386 @@
+//! Recording the lz77 tokens of a compression run, to encode them again with different settings.
+
+use std::io;
+use std::mem;
+
+use crate::allocator::BufferAllocator;
+use crate::compress::{write_compressed_block, write_stored_block, Flush};
+use crate::compression_options::{resolve_max_code_length, BlockLimits, CompressionOptions};
+use crate::deflate_state::{DeflateState, LengthBuffers};
+use crate::encoder_state::EncoderState;
+use crate::huffman_lengths::{gen_huffman_lengths, BlockType};
+use crate::lz77::{lz77_compress_block, LZ77Status};
+use crate::lzvalue::{LZType, LZValue};
+use crate::output_writer::{BufferStatus, DynamicWriter};
+
+/// The lz77 tokens (literals and matches) found when compressing some data, see
+/// [`record_tokens`](fn.record_tokens.html).
+#[derive(Clone, Debug)]
+pub struct TokenStream {
+    tokens: Vec<LZValue>,
+    /// The number of tokens before the end of each block of the recorded run.
+    block_ends: Vec<usize>,
+    /// The data the tokens were found in, which is needed for stored blocks.
+    data: Vec<u8>,
+}
+
+/// Where to end the blocks when replaying a [`TokenStream`](struct.TokenStream.html).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum BlockSplit {
+    /// End the blocks where they ended in the recorded compression run.
+    #[default]
+    Recorded,
+    /// End the blocks when any of these limits are reached, as with the `block_limits`
+    /// compression option. `max_blocks` is not used here.
+    Limits(BlockLimits),
+    /// End the blocks after these numbers of tokens, which have to be in order.
+    ///
+    /// A block that would have more than
+    /// [`MAX_BLOCK_SYMBOLS`](constant.MAX_BLOCK_SYMBOLS.html) tokens is split further.
+    At(Vec<usize>),
+}
+
+/// Settings for encoding a [`TokenStream`](struct.TokenStream.html).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReplayOptions {
+    /// Where to end the blocks.
+    ///
+    /// * Default value: `BlockSplit::Recorded`
+    pub block_split: BlockSplit,
+    /// Use fixed huffman codes for every block, rather than the block type giving the smallest
+    /// output, like `SpecialOptions::ForceFixed`.
+    ///
+    /// * Default value: `false`
+    pub force_fixed: bool,
+    /// The longest the huffman codes can be, in bits, as with the `max_code_length` compression
+    /// option.
+    ///
+    /// * `0`: Use the longest codes the format allows, `15`.
+    /// * Default value: `0`
+    pub max_code_length: u8,
+}
+
+/// Run the lz77 stage of compressing `input` with `options`, and record the tokens it finds, so
+/// they can be encoded with different block splits and block types without searching for
+/// matches again.
+///
+/// As when compressing, the `pre_filter` option is applied to the input first.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{deflate_bytes_conf, record_tokens, Compression, ReplayOptions};
+/// use deflate::{BlockLimits, BlockSplit};
+///
+/// let data = b"This is some test data, with some test data repeated.";
+/// let tokens = record_tokens(data, Compression::Default);
+/// let compressed = tokens.replay(&ReplayOptions::default());
+/// assert_eq!(compressed, deflate_bytes_conf(data, Compression::Default));
+///
+/// // Try out a different block size.
+/// let options = ReplayOptions {
+///     block_split: BlockSplit::Limits(BlockLimits {
+///         max_symbols: 16,
+///         ..BlockLimits::default()
+///     }),
+///     ..ReplayOptions::default()
+/// };
+/// let small_blocks = tokens.replay(&options);
+/// # let _ = small_blocks;
+/// ```
+pub fn record_tokens<O: Into<CompressionOptions>>(input: &[u8], options: O) -> TokenStream {
+    let options = options.into();
+    let data = if options.pre_filter.is_active() {
+        options.pre_filter.apply(input)
+    } else {
+        input.to_vec()
+    };
+
+    let mut state = Box::new(DeflateState::new(options, io::sink()));
+    state.detect_data_kind(&data);
+    let mut tokens = Vec::new();
+    let mut block_ends = Vec::new();
+    let mut slice = &data[..];
+    while !state.lz77_state.is_last_block() {
+        let (written, status, _) = lz77_compress_block(
+            slice,
+            &mut state.lz77_state,
+            &mut state.input_buffer,
+            &mut state.lz77_writer,
+            Flush::Finish,
+        );
+        slice = &slice[written..];
+
+        if state.lz77_state.current_block_input_bytes() > 0 {
+            tokens.extend(state.lz77_writer.drain());
+            block_ends.push(tokens.len());
+        }
+        state.lz77_writer.clear();
+        state.lz77_state.reset_input_bytes();
+
+        if status == LZ77Status::Finished {
+            break;
+        }
+    }
+
+    TokenStream {
+        tokens,
+        block_ends,
+        data,
+    }
+}
+
+/// The number of bytes of input a token covers.
+fn input_len(token: LZValue) -> usize {
+    match token.value() {
+        LZType::Literal(_) => 1,
+        LZType::StoredLengthDistance(length, _) => usize::from(length.actual_length()),
+    }
+}
+
+impl TokenStream {
+    /// The number of tokens.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Returns `true` if there are no tokens, i.e the input was empty.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The data the tokens were found in, after applying the `pre_filter` option, if any.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The number of tokens before the end of each block in the recorded compression run, for
+    /// use as a starting point for `BlockSplit::At`.
+    pub fn block_ends(&self) -> &[usize] {
+        &self.block_ends
+    }
+
+    /// Encode the tokens as raw DEFLATE data, using `options`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the block ends given with `BlockSplit::At` are not in order.
+    pub fn replay(&self, options: &ReplayOptions) -> Vec<u8> {
+        let (limits, split_at) = match options.block_split {
+            BlockSplit::Recorded => (BlockLimits::DEFAULT, &self.block_ends[..]),
+            BlockSplit::Limits(limits) => (limits, &[][..]),
+            BlockSplit::At(ref ends) => (BlockLimits::DEFAULT, &ends[..]),
+        };
+        assert!(
+            split_at.windows(2).all(|w| w[0] <= w[1]),
+            "Block ends are not in order!"
+        );
+
+        let mut encoder_state = EncoderState::new(Vec::with_capacity(self.data.len() / 3));
+        let mut lz77_writer = DynamicWriter::with_limits(limits, &BufferAllocator::default());
+        let mut length_buffers = LengthBuffers::new();
+        let max_code_length = resolve_max_code_length(options.max_code_length);
+        let mut split_at = split_at.iter().peekable();
+        // The position in the data of the start of the current block.
+        let mut block_start = 0;
+        let mut position = 0;
+
+        for (n, &token) in self.tokens.iter().enumerate() {
+            let status = match token.value() {
+                LZType::Literal(l) => lz77_writer.write_literal(l),
+                LZType::StoredLengthDistance(length, distance) => {
+                    lz77_writer.write_length_distance(length.actual_length(), distance)
+                }
+            };
+            position += input_len(token);
+
+            let mut end_block = status == BufferStatus::Full;
+            while split_at.next_if(|&&end| end <= n + 1).is_some() {
+                end_block = true;
+            }
+            let final_block = n + 1 == self.tokens.len();
+            if end_block || final_block {
+                write_block(
+                    &mut encoder_state,
+                    &mut lz77_writer,
+                    &mut length_buffers,
+                    &self.data[block_start..position],
+                    options.force_fixed,
+                    max_code_length,
+                    final_block,
+                );
+                block_start = position;
+            }
+        }
+        if self.tokens.is_empty() {
+            write_block(
+                &mut encoder_state,
+                &mut lz77_writer,
+                &mut length_buffers,
+                &[],
+                options.force_fixed,
+                max_code_length,
+                true,
+            );
+        }
+
+        encoder_state
+            .flush()
+            .expect("Bit writer in an invalid state!");
+        mem::take(encoder_state.inner_vec())
+    }
+}
+
+/// Write the tokens in `lz77_writer`, covering `input`, as a block.
+fn write_block(
+    encoder_state: &mut EncoderState,
+    lz77_writer: &mut DynamicWriter,
+    length_buffers: &mut LengthBuffers,
+    input: &[u8],
+    force_fixed: bool,
+    max_code_length: u8,
+    final_block: bool,
+) {
+    let block_type = if force_fixed {
+        BlockType::Fixed
+    } else {
+        let (l_freqs, d_freqs) = lz77_writer.get_frequencies();
+        let (l_lengths, d_lengths) = encoder_state.huffman_table.get_lengths_mut();
+        gen_huffman_lengths(
+            l_freqs,
+            d_freqs,
+            input.len() as u64,
+            encoder_state.writer.pending_bits(),
+            max_code_length,
+            l_lengths,
+            d_lengths,
+            length_buffers,
+        )
+    };
+    match block_type {
+        BlockType::Dynamic(header) => write_compressed_block(
+            encoder_state,
+            lz77_writer,
+            length_buffers,
+            Some(&header),
+            input.len() as u64,
+            final_block,
+            false,
+        )
+        .expect("Writing a block only fails when checking the header"),
+        BlockType::Fixed => write_compressed_block(
+            encoder_state,
+            lz77_writer,
+            length_buffers,
+            None,
+            input.len() as u64,
+            final_block,
+            false,
+        )
+        .expect("Writing a block only fails when checking the header"),
+        BlockType::Stored => write_stored_block(input, encoder_state, final_block),
+    }
+    lz77_writer.clear();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression_options::{Compression, SpecialOptions};
+    use crate::test_utils::{decompress_to_end, get_random_data, get_test_data};
+    use crate::{deflate_bytes_conf, PreFilter};
+
+    #[test]
+    fn replay_recorded() {
+        let test_data = get_test_data();
+        let random = get_random_data(50_000);
+        for data in [&test_data[..], &random, b"", b"a"] {
+            for &options in &[
+                Compression::Fast.into(),
+                Compression::Best.into(),
+                CompressionOptions::rle(),
+            ] {
+                let tokens = record_tokens(data, options);
+                assert_eq!(tokens.is_empty(), data.is_empty());
+                assert_eq!(
+                    tokens.replay(&ReplayOptions::default()),
+                    deflate_bytes_conf(data, options)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn replay_options() {
+        let data = get_test_data();
+        let tokens = record_tokens(&data, Compression::Default);
+
+        let fixed = ReplayOptions {
+            force_fixed: true,
+            ..ReplayOptions::default()
+        };
+        let fixed_options = CompressionOptions {
+            special: SpecialOptions::ForceFixed,
+            ..CompressionOptions::default()
+        };
+        assert_eq!(
+            tokens.replay(&fixed),
+            deflate_bytes_conf(&data, fixed_options)
+        );
+
+        let limits = BlockLimits {
+            max_symbols: 1000,
+            ..BlockLimits::default()
+        };
+        let limited = ReplayOptions {
+            block_split: BlockSplit::Limits(limits),
+            ..ReplayOptions::default()
+        };
+        let replayed = tokens.replay(&limited);
+        assert!(decompress_to_end(&replayed) == data);
+        assert_ne!(replayed, tokens.replay(&ReplayOptions::default()));
+
+        let limited_codes = ReplayOptions {
+            max_code_length: 10,
+            ..ReplayOptions::default()
+        };
+        let limited_options = CompressionOptions {
+            max_code_length: 10,
+            ..CompressionOptions::default()
+        };
+        assert_eq!(
+            tokens.replay(&limited_codes),
+            deflate_bytes_conf(&data, limited_options)
+        );
+
+        for ends in [
+            vec![],
+            vec![10, 10, 5000],
+            (0..tokens.len()).step_by(777).collect(),
+        ] {
+            let split = ReplayOptions {
+                block_split: BlockSplit::At(ends),
+                ..ReplayOptions::default()
+            };
+            assert!(decompress_to_end(&tokens.replay(&split)) == data);
+        }
+    }
+
+    #[test]
+    fn pre_filter() {
+        let data = get_test_data();
+        let options = CompressionOptions {
+            pre_filter: PreFilter {
+                delta: 1,
+                transpose: 0,
+            },
+            ..CompressionOptions::default()
+        };
+        let tokens = record_tokens(&data, options);
+        assert_eq!(tokens.data(), &options.pre_filter.apply(&data)[..]);
+        assert_eq!(
+            tokens.replay(&ReplayOptions::default()),
+            deflate_bytes_conf(&data, options)
+        );
+    }
+}
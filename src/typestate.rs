@@ -0,0 +1,293 @@
+//! A variant of the low-level compression API where the state of the encoder is part of its type.
+//!
+//! A [`Compressor`](struct.Compressor.html) goes through the following states:
+//!
+//! - [`NeedsInput`](struct.NeedsInput.html): Data can be compressed, and the stream can be
+//!   flushed or finished.
+//! - [`HasPendingOutput`](struct.HasPendingOutput.html): The output produced so far has to be
+//!   drained before the compressor can be used again. After finishing the stream, this is
+//!   `HasPendingOutput<Finished>`, which goes on to `Finished` rather than `NeedsInput`.
+//! - [`Finished`](struct.Finished.html): The stream is done, and the compressor can only be
+//!   reset to start a new one.
+//!
+//! Each method consumes the compressor and returns it in the new state, so using it the wrong
+//! way, like compressing more data after finishing, finishing twice, or not taking out the
+//! output before compressing more, is a compile error rather than a runtime error or corrupt
+//! output:
+//!
+//! ```compile_fail
+//! use deflate::typestate::Compressor;
+//! use deflate::Compression;
+//!
+//! let compressor = Compressor::new(Compression::Default);
+//! let compressor = compressor.compress(b"Some data").unwrap();
+//! // The output has to be drained first.
+//! let compressor = compressor.compress(b"More data").unwrap();
+//! ```
+//!
+//! ```compile_fail
+//! use deflate::typestate::Compressor;
+//! use deflate::Compression;
+//!
+//! let mut output = Vec::new();
+//! let compressor = Compressor::new(Compression::Default);
+//! let compressor = compressor.finish().unwrap().drain_to_vec(&mut output);
+//! // The stream has already been finished.
+//! let compressor = compressor.compress(b"More data").unwrap();
+//! ```
+//!
+//! # Examples
+//!
+//! ```rust
+//! use deflate::typestate::{Compressor, Drain};
+//! use deflate::Compression;
+//!
+//! let mut output = Vec::new();
+//! let compressor = Compressor::new(Compression::Default);
+//! let compressor = compressor
+//!     .compress(b"This is some test data")
+//!     .unwrap()
+//!     .drain_to_vec(&mut output);
+//!
+//! // Drain the rest of the output through a small buffer.
+//! let mut buf = [0; 8];
+//! let mut pending = compressor.finish().unwrap();
+//! let compressor = loop {
+//!     let (n, drain) = pending.drain(&mut buf);
+//!     output.extend_from_slice(&buf[..n]);
+//!     match drain {
+//!         Drain::Pending(compressor) => pending = compressor,
+//!         Drain::Done(compressor) => break compressor,
+//!     }
+//! };
+//!
+//! // Start on a new stream.
+//! let compressor = compressor.reset();
+//! # let _ = compressor;
+//! ```
+
+use std::io::{self, Write};
+use std::marker::PhantomData;
+
+use crate::compression_options::CompressionOptions;
+use crate::writer::DeflateEncoder;
+
+/// The state of a compressor that can take more input.
+#[derive(Debug)]
+pub struct NeedsInput(());
+
+/// The state of a compressor that has output waiting to be drained, after which it goes on to
+/// the state `Next`.
+#[derive(Debug)]
+pub struct HasPendingOutput<Next = NeedsInput>(PhantomData<Next>);
+
+/// The state of a compressor that has finished the stream, and has no more output.
+#[derive(Debug)]
+pub struct Finished(());
+
+/// A DEFLATE compressor with its state `S` tracked in its type, see the
+/// [module documentation](index.html).
+///
+/// The output is raw DEFLATE data, the same as from
+/// [`DeflateEncoder`](../write/struct.DeflateEncoder.html).
+pub struct Compressor<S> {
+    encoder: DeflateEncoder<Vec<u8>>,
+    /// The number of bytes at the start of the output buffer that have already been drained.
+    drained: usize,
+    state: PhantomData<S>,
+}
+
+/// The result of draining some of the output of a compressor.
+pub enum Drain<Next> {
+    /// There is more output to drain.
+    Pending(Compressor<HasPendingOutput<Next>>),
+    /// All of the output has been drained.
+    Done(Compressor<Next>),
+}
+
+impl<S> Compressor<S> {
+    /// Move the compressor to a different state.
+    fn into_state<T>(self) -> Compressor<T> {
+        Compressor {
+            encoder: self.encoder,
+            drained: self.drained,
+            state: PhantomData,
+        }
+    }
+
+    /// The compression options used.
+    pub fn options(&self) -> &CompressionOptions {
+        self.encoder.options()
+    }
+}
+
+impl Compressor<NeedsInput> {
+    /// Create a new compressor using the provided compression options.
+    pub fn new<O: Into<CompressionOptions>>(options: O) -> Compressor<NeedsInput> {
+        Compressor {
+            encoder: DeflateEncoder::new(Vec::new(), options),
+            drained: 0,
+            state: PhantomData,
+        }
+    }
+
+    /// Compress `input`.
+    ///
+    /// Not all of the input is output straight away, so there may not be any output to drain
+    /// yet. If this returns an error, which can only happen if the output failed to verify with
+    /// the `verify` option, the compressor is dropped.
+    pub fn compress(self, input: &[u8]) -> io::Result<Compressor<HasPendingOutput>> {
+        let mut compressor = self;
+        compressor.encoder.write_all(input)?;
+        Ok(compressor.into_state())
+    }
+
+    /// Output all of the input compressed so far, followed by a sync marker, like
+    /// `DeflateEncoder::flush_now`.
+    pub fn flush(self) -> io::Result<Compressor<HasPendingOutput>> {
+        let mut compressor = self;
+        compressor.encoder.flush_now()?;
+        Ok(compressor.into_state())
+    }
+
+    /// Finish the stream, outputting all of the remaining data.
+    pub fn finish(self) -> io::Result<Compressor<HasPendingOutput<Finished>>> {
+        let mut compressor = self;
+        compressor.encoder.try_finish()?;
+        Ok(compressor.into_state())
+    }
+}
+
+impl<Next> Compressor<HasPendingOutput<Next>> {
+    /// The output that has not been drained yet.
+    pub fn pending(&self) -> &[u8] {
+        &self.encoder.get_ref()[self.drained..]
+    }
+
+    /// Copy as much of the pending output as fits into `out`, returning the number of bytes
+    /// copied, and the compressor in the state depending on whether there is more output left.
+    pub fn drain(self, out: &mut [u8]) -> (usize, Drain<Next>) {
+        let mut compressor = self;
+        let n = out.len().min(compressor.pending().len());
+        out[..n].copy_from_slice(&compressor.pending()[..n]);
+        compressor.drained += n;
+        let drain = if compressor.pending().is_empty() {
+            Drain::Done(compressor.drained())
+        } else {
+            Drain::Pending(compressor)
+        };
+        (n, drain)
+    }
+
+    /// Append all of the pending output to `out`.
+    pub fn drain_to_vec(self, out: &mut Vec<u8>) -> Compressor<Next> {
+        out.extend_from_slice(self.pending());
+        self.drained()
+    }
+
+    /// Clear the output buffer, which has been drained, and move on to the next state.
+    fn drained(self) -> Compressor<Next> {
+        let mut compressor = self;
+        compressor.encoder.get_mut().clear();
+        compressor.drained = 0;
+        compressor.into_state()
+    }
+}
+
+impl Compressor<Finished> {
+    /// Reset the compressor to start compressing a new stream, keeping the compression options.
+    pub fn reset(self) -> Compressor<NeedsInput> {
+        let mut compressor = self;
+        // The stream has been finished, so there is nothing left to output that could fail.
+        compressor
+            .encoder
+            .reset(Vec::new())
+            .expect("Finished compressor had more output!");
+        compressor.into_state()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+    use crate::{deflate_bytes_conf, Compression};
+
+    /// Compress `data` in pieces of `input_len` bytes, draining the output through a buffer of
+    /// `output_len` bytes.
+    fn compress_pieces(
+        compressor: Compressor<NeedsInput>,
+        data: &[u8],
+        input_len: usize,
+        output_len: usize,
+    ) -> (Vec<u8>, Compressor<Finished>) {
+        fn drain_all<Next>(
+            mut compressor: Compressor<HasPendingOutput<Next>>,
+            buf: &mut [u8],
+            output: &mut Vec<u8>,
+        ) -> Compressor<Next> {
+            loop {
+                let (n, drain) = compressor.drain(buf);
+                output.extend_from_slice(&buf[..n]);
+                match drain {
+                    Drain::Pending(c) => {
+                        assert_eq!(n, buf.len());
+                        compressor = c;
+                    }
+                    Drain::Done(c) => return c,
+                }
+            }
+        }
+
+        let mut output = Vec::new();
+        let mut buf = vec![0; output_len];
+        let mut compressor = compressor;
+        for piece in data.chunks(input_len) {
+            compressor = drain_all(compressor.compress(piece).unwrap(), &mut buf, &mut output);
+        }
+        let finished = drain_all(compressor.finish().unwrap(), &mut buf, &mut output);
+        (output, finished)
+    }
+
+    #[test]
+    fn same_as_one_shot() {
+        let data = get_test_data();
+        let expected = deflate_bytes_conf(&data, Compression::Default);
+
+        let compressor = Compressor::new(Compression::Default);
+        let (output, finished) = compress_pieces(compressor, &data, 10000, 100);
+        assert_eq!(output, expected);
+
+        // Reset and compress again, with the whole input at once.
+        let (output, _) = compress_pieces(finished.reset(), &data, data.len(), 1);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn flush() {
+        let data = b"This is some test data";
+        let mut output = Vec::new();
+        let compressor = Compressor::new(Compression::Fast)
+            .compress(data)
+            .unwrap()
+            .drain_to_vec(&mut output);
+        let compressor = compressor.flush().unwrap();
+        assert!(compressor.pending().ends_with(&[0, 0, 0xff, 0xff]));
+        let compressor = compressor.drain_to_vec(&mut output);
+
+        compressor
+            .compress(data)
+            .unwrap()
+            .drain_to_vec(&mut output)
+            .finish()
+            .unwrap()
+            .drain_to_vec(&mut output);
+        assert_eq!(decompress_to_end(&output), [&data[..], &data[..]].concat());
+    }
+
+    #[test]
+    fn empty() {
+        let (output, _) = compress_pieces(Compressor::new(Compression::Default), &[], 1, 1);
+        assert_eq!(output, deflate_bytes_conf(&[], Compression::Default));
+    }
+}
@@ -0,0 +1,149 @@
+//! Checking the compressed output a block at a time as it's written, see
+//! [`CompressionOptions::verify`](../struct.CompressionOptions.html#structfield.verify).
+
+use std::io;
+use std::mem;
+
+use crate::chained_hash_table::WINDOW_SIZE;
+use crate::inflate;
+
+/// State used for checking that the blocks that are output decompress to the input.
+#[derive(Default)]
+pub struct Verifier {
+    /// The data that has been verified so far, of which the last 32 KiB can be referred to by
+    /// the following blocks.
+    window: Vec<u8>,
+    /// Input that has been consumed, but not verified yet.
+    pending: Vec<u8>,
+    /// Buffer for the decompressed data.
+    output: Vec<u8>,
+}
+
+impl Verifier {
+    pub fn new() -> Verifier {
+        Verifier::default()
+    }
+
+    /// Add data that the blocks can refer back to without it being part of the output, i.e
+    /// a preset dictionary.
+    pub fn add_history(&mut self, data: &[u8]) {
+        self.window.extend_from_slice(data);
+        // Only trim the window once it's grown a fair bit, to avoid moving the data each time.
+        if self.window.len() > WINDOW_SIZE * 2 {
+            let excess = self.window.len() - WINDOW_SIZE;
+            self.window.drain(..excess);
+        }
+    }
+
+    /// Add input consumed by the encoder, which the following blocks should decompress to.
+    pub fn add_input(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Check that the blocks that start at bit `start_bit` of `compressed` and end at bit
+    /// `end_bit` decompress to the next `input_len` bytes of input.
+    pub fn check_blocks(
+        &mut self,
+        compressed: &[u8],
+        start_bit: u8,
+        end_bit: u64,
+        input_len: usize,
+    ) -> io::Result<()> {
+        let history = &self.window[self.window.len().saturating_sub(WINDOW_SIZE)..];
+        self.output.clear();
+        inflate::decode_blocks(compressed, start_bit, end_bit, history, &mut self.output)?;
+        if self.pending.get(..input_len) != Some(&self.output[..]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Verification failed: the compressed block does not decompress to the input",
+            ));
+        }
+        self.pending.drain(..input_len);
+        let output = mem::take(&mut self.output);
+        self.add_history(&output);
+        self.output = output;
+        Ok(())
+    }
+
+    /// Forget the data verified so far, when the encoder does the same.
+    pub fn reset_context(&mut self) {
+        self.window.clear();
+    }
+
+    /// Clear all the state, to start verifying a new stream.
+    pub fn reset(&mut self) {
+        self.window.clear();
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Verifier;
+    use crate::compression_options::{BlockLimits, CompressionOptions};
+    use crate::deflate_bytes_conf;
+    use crate::inflate::{analyze, BlockInfo};
+    use crate::test_utils::get_test_data;
+
+    #[test]
+    fn check_blocks() {
+        let data = get_test_data();
+        let compressed = deflate_bytes_conf(&data, CompressionOptions::default());
+        let end_bit = analyze(&compressed).unwrap().last().unwrap().end_bit;
+
+        let mut verifier = Verifier::new();
+        verifier.add_input(&data);
+        // The blocks have to end exactly at the end bit.
+        assert!(verifier
+            .check_blocks(&compressed, 0, end_bit - 1, data.len())
+            .is_err());
+        verifier
+            .check_blocks(&compressed, 0, end_bit, data.len())
+            .unwrap();
+
+        // Different input.
+        let mut verifier = Verifier::new();
+        verifier.add_input(&data[1..]);
+        verifier.add_input(b"x");
+        assert!(verifier
+            .check_blocks(&compressed, 0, end_bit, data.len())
+            .is_err());
+    }
+
+    #[test]
+    fn check_blocks_with_history() {
+        let data = get_test_data();
+        let options = CompressionOptions {
+            block_limits: BlockLimits {
+                max_symbols: 1000,
+                ..BlockLimits::DEFAULT
+            },
+            ..CompressionOptions::default()
+        };
+        let compressed = deflate_bytes_conf(&data, options);
+        let blocks = analyze(&compressed).unwrap();
+        assert!(blocks.len() > 2);
+
+        let check = |verifier: &mut Verifier, block: &BlockInfo| {
+            let start_byte = (block.start_bit / 8) as usize;
+            verifier.check_blocks(
+                &compressed[start_byte..],
+                (block.start_bit % 8) as u8,
+                block.end_bit - start_byte as u64 * 8,
+                block.output_len as usize,
+            )
+        };
+
+        // The later blocks refer back to the data in the earlier ones.
+        let mut verifier = Verifier::new();
+        verifier.add_input(&data[blocks[1].output_start as usize..]);
+        assert!(check(&mut verifier, &blocks[1]).is_err());
+
+        let mut verifier = Verifier::new();
+        verifier.add_input(&data);
+        for block in &blocks {
+            check(&mut verifier, block).unwrap();
+        }
+        assert!(verifier.pending.is_empty());
+    }
+}
@@ -1,14 +1,19 @@
 use std::io::Write;
-use std::{io, thread};
+use std::{fmt, io, mem, thread};
 
 use byteorder::{BigEndian, WriteBytesExt};
 
-use crate::checksum::{Adler32Checksum, RollingChecksum};
-use crate::compress::compress_data_dynamic_n;
+#[cfg(feature = "allocator_api")]
+use crate::allocator::BufferAllocator;
+use crate::checksum::{Adler32Checksum, NoChecksum, RollingChecksum};
 use crate::compress::Flush;
-use crate::compression_options::CompressionOptions;
+use crate::compress::{compress_data_dynamic_n, write_pending_output};
+use crate::compression_options::{CompressionOptions, ConfigError};
+use crate::data_kind::DataKind;
 use crate::deflate_state::DeflateState;
-use crate::zlib::{write_zlib_header, CompressionLevel};
+use crate::encoder_state::BlockBoundary;
+use crate::flush_coalescing::FlushCoalescing;
+use crate::zlib::{write_zlib_header, write_zlib_header_dict, CompressionLevel};
 
 const ERR_STR: &str = "Error! The wrapped writer is missing.\
                        This is a bug, please file an issue.";
@@ -21,6 +26,11 @@ pub fn compress_until_done<W: Write>(
 ) -> io::Result<()> {
     // This should only be used for flushing.
     assert!(flush_mode != Flush::None);
+    if flush_mode == Flush::Sync && deflate_state.synced && input.is_empty() {
+        // Nothing has been input since the last sync flush, which emptied the output buffer,
+        // so there is nothing to do. Avoid writing another redundant sync marker.
+        return Ok(());
+    }
     loop {
         match compress_data_dynamic_n(input, deflate_state, flush_mode) {
             Ok(0) => {
@@ -56,14 +66,165 @@ pub fn compress_until_done<W: Write>(
         deflate_state.bytes_written_control.get()
     );
 
+    if flush_mode == Flush::Sync {
+        deflate_state.metrics.record_flush();
+        deflate_state.pending_input = false;
+        deflate_state.synced = true;
+        if let Some(ref mut coalescing) = deflate_state.flush_coalescing {
+            coalescing.flushed(deflate_state.bytes_written);
+        }
+    }
+    Ok(())
+}
+
+/// Write all of the data in the output buffer that has not been output yet to the wrapped writer.
+pub fn write_all_pending<W: Write>(deflate_state: &mut DeflateState<W>) -> io::Result<()> {
+    while deflate_state.output_buf_pos < deflate_state.output_buf().len() {
+        match write_pending_output(deflate_state) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
     Ok(())
 }
 
+/// Compress all of `input`, without flushing.
+pub fn compress_all<W: Write>(
+    mut input: &[u8],
+    deflate_state: &mut DeflateState<W>,
+) -> io::Result<()> {
+    while !input.is_empty() {
+        match compress_data_dynamic_n(input, deflate_state, Flush::None) {
+            Ok(n) => input = &input[n..],
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Compress the data in `buf`, updating `checksum` with the data that is passed on to the
+/// compressor.
+///
+/// If a pre-filter is used, the input is run through it first, and all of it is consumed, as
+/// filtered data that could not be compressed right away is kept until the next call.
+pub fn compress_filtered<W: Write, RC: RollingChecksum>(
+    buf: &[u8],
+    deflate_state: &mut DeflateState<W>,
+    mut checksum: RC,
+) -> io::Result<usize> {
+    if let Some(e) = deflate_state.deferred_error.take() {
+        return Err(e);
+    }
+    let flush_mode = deflate_state.flush_mode;
+    if !deflate_state.pre_filter.is_active() {
+        let res = compress_data_dynamic_n(buf, deflate_state, flush_mode);
+        if let Ok(n) = res {
+            checksum.update_from_slice(&buf[..n]);
+        }
+        return res;
+    }
+
+    // Deal with any data left over from the previous call before consuming more input, so
+    // errors from the writer are reported here.
+    if !deflate_state.pre_filter.output.is_empty() {
+        compress_pending_filtered(deflate_state, &mut checksum)?;
+    }
+
+    if !buf.is_empty() {
+        deflate_state.set_pending_input();
+    }
+    deflate_state.pre_filter.push(buf);
+    // The input is consumed at this point, so if this fails, the error is kept and returned by
+    // the next call instead.
+    if let Err(e) = compress_pending_filtered(deflate_state, &mut checksum) {
+        deflate_state.deferred_error = Some(e);
+    }
+    Ok(buf.len())
+}
+
+/// Try compressing the filtered data that is ready, keeping any data that was not consumed.
+fn compress_pending_filtered<W: Write, RC: RollingChecksum>(
+    deflate_state: &mut DeflateState<W>,
+    mut checksum: RC,
+) -> io::Result<()> {
+    let flush_mode = deflate_state.flush_mode;
+    // Move the data out of the state while compressing it.
+    let mut filtered = mem::take(&mut deflate_state.pre_filter.output);
+    let (consumed, res) = match compress_data_dynamic_n(&filtered, deflate_state, flush_mode) {
+        Ok(n) => (n, Ok(())),
+        // This just means the output buffer was full, so we try again on the next call.
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (0, Ok(())),
+        Err(e) => (0, Err(e)),
+    };
+    checksum.update_from_slice(&filtered[..consumed]);
+    filtered.drain(..consumed);
+    deflate_state.pre_filter.output = filtered;
+    res
+}
+
+/// Compress any data held back by the pre-filter, if any, and keep compressing until all
+/// the input has been output, see `compress_until_done`.
+///
+/// Data held back by the pre-filter waiting for more input is only output when finishing.
+pub fn compress_until_done_filtered<W: Write, RC: RollingChecksum>(
+    deflate_state: &mut DeflateState<W>,
+    mut checksum: RC,
+    flush_mode: Flush,
+) -> io::Result<()> {
+    if let Some(e) = deflate_state.deferred_error.take() {
+        return Err(e);
+    }
+    if deflate_state.pre_filter.is_active() {
+        if flush_mode == Flush::Finish {
+            deflate_state.pre_filter.finish();
+        }
+        let filtered = mem::take(&mut deflate_state.pre_filter.output);
+        checksum.update_from_slice(&filtered);
+        compress_all(&filtered, deflate_state)?;
+    }
+    compress_until_done(&[], deflate_state, flush_mode)
+}
+
+/// Pass the slices in `bufs` to `write` in order, stopping at the first one that is not fully
+/// consumed.
+///
+/// As the input is copied into the window of the compressor anyhow, slices that are not
+/// contiguous in memory, like the two halves of the data in a ring buffer, end up next to each
+/// other there, so matches can still span the boundary between them.
+///
+/// If an error occurs after some data has been consumed, the amount consumed is returned
+/// instead, as that input can't be given back. The error is then reported by the next call.
+fn write_vectored_with<F: FnMut(&[u8]) -> io::Result<usize>>(
+    bufs: &[io::IoSlice],
+    mut write: F,
+) -> io::Result<usize> {
+    let mut written = 0;
+    for buf in bufs.iter().filter(|buf| !buf.is_empty()) {
+        match write(buf) {
+            Ok(n) => {
+                written += n;
+                if n < buf.len() {
+                    break;
+                }
+            }
+            Err(e) => return if written == 0 { Err(e) } else { Ok(written) },
+        }
+    }
+    Ok(written)
+}
+
 /// A DEFLATE encoder/compressor.
 ///
 /// A struct implementing a [`Write`] interface that takes unencoded data and compresses it to
 /// the provided writer using DEFLATE compression.
 ///
+/// The part of the input that is still needed for finding matches is copied into a buffer owned
+/// by the encoder, so it never borrows the data passed to `write`. As long as the wrapped writer
+/// doesn't borrow anything either, the encoder is `'static`, and can be kept around between
+/// writes or moved into another thread or task.
+///
 /// # Examples
 ///
 /// ```rust
@@ -100,8 +261,41 @@ impl<W: Write> DeflateEncoder<W> {
         }
     }
 
+    /// Creates a new encoder using the provided compression options, after checking them with
+    /// [`CompressionOptions::validate`](../struct.CompressionOptions.html#method.validate).
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from `validate` if the options are inconsistent or out of range,
+    /// rather than treating them as the closest valid ones like `new` does.
+    pub fn try_new<O: Into<CompressionOptions>>(
+        writer: W,
+        options: O,
+    ) -> Result<DeflateEncoder<W>, ConfigError> {
+        let options = options.into();
+        options.validate()?;
+        Ok(DeflateEncoder::new(writer, options))
+    }
+
+    /// Creates a new encoder using the provided compression options, allocating the large
+    /// internal buffers using `alloc`.
+    ///
+    /// Only available with the `allocator_api` feature, which requires a nightly compiler.
+    #[cfg(feature = "allocator_api")]
+    pub fn new_in<O: Into<CompressionOptions>>(
+        writer: W,
+        options: O,
+        alloc: BufferAllocator,
+    ) -> DeflateEncoder<W> {
+        DeflateEncoder {
+            deflate_state: DeflateState::new_in(options.into(), writer, &alloc),
+        }
+    }
+
     /// Encode all pending data to the contained writer, consume this `DeflateEncoder`,
     /// and return the contained writer if writing succeeds.
+    ///
+    /// To be able to retry if writing fails, use [`try_finish`](#method.try_finish) first.
     pub fn finish(mut self) -> io::Result<W> {
         self.output_all()?;
         // We have to move the inner writer out of the encoder, and replace it with `None`
@@ -109,8 +303,21 @@ impl<W: Write> DeflateEncoder<W> {
         Ok(self.deflate_state.inner.take().expect(ERR_STR))
     }
 
+    /// Encode all pending data to the contained writer without consuming the encoder.
+    ///
+    /// If writing fails, no data is lost, so this can be called again to resume once the error
+    /// has been dealt with. Once this has succeeded, no more data should be written, and
+    /// [`finish`](#method.finish) will just return the writer.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.output_all()
+    }
+
     /// Resets the encoder (except the compression options), replacing the current writer
     /// with a new one, returning the old one.
+    ///
+    /// The internal buffers and hash table are cleared and kept rather than freed, so reusing an
+    /// encoder this way avoids allocating for every stream, e.g when compressing many small
+    /// payloads.
     pub fn reset(&mut self, w: W) -> io::Result<W> {
         self.output_all()?;
         self.deflate_state.reset(w)
@@ -118,14 +325,192 @@ impl<W: Write> DeflateEncoder<W> {
 
     /// Output all pending data as if encoding is done, but without resetting anything
     fn output_all(&mut self) -> io::Result<()> {
-        compress_until_done(&[], &mut self.deflate_state, Flush::Finish)
+        compress_until_done_filtered(&mut self.deflate_state, NoChecksum::new(), Flush::Finish)
+    }
+
+    /// Fill the window with the end of the uncompressed content that came before this stream,
+    /// so the data written next can refer back to it, e.g when compressing a stream of
+    /// snapshots that each differ little from the one before.
+    ///
+    /// Unlike a preset dictionary, this can be called several times with consecutive pieces of
+    /// the previous content, and only the last 32 KiB of it all is kept. The decoder has to have
+    /// the same content in its window, as the output does not refer to it in any way.
+    ///
+    /// This has to be done before any data is written (after creating or resetting the
+    /// encoder), otherwise an error of kind `InvalidInput` is returned. If a pre-filter is
+    /// used, the previous content should be given as filtered.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::io::Write;
+    ///
+    /// use deflate::write::DeflateEncoder;
+    /// use deflate::Compression;
+    ///
+    /// let previous = b"temperature=21.5 humidity=40 pressure=1013";
+    /// let current = b"temperature=21.6 humidity=40 pressure=1013";
+    /// let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+    /// encoder.prime_from_previous_output(previous).unwrap();
+    /// encoder.write_all(current).unwrap();
+    /// let compressed = encoder.finish().unwrap();
+    /// ```
+    pub fn prime_from_previous_output(&mut self, previous: &[u8]) -> io::Result<()> {
+        if self.deflate_state.bytes_written != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The window can only be primed before any data is written",
+            ));
+        }
+        self.deflate_state.add_history(previous);
+        Ok(())
+    }
+
+    /// Returns `true` if data has been written to the encoder since the last flush (or since
+    /// it was created or reset), meaning that calling `flush()` will output something.
+    ///
+    /// If this returns `false` the previous flush already output a sync marker, so another
+    /// one would be redundant and `flush()` does nothing.
+    pub fn has_pending(&self) -> bool {
+        self.deflate_state.has_pending()
+    }
+
+    /// Get the options used by the encoder, with the compression level resolved to the
+    /// individual parameters, and values out of range replaced with the ones that are actually
+    /// used, see [`CompressionOptions::resolved`](../struct.CompressionOptions.html#method.resolved).
+    pub fn options(&self) -> &CompressionOptions {
+        &self.deflate_state.compression_options
+    }
+
+    /// The kind of data detected at the start of the input when using `Compression::Auto`,
+    /// which decides the matching settings used.
+    ///
+    /// Returns `None` if no data has been written yet, or if `Compression::Auto` is not used.
+    pub fn data_kind(&self) -> Option<DataKind> {
+        self.deflate_state.data_kind
+    }
+
+    /// Get the compressed data that has been produced, but not written to the wrapped writer
+    /// yet, e.g to work out how large the output would be if flushed now.
+    ///
+    /// This does not include the bits of the last, incomplete, byte (see
+    /// [`pending_bits`](#method.pending_bits)), or the input that has not been compressed into
+    /// a block yet. The bytes may also still change before they are written, as the last block
+    /// is marked as the final one when finishing, if it has not been written out.
+    pub fn peek_pending(&self) -> &[u8] {
+        self.deflate_state.pending_output()
+    }
+
+    /// The number of bits of compressed data that have been produced, but are not part of a
+    /// whole byte yet, and so are not included in [`peek_pending`](#method.peek_pending).
+    pub fn pending_bits(&self) -> u8 {
+        self.deflate_state.encoder_state.writer.pending_bits()
+    }
+
+    /// Get a reference to the wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        self.deflate_state.inner.as_ref().expect(ERR_STR)
+    }
+
+    /// Get a mutable reference to the wrapped writer.
+    ///
+    /// Writing to it directly will corrupt the output, this is meant for taking out the data
+    /// output so far, e.g to send the contents of a `Vec<u8>` after a `flush()`.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.deflate_state.inner.as_mut().expect(ERR_STR)
+    }
+
+    /// End a message in a message-based protocol, like the permessage-deflate WebSocket
+    /// extension, by flushing the encoder the same way as `flush()`.
+    ///
+    /// If `retain_context` is `true`, the next messages can refer back to the data in this one
+    /// as usual (context takeover). If it is `false`, the data compressed so far is forgotten,
+    /// so the next message can be decompressed without the ones before it, as with the
+    /// `no_context_takeover` parameters of permessage-deflate. Either way, the output of all the
+    /// messages together makes up one valid DEFLATE stream.
+    ///
+    /// Outside of message-based protocols, this gives zlib's flush modes: `flush()` is a sync
+    /// flush (`Z_SYNC_FLUSH`), `end_message(false)` is a full flush (`Z_FULL_FLUSH`), which also
+    /// makes the output after it a valid starting point for decompression, and `finish()` ends the
+    /// stream (`Z_FINISH`).
+    ///
+    /// Note that permessage-deflate leaves out the `00 00 ff ff` at the end of the sync marker
+    /// following each message, which is left to the caller.
+    pub fn end_message(&mut self, retain_context: bool) -> io::Result<()> {
+        self.flush_now()?;
+        if !retain_context {
+            self.deflate_state.reset_context();
+        }
+        Ok(())
+    }
+
+    /// Combine flushes done in quick succession, so `flush()` is skipped unless enough input has
+    /// been written or enough time has passed since the last flush, see
+    /// [`FlushCoalescing`](../struct.FlushCoalescing.html). `None` turns this off again.
+    pub fn set_flush_coalescing(&mut self, settings: Option<FlushCoalescing>) {
+        self.deflate_state.set_flush_coalescing(settings);
+    }
+
+    /// Flush the encoder the same way as `flush()`, regardless of the flush coalescing
+    /// settings.
+    pub fn flush_now(&mut self) -> io::Result<()> {
+        compress_until_done_filtered(&mut self.deflate_state, NoChecksum::new(), Flush::Sync)
+    }
+
+    /// Set a callback to be invoked at the start of every block that is output, with the
+    /// position of the block in the uncompressed and compressed data, and its type.
+    ///
+    /// This can be used to build an index of the compressed data, e.g for random access. Note
+    /// that decompressing from a block boundary also requires the previous 32 KiB of data, as
+    /// matches can refer to data in earlier blocks. The callback is kept when the encoder is
+    /// reset, and the positions start over from zero.
+    pub fn set_block_callback<F: FnMut(&BlockBoundary) + Send + 'static>(&mut self, callback: F) {
+        self.deflate_state
+            .encoder_state
+            .set_block_callback(Some(Box::new(callback)));
+    }
+
+    /// Pad the end of the stream so that its length is a multiple of `alignment` bytes, e.g
+    /// for storage formats requiring aligned records. `0` or `1` turns this off.
+    ///
+    /// The padding is made up of empty blocks before the final one, each taking up a byte or
+    /// two, so the output is still a single valid DEFLATE stream without any trailing data.
+    /// Only the output of this encoder since it was created or reset is counted. The setting is
+    /// kept when the encoder is reset.
+    pub fn set_output_alignment(&mut self, alignment: u16) {
+        self.deflate_state.set_output_alignment(alignment, 0);
+    }
+
+    /// Make every block start on a byte boundary, by ending each block but the last one with a
+    /// sync marker, as if the encoder was flushed after every block.
+    ///
+    /// Together with the positions given to the
+    /// [block callback](#method.set_block_callback), this makes the output splittable for
+    /// parallel decompression: each reported block starts at bit `0` of its `output_byte`, and
+    /// the sync markers (`00 00 ff ff`) between blocks can also be found by scanning the
+    /// output. The sync markers themselves are not reported. As matches can still refer to the
+    /// previous 32 KiB of data, a block can only be decompressed on its own if that data is
+    /// known, e.g from an index; use [`FrameEncoder`](../struct.FrameEncoder.html) for pieces
+    /// that are fully independent.
+    ///
+    /// Each sync marker takes up five bytes, plus the padding to the byte boundary. The setting
+    /// is kept when the encoder is reset.
+    pub fn set_aligned_blocks(&mut self, aligned: bool) {
+        self.deflate_state.aligned_blocks = aligned;
     }
 }
 
 impl<W: Write> io::Write for DeflateEncoder<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let flush_mode = self.deflate_state.flush_mode;
-        compress_data_dynamic_n(buf, &mut self.deflate_state, flush_mode)
+        compress_filtered(buf, &mut self.deflate_state, NoChecksum::new())
+    }
+
+    /// Compress the data in several slices, as if it was one contiguous slice.
+    ///
+    /// This can be used to compress e.g the data in a ring buffer without copying it into a
+    /// contiguous buffer first. Matches can refer across the boundaries between the slices.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        write_vectored_with(bufs, |buf| self.write(buf))
     }
 
     /// Flush the encoder.
@@ -133,8 +518,25 @@ impl<W: Write> io::Write for DeflateEncoder<W> {
     /// This will flush the encoder, emulating the Sync flush method from Zlib.
     /// This essentially finishes the current block, and sends an additional empty stored block to
     /// the writer.
+    ///
+    /// If nothing has been written since the last flush, this does nothing, so no redundant
+    /// empty stored blocks are output. This also does nothing if flush coalescing is enabled and
+    /// the flush is not yet due, see `set_flush_coalescing`.
     fn flush(&mut self) -> io::Result<()> {
-        compress_until_done(&[], &mut self.deflate_state, Flush::Sync)
+        if self.deflate_state.flush_due() {
+            self.flush_now()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for DeflateEncoder<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeflateEncoder")
+            .field("inner", &self.deflate_state.inner)
+            .field("options", &self.deflate_state.compression_options)
+            .finish_non_exhaustive()
     }
 }
 
@@ -186,6 +588,9 @@ pub struct ZlibEncoder<W: Write> {
     deflate_state: DeflateState<W>,
     checksum: Adler32Checksum,
     header_written: bool,
+    trailer_written: bool,
+    /// The adler32 checksum of the preset dictionary, if one is used.
+    dict_id: Option<u32>,
 }
 
 impl<W: Write> ZlibEncoder<W> {
@@ -195,19 +600,75 @@ impl<W: Write> ZlibEncoder<W> {
             deflate_state: DeflateState::new(options.into(), writer),
             checksum: Adler32Checksum::new(),
             header_written: false,
+            trailer_written: false,
+            dict_id: None,
+        }
+    }
+
+    /// Create a new `ZlibEncoder` using the provided compression options, after checking them,
+    /// see [`DeflateEncoder::try_new`](struct.DeflateEncoder.html#method.try_new).
+    pub fn try_new<O: Into<CompressionOptions>>(
+        writer: W,
+        options: O,
+    ) -> Result<ZlibEncoder<W>, ConfigError> {
+        let options = options.into();
+        options.validate()?;
+        Ok(ZlibEncoder::new(writer, options))
+    }
+
+    /// Create a new `ZlibEncoder` using the provided compression options, allocating the large
+    /// internal buffers using `alloc`.
+    ///
+    /// Only available with the `allocator_api` feature, which requires a nightly compiler.
+    #[cfg(feature = "allocator_api")]
+    pub fn new_in<O: Into<CompressionOptions>>(
+        writer: W,
+        options: O,
+        alloc: BufferAllocator,
+    ) -> ZlibEncoder<W> {
+        ZlibEncoder {
+            deflate_state: DeflateState::new_in(options.into(), writer, &alloc),
+            checksum: Adler32Checksum::new(),
+            header_written: false,
+            trailer_written: false,
+            dict_id: None,
         }
     }
 
+    /// Create a new `ZlibEncoder` using the provided compression options and a preset
+    /// dictionary.
+    ///
+    /// The compressor may refer back to the last 32 KiB of the dictionary as if it was data
+    /// that came before the input. The zlib header will contain the adler32 checksum of the
+    /// dictionary, and the decompressor has to be given the same dictionary to decompress the
+    /// data.
+    ///
+    /// If the encoder is reset, the dictionary is discarded.
+    pub fn with_dictionary<O: Into<CompressionOptions>>(
+        writer: W,
+        options: O,
+        dictionary: &[u8],
+    ) -> ZlibEncoder<W> {
+        let mut encoder = ZlibEncoder::new(writer, options);
+        encoder.deflate_state.set_dictionary(dictionary);
+        let mut dict_checksum = Adler32Checksum::new();
+        dict_checksum.update_from_slice(dictionary);
+        encoder.dict_id = Some(dict_checksum.current_hash());
+        encoder
+    }
+
     /// Output all pending data ,including the trailer(checksum) as if encoding is done,
     /// but without resetting anything.
     fn output_all(&mut self) -> io::Result<()> {
         self.check_write_header()?;
-        compress_until_done(&[], &mut self.deflate_state, Flush::Finish)?;
+        compress_until_done_filtered(&mut self.deflate_state, &mut self.checksum, Flush::Finish)?;
         self.write_trailer()
     }
 
     /// Encode all pending data to the contained writer, consume this `ZlibEncoder`,
     /// and return the contained writer if writing succeeds.
+    ///
+    /// To be able to retry if writing fails, use [`try_finish`](#method.try_finish) first.
     pub fn finish(mut self) -> io::Result<W> {
         self.output_all()?;
         // We have to move the inner writer out of the encoder, and replace it with `None`
@@ -215,55 +676,172 @@ impl<W: Write> ZlibEncoder<W> {
         Ok(self.deflate_state.inner.take().expect(ERR_STR))
     }
 
+    /// Encode all pending data, including the trailer, to the contained writer without
+    /// consuming the encoder.
+    ///
+    /// If writing fails, no data is lost, so this can be called again to resume once the error
+    /// has been dealt with. Once this has succeeded, no more data should be written, and
+    /// [`finish`](#method.finish) will just return the writer.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.output_all()
+    }
+
     /// Resets the encoder (except the compression options), replacing the current writer
     /// with a new one, returning the old one.
     pub fn reset(&mut self, writer: W) -> io::Result<W> {
         self.output_all()?;
         self.header_written = false;
+        self.trailer_written = false;
         self.checksum = Adler32Checksum::new();
+        self.dict_id = None;
         self.deflate_state.reset(writer)
     }
 
     /// Check if a zlib header should be written.
     fn check_write_header(&mut self) -> io::Result<()> {
         if !self.header_written {
-            write_zlib_header(self.deflate_state.output_buf(), CompressionLevel::Default)?;
+            match self.dict_id {
+                Some(dict_id) => write_zlib_header_dict(
+                    self.deflate_state.output_buf(),
+                    CompressionLevel::Default,
+                    dict_id,
+                )?,
+                None => {
+                    write_zlib_header(self.deflate_state.output_buf(), CompressionLevel::Default)?
+                }
+            }
             self.header_written = true;
         }
         Ok(())
     }
 
     /// Write the trailer, which for zlib is the Adler32 checksum.
+    ///
+    /// The trailer is added to the output buffer, so it's not written twice, or only partially
+    /// written, if writing fails and this is called again.
     fn write_trailer(&mut self) -> io::Result<()> {
-        let hash = self.checksum.current_hash();
-
-        self.deflate_state
-            .inner
-            .as_mut()
-            .expect(ERR_STR)
-            .write_u32::<BigEndian>(hash)
+        if !self.trailer_written {
+            let hash = self.checksum.current_hash();
+            self.deflate_state
+                .output_buf()
+                .write_u32::<BigEndian>(hash)?;
+            self.trailer_written = true;
+        }
+        write_all_pending(&mut self.deflate_state)
     }
 
     /// Return the adler32 checksum of the currently consumed data.
     pub fn checksum(&self) -> u32 {
         self.checksum.current_hash()
     }
+
+    /// Returns `true` if data has been written to the encoder since the last flush (or since
+    /// it was created or reset), meaning that calling `flush()` will output something.
+    ///
+    /// If this returns `false` the previous flush already output a sync marker, so another
+    /// one would be redundant and `flush()` does nothing.
+    pub fn has_pending(&self) -> bool {
+        self.deflate_state.has_pending()
+    }
+
+    /// Get the options used by the encoder, see
+    /// [`DeflateEncoder::options`](struct.DeflateEncoder.html#method.options).
+    pub fn options(&self) -> &CompressionOptions {
+        &self.deflate_state.compression_options
+    }
+
+    /// The kind of data detected at the start of the input when using `Compression::Auto`, see
+    /// [`DeflateEncoder::data_kind`](struct.DeflateEncoder.html#method.data_kind).
+    pub fn data_kind(&self) -> Option<DataKind> {
+        self.deflate_state.data_kind
+    }
+
+    /// Get the compressed data that has been produced, but not written to the wrapped writer
+    /// yet, see [`DeflateEncoder::peek_pending`](struct.DeflateEncoder.html#method.peek_pending).
+    ///
+    /// This includes the zlib header until it's been written.
+    pub fn peek_pending(&self) -> &[u8] {
+        self.deflate_state.pending_output()
+    }
+
+    /// The number of bits of compressed data that are not part of a whole byte yet, see
+    /// [`DeflateEncoder::pending_bits`](struct.DeflateEncoder.html#method.pending_bits).
+    pub fn pending_bits(&self) -> u8 {
+        self.deflate_state.encoder_state.writer.pending_bits()
+    }
+
+    /// Get a reference to the wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        self.deflate_state.inner.as_ref().expect(ERR_STR)
+    }
+
+    /// Get a mutable reference to the wrapped writer, see
+    /// [`DeflateEncoder::get_mut`](struct.DeflateEncoder.html#method.get_mut).
+    pub fn get_mut(&mut self) -> &mut W {
+        self.deflate_state.inner.as_mut().expect(ERR_STR)
+    }
+
+    /// End a message in a message-based protocol by flushing the encoder, optionally
+    /// forgetting the data compressed so far, see
+    /// [`DeflateEncoder::end_message`](struct.DeflateEncoder.html#method.end_message).
+    ///
+    /// The checksum in the trailer covers all of the messages.
+    pub fn end_message(&mut self, retain_context: bool) -> io::Result<()> {
+        self.flush_now()?;
+        if !retain_context {
+            self.deflate_state.reset_context();
+        }
+        Ok(())
+    }
+
+    /// Combine flushes done in quick succession, see
+    /// [`DeflateEncoder::set_flush_coalescing`](struct.DeflateEncoder.html#method.set_flush_coalescing).
+    pub fn set_flush_coalescing(&mut self, settings: Option<FlushCoalescing>) {
+        self.deflate_state.set_flush_coalescing(settings);
+    }
+
+    /// Flush the encoder the same way as `flush()`, regardless of the flush coalescing
+    /// settings.
+    pub fn flush_now(&mut self) -> io::Result<()> {
+        self.check_write_header()?;
+        compress_until_done_filtered(&mut self.deflate_state, &mut self.checksum, Flush::Sync)
+    }
+
+    /// Set a callback to be invoked at the start of every block that is output, see
+    /// [`DeflateEncoder::set_block_callback`](struct.DeflateEncoder.html#method.set_block_callback).
+    ///
+    /// The output positions include the two byte zlib header.
+    pub fn set_block_callback<F: FnMut(&BlockBoundary) + Send + 'static>(&mut self, callback: F) {
+        self.deflate_state
+            .encoder_state
+            .set_block_callback(Some(Box::new(callback)));
+    }
+
+    /// Pad the end of the stream so that its length is a multiple of `alignment` bytes, see
+    /// [`DeflateEncoder::set_output_alignment`](struct.DeflateEncoder.html#method.set_output_alignment).
+    ///
+    /// The length includes the zlib header and trailer.
+    pub fn set_output_alignment(&mut self, alignment: u16) {
+        self.deflate_state.set_output_alignment(alignment, 4);
+    }
+
+    /// Make every block start on a byte boundary, see
+    /// [`DeflateEncoder::set_aligned_blocks`](struct.DeflateEncoder.html#method.set_aligned_blocks).
+    pub fn set_aligned_blocks(&mut self, aligned: bool) {
+        self.deflate_state.aligned_blocks = aligned;
+    }
 }
 
 impl<W: Write> io::Write for ZlibEncoder<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.check_write_header()?;
-        let flush_mode = self.deflate_state.flush_mode;
-        let res = compress_data_dynamic_n(buf, &mut self.deflate_state, flush_mode);
-        match res {
-            // If this is returned, the whole buffer was consumed
-            Ok(0) => self.checksum.update_from_slice(buf),
-            // Otherwise, only part of it was consumed, so only that part
-            // added to the checksum.
-            Ok(n) => self.checksum.update_from_slice(&buf[0..n]),
-            _ => (),
-        };
-        res
+        compress_filtered(buf, &mut self.deflate_state, &mut self.checksum)
+    }
+
+    /// Compress the data in several slices, as if it was one contiguous slice, see
+    /// [`DeflateEncoder::write_vectored`](struct.DeflateEncoder.html#method.write_vectored).
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        write_vectored_with(bufs, |buf| self.write(buf))
     }
 
     /// Flush the encoder.
@@ -271,8 +849,27 @@ impl<W: Write> io::Write for ZlibEncoder<W> {
     /// This will flush the encoder, emulating the Sync flush method from Zlib.
     /// This essentially finishes the current block, and sends an additional empty stored block to
     /// the writer.
+    ///
+    /// If nothing has been written since the last flush, this does nothing, so no redundant
+    /// empty stored blocks are output. This also does nothing if flush coalescing is enabled and
+    /// the flush is not yet due, see `set_flush_coalescing`.
     fn flush(&mut self) -> io::Result<()> {
-        compress_until_done(&[], &mut self.deflate_state, Flush::Sync)
+        if self.deflate_state.flush_due() {
+            self.flush_now()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for ZlibEncoder<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZlibEncoder")
+            .field("inner", &self.deflate_state.inner)
+            .field("options", &self.deflate_state.compression_options)
+            .field("checksum", &self.checksum())
+            .field("dict_id", &self.dict_id)
+            .finish_non_exhaustive()
     }
 }
 
@@ -292,8 +889,8 @@ impl<W: Write> Drop for ZlibEncoder<W> {
 #[cfg(feature = "gzip")]
 pub mod gzip {
 
-    use std::io::{Cursor, Write};
-    use std::{io, thread};
+    use std::io::Write;
+    use std::{fmt, io, thread};
 
     use super::*;
 
@@ -333,6 +930,7 @@ pub mod gzip {
         inner: DeflateEncoder<W>,
         checksum: Crc,
         header: Vec<u8>,
+        trailer_written: bool,
     }
 
     impl<W: Write> GzEncoder<W> {
@@ -342,6 +940,18 @@ pub mod gzip {
             GzEncoder::from_builder(GzBuilder::new(), writer, options)
         }
 
+        /// Create a new `GzEncoder` with a blank header, after checking the compression
+        /// options, see
+        /// [`DeflateEncoder::try_new`](struct.DeflateEncoder.html#method.try_new).
+        pub fn try_new<O: Into<CompressionOptions>>(
+            writer: W,
+            options: O,
+        ) -> Result<GzEncoder<W>, ConfigError> {
+            let options = options.into();
+            options.validate()?;
+            Ok(GzEncoder::new(writer, options))
+        }
+
         /// Create a new GzEncoder from the provided `GzBuilder`. This allows customising
         /// the detalis of the header, such as the filename and comment fields.
         pub fn from_builder<O: Into<CompressionOptions>>(
@@ -352,7 +962,8 @@ pub mod gzip {
             GzEncoder {
                 inner: DeflateEncoder::new(writer, options),
                 checksum: Crc::new(),
-                header: builder.into_header(),
+                header: crate::gzip::header_from_builder(builder),
+                trailer_written: false,
             }
         }
 
@@ -371,12 +982,18 @@ pub mod gzip {
         /// but without resetting anything.
         fn output_all(&mut self) -> io::Result<()> {
             self.check_write_header();
-            self.inner.output_all()?;
+            compress_until_done_filtered(
+                &mut self.inner.deflate_state,
+                &mut self.checksum,
+                Flush::Finish,
+            )?;
             self.write_trailer()
         }
 
         /// Encode all pending data to the contained writer, consume this `GzEncoder`,
         /// and return the contained writer if writing succeeds.
+        ///
+        /// To be able to retry if writing fails, use [`try_finish`](#method.try_finish) first.
         pub fn finish(mut self) -> io::Result<W> {
             self.output_all()?;
             // We have to move the inner writer out of the encoder, and replace it with `None`
@@ -384,9 +1001,20 @@ pub mod gzip {
             Ok(self.inner.deflate_state.inner.take().expect(ERR_STR))
         }
 
+        /// Encode all pending data, including the trailer, to the contained writer without
+        /// consuming the encoder.
+        ///
+        /// If writing fails, no data is lost, so this can be called again to resume once the
+        /// error has been dealt with. Once this has succeeded, no more data should be written,
+        /// and [`finish`](#method.finish) will just return the writer.
+        pub fn try_finish(&mut self) -> io::Result<()> {
+            self.output_all()
+        }
+
         fn reset_no_header(&mut self, writer: W) -> io::Result<W> {
             self.output_all()?;
             self.checksum = Crc::new();
+            self.trailer_written = false;
             self.inner.deflate_state.reset(writer)
         }
 
@@ -394,7 +1022,7 @@ pub mod gzip {
         /// with a new one, returning the old one. (Using a blank header).
         pub fn reset(&mut self, writer: W) -> io::Result<W> {
             let w = self.reset_no_header(writer);
-            self.header = GzBuilder::new().into_header();
+            self.header = crate::gzip::header_from_builder(GzBuilder::new());
             w
         }
 
@@ -403,45 +1031,144 @@ pub mod gzip {
         /// create the header.
         pub fn reset_with_builder(&mut self, writer: W, builder: GzBuilder) -> io::Result<W> {
             let w = self.reset_no_header(writer);
-            self.header = builder.into_header();
+            self.header = crate::gzip::header_from_builder(builder);
             w
         }
 
         /// Write the checksum and number of bytes mod 2^32 to the output writer.
+        ///
+        /// The trailer is added to the output buffer, so it's not written twice, or only
+        /// partially written, if writing fails and this is called again.
         fn write_trailer(&mut self) -> io::Result<()> {
-            let crc = self.checksum.sum();
-            let amount = self.checksum.amt_as_u32();
-
-            // We use a buffer here to make sure we don't end up writing only half the header if
-            // writing fails.
-            let mut buf = [0u8; 8];
-            let mut temp = Cursor::new(&mut buf[..]);
-            temp.write_u32::<LittleEndian>(crc).unwrap();
-            temp.write_u32::<LittleEndian>(amount).unwrap();
-            self.inner
-                .deflate_state
-                .inner
-                .as_mut()
-                .expect(ERR_STR)
-                .write_all(temp.into_inner())
+            if !self.trailer_written {
+                let crc = self.checksum.sum();
+                let amount = self.checksum.amt_as_u32();
+                let output_buf = self.inner.deflate_state.output_buf();
+                output_buf.write_u32::<LittleEndian>(crc)?;
+                output_buf.write_u32::<LittleEndian>(amount)?;
+                self.trailer_written = true;
+            }
+            write_all_pending(&mut self.inner.deflate_state)
         }
 
         /// Get the crc32 checksum of the data comsumed so far.
         pub fn checksum(&self) -> u32 {
             self.checksum.sum()
         }
+
+        /// Returns `true` if data has been written to the encoder since the last flush (or since
+        /// it was created or reset), meaning that calling `flush()` will output something.
+        ///
+        /// If this returns `false` the previous flush already output a sync marker, so another
+        /// one would be redundant and `flush()` does nothing.
+        pub fn has_pending(&self) -> bool {
+            self.inner.has_pending()
+        }
+
+        /// Get the options used by the encoder, see
+        /// [`DeflateEncoder::options`](struct.DeflateEncoder.html#method.options).
+        pub fn options(&self) -> &CompressionOptions {
+            self.inner.options()
+        }
+
+        /// The kind of data detected at the start of the input when using `Compression::Auto`,
+        /// see [`DeflateEncoder::data_kind`](struct.DeflateEncoder.html#method.data_kind).
+        pub fn data_kind(&self) -> Option<DataKind> {
+            self.inner.data_kind()
+        }
+
+        /// Get the compressed data that has been produced, but not written to the wrapped
+        /// writer yet, see
+        /// [`DeflateEncoder::peek_pending`](struct.DeflateEncoder.html#method.peek_pending).
+        ///
+        /// This includes the gzip header until it's been written.
+        pub fn peek_pending(&self) -> &[u8] {
+            self.inner.peek_pending()
+        }
+
+        /// The number of bits of compressed data that are not part of a whole byte yet, see
+        /// [`DeflateEncoder::pending_bits`](struct.DeflateEncoder.html#method.pending_bits).
+        pub fn pending_bits(&self) -> u8 {
+            self.inner.pending_bits()
+        }
+
+        /// Get a reference to the wrapped writer.
+        pub fn get_ref(&self) -> &W {
+            self.inner.get_ref()
+        }
+
+        /// Get a mutable reference to the wrapped writer, see
+        /// [`DeflateEncoder::get_mut`](struct.DeflateEncoder.html#method.get_mut).
+        pub fn get_mut(&mut self) -> &mut W {
+            self.inner.get_mut()
+        }
+
+        /// End a message in a message-based protocol by flushing the encoder, optionally
+        /// forgetting the data compressed so far, see
+        /// [`DeflateEncoder::end_message`](struct.DeflateEncoder.html#method.end_message).
+        ///
+        /// The checksum in the trailer covers all of the messages.
+        pub fn end_message(&mut self, retain_context: bool) -> io::Result<()> {
+            self.flush_now()?;
+            if !retain_context {
+                self.inner.deflate_state.reset_context();
+            }
+            Ok(())
+        }
+
+        /// Combine flushes done in quick succession, see
+        /// [`DeflateEncoder::set_flush_coalescing`](struct.DeflateEncoder.html#method.set_flush_coalescing).
+        pub fn set_flush_coalescing(&mut self, settings: Option<FlushCoalescing>) {
+            self.inner.set_flush_coalescing(settings);
+        }
+
+        /// Flush the encoder the same way as `flush()`, regardless of the flush coalescing
+        /// settings.
+        pub fn flush_now(&mut self) -> io::Result<()> {
+            self.check_write_header();
+            compress_until_done_filtered(
+                &mut self.inner.deflate_state,
+                &mut self.checksum,
+                Flush::Sync,
+            )
+        }
+
+        /// Set a callback to be invoked at the start of every block that is output, see
+        /// [`DeflateEncoder::set_block_callback`](struct.DeflateEncoder.html#method.set_block_callback).
+        ///
+        /// The output positions include the gzip header.
+        pub fn set_block_callback<F: FnMut(&BlockBoundary) + Send + 'static>(
+            &mut self,
+            callback: F,
+        ) {
+            self.inner.set_block_callback(callback)
+        }
+
+        /// Pad the end of the stream so that its length is a multiple of `alignment` bytes, see
+        /// [`DeflateEncoder::set_output_alignment`](struct.DeflateEncoder.html#method.set_output_alignment).
+        ///
+        /// The length includes the gzip header and trailer.
+        pub fn set_output_alignment(&mut self, alignment: u16) {
+            self.inner.deflate_state.set_output_alignment(alignment, 8);
+        }
+
+        /// Make every block start on a byte boundary, see
+        /// [`DeflateEncoder::set_aligned_blocks`](struct.DeflateEncoder.html#method.set_aligned_blocks).
+        pub fn set_aligned_blocks(&mut self, aligned: bool) {
+            self.inner.set_aligned_blocks(aligned)
+        }
     }
 
     impl<W: Write> io::Write for GzEncoder<W> {
         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
             self.check_write_header();
-            let res = self.inner.write(buf);
-            match res {
-                Ok(0) => self.checksum.update(buf),
-                Ok(n) => self.checksum.update(&buf[0..n]),
-                _ => (),
-            };
-            res
+            compress_filtered(buf, &mut self.inner.deflate_state, &mut self.checksum)
+        }
+
+        /// Compress the data in several slices, as if it was one contiguous slice, see
+        /// [`DeflateEncoder::write_vectored`](struct.DeflateEncoder.html#method.write_vectored).
+        fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+            write_vectored_with(bufs, |buf| self.write(buf))
         }
 
         /// Flush the encoder.
@@ -449,8 +1176,27 @@ pub mod gzip {
         /// This will flush the encoder, emulating the Sync flush method from Zlib.
         /// This essentially finishes the current block, and sends an additional empty stored
         /// block to the writer.
+        ///
+        /// If nothing has been written since the last flush, this does nothing, so no redundant
+        /// empty stored blocks are output. This also does nothing if flush coalescing is enabled
+        /// and the flush is not yet due, see `set_flush_coalescing`.
         fn flush(&mut self) -> io::Result<()> {
-            self.inner.flush()
+            if self.inner.deflate_state.flush_due() {
+                self.flush_now()
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl<W: Write + fmt::Debug> fmt::Debug for GzEncoder<W> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("GzEncoder")
+                .field("inner", &self.inner.deflate_state.inner)
+                .field("options", &self.inner.deflate_state.compression_options)
+                .field("checksum", &self.checksum.sum())
+                .field("header", &self.header)
+                .finish_non_exhaustive()
         }
     }
 
@@ -470,7 +1216,7 @@ pub mod gzip {
     #[cfg(test)]
     mod test {
         use super::*;
-        use test_utils::{decompress_gzip, get_test_data};
+        use crate::test_utils::{decompress_gzip, get_test_data, FaultyWriter};
         #[test]
         fn gzip_writer() {
             let data = get_test_data();
@@ -487,19 +1233,98 @@ pub mod gzip {
             };
 
             let (dec, res) = decompress_gzip(&compressed);
-            assert_eq!(dec.header().comment().unwrap(), comment);
+            assert_eq!(dec.header().unwrap().comment().unwrap(), comment);
             assert!(res == data);
         }
+
+        #[test]
+        fn gzip_try_new() {
+            let bad = CompressionOptions {
+                nice_match: 300,
+                ..CompressionOptions::default()
+            };
+            assert!(GzEncoder::try_new(Vec::new(), bad).is_err());
+            let data = get_test_data();
+            let mut compressor =
+                GzEncoder::try_new(Vec::new(), CompressionOptions::high()).unwrap();
+            compressor.write_all(&data).unwrap();
+            assert!(decompress_gzip(&compressor.finish().unwrap()).1 == data);
+        }
+
+        #[test]
+        fn gzip_writer_resume_after_error() {
+            let data = get_test_data();
+            let expected = crate::deflate_bytes_gzip_conf(
+                &data,
+                CompressionOptions::default(),
+                GzBuilder::new(),
+            );
+            // Fail in the middle of the trailer.
+            let faulty = FaultyWriter {
+                fail_at: Some(expected.len() - 5),
+                failures: 1,
+                ..FaultyWriter::default()
+            };
+            let mut compressor = GzEncoder::new(faulty, CompressionOptions::default());
+            compressor.write_all(&data).unwrap();
+            assert!(compressor.try_finish().is_err());
+            compressor.try_finish().unwrap();
+            assert!(compressor.finish().unwrap().data == expected);
+        }
+
+        #[test]
+        fn gzip_output_alignment() {
+            let data = get_test_data();
+            for &alignment in &[4, 16, 4096] {
+                let mut compressor = GzEncoder::from_builder(
+                    GzBuilder::new().filename(&b"file.txt"[..]),
+                    Vec::new(),
+                    CompressionOptions::default(),
+                );
+                compressor.set_output_alignment(alignment);
+                compressor.write_all(&data).unwrap();
+                let compressed = compressor.finish().unwrap();
+                assert_eq!(compressed.len() % usize::from(alignment), 0);
+                assert!(decompress_gzip(&compressed).1 == data);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::compression_options::CompressionOptions;
-    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+    use crate::compression_options::{Compression, CompressionOptions};
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data, FaultyWriter};
+    use crate::{deflate_bytes_conf, deflate_bytes_zlib_conf};
     use std::io::Write;
 
+    #[test]
+    fn encoders_owned() {
+        fn assert_owned<T: Send + 'static>(_: &T) {}
+
+        let data = get_test_data();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        assert_owned(&encoder);
+        // The encoder keeps what it needs of the input, so the buffers written can be dropped,
+        // and the rest written from another thread.
+        for chunk in data[..data.len() / 2].chunks(10_000) {
+            let buffer = chunk.to_vec();
+            encoder.write_all(&buffer).unwrap();
+            drop(buffer);
+        }
+        let second_half = data[data.len() / 2..].to_vec();
+        let compressed = thread::spawn(move || {
+            encoder.write_all(&second_half).unwrap();
+            encoder.finish().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert!(decompress_zlib(&compressed) == data);
+        assert!(compressed == deflate_bytes_zlib_conf(&data, Compression::Default));
+        assert_owned(&DeflateEncoder::new(Vec::new(), Compression::Default));
+    }
+
     #[test]
     fn deflate_writer() {
         let data = get_test_data();
@@ -552,6 +1377,62 @@ mod test {
         assert!(res1 == res2);
     }
 
+    #[test]
+    fn writer_reset_keeps_buffers() {
+        let data = get_test_data();
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        compressor.write_all(&data).unwrap();
+        compressor.reset(Vec::new()).unwrap();
+        let state = &mut compressor.deflate_state;
+        let input = state.input_buffer.get_buffer().as_ptr();
+        let output = state.output_buf().as_ptr();
+
+        for n in 0..20 {
+            let payload = &data[n * 100..n * 100 + 500];
+            compressor.write_all(payload).unwrap();
+            let compressed = compressor.reset(Vec::new()).unwrap();
+            assert!(decompress_to_end(&compressed) == payload);
+            let state = &mut compressor.deflate_state;
+            assert_eq!(state.input_buffer.get_buffer().as_ptr(), input);
+            assert_eq!(state.output_buf().as_ptr(), output);
+        }
+    }
+
+    #[test]
+    fn try_new_validates() {
+        let bad = CompressionOptions {
+            nice_match: 300,
+            ..CompressionOptions::default()
+        };
+        assert_eq!(
+            DeflateEncoder::try_new(Vec::new(), bad).unwrap_err(),
+            ConfigError::NiceMatchTooHigh(300)
+        );
+        assert_eq!(
+            ZlibEncoder::try_new(Vec::new(), bad).unwrap_err(),
+            ConfigError::NiceMatchTooHigh(300)
+        );
+        assert_eq!(
+            crate::read::DeflateEncoder::try_new(&[][..], bad).unwrap_err(),
+            ConfigError::NiceMatchTooHigh(300)
+        );
+
+        let data = get_test_data();
+        let mut encoder = ZlibEncoder::try_new(Vec::new(), Compression::Default).unwrap();
+        encoder.write_all(&data).unwrap();
+        assert!(decompress_zlib(&encoder.finish().unwrap()) == data);
+    }
+
+    #[test]
+    fn encoder_debug() {
+        let encoder = ZlibEncoder::new(Vec::new(), Compression::Fast);
+        let debug = format!("{:?}", encoder);
+        assert!(debug.starts_with("ZlibEncoder { inner: Some([]), options: CompressionOptions"));
+        assert!(debug.contains("matching_type: Greedy"));
+        let encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+        assert!(format!("{:?}", encoder).starts_with("DeflateEncoder {"));
+    }
+
     #[test]
     fn writer_reset_zlib() {
         let data = get_test_data();
@@ -595,6 +1476,117 @@ mod test {
         assert!(decompressed == data);
     }
 
+    #[test]
+    fn writer_flush_no_pending() {
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        assert!(!compressor.has_pending());
+        // Flushing at the start should output a sync marker (an empty stored block) once.
+        compressor.flush().unwrap();
+        compressor.flush().unwrap();
+        assert_eq!(
+            compressor.deflate_state.inner.as_ref().unwrap()[..],
+            [0, 0, 0, 255, 255]
+        );
+
+        compressor.write_all(b"Some data").unwrap();
+        assert!(compressor.has_pending());
+        compressor.flush().unwrap();
+        assert!(!compressor.has_pending());
+        let len = compressor.deflate_state.inner.as_ref().unwrap().len();
+        compressor.flush().unwrap();
+        compressor.flush().unwrap();
+        assert_eq!(compressor.deflate_state.inner.as_ref().unwrap().len(), len);
+
+        compressor.write_all(b" and some more data").unwrap();
+        assert!(compressor.has_pending());
+        let compressed = compressor.finish().unwrap();
+        assert_eq!(
+            decompress_to_end(&compressed),
+            b"Some data and some more data"
+        );
+    }
+
+    #[test]
+    fn zlib_writer_flush_before_write() {
+        // The header should be written even if nothing has been written to the encoder.
+        let mut compressor = ZlibEncoder::new(Vec::new(), CompressionOptions::default());
+        compressor.flush().unwrap();
+        compressor.flush().unwrap();
+        compressor.write_all(b"Some data").unwrap();
+        let compressed = compressor.finish().unwrap();
+        assert_eq!(decompress_zlib(&compressed), b"Some data");
+    }
+
+    #[test]
+    /// Check that pre-filters are applied when writing in multiple steps with sync flushes, and
+    /// that the checksum is calculated from the filtered data.
+    fn zlib_writer_pre_filter() {
+        use crate::pre_filter::PreFilter;
+        let data = get_test_data();
+        let filter = PreFilter {
+            delta: 2,
+            transpose: 6,
+        };
+        let options = CompressionOptions {
+            pre_filter: filter,
+            ..CompressionOptions::default()
+        };
+        let compressed = {
+            let mut compressor = ZlibEncoder::new(Vec::with_capacity(data.len() / 3), options);
+            for chunk in data.chunks(10000) {
+                compressor.write_all(chunk).unwrap();
+                compressor.flush().unwrap();
+            }
+            compressor.finish().unwrap()
+        };
+
+        let decompressed = decompress_zlib(&compressed);
+        assert!(decompressed == filter.apply(&data));
+        assert!(filter.reverse(&decompressed) == data);
+    }
+
+    #[test]
+    /// Check that an error from the wrapped writer that happens after the input of a call was
+    /// consumed is returned by the next call when using a pre-filter.
+    fn pre_filter_deferred_error() {
+        use crate::pre_filter::PreFilter;
+        use crate::test_utils::get_random_data;
+        let data = get_random_data(100_000);
+        let options = CompressionOptions {
+            pre_filter: PreFilter {
+                delta: 1,
+                transpose: 0,
+            },
+            ..CompressionOptions::default()
+        };
+        // Writing the output a bit at a time keeps the output buffer full, so the third write
+        // fails after the input of the call has been taken.
+        let faulty = FaultyWriter {
+            max_write: Some(1000),
+            fail_call: Some(3),
+            ..FaultyWriter::default()
+        };
+        let mut compressor = DeflateEncoder::new(faulty, options);
+        let mut errors = 0;
+        let mut chunks = data.chunks(1000);
+        let mut chunk = chunks.next();
+        while let Some(c) = chunk {
+            match compressor.write(c) {
+                Ok(n) => {
+                    assert_eq!(n, c.len());
+                    chunk = chunks.next();
+                }
+                Err(e) => {
+                    assert_eq!(e.to_string(), "Write failed!");
+                    errors += 1;
+                }
+            }
+        }
+        assert_eq!(errors, 1);
+        let faulty = compressor.finish().unwrap();
+        assert!(decompress_to_end(&faulty.data) == options.pre_filter.apply(&data));
+    }
+
     #[test]
     /// Make sure compression works with the writer when the input is between 1 and 2 window sizes.
     fn issue_18() {
@@ -659,4 +1651,385 @@ mod test {
 
         assert_eq!(decompressed, [1, 2, 3]);
     }
+
+    /// Write `data` in chunks, retrying until all of it has been accepted, and then finish,
+    /// returning the number of errors that had to be retried.
+    fn write_retrying<W: Write, F: FnMut(&mut W) -> io::Result<()>>(
+        encoder: &mut W,
+        data: &[u8],
+        mut try_finish: F,
+    ) -> usize {
+        let mut errors = 0;
+        for mut chunk in data.chunks(4096) {
+            while !chunk.is_empty() {
+                match encoder.write(chunk) {
+                    Ok(n) => chunk = &chunk[n..],
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(_) => errors += 1,
+                }
+            }
+        }
+        while try_finish(encoder).is_err() {
+            errors += 1;
+        }
+        errors
+    }
+
+    #[test]
+    fn writer_short_and_interrupted_writes() {
+        let data = get_test_data();
+        let faulty = || FaultyWriter {
+            max_write: Some(7),
+            interrupt_every: Some(3),
+            ..FaultyWriter::default()
+        };
+
+        let mut compressor = DeflateEncoder::new(faulty(), Compression::Default);
+        compressor.write_all(&data).unwrap();
+        let compressed = compressor.finish().unwrap().data;
+        assert!(compressed == deflate_bytes_conf(&data, Compression::Default));
+
+        let mut compressor = ZlibEncoder::new(faulty(), Compression::Default);
+        compressor.write_all(&data).unwrap();
+        compressor.flush().unwrap();
+        let compressed = compressor.finish().unwrap().data;
+        assert!(decompress_zlib(&compressed) == data);
+    }
+
+    #[test]
+    fn writer_resume_after_error() {
+        let data = get_test_data();
+        let expected = deflate_bytes_conf(&data, Compression::Default);
+        // Fail in the middle of the data, and at the very end.
+        for &fail_at in &[5000, expected.len() - 1] {
+            let faulty = FaultyWriter {
+                fail_at: Some(fail_at),
+                failures: 3,
+                max_write: Some(1000),
+                ..FaultyWriter::default()
+            };
+            let mut compressor = DeflateEncoder::new(faulty, Compression::Default);
+            let errors = write_retrying(&mut compressor, &data, DeflateEncoder::try_finish);
+            assert_eq!(errors, 3);
+            assert!(compressor.finish().unwrap().data == expected);
+        }
+    }
+
+    #[test]
+    fn zlib_writer_resume_after_error() {
+        let data = get_test_data();
+        let expected = deflate_bytes_zlib_conf(&data, Compression::Default);
+        // Fail in the middle of the trailer.
+        let faulty = FaultyWriter {
+            fail_at: Some(expected.len() - 2),
+            failures: 2,
+            ..FaultyWriter::default()
+        };
+        let mut compressor = ZlibEncoder::new(faulty, Compression::Default);
+        let errors = write_retrying(&mut compressor, &data, ZlibEncoder::try_finish);
+        assert_eq!(errors, 2);
+        // Finishing again should not output the trailer again.
+        compressor.try_finish().unwrap();
+        assert!(compressor.finish().unwrap().data == expected);
+    }
+
+    #[test]
+    fn writer_write_zero() {
+        let faulty = FaultyWriter {
+            max_write: Some(0),
+            ..FaultyWriter::default()
+        };
+        let mut compressor = DeflateEncoder::new(faulty, Compression::Default);
+        compressor.write_all(b"Some data").unwrap();
+        let err = compressor.try_finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        // Don't try to finish again when dropping.
+        compressor.deflate_state.inner.take();
+    }
+
+    #[test]
+    fn block_callback() {
+        use crate::inflate::analyze;
+        use crate::BlockLimits;
+        use std::sync::{Arc, Mutex};
+
+        let data = get_test_data();
+        let options = CompressionOptions {
+            block_limits: BlockLimits {
+                max_input_bytes: 10000,
+                ..BlockLimits::default()
+            },
+            ..CompressionOptions::default()
+        };
+        let boundaries = Arc::new(Mutex::new(Vec::new()));
+        let b = boundaries.clone();
+        let mut encoder = DeflateEncoder::new(Vec::new(), options);
+        encoder.set_block_callback(move |boundary| b.lock().unwrap().push(*boundary));
+        for chunk in data.chunks(50000) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+
+        let blocks = analyze(&compressed).unwrap();
+        let boundaries = boundaries.lock().unwrap();
+        assert!(blocks.len() > 10);
+        assert_eq!(boundaries.len(), blocks.len());
+        for (boundary, block) in boundaries.iter().zip(blocks.iter()) {
+            assert_eq!(boundary.input_offset, block.output_start);
+            assert_eq!(
+                boundary.output_byte * 8 + u64::from(boundary.output_bit),
+                block.start_bit
+            );
+            assert_eq!(boundary.block_type, block.block_type);
+        }
+    }
+
+    #[test]
+    fn block_callback_zlib_reset() {
+        use std::sync::{Arc, Mutex};
+
+        let data = get_test_data();
+        let boundaries = Arc::new(Mutex::new(Vec::new()));
+        let b = boundaries.clone();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        encoder.set_block_callback(move |boundary| b.lock().unwrap().push(*boundary));
+        encoder.write_all(&data).unwrap();
+        encoder.reset(Vec::new()).unwrap();
+        let first_stream = boundaries.lock().unwrap().len();
+        encoder.write_all(&data[..100]).unwrap();
+        encoder.finish().unwrap();
+
+        let boundaries = boundaries.lock().unwrap();
+        assert!(first_stream > 1);
+        // The positions should include the zlib header, and start over when resetting.
+        for &start in &[boundaries[0], boundaries[first_stream]] {
+            assert_eq!(start.input_offset, 0);
+            assert_eq!(start.output_byte, 2);
+            assert_eq!(start.output_bit, 0);
+        }
+        assert!(boundaries[1].input_offset > 0);
+    }
+
+    /// Write all of `bufs` using `write_vectored`.
+    fn write_all_vectored<W: Write>(writer: &mut W, mut bufs: &[&[u8]]) {
+        let mut offset = 0;
+        while !bufs.is_empty() {
+            let slices: Vec<_> = bufs
+                .iter()
+                .enumerate()
+                .map(|(i, b)| io::IoSlice::new(if i == 0 { &b[offset..] } else { b }))
+                .collect();
+            let mut n = writer.write_vectored(&slices).unwrap() + offset;
+            while !bufs.is_empty() && n >= bufs[0].len() {
+                n -= bufs[0].len();
+                bufs = &bufs[1..];
+            }
+            offset = n;
+        }
+    }
+
+    #[test]
+    fn write_ring_buffer() {
+        let data = get_test_data();
+        let expected = deflate_bytes_conf(&data, Compression::Default);
+        // Simulate the data being stored in a ring buffer, where the start of the data is stored
+        // after the end.
+        for &seam in &[1, 100, 32768, data.len() / 2, data.len() - 3] {
+            let ring: Vec<u8> = data[seam..].iter().chain(&data[..seam]).cloned().collect();
+            let (tail, head) = ring.split_at(data.len() - seam);
+
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+            write_all_vectored(&mut encoder, &[head, tail]);
+            let compressed = encoder.finish().unwrap();
+            assert!(
+                compressed == expected,
+                "Output differs with seam at {}",
+                seam
+            );
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        write_all_vectored(
+            &mut encoder,
+            &[&data[..7], &[], &data[7..1000], &data[1000..]],
+        );
+        assert!(decompress_zlib(&encoder.finish().unwrap()) == data);
+    }
+
+    #[test]
+    fn encoder_options() {
+        let data = get_test_data();
+        let options = CompressionOptions {
+            nice_match: 0,
+            ..CompressionOptions::high()
+        };
+        let mut encoder = ZlibEncoder::new(Vec::new(), options);
+        assert_eq!(*encoder.options(), options.resolved());
+        assert_eq!(
+            *DeflateEncoder::new(Vec::new(), Compression::Best).options(),
+            CompressionOptions::high()
+        );
+
+        // The options can be used to compress the same way again.
+        encoder.write_all(&data).unwrap();
+        let reused = ZlibEncoder::new(Vec::new(), *encoder.options());
+        let compressed = encoder.finish().unwrap();
+        assert!(deflate_bytes_zlib_conf(&data, *reused.options()) == compressed);
+    }
+
+    #[test]
+    fn take_output() {
+        let data = get_test_data();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        let mut output = Vec::new();
+        for chunk in data.chunks(10000) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+            // The flush outputs everything written so far, ending with a sync marker.
+            assert!(!encoder.get_ref().is_empty());
+            output.append(encoder.get_mut());
+        }
+        output.extend(encoder.finish().unwrap());
+        assert!(decompress_zlib(&output) == data);
+    }
+
+    #[test]
+    fn peek_pending() {
+        let data = crate::test_utils::get_random_data(200_000);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+        assert!(encoder.peek_pending().is_empty());
+        let mut written = 0;
+        while encoder.peek_pending().is_empty() {
+            encoder.write_all(&data[written..written + 1000]).unwrap();
+            written += 1000;
+        }
+        // A block has been produced, but not written out yet.
+        let pending = encoder.peek_pending().to_vec();
+        let written_out = encoder.get_ref().len();
+
+        encoder.flush().unwrap();
+        assert!(encoder.peek_pending().is_empty());
+        assert_eq!(encoder.pending_bits(), 0);
+        assert!(encoder.get_ref()[written_out..].starts_with(&pending));
+        encoder.write_all(&data[written..]).unwrap();
+        assert!(decompress_to_end(&encoder.finish().unwrap()) == data);
+    }
+
+    #[test]
+    fn prime_from_previous_output() {
+        use crate::test_utils::decompress_with_history;
+        let data = get_test_data();
+        let (previous, current) = data.split_at(data.len() / 2);
+        let window = &previous[previous.len() - 32768..];
+
+        let prime = |pieces: &[&[u8]]| {
+            let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+            for piece in pieces {
+                encoder.prime_from_previous_output(piece).unwrap();
+            }
+            encoder.write_all(current).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let primed = prime(&[previous]);
+        assert!(decompress_with_history(window, &primed) == current);
+        assert!(primed.len() < deflate_bytes_conf(current, CompressionOptions::default()).len());
+
+        // Priming in pieces, with and without outgrowing the window, gives the same result as
+        // priming with the last window in one go.
+        let pieces: Vec<&[u8]> = previous.chunks(1000).collect();
+        assert!(prime(&pieces) == primed);
+        let pieces: Vec<&[u8]> = window.chunks(1000).collect();
+        assert!(prime(&pieces) == primed);
+        assert!(prime(&[&previous[..40000], &previous[40000..]]) == primed);
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        encoder.write_all(current).unwrap();
+        let err = encoder.prime_from_previous_output(previous).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn output_alignment() {
+        use crate::test_utils::get_random_data;
+        let test_data = get_test_data();
+        let random = get_random_data(40000);
+        for data in [&test_data[..1000], &test_data, &random, b"", b"a"] {
+            for &alignment in &[2, 4, 16, 512] {
+                for &options in &[Compression::Fast.into(), CompressionOptions::high()] {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), options);
+                    encoder.set_output_alignment(alignment);
+                    encoder.write_all(data).unwrap();
+                    let compressed = encoder.finish().unwrap();
+                    assert_eq!(compressed.len() % usize::from(alignment), 0);
+                    assert!(decompress_to_end(&compressed) == data);
+
+                    let mut encoder = ZlibEncoder::new(Vec::new(), options);
+                    encoder.set_output_alignment(alignment);
+                    encoder.write_all(data).unwrap();
+                    encoder.flush().unwrap();
+                    encoder.write_all(data).unwrap();
+                    let compressed = encoder.finish().unwrap();
+                    assert_eq!(compressed.len() % usize::from(alignment), 0);
+                    assert!(decompress_zlib(&compressed) == [data, data].concat());
+                }
+            }
+        }
+
+        // Output that is already aligned is left as is.
+        let unpadded = deflate_bytes_conf(&test_data, Compression::Default);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+        encoder.set_output_alignment(unpadded.len() as u16);
+        encoder.write_all(&test_data).unwrap();
+        assert_eq!(encoder.finish().unwrap(), unpadded);
+    }
+
+    #[test]
+    fn aligned_blocks() {
+        use crate::inflate::{analyze, BlockType};
+        use crate::BlockLimits;
+        use std::sync::{Arc, Mutex};
+
+        let data = get_test_data();
+        let options = CompressionOptions {
+            block_limits: BlockLimits {
+                max_input_bytes: 10000,
+                ..BlockLimits::default()
+            },
+            ..CompressionOptions::default()
+        };
+        let boundaries = Arc::new(Mutex::new(Vec::new()));
+        let b = boundaries.clone();
+        let mut encoder = DeflateEncoder::new(Vec::new(), options);
+        encoder.set_aligned_blocks(true);
+        encoder.set_block_callback(move |boundary| b.lock().unwrap().push(*boundary));
+        for chunk in data.chunks(25000) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+
+        let boundaries = boundaries.lock().unwrap();
+        assert!(boundaries.len() > 10);
+        for (n, boundary) in boundaries.iter().enumerate() {
+            assert_eq!(boundary.output_bit, 0);
+            // Every block after the first one follows a sync marker.
+            let start = boundary.output_byte as usize;
+            if n > 0 {
+                assert_eq!(compressed[start - 4..start], [0, 0, 0xff, 0xff]);
+            }
+        }
+
+        // The sync markers are the only unreported blocks, and flushing doesn't add another one
+        // after the one that already ends the block.
+        let blocks = analyze(&compressed).unwrap();
+        let markers = blocks
+            .iter()
+            .filter(|b| b.block_type == BlockType::Stored && b.output_len == 0)
+            .count();
+        assert_eq!(blocks.len(), boundaries.len() + markers);
+        assert_eq!(markers, boundaries.len() - 1);
+    }
 }
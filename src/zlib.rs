@@ -3,13 +3,16 @@
 //!
 //! The Zlib header contains some metadata (a window size and a compression level), and optionally
 //! a block of data serving as an extra dictionary for the compressor/decompressor.
-//! The dictionary is not implemented in this library.
+//! If a dictionary is used, the header only contains the Adler-32 checksum of it, the
+//! decompressor has to get the dictionary itself from elsewhere.
 //! The data in the header aside from the dictionary doesn't actually have any effect on the
 //! decompressed data, it only offers some hints for the decompressor on how the data was
 //! compressed.
 
 use std::io::{Result, Write};
 
+use byteorder::{BigEndian, WriteBytesExt};
+
 // CM = 8 means to use the DEFLATE compression method.
 const DEFAULT_CM: u8 = 8;
 // CINFO = 7 Indicates a 32k window size.
@@ -19,6 +22,8 @@ const DEFAULT_CMF: u8 = DEFAULT_CM | DEFAULT_CINFO;
 // No dict by default.
 #[cfg(test)]
 const DEFAULT_FDICT: u8 = 0;
+// FDICT = 1 means that the header is followed by the checksum of a preset dictionary.
+const FDICT: u8 = 1 << 5;
 // FLEVEL = 0 means fastest compression algorithm.
 const _DEFAULT_FLEVEL: u8 = 0 << 7;
 
@@ -26,6 +31,7 @@ const _DEFAULT_FLEVEL: u8 = 0 << 7;
 const FCHECK_DIVISOR: u8 = 31;
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u8)]
 pub enum CompressionLevel {
     Fastest = 0 << 6,
@@ -54,6 +60,18 @@ pub fn write_zlib_header<W: Write>(writer: &mut W, level: CompressionLevel) -> R
     writer.write_all(&get_zlib_header(level))
 }
 
+/// Write a zlib header indicating that a preset dictionary with the adler32 checksum `dict_id`
+/// was used, using the specified compression level preset.
+pub fn write_zlib_header_dict<W: Write>(
+    writer: &mut W,
+    level: CompressionLevel,
+    dict_id: u32,
+) -> Result<()> {
+    let cmf = DEFAULT_CMF;
+    writer.write_all(&[cmf, add_fcheck(cmf, level as u8 | FDICT)])?;
+    writer.write_u32::<BigEndian>(dict_id)
+}
+
 /// Get the zlib header for the `CompressionLevel` level using the default window size and no
 /// dictionary.
 pub fn get_zlib_header(level: CompressionLevel) -> [u8; 2] {
@@ -84,4 +102,17 @@ mod test {
             0
         );
     }
+
+    #[test]
+    fn test_header_dict() {
+        let mut header = Vec::new();
+        write_zlib_header_dict(&mut header, CompressionLevel::Default, 0x1234_5678).unwrap();
+        assert_eq!(header.len(), 6);
+        assert_eq!(
+            ((usize::from(header[0]) * 256) + usize::from(header[1])) % 31,
+            0
+        );
+        assert_eq!(header[1] & FDICT, FDICT);
+        assert_eq!(header[2..], [0x12, 0x34, 0x56, 0x78]);
+    }
 }
@@ -0,0 +1,151 @@
+//! Functions mirroring the utility functions in zlib, to make porting code using them easier.
+
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::checksum::{self, RollingChecksum};
+use crate::compression_options::CompressionOptions;
+use crate::zlib::{write_zlib_header, CompressionLevel};
+
+/// Get an upper bound on the size of the zlib stream [`compress2`](fn.compress2.html) produces
+/// from `source_len` bytes of input, like `compressBound` in zlib.
+///
+/// This uses the same formula as zlib, so a buffer sized for zlib is also large enough here.
+/// Incompressible data is output in stored blocks, which adds 5 bytes for every 31 KiB or so of
+/// input, well within the bound.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::compress_bound;
+///
+/// assert_eq!(compress_bound(0), 13);
+/// assert_eq!(compress_bound(100_000), 100_043);
+/// ```
+pub fn compress_bound(source_len: usize) -> usize {
+    source_len
+        .saturating_add(source_len >> 12)
+        .saturating_add(source_len >> 14)
+        .saturating_add(source_len >> 25)
+        .saturating_add(13)
+}
+
+/// A writer appending to a `Vec<u8>`, failing instead of growing it past its capacity.
+struct BoundedWriter<'a> {
+    dest: &'a mut Vec<u8>,
+}
+
+impl Write for BoundedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.dest.capacity() - self.dest.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Destination buffer is too small for the compressed data",
+            ));
+        }
+        self.dest.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compress `source` into a zlib stream appended to `dest`, like `compress2` in zlib.
+///
+/// As in zlib, the output has to fit in the space already allocated for `dest`, i.e
+/// `dest.capacity() - dest.len()` bytes, which plays the part of `destLen`. Reserving
+/// [`compress_bound(source.len())`](fn.compress_bound.html) bytes is always enough.
+///
+/// Returns the number of bytes of input consumed, which is always all of it, and the number of
+/// compressed bytes appended to `dest`.
+///
+/// # Errors
+///
+/// If the compressed data does not fit, an error of the kind `WriteZero` (corresponding to
+/// `Z_BUF_ERROR`) is returned, and `dest` is left as it was.
+///
+/// # Examples
+///
+/// ```rust
+/// use deflate::{compress2, compress_bound, Compression};
+///
+/// let data = b"This is some test data";
+/// let mut dest = Vec::with_capacity(compress_bound(data.len()));
+/// let (consumed, produced) = compress2(&mut dest, data, Compression::Best).unwrap();
+/// assert_eq!(consumed, data.len());
+/// assert_eq!(produced, dest.len());
+///
+/// // The output doesn't fit.
+/// let mut dest = Vec::with_capacity(8);
+/// assert!(compress2(&mut dest, data, Compression::Best).is_err());
+/// assert!(dest.is_empty());
+/// ```
+pub fn compress2<O: Into<CompressionOptions>>(
+    dest: &mut Vec<u8>,
+    source: &[u8],
+    level: O,
+) -> io::Result<(usize, usize)> {
+    let start = dest.len();
+    let mut writer = BoundedWriter { dest };
+    let mut checksum = checksum::Adler32Checksum::new();
+    let res = write_zlib_header(&mut writer, CompressionLevel::Default)
+        .and_then(|_| {
+            crate::compress_data_dynamic(source, &mut writer, &mut checksum, level.into())
+        })
+        .and_then(|_| writer.write_u32::<BigEndian>(checksum.current_hash()));
+    match res {
+        Ok(()) => Ok((source.len(), dest.len() - start)),
+        Err(e) => {
+            dest.truncate(start);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression_options::Compression;
+    use crate::test_utils::{decompress_zlib, get_random_data, get_test_data};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn bound_holds() {
+        for &len in &[0, 1, 100, 31744, 31745, 65536, 300_000] {
+            let data = get_random_data(len);
+            for level in 1..=9 {
+                let options = CompressionOptions::try_from(level).unwrap();
+                let mut dest = Vec::with_capacity(compress_bound(len));
+                let (consumed, produced) = compress2(&mut dest, &data, options).unwrap();
+                assert_eq!(consumed, len);
+                assert_eq!(produced, dest.len());
+                assert!(produced <= compress_bound(len));
+                assert_eq!(decompress_zlib(&dest), data);
+            }
+        }
+    }
+
+    #[test]
+    fn exact_space() {
+        let data = get_test_data();
+        let expected = crate::deflate_bytes_zlib_conf(&data, Compression::Fast);
+
+        // Appended after what's already in the buffer.
+        let mut dest = Vec::with_capacity(3 + expected.len());
+        dest.extend_from_slice(b"abc");
+        let (consumed, produced) = compress2(&mut dest, &data, Compression::Fast).unwrap();
+        assert_eq!((consumed, produced), (data.len(), expected.len()));
+        assert_eq!(dest[..3], *b"abc");
+        assert_eq!(dest[3..], expected[..]);
+
+        // One byte too few. (`with_capacity` gives exactly the requested capacity.)
+        let mut dest = Vec::with_capacity(3 + expected.len() - 1);
+        dest.extend_from_slice(b"abc");
+        let err = compress2(&mut dest, &data, Compression::Fast).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(dest, b"abc");
+    }
+}
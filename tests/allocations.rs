@@ -0,0 +1,161 @@
+//! Tests checking that the encoders don't allocate any memory per block once they have been set
+//! up, so the cost of compressing is the same no matter how the input is split into blocks.
+
+extern crate deflate;
+
+use deflate::write::{DeflateEncoder, ZlibEncoder};
+use deflate::{BlockLimits, Compression, CompressionOptions};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{Read, Write};
+
+thread_local! {
+    /// The number of allocations done by the current thread, so tests running at the same time
+    /// don't count each other's allocations.
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The system allocator, counting the number of allocations and reallocations.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+fn get_test_data() -> Vec<u8> {
+    let mut input = Vec::new();
+    std::fs::File::open("tests/pg11.txt")
+        .unwrap()
+        .read_to_end(&mut input)
+        .unwrap();
+    input
+}
+
+/// Options producing many small blocks of every type.
+fn test_options() -> Vec<CompressionOptions> {
+    let small_blocks = |options: CompressionOptions| CompressionOptions {
+        block_limits: BlockLimits {
+            max_input_bytes: 2000,
+            ..BlockLimits::default()
+        },
+        ..options
+    };
+    vec![
+        Compression::Default.into(),
+        CompressionOptions::high(),
+        CompressionOptions::fast(),
+        CompressionOptions::huffman_only(),
+        CompressionOptions::rle(),
+        small_blocks(Compression::Default.into()),
+        small_blocks(CompressionOptions::huffman_only()),
+    ]
+}
+
+#[test]
+fn no_allocations_per_block() {
+    let data = get_test_data();
+    for options in test_options() {
+        // The first stream sets up the buffers, and grows the output buffer as needed.
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len()), options);
+        encoder.write_all(&data).unwrap();
+        encoder.reset(Vec::with_capacity(data.len())).unwrap();
+
+        let before = allocations();
+        for chunk in data.chunks(4096) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.flush().unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(allocations() - before, 0, "Allocated with {:?}", options);
+    }
+}
+
+#[test]
+fn no_allocations_per_block_zlib() {
+    let data = get_test_data();
+    let mut encoder = ZlibEncoder::new(Vec::with_capacity(data.len()), Compression::Default);
+    encoder.write_all(&data).unwrap();
+    encoder.reset(Vec::with_capacity(data.len())).unwrap();
+
+    let before = allocations();
+    encoder.write_all(&data).unwrap();
+    encoder.finish().unwrap();
+    assert_eq!(allocations() - before, 0);
+}
+
+/// With the `metrics` feature, recording the metrics doesn't allocate either, as the handles are
+/// registered when the encoder is created.
+#[cfg(feature = "metrics")]
+#[test]
+fn no_allocations_per_block_metrics() {
+    use metrics::{
+        Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString,
+        Unit,
+    };
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// Counts the samples recorded, without storing them.
+    struct Samples(AtomicU64);
+
+    impl HistogramFn for Samples {
+        fn record(&self, _: f64) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A recorder whose metrics are plain atomics, so recording doesn't allocate.
+    struct AtomicRecorder;
+
+    impl Recorder for AtomicRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, _: &Key, _: &Metadata) -> Counter {
+            Counter::from_arc(Arc::new(AtomicU64::new(0)))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata) -> Histogram {
+            Histogram::from_arc(Arc::new(Samples(AtomicU64::new(0))))
+        }
+    }
+
+    let data = get_test_data();
+    metrics::with_local_recorder(&AtomicRecorder, || {
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len()), Compression::Default);
+        encoder.write_all(&data).unwrap();
+        encoder.reset(Vec::with_capacity(data.len())).unwrap();
+
+        let before = allocations();
+        for chunk in data.chunks(4096) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+        }
+        encoder.finish().unwrap();
+        assert_eq!(allocations() - before, 0);
+    });
+}
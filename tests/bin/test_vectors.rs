@@ -0,0 +1,150 @@
+//! Generates a set of encoder conformance test vectors.
+//!
+//! Run with `cargo run --features test-vectors --bin test_vectors -- [OUTPUT_DIR]`, the output
+//! directory defaults to `test-vectors`.
+//!
+//! The output is deterministic: the same version of this library will always produce the same
+//! files on every platform, so decoder implementations can be validated against them, and
+//! changes to the compressed output of the encoder show up when comparing two sets.
+//!
+//! For every input and set of compression options, the output directory will contain:
+//!
+//! * `<input>.bin`: The uncompressed input.
+//! * `<input>.<options>.deflate`: The input compressed to raw DEFLATE data.
+//! * `<input>.<options>.zlib`: The input compressed with a zlib header and trailer.
+//! * `<input>.<options>.gz`: The input compressed with a gzip header and trailer. The header has
+//!   no file name, an `mtime` of 0, and the OS field set to Unix.
+//!
+//! The compression options used are:
+//!
+//! * `fast`: `Compression::Fast`
+//! * `default`: `Compression::Default`
+//! * `best`: `Compression::Best`
+//! * `huffman-only`: `CompressionOptions::huffman_only()`
+//! * `rle`: `CompressionOptions::rle()`
+//!
+//! The file `MANIFEST` lists every output file with one tab-separated line per file and the
+//! fields:
+//!
+//! 1. Output file name.
+//! 2. Input file name.
+//! 3. Compression options.
+//! 4. Format (`deflate`, `zlib` or `gzip`).
+//! 5. Input size in bytes.
+//! 6. Adler-32 checksum of the input, as 8 hexadecimal digits.
+//! 7. CRC-32 checksum of the input, as 8 hexadecimal digits.
+//! 8. Output size in bytes.
+//! 9. CRC-32 checksum of the output, as 8 hexadecimal digits.
+//!
+//! Lines starting with `#` are comments.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use adler32::RollingAdler32;
+use deflate::{
+    deflate_bytes_conf, deflate_bytes_gzip_conf, deflate_bytes_zlib_conf, Compression,
+    CompressionOptions,
+};
+use gzip_header::{Crc, FileSystemType, GzBuilder};
+
+#[path = "../common/mod.rs"]
+mod common;
+
+use common::random_data;
+
+const DEFAULT_OUTPUT_DIR: &str = "test-vectors";
+
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    let text = include_bytes!("../pg11.txt");
+    // Random data repeated at the maximum match distance.
+    let mut window_edge = random_data(32 * 1024);
+    window_edge.extend_from_within(..);
+    window_edge.extend_from_within(..1000);
+
+    vec![
+        ("empty", Vec::new()),
+        ("single-byte", b"a".to_vec()),
+        ("short", include_bytes!("../short.bin").to_vec()),
+        ("text", text[..100_000].to_vec()),
+        ("zeroes", vec![0; 70_000]),
+        ("random", random_data(40_000)),
+        ("window-edge", window_edge),
+    ]
+}
+
+fn options() -> Vec<(&'static str, CompressionOptions)> {
+    vec![
+        ("fast", Compression::Fast.into()),
+        ("default", Compression::Default.into()),
+        ("best", Compression::Best.into()),
+        ("huffman-only", CompressionOptions::huffman_only()),
+        ("rle", CompressionOptions::rle()),
+    ]
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn main() -> io::Result<()> {
+    let output_dir = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_OUTPUT_DIR.to_string());
+    let output_dir = Path::new(&output_dir);
+    fs::create_dir_all(output_dir)?;
+
+    let mut manifest = Vec::new();
+    writeln!(
+        manifest,
+        "# Test vectors generated by deflate {}",
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(
+        manifest,
+        "# output\tinput\toptions\tformat\tinput_size\tinput_adler32\tinput_crc32\t\
+         output_size\toutput_crc32"
+    )?;
+
+    for (input_name, input) in inputs() {
+        let input_file = format!("{}.bin", input_name);
+        fs::write(output_dir.join(&input_file), &input)?;
+        let input_adler32 = RollingAdler32::from_buffer(&input).hash();
+        let input_crc32 = crc32(&input);
+
+        for (options_name, options) in options() {
+            let gzip_header = GzBuilder::new().mtime(0).os(FileSystemType::Unix);
+            let outputs = [
+                ("deflate", "deflate", deflate_bytes_conf(&input, options)),
+                ("zlib", "zlib", deflate_bytes_zlib_conf(&input, options)),
+                (
+                    "gzip",
+                    "gz",
+                    deflate_bytes_gzip_conf(&input, options, gzip_header),
+                ),
+            ];
+            for (format, extension, output) in outputs.iter() {
+                let output_file = format!("{}.{}.{}", input_name, options_name, extension);
+                fs::write(output_dir.join(&output_file), output)?;
+                writeln!(
+                    manifest,
+                    "{}\t{}\t{}\t{}\t{}\t{:08x}\t{:08x}\t{}\t{:08x}",
+                    output_file,
+                    input_file,
+                    options_name,
+                    format,
+                    input.len(),
+                    input_adler32,
+                    input_crc32,
+                    output.len(),
+                    crc32(output)
+                )?;
+            }
+        }
+    }
+
+    fs::write(output_dir.join("MANIFEST"), manifest)
+}
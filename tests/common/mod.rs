@@ -0,0 +1,28 @@
+//! Helpers shared by the integration tests, the unit tests and the test vector generator.
+
+/// A simple xorshift generator, used for pseudo-random but reproducible test data.
+pub struct XorShift(u32);
+
+impl XorShift {
+    pub fn new() -> XorShift {
+        XorShift(0x2545_f491)
+    }
+}
+
+impl Iterator for XorShift {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        Some(x)
+    }
+}
+
+/// Generate `len` bytes of pseudo-random, incompressible data.
+pub fn random_data(len: usize) -> Vec<u8> {
+    XorShift::new().take(len).map(|x| x as u8).collect()
+}
@@ -0,0 +1,103 @@
+//! Tests for finding all the match candidates at each position through `MatchFinder`.
+
+extern crate deflate;
+
+mod common;
+
+use deflate::MatchFinder;
+use std::io::Read;
+
+const WINDOW_SIZE: usize = 32 * 1024;
+
+fn get_test_data() -> Vec<u8> {
+    let mut input = Vec::new();
+    std::fs::File::open("tests/pg11.txt")
+        .unwrap()
+        .read_to_end(&mut input)
+        .unwrap();
+    input
+}
+
+/// The closest of the longest matches at `position`, by checking every distance.
+fn brute_force_longest(data: &[u8], position: usize) -> Option<(usize, usize)> {
+    let max_length = (data.len() - position).min(258);
+    let mut best = None;
+    let mut best_length = 2;
+    for distance in 1..=position.min(WINDOW_SIZE) {
+        let length = (0..max_length)
+            .take_while(|&n| data[position + n] == data[position - distance + n])
+            .count();
+        if length > best_length {
+            best_length = length;
+            best = Some((length, distance));
+        }
+    }
+    best
+}
+
+#[test]
+fn matches_are_valid() {
+    // Long enough for the hash chains to have to slide a few times.
+    let data = get_test_data();
+    let mut finder = MatchFinder::new(&data, 128);
+    let mut found = 0;
+    for position in 0..data.len() {
+        let matches = finder.matches_at(position, 8);
+        assert!(matches.len() <= 8);
+        for (n, &(length, distance)) in matches.iter().enumerate() {
+            assert!((3..=258).contains(&length));
+            assert!((1..=WINDOW_SIZE).contains(&distance) && distance <= position);
+            let start = position - distance;
+            assert_eq!(
+                data[position..position + length],
+                data[start..start + length]
+            );
+            if n > 0 {
+                assert!(length < matches[n - 1].0 && distance < matches[n - 1].1);
+            }
+        }
+        if position > 3 * WINDOW_SIZE && !matches.is_empty() {
+            found += 1;
+        }
+    }
+    // Text has matches at most positions, also after sliding.
+    assert!(found > (data.len() - 3 * WINDOW_SIZE) / 2);
+}
+
+#[test]
+fn finds_longest_match() {
+    // Few different bytes, so there are a lot of matches of different lengths.
+    let data: Vec<u8> = common::XorShift::new()
+        .take(3000)
+        .map(|x| b"abcd"[x as usize % 4])
+        .collect();
+    let mut finder = MatchFinder::new(&data, u16::MAX);
+    for position in 0..data.len() {
+        let matches = finder.matches_at(position, usize::MAX);
+        assert_eq!(
+            matches.first().copied(),
+            brute_force_longest(&data, position),
+            "position {}",
+            position
+        );
+    }
+}
+
+#[test]
+fn few_matches_in_random_data() {
+    let data = common::random_data(20000);
+    let mut finder = MatchFinder::new(&data, 4096);
+    let found = (0..data.len())
+        .filter(|&position| !finder.matches_at(position, 8).is_empty())
+        .count();
+    assert!(found < 100, "{} matches in random data", found);
+}
+
+#[test]
+#[should_panic(expected = "Positions have to be passed in increasing order!")]
+fn decreasing_position() {
+    let data = get_test_data();
+    let mut finder = MatchFinder::new(&data, 128);
+    finder.matches_at(1000, 4);
+    finder.matches_at(999, 4);
+}
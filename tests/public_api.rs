@@ -0,0 +1,108 @@
+//! Checks that the core public API keeps compiling with the same signatures, so accidental
+//! breaking changes show up as test failures rather than in downstream crates.
+//!
+//! This covers the items that are considered stable: the compression settings, the encoders
+//! and the one-shot functions. Adding items is fine, but changing anything here requires a new
+//! major version.
+
+// Spelling out the full signatures is the point here.
+#![allow(clippy::type_complexity)]
+
+extern crate deflate;
+#[cfg(feature = "gzip")]
+extern crate gzip_header;
+
+use std::io::{self, Write};
+
+use deflate::write::{DeflateEncoder, ZlibEncoder};
+use deflate::{Compression, CompressionOptions, MatchingType, SpecialOptions};
+
+#[test]
+fn compression_settings() {
+    let levels = [Compression::Fast, Compression::Default, Compression::Best];
+    for &level in &levels {
+        let options: CompressionOptions = level.into();
+        let _ = options;
+    }
+    assert!(Compression::default() == Compression::Default);
+
+    let presets: [fn() -> CompressionOptions; 4] = [
+        CompressionOptions::fast,
+        CompressionOptions::high,
+        CompressionOptions::huffman_only,
+        CompressionOptions::rle,
+    ];
+    for preset in &presets {
+        let _ = preset();
+    }
+
+    let options = CompressionOptions {
+        max_hash_checks: 128,
+        lazy_if_less_than: 32,
+        matching_type: MatchingType::Lazy,
+        special: SpecialOptions::Normal,
+        ..CompressionOptions::default()
+    };
+    let _: Result<(), deflate::ConfigError> = options.validate();
+}
+
+#[test]
+fn one_shot_functions() {
+    let plain: [fn(&[u8]) -> Vec<u8>; 2] = [deflate::deflate_bytes, deflate::deflate_bytes_zlib];
+    let conf: [fn(&[u8], Compression) -> Vec<u8>; 2] = [
+        deflate::deflate_bytes_conf::<Compression>,
+        deflate::deflate_bytes_zlib_conf::<Compression>,
+    ];
+    let _: fn(&[u8], CompressionOptions) -> Vec<u8> =
+        deflate::deflate_bytes_conf::<CompressionOptions>;
+    for f in &plain {
+        assert!(!f(b"data").is_empty());
+    }
+    for f in &conf {
+        assert!(!f(b"data", Compression::Best).is_empty());
+    }
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn one_shot_functions_gzip() {
+    let _: fn(&[u8]) -> Vec<u8> = deflate::deflate_bytes_gzip;
+    let _: fn(&[u8], Compression, gzip_header::GzBuilder) -> Vec<u8> =
+        deflate::deflate_bytes_gzip_conf::<Compression>;
+}
+
+/// Check the methods shared by all the encoders.
+macro_rules! check_encoder {
+    ($encoder:ident) => {{
+        let new: fn(Vec<u8>, Compression) -> $encoder<Vec<u8>> = $encoder::new::<Compression>;
+        let finish: fn($encoder<Vec<u8>>) -> io::Result<Vec<u8>> = $encoder::finish;
+        let try_finish: fn(&mut $encoder<Vec<u8>>) -> io::Result<()> = $encoder::try_finish;
+        let reset: fn(&mut $encoder<Vec<u8>>, Vec<u8>) -> io::Result<Vec<u8>> = $encoder::reset;
+
+        let mut encoder = new(Vec::new(), Compression::Default);
+        encoder.write_all(b"data").unwrap();
+        encoder.flush().unwrap();
+        try_finish(&mut encoder).unwrap();
+        let first = reset(&mut encoder, Vec::new()).unwrap();
+        encoder.write_all(b"data").unwrap();
+        encoder.flush().unwrap();
+        assert!(finish(encoder).unwrap() == first);
+    }};
+}
+
+#[test]
+fn encoders() {
+    check_encoder!(DeflateEncoder);
+    check_encoder!(ZlibEncoder);
+    let _: fn(&ZlibEncoder<Vec<u8>>) -> u32 = ZlibEncoder::checksum;
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn encoders_gzip() {
+    use deflate::write::GzEncoder;
+    check_encoder!(GzEncoder);
+    let _: fn(gzip_header::GzBuilder, Vec<u8>, Compression) -> GzEncoder<Vec<u8>> =
+        GzEncoder::from_builder::<Compression>;
+    let _: fn(&GzEncoder<Vec<u8>>) -> u32 = GzEncoder::checksum;
+}
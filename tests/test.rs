@@ -4,6 +4,8 @@ extern crate flate2;
 use deflate::CompressionOptions;
 use std::io::{Read, Write};
 
+mod common;
+
 fn get_test_file_data(name: &str) -> Vec<u8> {
     use std::fs::File;
     let mut input = Vec::new();
@@ -108,7 +110,37 @@ fn rle() {
     assert!(test_data == decompressed);
 }
 
+// Data repeating with a period of exactly the window size, written in small chunks, should
+// compress using matches at the maximum distance, also after the window has been slid.
 #[test]
+fn max_distance_streaming() {
+    use deflate::write::DeflateEncoder;
+    const WINDOW_SIZE: usize = 32768;
+
+    let window = common::random_data(WINDOW_SIZE);
+    let mut data = Vec::new();
+    for _ in 0..5 {
+        data.extend_from_slice(&window);
+    }
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+    for chunk in data.chunks(1000) {
+        encoder.write_all(chunk).unwrap();
+    }
+    let compressed = encoder.finish().unwrap();
+    assert!(compressed.len() < WINDOW_SIZE * 2);
+
+    let decompressed = {
+        let mut d = flate2::read::DeflateDecoder::new(compressed.as_slice());
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        out
+    };
+    assert!(decompressed == data);
+}
+
+#[test]
+#[allow(clippy::unused_io_amount)]
 fn issue_26() {
     use deflate::write::ZlibEncoder;
     let fp = Vec::new();
@@ -122,6 +154,7 @@ fn issue_26() {
 
 #[cfg(feature = "gzip")]
 #[test]
+#[allow(clippy::unused_io_amount)]
 fn issue_26_gzip() {
     use deflate::write::DeflateEncoder;
     let fp = Vec::new();
@@ -132,3 +165,68 @@ fn issue_26_gzip() {
     fp.write(&[0]).unwrap();
     fp.write(&[0, 0]).unwrap();
 }
+
+/// The default amount, in percent, the output of a level is allowed to be larger than that of the
+/// same level in zlib (via flate2) in `level_ratio_parity`.
+///
+/// This is kept close to the current numbers, so a change to the heuristics that makes a level
+/// noticeably worse fails the test, rather than going unnoticed. If a change is intentional,
+/// the budget for that corpus and level has to be adjusted.
+const RATIO_BUDGET: f64 = 1.0;
+
+/// Slack in bytes on top of the ratio budget, so tiny inputs where a byte is a large fraction of
+/// the output don't fail the test.
+const RATIO_SLACK: usize = 4;
+
+/// Structured binary data, consisting of records with some fields that change slowly and some
+/// that are noisy.
+fn get_record_data() -> Vec<u8> {
+    let mut data = Vec::new();
+    for (i, x) in (0..20000u32).zip(common::XorShift::new()) {
+        data.extend_from_slice(&i.to_le_bytes());
+        data.extend_from_slice(&(x % 64).to_le_bytes());
+        data.extend_from_slice(b"rec");
+        data.push((i / 100) as u8);
+    }
+    data
+}
+
+#[test]
+fn level_ratio_parity() {
+    use deflate::deflate_bytes_conf;
+    use flate2::write::DeflateEncoder;
+    use std::convert::TryFrom;
+
+    const B: f64 = RATIO_BUDGET;
+    // Budgets for levels 1-9 for each corpus.
+    let corpora = [
+        ("pg11.txt", get_test_file_data("tests/pg11.txt"), [B; 9]),
+        ("short.bin", get_test_file_data("tests/short.bin"), [B; 9]),
+        // Greedy matching with more than one hash check tends to pick long, far away matches
+        // over closer short ones here, which zlib avoids by not inserting the strings inside long
+        // matches into the hash table at this level.
+        ("records", get_record_data(), [B, 7.0, B, B, B, B, B, B, B]),
+    ];
+
+    let mut failures = Vec::new();
+    for (name, data, budgets) in &corpora {
+        for (level, budget) in (1..=9u8).zip(budgets.iter()) {
+            let options = CompressionOptions::try_from(level).unwrap();
+            let ours = deflate_bytes_conf(data, options).len();
+            let zlib = {
+                let mut e = DeflateEncoder::new(Vec::new(), flate2::Compression::new(level.into()));
+                e.write_all(data).unwrap();
+                e.finish().unwrap().len()
+            };
+            let allowed = (zlib as f64 * (1.0 + budget / 100.0)) as usize + RATIO_SLACK;
+            if ours > allowed {
+                failures.push((*name, level, ours, zlib));
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "Levels over their ratio budget (corpus, level, size, zlib size): {:?}",
+        failures
+    );
+}
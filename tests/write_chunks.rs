@@ -0,0 +1,165 @@
+//! Tests checking that the encoders write their output to the wrapped writer in large chunks
+//! rather than a few bytes at a time, which matters when writing to files or sockets without
+//! buffering.
+
+extern crate deflate;
+extern crate flate2;
+
+use deflate::write::{DeflateEncoder, ZlibEncoder};
+use deflate::{Compression, CompressionOptions};
+use std::io::{self, Read, Write};
+
+/// The size of the chunks the encoders collect their output in before writing it out.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// A writer recording the size of every call to `write`.
+#[derive(Default)]
+struct RecordingSink {
+    data: Vec<u8>,
+    writes: Vec<usize>,
+}
+
+impl Write for RecordingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        assert!(!buf.is_empty(), "Empty write!");
+        self.writes.push(buf.len());
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RecordingSink {
+    /// Check that the data was written in as few calls as the chunk size allows, with the output
+    /// of each of `flushes` flushes, the end of the stream, and the trailer, if any, each
+    /// written at once.
+    fn check_chunks(&self, flushes: usize, has_trailer: bool) {
+        let end_writes = 1 + usize::from(has_trailer);
+        let expected_max = self.data.len() / CHUNK_SIZE + flushes + end_writes;
+        assert!(
+            self.writes.len() <= expected_max,
+            "{} writes for {} bytes with {} flushes: {:?}",
+            self.writes.len(),
+            self.data.len(),
+            flushes,
+            self.writes
+        );
+        if flushes == 0 {
+            // Apart from the end of the stream, all the writes are whole chunks.
+            let end = self.writes.len().saturating_sub(end_writes);
+            assert!(
+                self.writes[..end].iter().all(|&len| len >= CHUNK_SIZE),
+                "{:?}",
+                self.writes
+            );
+        }
+    }
+}
+
+fn get_test_data() -> Vec<u8> {
+    let mut input = Vec::new();
+    std::fs::File::open("tests/pg11.txt")
+        .unwrap()
+        .read_to_end(&mut input)
+        .unwrap();
+    // Make the data long enough to need several chunks of output.
+    input.repeat(4)
+}
+
+fn decompress(compressed: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    flate2::read::DeflateDecoder::new(compressed)
+        .read_to_end(&mut out)
+        .unwrap();
+    out
+}
+
+fn decompress_zlib(compressed: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    flate2::read::ZlibDecoder::new(compressed)
+        .read_to_end(&mut out)
+        .unwrap();
+    out
+}
+
+fn options() -> Vec<CompressionOptions> {
+    vec![
+        Compression::Fast.into(),
+        CompressionOptions::high(),
+        CompressionOptions::huffman_only(),
+    ]
+}
+
+#[test]
+fn deflate_write_chunks() {
+    let data = get_test_data();
+    for options in options() {
+        // Writing the input a byte at a time should not make the output come out a byte at a
+        // time.
+        for &input_len in &[1, 100, 64 * 1024, data.len()] {
+            let mut encoder = DeflateEncoder::new(RecordingSink::default(), options);
+            for chunk in data.chunks(input_len) {
+                encoder.write_all(chunk).unwrap();
+            }
+            let sink = encoder.finish().unwrap();
+            sink.check_chunks(0, false);
+            assert!(decompress(&sink.data) == data);
+        }
+    }
+}
+
+#[test]
+fn zlib_write_chunks() {
+    let data = get_test_data();
+    for options in options() {
+        for &input_len in &[1, 1000, data.len()] {
+            let mut encoder = ZlibEncoder::new(RecordingSink::default(), options);
+            for chunk in data.chunks(input_len) {
+                encoder.write_all(chunk).unwrap();
+            }
+            let sink = encoder.finish().unwrap();
+            sink.check_chunks(0, true);
+            assert!(decompress_zlib(&sink.data) == data);
+        }
+    }
+}
+
+#[test]
+fn flush_write_chunks() {
+    let data = get_test_data();
+    let flush_every = 50_000;
+    let mut encoder = ZlibEncoder::new(RecordingSink::default(), Compression::Default);
+    let mut flushes = 0;
+    for chunk in data.chunks(flush_every) {
+        for piece in chunk.chunks(10) {
+            encoder.write_all(piece).unwrap();
+        }
+        encoder.flush().unwrap();
+        flushes += 1;
+    }
+    let sink = encoder.finish().unwrap();
+    // Each flush outputs everything compressed so far in one go.
+    sink.check_chunks(flushes, true);
+    assert!(decompress_zlib(&sink.data) == data);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_write_chunks() {
+    use deflate::write::GzEncoder;
+    let data = get_test_data();
+    let mut encoder = GzEncoder::new(RecordingSink::default(), Compression::Default);
+    for chunk in data.chunks(10) {
+        encoder.write_all(chunk).unwrap();
+    }
+    let sink = encoder.finish().unwrap();
+    sink.check_chunks(0, true);
+    let mut out = Vec::new();
+    flate2::read::GzDecoder::new(&sink.data[..])
+        .read_to_end(&mut out)
+        .unwrap();
+    assert!(out == data);
+}